@@ -0,0 +1,64 @@
+//! Authorize clients with your own service instead of the whitelist file.
+//!
+//! `DirectoryAuthorizer` stands in for an LDAP or REST lookup: it maps each
+//! connect key hash to the client key fingerprints allowed to use it, and
+//! takes a moment to answer like a remote call would. A loopback server uses
+//! it, then one allowed and one unknown client try to send a file.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use rsa::RsaPublicKey;
+use stl_finapp::auth::{hash_connect_key, AuthorizeFuture, Authorizer};
+use stl_finapp::client::Client;
+use stl_finapp::crypto::{fingerprint, KeyPair};
+use stl_finapp::error::Result;
+use stl_finapp::server::Server;
+
+struct DirectoryAuthorizer {
+    allowed: HashMap<String, HashSet<String>>,
+}
+
+impl Authorizer for DirectoryAuthorizer {
+    fn is_authorized<'a>(&'a self, connect_key_hash: &'a str, peer_key: &'a RsaPublicKey) -> AuthorizeFuture<'a> {
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let peer = fingerprint(peer_key)?;
+            Ok(self.allowed.get(connect_key_hash).is_some_and(|peers| peers.contains(&peer)))
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let trusted_keys = KeyPair::generate_async().await?;
+    let unknown_keys = KeyPair::generate_async().await?;
+
+    let authorizer = DirectoryAuthorizer {
+        allowed: HashMap::from([(
+            hash_connect_key("treasury"),
+            HashSet::from([trusted_keys.fingerprint()?]),
+        )]),
+    };
+
+    // No whitelist file: the authorizer alone decides who may connect
+    let messages_dir = dir.path().join("messages");
+    let server = Server::with_authorizer_only(0, Arc::new(authorizer), KeyPair::generate_async().await?, &messages_dir.to_string_lossy())
+        .spawn()
+        .await?;
+
+    let trusted = Client::new("127.0.0.1", server.port(), trusted_keys);
+    let saved_as = trusted.send_bytes(b"ACC-001,100.00\n", "balances.csv", "treasury", None, None).await?;
+    println!("Trusted client stored {}", saved_as);
+
+    let unknown = Client::new("127.0.0.1", server.port(), unknown_keys);
+    match unknown.send_bytes(b"ACC-001,999.00\n", "balances.csv", "treasury", None, None).await {
+        Ok(_) => println!("Unknown client was let in?!"),
+        Err(e) => println!("Unknown client refused: {}", e),
+    }
+
+    server.shutdown();
+    server.wait().await?;
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//! Reuse the finapp handshake for a custom request/response protocol.
+//!
+//! Starts a loopback server that authenticates the client against a
+//! temporary whitelist, then exchanges one `Custom` message each way.
+
+use tokio::net::{TcpListener, TcpStream};
+use stl_finapp::auth::{Whitelist, WhitelistAuthorizer};
+use stl_finapp::crypto::{fingerprint, KeyPair};
+use stl_finapp::error::Result;
+use stl_finapp::protocol::{authenticate, AuthenticatedChannel, HandshakeOptions, Message, MessageType};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt"))?;
+    whitelist.add("example-key", None)?;
+    let server_keys = KeyPair::generate_async().await?;
+    let client_keys = KeyPair::generate_async().await?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await?;
+        let mut channel = AuthenticatedChannel::accept(stream, &WhitelistAuthorizer::new(whitelist), &server_keys, &HandshakeOptions::default()).await?;
+        let request = channel.receive().await?;
+        request.expect_type(MessageType::Custom)?;
+        let reply = format!("balance for {}: 100.00", String::from_utf8_lossy(&request.payload));
+        channel.send(&Message::new(MessageType::Custom, reply.into_bytes())).await
+    });
+
+    let stream = TcpStream::connect(addr).await?;
+    let mut channel = authenticate(stream, "example-key", &client_keys).await?;
+    println!("Authenticated to server {}", fingerprint(channel.peer_public_key())?);
+
+    channel.send(&Message::new(MessageType::Custom, b"ACC-001".to_vec())).await?;
+    let reply = channel.receive().await?;
+    println!("{}", String::from_utf8_lossy(&reply.payload));
+
+    server.await.expect("server task panicked")?;
+    Ok(())
+}
@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use stl_finapp::protocol::{FrameCodec, Message};
+
+// Feed arbitrary bytes to both frame readers: they must return frames within
+// the limit or an error, never panic or allocate past the limit.
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    runtime.block_on(async {
+        for codec in [FrameCodec::control(), FrameCodec::data().with_max_len(1 << 20)] {
+            let mut reader = data;
+            while let Ok(Some(frame)) = codec.read_frame_or_eof(&mut reader).await {
+                assert!(frame.len() <= codec.max_len());
+                let _ = Message::from_bytes(&frame);
+            }
+        }
+    });
+});
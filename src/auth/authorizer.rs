@@ -0,0 +1,74 @@
+use std::future::Future;
+use std::pin::Pin;
+use rsa::RsaPublicKey;
+use crate::error::Result;
+use super::whitelist::{Whitelist, WhitelistEntry};
+
+/// Future returned by [`Authorizer::is_authorized`]
+pub type AuthorizeFuture<'a> = Pin<Box<dyn Future<Output = Result<bool>> + Send + 'a>>;
+
+/// Decides whether a client may connect, given its connect key hash and public key
+///
+/// The server asks only once the client's signature over this connection's
+/// challenge has been verified, so `peer_key` is known to be held by the
+/// client and an implementation may call out to a directory or
+/// authorization service without unauthenticated peers triggering lookups. Returning an error refuses the client
+/// just like `false`, but is logged as the authorizer failing rather than as
+/// a bad key. [`WhitelistAuthorizer`] is the flat-file check the server uses
+/// by default; closures `Fn(&str, &RsaPublicKey) -> Result<bool>` work too.
+pub trait Authorizer: Send + Sync {
+    /// Whether the client holding `peer_key` may connect with `connect_key_hash`
+    fn is_authorized<'a>(&'a self, connect_key_hash: &'a str, peer_key: &'a RsaPublicKey) -> AuthorizeFuture<'a>;
+
+    /// Whitelist entry whose filename pattern and quota apply to an authorized key
+    ///
+    /// Authorizers without per-key restrictions keep the default of `None`.
+    fn entry(&self, _connect_key_hash: &str) -> Option<WhitelistEntry> {
+        None
+    }
+}
+
+/// Authorizes the connect keys listed in a [`Whitelist`]
+#[derive(Clone)]
+pub struct WhitelistAuthorizer {
+    whitelist: Whitelist,
+}
+
+impl WhitelistAuthorizer {
+    /// Authorize against the entries of `whitelist`
+    pub fn new(whitelist: Whitelist) -> Self {
+        Self { whitelist }
+    }
+
+    /// The wrapped whitelist
+    pub fn whitelist(&self) -> &Whitelist {
+        &self.whitelist
+    }
+}
+
+impl From<Whitelist> for WhitelistAuthorizer {
+    fn from(whitelist: Whitelist) -> Self {
+        Self::new(whitelist)
+    }
+}
+
+impl Authorizer for WhitelistAuthorizer {
+    fn is_authorized<'a>(&'a self, connect_key_hash: &'a str, _peer_key: &'a RsaPublicKey) -> AuthorizeFuture<'a> {
+        let found = self.whitelist.find_by_hash(connect_key_hash).is_some();
+        Box::pin(async move { Ok(found) })
+    }
+
+    fn entry(&self, connect_key_hash: &str) -> Option<WhitelistEntry> {
+        self.whitelist.find_by_hash(connect_key_hash).cloned()
+    }
+}
+
+impl<F> Authorizer for F
+where
+    F: Fn(&str, &RsaPublicKey) -> Result<bool> + Send + Sync,
+{
+    fn is_authorized<'a>(&'a self, connect_key_hash: &'a str, peer_key: &'a RsaPublicKey) -> AuthorizeFuture<'a> {
+        let authorized = self(connect_key_hash, peer_key);
+        Box::pin(async move { authorized })
+    }
+}
@@ -0,0 +1,143 @@
+use std::path::{Path, PathBuf};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use crate::error::{AppError, Result};
+
+/// File name of the known hosts file inside an identity directory
+pub const KNOWN_HOSTS_FILE: &str = "known_hosts";
+
+/// One pinned server key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KnownHost {
+    /// Fingerprint of the local key that made the pin
+    pub local: String,
+    /// Server address as it was connected to
+    pub host: String,
+    /// Fingerprint of the server key seen on first contact
+    pub fingerprint: String,
+}
+
+/// What [`KnownHosts::check`] found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostCheck {
+    /// The server presented the key pinned for it
+    Known,
+    /// First contact: the key was pinned
+    Added,
+}
+
+/// Trust-on-first-use pins of server keys
+///
+/// Each line is `<local fingerprint> <host> <server fingerprint>`. Pins are
+/// namespaced by the local key, so several identities can share one file and
+/// still keep independent trust.
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: Vec<KnownHost>,
+}
+
+impl KnownHosts {
+    /// Load pins from `path`; a missing file has none
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(AppError::Config(format!("Failed to read {}: {}", path.display(), e))),
+        };
+
+        let hosts = text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim().starts_with('#'))
+            .map(|(index, line)| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [local, host, fingerprint] => Ok(KnownHost {
+                    local: local.to_string(),
+                    host: host.to_string(),
+                    fingerprint: fingerprint.to_string(),
+                }),
+                _ => Err(AppError::Config(format!(
+                    "{}:{}: expected '<local fingerprint> <host> <server fingerprint>'",
+                    path.display(),
+                    index + 1
+                ))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { path: path.to_path_buf(), hosts })
+    }
+
+    /// Path the pins are stored in
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Server fingerprint pinned for `host` by the local key `local`
+    pub fn pinned(&self, local: &str, host: &str) -> Option<&str> {
+        self.hosts
+            .iter()
+            .find(|h| h.local == local && h.host == host)
+            .map(|h| h.fingerprint.as_str())
+    }
+
+    /// Compare a server's key with its pin, pinning it on first contact
+    pub fn check(&mut self, local: &str, host: &str, fingerprint: &str) -> Result<HostCheck> {
+        match self.pinned(local, host) {
+            Some(pinned) if pinned == fingerprint => Ok(HostCheck::Known),
+            Some(pinned) => Err(AppError::Auth(format!(
+                "Server key for {} changed: pinned {}, got {}. If the server rotated its key, remove its line from {}",
+                host,
+                pinned,
+                fingerprint,
+                self.path.display()
+            ))),
+            None => {
+                let host = KnownHost {
+                    local: local.to_string(),
+                    host: host.to_string(),
+                    fingerprint: fingerprint.to_string(),
+                };
+                self.append(&host)?;
+                self.hosts.push(host);
+                Ok(HostCheck::Added)
+            }
+        }
+    }
+
+    fn append(&self, host: &KnownHost) -> Result<()> {
+        if let Some(dir) = self.path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)
+                .map_err(|e| AppError::Config(format!("Failed to create {}: {}", dir.display(), e)))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| AppError::Config(format!("Failed to open {}: {}", self.path.display(), e)))?;
+        writeln!(file, "{} {} {}", host.local, host.host, host.fingerprint)
+            .map_err(|e| AppError::Config(format!("Failed to write {}: {}", self.path.display(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pins_are_separate_per_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KNOWN_HOSTS_FILE);
+        let mut hosts = KnownHosts::load(&path).unwrap();
+
+        assert_eq!(hosts.check("alice", "10.0.0.5:8080", "server-1").unwrap(), HostCheck::Added);
+        assert_eq!(hosts.check("alice", "10.0.0.5:8080", "server-1").unwrap(), HostCheck::Known);
+
+        // Another identity on the same host does not see alice's pin
+        let mut reloaded = KnownHosts::load(&path).unwrap();
+        assert_eq!(reloaded.pinned("bob", "10.0.0.5:8080"), None);
+        assert_eq!(reloaded.check("bob", "10.0.0.5:8080", "server-2").unwrap(), HostCheck::Added);
+
+        let err = reloaded.check("alice", "10.0.0.5:8080", "server-2").unwrap_err();
+        assert!(err.to_string().contains("changed"), "{}", err);
+        assert_eq!(KnownHosts::load(&path).unwrap().pinned("bob", "10.0.0.5:8080"), Some("server-2"));
+    }
+}
@@ -1,5 +1,11 @@
+pub mod authorizer;
+pub mod known_hosts;
+pub mod quota;
 pub mod token;
 pub mod whitelist;
 
-pub use whitelist::Whitelist;
-pub use token::{AuthToken, hash_connect_key};
+pub use authorizer::{Authorizer, AuthorizeFuture, WhitelistAuthorizer};
+pub use whitelist::{Whitelist, WhitelistEntry, WhitelistChange, LintIssue, HASHED_KEY_PREFIX, MIN_CONNECT_KEY_LEN};
+pub use token::{AuthToken, hash_connect_key, TOKEN_LIFETIME};
+pub use known_hosts::{KnownHosts, KnownHost, HostCheck, KNOWN_HOSTS_FILE};
+pub use quota::{Quota, QuotaWindow, parse_size};
@@ -0,0 +1,121 @@
+use std::fmt;
+
+/// Length of the window a [`Quota`] is counted over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuotaWindow {
+    /// Clock hours (UTC)
+    Hour,
+    /// Calendar days (UTC)
+    Day,
+}
+
+impl QuotaWindow {
+    /// Window length in seconds
+    pub fn secs(self) -> i64 {
+        match self {
+            QuotaWindow::Hour => 3600,
+            QuotaWindow::Day => 86_400,
+        }
+    }
+}
+
+impl fmt::Display for QuotaWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            QuotaWindow::Hour => "hour",
+            QuotaWindow::Day => "day",
+        })
+    }
+}
+
+/// How much one peer may send per window
+///
+/// Written in a whitelist entry as `quota=<limits>/<window>`, where limits
+/// are a byte size, a file count or both separated by a comma:
+/// `acme;quota=5GiB/day`, `acme;quota=5GiB,1000files/day`,
+/// `acme;quota=50files/hour`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    /// Bytes stored per window
+    pub max_bytes: Option<u64>,
+    /// Files stored per window
+    pub max_files: Option<u64>,
+    /// Window the limits apply to
+    pub window: QuotaWindow,
+}
+
+impl Quota {
+    /// Parse the value of a `quota=` option
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (limits, window) = spec
+            .split_once('/')
+            .ok_or_else(|| format!("quota '{}' has no window, e.g. 5GiB/day", spec))?;
+        let window = match window.trim() {
+            "hour" => QuotaWindow::Hour,
+            "day" => QuotaWindow::Day,
+            other => return Err(format!("unknown quota window '{}', expected hour or day", other)),
+        };
+
+        let mut quota = Quota { max_bytes: None, max_files: None, window };
+        for limit in limits.split(',').map(str::trim) {
+            if let Some(count) = limit.strip_suffix("files") {
+                let count = count.trim().parse().map_err(|_| format!("invalid file count '{}'", limit))?;
+                quota.max_files = Some(count);
+            } else {
+                quota.max_bytes = Some(parse_size(limit)?);
+            }
+        }
+        Ok(quota)
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limits: Vec<String> = self
+            .max_bytes
+            .map(|b| format!("{} bytes", b))
+            .into_iter()
+            .chain(self.max_files.map(|n| format!("{} files", n)))
+            .collect();
+        write!(f, "{} per {}", limits.join(", "), self.window)
+    }
+}
+
+/// Parse a byte size such as `512`, `10MB` or `5GiB`
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let split = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: u64 = number.parse().map_err(|_| format!("invalid size '{}'", size))?;
+    let multiplier: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        other => return Err(format!("unknown size unit '{}' in '{}'", other, size)),
+    };
+    number.checked_mul(multiplier).ok_or_else(|| format!("size '{}' is too large", size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quota() {
+        let quota = Quota::parse("5GiB/day").unwrap();
+        assert_eq!(quota, Quota { max_bytes: Some(5 << 30), max_files: None, window: QuotaWindow::Day });
+
+        let quota = Quota::parse("10MB, 100files/hour").unwrap();
+        assert_eq!((quota.max_bytes, quota.max_files), (Some(10_000_000), Some(100)));
+        assert_eq!(quota.to_string(), "10000000 bytes, 100 files per hour");
+
+        assert!(Quota::parse("5GiB").is_err());
+        assert!(Quota::parse("5GiB/week").is_err());
+        assert!(Quota::parse("5 parsecs/day").is_err());
+    }
+}
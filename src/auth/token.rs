@@ -1,8 +1,12 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
 use rand::Rng;
 use crate::error::{AppError, Result};
+use crate::clock::{Clock, SystemClock};
+
+/// How long a token stays valid after it was created
+pub const TOKEN_LIFETIME: Duration = Duration::minutes(5);
 
 /// Authentication token for secure communication
 #[derive(Serialize, Deserialize, Debug)]
@@ -18,18 +22,26 @@ pub struct AuthToken {
 impl AuthToken {
     /// Create a new auth token
     pub fn new(connect_key: &str) -> Self {
+        Self::new_at(connect_key, &SystemClock)
+    }
+
+    /// Create a new auth token stamped with `clock`'s time
+    pub fn new_at(connect_key: &str, clock: &dyn Clock) -> Self {
         Self {
             connect_key_hash: hash_connect_key(connect_key),
-            timestamp: Utc::now(),
+            timestamp: clock.now(),
             nonce: generate_nonce(),
         }
     }
 
     /// Check if token is expired (5 minute window)
     pub fn is_valid_time(&self) -> bool {
-        let now = Utc::now();
-        let diff = now.signed_duration_since(self.timestamp);
-        diff.num_minutes() < 5
+        self.is_valid_at(&SystemClock)
+    }
+
+    /// Check the token against `clock`; it expires once `TOKEN_LIFETIME` has elapsed
+    pub fn is_valid_at(&self, clock: &dyn Clock) -> bool {
+        clock.now().signed_duration_since(self.timestamp) < TOKEN_LIFETIME
     }
 
     /// Verify the connect key matches
@@ -66,6 +78,7 @@ fn generate_nonce() -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
 
     #[test]
     fn test_token_creation() {
@@ -90,4 +103,16 @@ mod tests {
         let hash3 = hash_connect_key("different");
         assert_ne!(hash1, hash3);
     }
+
+    #[test]
+    fn test_token_expires_at_lifetime_boundary() {
+        let clock = MockClock::new(Utc::now());
+        let token = AuthToken::new_at("secret123", &clock);
+
+        clock.advance(TOKEN_LIFETIME - Duration::milliseconds(1));
+        assert!(token.is_valid_at(&clock));
+
+        clock.advance(Duration::milliseconds(1));
+        assert!(!token.is_valid_at(&clock));
+    }
 }
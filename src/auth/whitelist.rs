@@ -1,62 +1,477 @@
-use std::path::Path;
-use std::fs::{self, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
 use crate::error::{AppError, Result};
+use crate::auth::{hash_connect_key, Quota};
+
+/// Prefix marking a whitelist key stored as its SHA-256 hash
+pub const HASHED_KEY_PREFIX: &str = "sha256:";
+
+/// One whitelist line: a connect key plus optional restrictions
+///
+/// Restrictions follow the key separated by `;`, e.g.
+/// `acme-key;pattern=settlement_*.json` only lets that peer send files whose
+/// name matches the glob, and `acme-key;quota=5GiB/day` caps how much it may
+/// store per day. The key may be stored as `sha256:<hex>` instead of in
+/// plaintext. A trailing `# label` names the peer, e.g.
+/// `acme-key;pattern=settlement_*.json # Acme Settlements`; the `#` must
+/// follow whitespace so it cannot be part of the key.
+#[derive(Clone, Debug)]
+pub struct WhitelistEntry {
+    /// The connect key as written in the file (plaintext or `sha256:<hex>`)
+    pub key: String,
+    /// SHA-256 of the connect key, as sent in the handshake
+    pub key_hash: String,
+    /// Filenames this peer may send, if restricted
+    pub pattern: Option<glob::Pattern>,
+    /// How much this peer may store per window, if limited
+    pub quota: Option<Quota>,
+    /// 1-based line of the whitelist file holding the entry, if it came from one
+    pub line: Option<usize>,
+    /// Human-readable name of the peer, if the entry has one
+    pub label: Option<String>,
+}
+
+impl WhitelistEntry {
+    /// Parse a whitelist line
+    pub fn parse(line: &str) -> Result<Self> {
+        Self::parse_line(line).map_err(|(_, msg)| AppError::Auth(msg))
+    }
+
+    /// Parse a whitelist line, reporting errors with the 1-based column they occur at
+    fn parse_line(line: &str) -> std::result::Result<Self, (usize, String)> {
+        let (line, label) = split_label(line);
+        let label = match label {
+            Some(label) if label.trim().is_empty() => {
+                return Err((line.len() + 1, format!("Empty label in whitelist entry: {}", line.trim())));
+            }
+            label => label.map(|label| label.trim().to_string()),
+        };
+        let mut parts = line.split(';');
+        let key_part = parts.next().unwrap_or_default();
+        let key = key_part.trim();
+        if key.is_empty() {
+            return Err((1, format!("Whitelist entry has no connect key: {}", line)));
+        }
+        let key_column = key_part.len() - key_part.trim_start().len() + 1;
+
+        let mut entry = Self {
+            key: key.to_string(),
+            key_hash: key_hash(key).map_err(|msg| (key_column, msg))?,
+            pattern: None,
+            quota: None,
+            line: None,
+            label,
+        };
+
+        let mut offset = key_part.len() + 1;
+        for option in parts {
+            let column = offset + option.len() - option.trim_start().len() + 1;
+            offset += option.len() + 1;
+            match option.trim().split_once('=') {
+                Some(("pattern", pattern)) => {
+                    let pattern = glob::Pattern::new(pattern.trim())
+                        .map_err(|e| (column, format!("Invalid pattern in whitelist entry '{}': {}", key, e)))?;
+                    entry.pattern = Some(pattern);
+                }
+                Some(("quota", quota)) => {
+                    let quota = Quota::parse(quota.trim())
+                        .map_err(|e| (column, format!("Invalid quota in whitelist entry '{}': {}", key, e)))?;
+                    entry.quota = Some(quota);
+                }
+                _ => {
+                    return Err((
+                        column,
+                        format!("Unknown option '{}' in whitelist entry '{}'", option.trim(), key),
+                    ));
+                }
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Check whether this peer may send a file with the given name
+    pub fn allows(&self, filename: &str) -> bool {
+        self.pattern.as_ref().is_none_or(|p| p.matches(filename))
+    }
+
+    /// Whether the key is stored hashed rather than in plaintext
+    pub fn is_hashed(&self) -> bool {
+        self.key.starts_with(HASHED_KEY_PREFIX)
+    }
+
+    /// The label if the entry has one, otherwise the key as [`display_key`](Self::display_key) shows it
+    pub fn name(&self) -> String {
+        self.label.clone().unwrap_or_else(|| self.display_key())
+    }
+
+    /// The key for display: plaintext as written, hashed keys cut to a prefix
+    pub fn display_key(&self) -> String {
+        match self.key.strip_prefix(HASHED_KEY_PREFIX) {
+            Some(hash) => format!("{}{}...", HASHED_KEY_PREFIX, &hash[..hash.len().min(HASH_DISPLAY_LEN)]),
+            None => self.key.clone(),
+        }
+    }
+}
+
+/// Split a trailing `# label` off a whitelist line
+///
+/// Returns the line before the `#` and the text after it; a `#` only starts a
+/// label when it follows whitespace.
+fn split_label(line: &str) -> (&str, Option<&str>) {
+    let start = line
+        .match_indices('#')
+        .map(|(index, _)| index)
+        .find(|index| line[..*index].ends_with(char::is_whitespace));
+    match start {
+        Some(index) => (&line[..index], Some(&line[index + 1..])),
+        None => (line, None),
+    }
+}
+
+/// Hex digits of a hashed key shown by [`WhitelistEntry::display_key`]
+const HASH_DISPLAY_LEN: usize = 12;
+
+/// Hash of a whitelist key, taking `sha256:<hex>` keys as already hashed
+fn key_hash(key: &str) -> std::result::Result<String, String> {
+    let Some(hex) = key.strip_prefix(HASHED_KEY_PREFIX) else {
+        return Ok(hash_connect_key(key));
+    };
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid SHA-256 hash in whitelist entry: {}", key));
+    }
+    Ok(hex.to_ascii_lowercase())
+}
+
+/// Rewrite a whitelist line so its connect key is stored as `sha256:<hex>`
+///
+/// Restrictions and the label after the key are kept as they are.
+pub fn hash_entry_line(line: &str) -> Result<String> {
+    let entry = WhitelistEntry::parse(line)?;
+    let (before_label, _) = split_label(line);
+    let key_end = before_label.find(';').unwrap_or(before_label.len());
+    let rest = &line[before_label[..key_end].trim_end().len()..];
+    Ok(format!("{}{}{}", HASHED_KEY_PREFIX, entry.key_hash, rest.trim_end()))
+}
+
+/// Plaintext connect keys shorter than this are reported as weak by [`Whitelist::lint`]
+pub const MIN_CONNECT_KEY_LEN: usize = 12;
+
+/// A problem [`Whitelist::lint`] found in a whitelist file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LintIssue {
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column the problem starts at
+    pub column: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Extension appended to the whitelist path for the copy of its previous version
+pub const BACKUP_EXTENSION: &str = "bak";
+
+/// Where the previous version of the whitelist at `path` is kept
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, BACKUP_EXTENSION)
+}
+
+/// `path` with `.{suffix}` appended to its file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Take the exclusive edit lock for the whitelist at `path`
+///
+/// The lock is on a `.lock` file next to the whitelist and is released when
+/// the returned file is dropped, or by the OS if the process dies.
+fn lock_for_edit(path: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(with_suffix(path, "lock"))
+        .map_err(|e| AppError::Auth(format!("Failed to open whitelist lock: {}", e)))?;
+    lock.lock()
+        .map_err(|e| AppError::Auth(format!("Failed to lock whitelist: {}", e)))?;
+    Ok(lock)
+}
+
+/// Replace the whitelist at `path` with what `write` produces, keeping a `.bak`
+///
+/// The new contents go to a `.tmp` file that is synced and then renamed over
+/// the whitelist, so a reader or a crash mid-write sees either the old file or
+/// the new one. The temp file takes the whitelist's permissions first, so a
+/// file kept private stays private. The caller must hold the edit lock.
+fn replace_with(path: &Path, write: impl FnOnce(&mut File) -> io::Result<()>) -> Result<()> {
+    let staged = with_suffix(path, "tmp");
+    let permissions = fs::metadata(path).ok().map(|metadata| metadata.permissions());
+    let written = File::create(&staged).and_then(|mut file| {
+        if let Some(permissions) = permissions {
+            file.set_permissions(permissions)?;
+        }
+        write(&mut file)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&staged);
+        return Err(AppError::Auth(format!("Failed to write to whitelist: {}", e)));
+    }
+
+    if path.exists() {
+        fs::copy(path, backup_path(path))
+            .map_err(|e| AppError::Auth(format!("Failed to back up whitelist: {}", e)))?;
+    }
+    fs::rename(&staged, path)
+        .map_err(|e| AppError::Auth(format!("Failed to replace whitelist: {}", e)))
+}
+
+/// Read a whitelist file, refusing a missing path or a directory
+fn read_whitelist(path: &Path) -> Result<String> {
+    let metadata = fs::metadata(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => AppError::Config(format!("Whitelist {} does not exist", path.display())),
+        _ => AppError::Config(format!("Cannot read whitelist {}: {}", path.display(), e)),
+    })?;
+    if metadata.is_dir() {
+        return Err(AppError::Config(format!("Whitelist {} is a directory, not a file", path.display())));
+    }
+    fs::read_to_string(path).map_err(|e| AppError::Config(format!("Cannot read whitelist {}: {}", path.display(), e)))
+}
+
+/// Whitelist line for `connect_key` with `label` appended as `# label`
+fn labeled_line(connect_key: &str, label: Option<&str>) -> Result<String> {
+    let Some(label) = label else {
+        return Ok(connect_key.to_string());
+    };
+    let label = label.trim();
+    if label.is_empty() || label.contains(['\n', '\r']) {
+        return Err(AppError::Auth(format!("Invalid whitelist label: {:?}", label)));
+    }
+    if split_label(connect_key).1.is_some() {
+        return Err(AppError::Auth(format!("Whitelist entry already has a label: {}", connect_key)));
+    }
+    Ok(format!("{} # {}", connect_key, label))
+}
+
+/// Lines holding entries, with their 0-based index; blanks and `#` comments are skipped
+fn entry_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim().starts_with('#'))
+}
 
 /// Whitelist manager for connect keys
 #[derive(Clone)]
 pub struct Whitelist {
-    keys: Vec<String>,
+    entries: Vec<WhitelistEntry>,
     path: String,
 }
 
 impl Whitelist {
     /// Load whitelist from file
+    ///
+    /// A missing path is an error rather than an empty whitelist, so a
+    /// mistyped path cannot silently lock every peer out; see
+    /// [`Whitelist::load_or_create`].
     pub fn load(path: &Path) -> Result<Self> {
-        if !path.exists() {
-            return Self::create(path);
-        }
+        let text = read_whitelist(path)?;
 
-        let file = fs::File::open(path)
-            .map_err(|e| AppError::Auth(format!("Failed to open whitelist: {}", e)))?;
-        let reader = BufReader::new(file);
-
-        let keys: Vec<String> = reader
-            .lines()
-            .filter_map(|line| line.ok())
-            .map(|line| line.trim().to_string())
-            .filter(|line| !line.is_empty() && !line.starts_with('#'))
-            .collect();
+        // Parse errors name the file, line and column so a bad entry is easy to find
+        let entries = entry_lines(&text)
+            .map(|(index, line)| {
+                let entry = WhitelistEntry::parse_line(line).map_err(|(column, msg)| {
+                    AppError::Config(format!("{}:{}:{}: {}", path.display(), index + 1, column, msg))
+                })?;
+                Ok(WhitelistEntry { line: Some(index + 1), ..entry })
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(Self {
-            keys,
+            entries,
             path: path.to_string_lossy().to_string(),
         })
     }
 
-    /// Check if a connect key is whitelisted
+    /// Check a whitelist file without loading or changing it
+    ///
+    /// Reports every line that would fail to load, plaintext keys shorter
+    /// than [`MIN_CONNECT_KEY_LEN`], keys listed more than once (in
+    /// plaintext or hashed) and labels given to more than one entry, in file
+    /// order. An empty result means the file
+    /// is clean; a missing or unreadable file is an error.
+    pub fn lint(path: &Path) -> Result<Vec<LintIssue>> {
+        let text = read_whitelist(path)?;
+        let mut issues = Vec::new();
+        let mut first_seen = HashMap::new();
+        let mut labels_seen = HashMap::new();
+
+        for (index, line) in entry_lines(&text) {
+            let line_number = index + 1;
+            let entry = match WhitelistEntry::parse_line(line) {
+                Ok(entry) => entry,
+                Err((column, message)) => {
+                    issues.push(LintIssue { line: line_number, column, message });
+                    continue;
+                }
+            };
+            let key_column = line.len() - line.trim_start().len() + 1;
+            if !entry.is_hashed() && entry.key.chars().count() < MIN_CONNECT_KEY_LEN {
+                issues.push(LintIssue {
+                    line: line_number,
+                    column: key_column,
+                    message: format!("Weak connect key: shorter than {} characters", MIN_CONNECT_KEY_LEN),
+                });
+            }
+            match first_seen.entry(entry.key_hash) {
+                Entry::Occupied(first) => issues.push(LintIssue {
+                    line: line_number,
+                    column: key_column,
+                    message: format!("Duplicate connect key, already on line {}", first.get()),
+                }),
+                Entry::Vacant(slot) => {
+                    slot.insert(line_number);
+                }
+            }
+            if let Some(label) = entry.label {
+                match labels_seen.entry(label) {
+                    Entry::Occupied(first) => issues.push(LintIssue {
+                        line: line_number,
+                        column: split_label(line).0.len() + 1,
+                        message: format!("Duplicate label '{}', already on line {}", first.key(), first.get()),
+                    }),
+                    Entry::Vacant(slot) => {
+                        slot.insert(line_number);
+                    }
+                }
+            }
+        }
+        Ok(issues)
+    }
+
+    /// Load the whitelist at `path`, creating an empty one if there is none
+    pub fn load_or_create(path: &Path) -> Result<Self> {
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Self::create(path)
+        }
+    }
+
+    /// Check if a connect key is whitelisted, whether stored in plaintext or hashed
+    ///
+    /// `connect_key` may itself be given as `sha256:<hex>`.
     pub fn contains(&self, connect_key: &str) -> bool {
-        self.keys.iter().any(|k| k == connect_key)
+        key_hash(connect_key).is_ok_and(|hash| self.find_by_hash(&hash).is_some())
     }
 
-    /// Add a new connect key to the whitelist
-    pub fn add(&mut self, connect_key: &str) -> Result<()> {
-        if self.contains(connect_key) {
+    /// Add a new connect key (optionally with restrictions) to the whitelist
+    ///
+    /// `label` is written after the entry as `# label`. The file is rewritten
+    /// atomically and its previous version kept as `.bak`; see
+    /// [`Whitelist::restore`].
+    pub fn add(&mut self, connect_key: &str, label: Option<&str>) -> Result<()> {
+        let line = labeled_line(connect_key, label)?;
+        let mut entry = WhitelistEntry::parse(&line)?;
+        if self.find_by_hash(&entry.key_hash).is_some() {
             return Ok(());
         }
 
-        let mut file = OpenOptions::new()
-            .append(true)
-            .open(&self.path)
-            .map_err(|e| AppError::Auth(format!("Failed to open whitelist for writing: {}", e)))?;
-
-        writeln!(file, "{}", connect_key)
-            .map_err(|e| AppError::Auth(format!("Failed to write to whitelist: {}", e)))?;
+        let path = self.path().to_path_buf();
+        let _lock = lock_for_edit(&path)?;
+        // Start from the file as it is now, in case another process edited it
+        let mut text = fs::read_to_string(&path)
+            .map_err(|e| AppError::Auth(format!("Failed to read whitelist: {}", e)))?;
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        replace_with(&path, |file| {
+            file.write_all(text.as_bytes())?;
+            writeln!(file, "{}", line)
+        })?;
 
-        self.keys.push(connect_key.to_string());
+        entry.line = Some(text.lines().count() + 1);
+        self.entries.push(entry);
         Ok(())
     }
 
+    /// Remove a connect key, returning whether it was listed
+    ///
+    /// `connect_key` may be given in plaintext or as `sha256:<hex>`, and
+    /// matches the entry either way it is stored; every line holding it is
+    /// dropped, restrictions included. Comments and all other lines are kept
+    /// as they are. The file is rewritten atomically with a `.bak`, as for
+    /// [`Whitelist::add`], and left untouched if the key is not listed.
+    pub fn remove(&mut self, connect_key: &str) -> Result<bool> {
+        let hash = key_hash(connect_key).map_err(AppError::Auth)?;
+
+        let path = self.path().to_path_buf();
+        let _lock = lock_for_edit(&path)?;
+        // Start from the file as it is now, in case another process edited it
+        let text = fs::read_to_string(&path)
+            .map_err(|e| AppError::Auth(format!("Failed to read whitelist: {}", e)))?;
+        let removed: Vec<usize> = entry_lines(&text)
+            .filter(|(_, line)| WhitelistEntry::parse(line).is_ok_and(|entry| entry.key_hash == hash))
+            .map(|(index, _)| index)
+            .collect();
+
+        self.entries.retain(|entry| entry.key_hash != hash);
+        if removed.is_empty() {
+            return Ok(false);
+        }
+        // Entries below a removed line move up
+        for entry in &mut self.entries {
+            entry.line = entry.line.map(|line| line - removed.iter().filter(|index| **index + 1 < line).count());
+        }
+        replace_with(&path, |file| {
+            for (index, line) in text.lines().enumerate() {
+                if !removed.contains(&index) {
+                    writeln!(file, "{}", line)?;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(true)
+    }
+
+    /// Swap the whitelist at `path` with its `.bak`, undoing the last edit
+    ///
+    /// The version being replaced becomes the new backup, so restoring twice
+    /// gets it back. The backup is checked to parse before anything is
+    /// written, and is then put in place like any other edit: one rename of a
+    /// synced temp file, so the whitelist is never missing or half-written.
+    pub fn restore(path: &Path) -> Result<Self> {
+        let _lock = lock_for_edit(path)?;
+        let backup = backup_path(path);
+        if !backup.exists() {
+            return Err(AppError::Config(format!("No backup of whitelist {} to restore", path.display())));
+        }
+        Self::load(&backup)?;
+        let contents = fs::read(&backup)
+            .map_err(|e| AppError::Config(format!("Cannot read whitelist {}: {}", backup.display(), e)))?;
+
+        replace_with(path, |file| file.write_all(&contents))?;
+        Self::load(path)
+    }
+
+    /// Add a connect key stored as its SHA-256 hash, so no plaintext secret is on disk
+    ///
+    /// Returns the line as written to the file.
+    pub fn add_hashed(&mut self, connect_key: &str, label: Option<&str>) -> Result<String> {
+        let line = hash_entry_line(&labeled_line(connect_key, label)?)?;
+        self.add(&line, None)?;
+        Ok(line)
+    }
+
     /// Create a new empty whitelist file
     pub fn create(path: &Path) -> Result<Self> {
         if let Some(parent) = path.parent() {
@@ -70,8 +485,323 @@ impl Whitelist {
         Self::load(path)
     }
 
+    /// All entries, in file order
+    pub fn list(&self) -> &[WhitelistEntry] {
+        &self.entries
+    }
+
     /// Get all keys
-    pub fn keys(&self) -> &[String] {
-        &self.keys
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|e| e.key.as_str())
+    }
+
+    /// Whether the whitelist has no entries, so every peer is refused
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Find the entry whose connect key hashes to `key_hash`
+    pub fn find_by_hash(&self, key_hash: &str) -> Option<&WhitelistEntry> {
+        self.entries.iter().find(|e| e.key_hash == key_hash)
+    }
+
+    /// File the whitelist was loaded from
+    pub fn path(&self) -> &Path {
+        Path::new(&self.path)
+    }
+
+    /// Keys added and removed going from `previous` to this whitelist
+    pub fn changes_since(&self, previous: &Whitelist) -> WhitelistChange {
+        let missing_from = |list: &Whitelist, other: &Whitelist| -> Vec<String> {
+            list.entries
+                .iter()
+                .filter(|e| other.find_by_hash(&e.key_hash).is_none())
+                .map(|e| e.key_hash.clone())
+                .collect()
+        };
+        WhitelistChange {
+            added: missing_from(self, previous),
+            removed: missing_from(previous, self),
+        }
+    }
+}
+
+/// Difference between two loads of a whitelist, by key hash
+///
+/// Only hashes are kept so the change can be logged without exposing keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhitelistChange {
+    /// Hashes of keys that were added
+    pub added: Vec<String>,
+    /// Hashes of keys that were removed
+    pub removed: Vec<String>,
+}
+
+impl WhitelistChange {
+    /// Whether the set of keys is unchanged
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl std::fmt::Display for WhitelistChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "whitelist changed: {} added, {} removed", self.added.len(), self.removed.len())?;
+        for hash in &self.added {
+            write!(f, "; +{}{}", HASHED_KEY_PREFIX, hash)?;
+        }
+        for hash in &self.removed {
+            write!(f, "; -{}{}", HASHED_KEY_PREFIX, hash)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern_restricted_entry() {
+        let entry = WhitelistEntry::parse("acme-key;pattern=settlement_*.json").unwrap();
+        assert_eq!(entry.key, "acme-key");
+        assert!(entry.allows("settlement_20240101.json"));
+        assert!(!entry.allows("payload.sh"));
+        assert!(!entry.allows("settlement_20240101.csv"));
+
+        let open = WhitelistEntry::parse("other-key").unwrap();
+        assert!(open.allows("anything.bin"));
+
+        assert!(WhitelistEntry::parse("acme-key;colour=blue").is_err());
+
+        let limited = WhitelistEntry::parse("acme-key;pattern=*.json;quota=5GiB/day").unwrap();
+        assert_eq!(limited.quota.unwrap().max_bytes, Some(5 << 30));
+        assert!(WhitelistEntry::parse("acme-key;quota=lots").is_err());
+    }
+
+    #[test]
+    fn test_hashed_and_plaintext_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add("plain-key", None).unwrap();
+        let line = whitelist.add_hashed("hashed-key;pattern=*.json", None).unwrap();
+        assert_eq!(line, format!("sha256:{};pattern=*.json", hash_connect_key("hashed-key")));
+
+        let contents = fs::read_to_string(dir.path().join("whitelist.txt")).unwrap();
+        assert!(!contents.contains("hashed-key"));
+
+        let whitelist = Whitelist::load(&dir.path().join("whitelist.txt")).unwrap();
+        assert!(whitelist.contains("plain-key"));
+        assert!(whitelist.contains("hashed-key"));
+        assert!(!whitelist.contains("other-key"));
+
+        let hashed = whitelist.find_by_hash(&hash_connect_key("hashed-key")).unwrap();
+        assert!(hashed.is_hashed());
+        assert!(!hashed.allows("payload.sh"));
+        assert!(!whitelist.find_by_hash(&hash_connect_key("plain-key")).unwrap().is_hashed());
+
+        assert!(WhitelistEntry::parse("sha256:not-a-hash").is_err());
+    }
+
+    #[test]
+    fn test_labels_name_entries() {
+        let entry = WhitelistEntry::parse("acme-key;pattern=*.json  # Acme Settlements ").unwrap();
+        assert_eq!(entry.label.as_deref(), Some("Acme Settlements"));
+        assert!(!entry.allows("payload.sh"));
+        assert_eq!(WhitelistEntry::parse("key#1").unwrap().key, "key#1");
+        assert_eq!(WhitelistEntry::parse("other-key").unwrap().name(), "other-key");
+        assert!(WhitelistEntry::parse("acme-key #").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whitelist.txt");
+        let mut whitelist = Whitelist::create(&path).unwrap();
+        whitelist.add("acme-key", Some("Acme")).unwrap();
+        let line = whitelist.add_hashed("globex-key;quota=1GiB/day", Some("Globex")).unwrap();
+        assert_eq!(line, format!("sha256:{};quota=1GiB/day # Globex", hash_connect_key("globex-key")));
+        assert!(whitelist.add("third-key # Third", Some("Other")).is_err());
+        assert!(whitelist.add("third-key", Some(" ")).is_err());
+
+        let whitelist = Whitelist::load(&path).unwrap();
+        let names: Vec<String> = whitelist.list().iter().map(WhitelistEntry::name).collect();
+        assert_eq!(names, ["Acme", "Globex"]);
+        assert!(fs::read_to_string(&path).unwrap().contains("acme-key # Acme\n"));
+    }
+
+    #[test]
+    fn test_changes_logged_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whitelist.txt");
+        fs::write(&path, "kept-key\nold-key\n").unwrap();
+        let before = Whitelist::load(&path).unwrap();
+        fs::write(&path, "kept-key;pattern=*.csv\nnew-key\n").unwrap();
+        let after = Whitelist::load(&path).unwrap();
+
+        let change = after.changes_since(&before);
+        assert_eq!(change.added, [hash_connect_key("new-key")]);
+        assert_eq!(change.removed, [hash_connect_key("old-key")]);
+        let logged = change.to_string();
+        assert!(logged.starts_with("whitelist changed: 1 added, 1 removed"));
+        assert!(!logged.contains("new-key") && !logged.contains("old-key"));
+
+        assert!(after.changes_since(&after).is_empty());
+    }
+
+    #[test]
+    fn test_malformed_whitelist_names_file_and_location() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whitelist.txt");
+        fs::write(&path, "# Whitelist for connect keys\ngood-key\n\nacme-key; colour=blue\n").unwrap();
+
+        let err = Whitelist::load(&path).err().unwrap();
+        assert!(matches!(err, AppError::Config(_)));
+        let expected = format!("{}:4:11: Unknown option 'colour=blue'", path.display());
+        assert!(err.to_string().contains(&expected), "{}", err);
+    }
+
+    #[test]
+    fn test_lint_clean_whitelist() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whitelist.txt");
+        let hashed = format!("{}{}", HASHED_KEY_PREFIX, hash_connect_key("another-long-secret"));
+        let text = format!("# Peers\nacme-settlements-key;pattern=*.csv\n\n{}\n", hashed);
+        fs::write(&path, text).unwrap();
+
+        assert_eq!(Whitelist::lint(&path).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn test_lint_reports_each_defect_with_its_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whitelist.txt");
+        let duplicate = format!("{}{}", HASHED_KEY_PREFIX, hash_connect_key("acme-settlements-key"));
+        let text = [
+            "# Peers",
+            "acme-settlements-key",
+            "short",
+            "beta-clearing-key; colour=blue",
+            "",
+            "gamma-ledger-key;quota=lots/day # Gamma",
+            &duplicate,
+            "sha256:abc",
+            "delta-payments-key # Gamma",
+            "epsilon-fx-key # Gamma",
+        ]
+        .join("\n");
+        fs::write(&path, &text).unwrap();
+
+        let issues = Whitelist::lint(&path).unwrap();
+        let found: Vec<(usize, usize)> = issues.iter().map(|issue| (issue.line, issue.column)).collect();
+        assert_eq!(found, [(3, 1), (4, 20), (6, 18), (7, 1), (8, 1), (10, 16)], "{:#?}", issues);
+        assert!(issues[0].message.contains("Weak connect key"));
+        assert!(issues[1].message.contains("Unknown option 'colour=blue'"));
+        assert!(issues[2].message.contains("Invalid quota"));
+        assert!(issues[3].message.contains("already on line 2"));
+        assert!(issues[4].message.contains("Invalid SHA-256 hash"));
+        assert!(issues[5].message.contains("Duplicate label 'Gamma', already on line 9"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+    }
+
+    #[test]
+    fn test_unusable_whitelist_path_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // A typo'd path is reported, not created as an empty whitelist
+        let typo = dir.path().join("whitelsit.txt");
+        let err = Whitelist::load(&typo).err().unwrap();
+        assert!(err.to_string().contains("does not exist"), "{}", err);
+        assert!(!typo.exists());
+        assert!(Whitelist::load_or_create(&typo).unwrap().is_empty());
+
+        let err = Whitelist::load(dir.path()).err().unwrap();
+        assert!(matches!(err, AppError::Config(_)));
+        assert!(err.to_string().contains("is a directory"), "{}", err);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = dir.path().join("locked.txt");
+            fs::write(&locked, "secret\n").unwrap();
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+            // Root reads the file regardless of its mode
+            if fs::read(&locked).is_err() {
+                let err = Whitelist::load(&locked).err().unwrap();
+                assert!(err.to_string().contains("Cannot read whitelist"), "{}", err);
+                assert!(err.to_string().to_lowercase().contains("permission denied"), "{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_interrupted_rewrite_leaves_whitelist_intact() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whitelist.txt");
+        fs::write(&path, "kept-key\n").unwrap();
+
+        let _lock = lock_for_edit(&path).unwrap();
+        let failed = replace_with(&path, |file| {
+            file.write_all(b"kept-key\nhalf-writ")?;
+            Err(io::Error::other("disk full"))
+        });
+        assert!(failed.unwrap_err().to_string().contains("disk full"));
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "kept-key\n");
+        assert!(!with_suffix(&path, "tmp").exists());
+        assert!(!backup_path(&path).exists());
+    }
+
+    #[test]
+    fn test_remove_keeps_comments_and_other_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whitelist.txt");
+        let hashed = hash_entry_line("hashed-partner-key").unwrap();
+        fs::write(&path, format!("# Partners\nacme-key;pattern=*.csv\n\n# Retired\n{}\nglobex-key\nacme-key\n", hashed)).unwrap();
+        let mut whitelist = Whitelist::load(&path).unwrap();
+
+        assert!(whitelist.remove("acme-key").unwrap());
+        assert!(whitelist.remove("hashed-partner-key").unwrap());
+        assert!(!whitelist.remove("unknown-key").unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "# Partners\n\n# Retired\nglobex-key\n");
+        assert_eq!(whitelist.keys().collect::<Vec<_>>(), ["globex-key"]);
+        assert_eq!(whitelist.list()[0].line, Some(4));
+        assert!(fs::read_to_string(backup_path(&path)).unwrap().contains(&hashed));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rewrite_keeps_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whitelist.txt");
+        let mut whitelist = Whitelist::create(&path).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+
+        whitelist.add("first-key", None).unwrap();
+        whitelist.remove("first-key").unwrap();
+        Whitelist::restore(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::metadata(backup_path(&path)).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_restore_swaps_in_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("whitelist.txt");
+        assert!(Whitelist::restore(&path).is_err());
+
+        let mut whitelist = Whitelist::create(&path).unwrap();
+        whitelist.add("first-key", None).unwrap();
+        whitelist.add("second-key", None).unwrap();
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), "# Whitelist for connect keys\nfirst-key\n");
+
+        let restored = Whitelist::restore(&path).unwrap();
+        assert!(restored.contains("first-key"));
+        assert!(!restored.contains("second-key"));
+
+        // Restoring again undoes the restore
+        assert!(Whitelist::restore(&path).unwrap().contains("second-key"));
+
+        fs::write(backup_path(&path), "acme-key;colour=blue\n").unwrap();
+        assert!(Whitelist::restore(&path).is_err());
+        assert!(Whitelist::load(&path).unwrap().contains("second-key"));
     }
 }
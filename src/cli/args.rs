@@ -1,4 +1,12 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use crate::server::{CollisionPolicy, TypeMismatchPolicy, DEFAULT_TRANSFER_LOG_KEEP};
+use crate::cli::ColorChoice;
+use crate::crypto::{AuthMethod, CipherSuite, PublicKeyFormat};
+use crate::protocol::PROTOCOL_VERSION;
+use crate::compression::Compression;
+use crate::auth::parse_size;
+use crate::security::SecurityLevel;
+use crate::error::{AppError, Result};
 
 /// Secure Finance Messaging Block Application
 #[derive(Parser, Debug)]
@@ -25,17 +33,92 @@ pub struct Args {
     #[arg(short = 's', long = "save-as", value_name = "FILENAME")]
     pub save_as: Option<String>,
 
+    /// Optional note sent alongside the file (max 1 KiB)
+    #[arg(long = "note", value_name = "TEXT")]
+    pub note: Option<String>,
+
     /// Interactive mode
     #[arg(short = 'm', long = "interactive")]
     pub interactive: bool,
 
+    /// In interactive mode, stop the server after this many seconds without a new connection
+    #[arg(long = "auto-stop-idle-secs", value_name = "SECS", requires = "interactive", value_parser = clap::value_parser!(u64).range(1..), env = "FINAPP_AUTO_STOP_IDLE_SECS")]
+    pub auto_stop_idle_secs: Option<u64>,
+
     /// Connect key for authentication
-    #[arg(long = "ck", value_name = "KEY")]
+    #[arg(long = "ck", value_name = "KEY", env = "FINAPP_CONNECT_KEY", hide_env_values = true)]
     pub connect_key: Option<String>,
 
     /// Listening port number
-    #[arg(long = "lp", value_name = "PORT", default_value = "8080")]
+    #[arg(long = "lp", value_name = "PORT", default_value = "8080", env = "FINAPP_PORT")]
     pub port: u16,
+
+    /// Print fatal errors to stderr as JSON
+    #[arg(long = "json-errors", global = true, env = "FINAPP_JSON_ERRORS")]
+    pub json_errors: bool,
+
+    /// Take newly needed key pairs from this pool (filled by `keygen --count`) instead of generating them
+    #[arg(long = "key-pool", value_name = "DIR", global = true, env = "FINAPP_KEY_POOL")]
+    pub key_pool: Option<String>,
+
+    /// When to color output: auto (only on a terminal), always or never
+    #[arg(long = "color", value_enum, default_value_t = ColorChoice::Auto, global = true, env = "FINAPP_COLOR")]
+    pub color: ColorChoice,
+}
+
+/// Output format of `protocol dump`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, ValueEnum)]
+pub enum DumpFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// JSON, for generating compatible clients
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ProtocolCommand {
+    /// Print message types, framing and payload field layouts
+    Dump {
+        /// Output format
+        #[arg(long = "format", value_enum, default_value_t = DumpFormat::Text)]
+        format: DumpFormat,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum WhitelistCommand {
+    /// Swap the whitelist with the backup kept by the last edit
+    Restore {
+        /// Whitelist file path
+        #[arg(short = 'f', long = "file", default_value = "keys/whitelist.txt", env = "FINAPP_WHITELIST")]
+        file: String,
+    },
+
+    /// Show the authorized connect keys with their line numbers (hashed keys by prefix)
+    List {
+        /// Whitelist file path
+        #[arg(short = 'f', long = "file", default_value = "keys/whitelist.txt", env = "FINAPP_WHITELIST")]
+        file: String,
+    },
+
+    /// Revoke a connect key, keeping comments and all other entries
+    Remove {
+        /// Connect key to remove, in plaintext or as sha256:<hex>
+        #[arg(value_name = "KEY")]
+        connect_key: String,
+
+        /// Whitelist file path
+        #[arg(short = 'f', long = "file", default_value = "keys/whitelist.txt", env = "FINAPP_WHITELIST")]
+        file: String,
+    },
+
+    /// Check the whitelist for malformed lines, weak and duplicate keys without changing it
+    Lint {
+        /// Whitelist file path
+        #[arg(short = 'f', long = "file", default_value = "keys/whitelist.txt", env = "FINAPP_WHITELIST")]
+        file: String,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -43,60 +126,507 @@ pub enum Commands {
     /// Start the server in listening mode
     Listen {
         /// Port to listen on
-        #[arg(short = 'p', long = "port", default_value = "8080")]
+        #[arg(short = 'p', long = "port", default_value = "8080", env = "FINAPP_PORT")]
         port: u16,
 
+        /// Address to listen on, e.g. 127.0.0.1, :: or [::1]
+        #[arg(long = "bind", value_name = "ADDR", default_value = "0.0.0.0", conflicts_with = "unix_socket", env = "FINAPP_BIND")]
+        bind: String,
+
+        /// Listen on a Unix domain socket at this path instead of a TCP port
+        #[arg(long = "unix-socket", value_name = "PATH", env = "FINAPP_UNIX_SOCKET")]
+        unix_socket: Option<String>,
+
         /// Path to whitelist file
-        #[arg(short = 'w', long = "whitelist", default_value = "keys/whitelist.txt")]
+        #[arg(short = 'w', long = "whitelist", default_value = "keys/whitelist.txt", env = "FINAPP_WHITELIST")]
         whitelist: String,
 
+        /// Start with an empty whitelist if the whitelist file does not exist
+        #[arg(long = "create-whitelist", env = "FINAPP_CREATE_WHITELIST")]
+        create_whitelist: bool,
+
+        /// Re-read the whitelist about every N seconds, logging added and removed keys
+        #[arg(long = "whitelist-reload-secs", value_name = "N", value_parser = clap::value_parser!(u64).range(1..), env = "FINAPP_WHITELIST_RELOAD_SECS")]
+        whitelist_reload_secs: Option<u64>,
+
+        /// Append one JSON line per stored transfer to this file
+        #[arg(long = "transfer-log", value_name = "PATH", env = "FINAPP_TRANSFER_LOG")]
+        transfer_log: Option<String>,
+
+        /// Rotate the transfer log once it would grow past N MiB
+        #[arg(long = "transfer-log-max-mb", value_name = "N", requires = "transfer_log", value_parser = clap::value_parser!(u64).range(1..), env = "FINAPP_TRANSFER_LOG_MAX_MB")]
+        transfer_log_max_mb: Option<u64>,
+
+        /// Rotated transfer logs to keep (<PATH>.1 is the newest)
+        #[arg(long = "transfer-log-keep", value_name = "N", default_value_t = DEFAULT_TRANSFER_LOG_KEEP, env = "FINAPP_TRANSFER_LOG_KEEP")]
+        transfer_log_keep: usize,
+
+        /// File the per-key quota counters are kept in across restarts
+        #[arg(long = "quota-usage", value_name = "FILE", default_value = "keys/quota_usage.json", env = "FINAPP_QUOTA_USAGE")]
+        quota_usage: String,
+
         /// Path to keys directory
-        #[arg(short = 'k', long = "keys", default_value = "keys")]
+        #[arg(short = 'k', long = "keys", default_value = "keys", env = "FINAPP_KEYS_DIR")]
         keys_dir: String,
+
+        /// Directory received messages are stored in
+        #[arg(long = "messages-dir", default_value = "messages", env = "FINAPP_MESSAGES_DIR")]
+        messages_dir: String,
+
+        /// Stage in-progress files here before moving them into the messages directory [default: the messages directory]
+        #[arg(long = "temp-dir", value_name = "PATH", env = "FINAPP_TEMP_DIR")]
+        temp_dir: Option<String>,
+
+        /// Fail at startup if the messages directory already contains files
+        #[arg(long = "require-empty-messages-dir", env = "FINAPP_REQUIRE_EMPTY_MESSAGES_DIR")]
+        require_empty_messages_dir: bool,
+
+        /// Unpack received directory archives into messages/<name>/
+        #[arg(long = "extract-dirs", env = "FINAPP_EXTRACT_DIRS")]
+        extract_dirs: bool,
+
+        /// Store received files encrypted to this node's public key (see `read`)
+        #[arg(long = "encrypt-at-rest", conflicts_with = "extract_dirs", env = "FINAPP_ENCRYPT_AT_REST")]
+        encrypt_at_rest: bool,
+
+        /// Total memory all connections may use for message data at once (e.g. 512MiB), counting every copy a transfer needs; larger transfers queue
+        #[arg(long = "decrypt-memory-budget", value_name = "SIZE", value_parser = parse_size, env = "FINAPP_DECRYPT_MEMORY_BUDGET")]
+        decrypt_memory_budget: Option<u64>,
+
+        /// Refuse messages larger than this before reading their data (e.g. 1GiB; 0 = unlimited)
+        #[arg(long = "max-message-size", value_name = "SIZE", default_value = "100MiB", value_parser = parse_size, env = "FINAPP_MAX_MESSAGE_SIZE")]
+        max_message_size: u64,
+
+        /// Drop a connection after this many seconds without receiving data (0 = never)
+        #[arg(long = "read-timeout-secs", default_value = "30", env = "FINAPP_READ_TIMEOUT_SECS")]
+        read_timeout_secs: u64,
+
+        /// On Ctrl+C or SIGTERM, wait up to N seconds for transfers in progress (0 = stop at once)
+        #[arg(long = "drain-timeout-secs", value_name = "N", default_value = "30", env = "FINAPP_DRAIN_TIMEOUT_SECS")]
+        drain_timeout_secs: u64,
+
+        /// Handle at most N connections at once, closing any beyond that
+        #[arg(long = "max-connections", value_name = "N", value_parser = clap::value_parser!(u64).range(1..), env = "FINAPP_MAX_CONNECTIONS")]
+        max_connections: Option<u64>,
+
+        /// Accept at most N new connections per minute from one IP address
+        #[arg(long = "connection-rate-limit", value_name = "N", value_parser = clap::value_parser!(u32).range(1..), env = "FINAPP_CONNECTION_RATE_LIMIT")]
+        connection_rate_limit: Option<u32>,
+
+        /// Only accept files with these extensions (comma separated, e.g. json,csv)
+        #[arg(long = "allowed-ext", value_delimiter = ',', env = "FINAPP_ALLOWED_EXT")]
+        allowed_ext: Vec<String>,
+
+        /// Check each received file's content against its extension (e.g. binary sent as .json)
+        #[arg(long = "detect-type", env = "FINAPP_DETECT_TYPE")]
+        detect_type: bool,
+
+        /// What to do when --detect-type finds a mismatch
+        #[arg(long = "on-type-mismatch", value_enum, default_value = "warn", requires = "detect_type", env = "FINAPP_ON_TYPE_MISMATCH")]
+        on_type_mismatch: TypeMismatchPolicy,
+
+        /// What to do when a received file already exists
+        #[arg(long = "on-collision", value_enum, default_value = "suffix", env = "FINAPP_ON_COLLISION")]
+        on_collision: CollisionPolicy,
+
+        /// Bundle of hardening options; individual flags below override it
+        #[arg(long = "security-level", value_enum, default_value = "default", env = "FINAPP_SECURITY_LEVEL")]
+        security_level: SecurityLevel,
+
+        /// Refuse clients authenticating with a weaker method than this [default: set by --security-level]
+        #[arg(long = "min-auth-method", value_enum, env = "FINAPP_MIN_AUTH_METHOD")]
+        min_auth_method: Option<AuthMethod>,
+
+        /// Refuse clients announcing an older wire protocol version than this
+        #[arg(long = "min-protocol-version", value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=PROTOCOL_VERSION as i64), env = "FINAPP_MIN_PROTOCOL_VERSION")]
+        min_protocol_version: u32,
+
+        /// Refuse clients whose strongest cipher suite is weaker than this
+        #[arg(long = "min-crypto-suite", value_enum, default_value = "aes-128-gcm", env = "FINAPP_MIN_CRYPTO_SUITE")]
+        min_crypto_suite: CipherSuite,
+
+        /// Compression algorithms clients may use (comma separated; "none" to refuse compression) [default: zstd,gzip unless strict]
+        #[arg(long = "compression", value_enum, value_delimiter = ',', env = "FINAPP_COMPRESSION")]
+        compression: Option<Vec<Compression>>,
     },
 
     /// Send a message to a server
     Send {
-        /// Server IP address
-        #[arg(short = 'i', long = "ip")]
+        /// Server IP address, or unix:/path/to.sock for a local Unix socket
+        #[arg(short = 'i', long = "ip", env = "FINAPP_IP")]
         ip: String,
 
         /// Server port
-        #[arg(short = 'p', long = "port", default_value = "8080")]
+        #[arg(short = 'p', long = "port", default_value = "8080", env = "FINAPP_PORT")]
         port: u16,
 
-        /// Message file path
-        #[arg(short = 'f', long = "file")]
-        file: String,
+        /// Message file path or glob; may be repeated to send several files
+        #[arg(
+            short = 'f',
+            long = "file",
+            num_args = 1..,
+            required_unless_present_any = ["dir", "manifest"],
+            conflicts_with_all = ["dir", "manifest"]
+        )]
+        file: Vec<String>,
+
+        /// Directory to send as a single tar archive, streamed in encrypted chunks
+        #[arg(long = "dir", conflicts_with = "manifest")]
+        dir: Option<String>,
+
+        /// With --dir, send each file as its own transfer over one connection instead of a tar archive
+        #[arg(long = "each-file", requires = "dir", conflicts_with = "save_as")]
+        each_file: bool,
+
+        /// TOML manifest listing files with per-file save_as, note and ttl
+        #[arg(long = "manifest", value_name = "FILE", conflicts_with_all = ["pipeline", "save_as", "note"])]
+        manifest: Option<String>,
+
+        /// Send multiple files over one connection without waiting for each ack
+        #[arg(long = "pipeline", conflicts_with = "dir")]
+        pipeline: bool,
+
+        /// After the ack, have the server read the stored file back and compare checksums
+        #[arg(long = "verify-delivery", conflicts_with = "pipeline")]
+        verify_delivery: bool,
+
+        /// Give up waiting for the server's acknowledgment after N seconds of silence once the data is sent
+        #[arg(long = "ack-timeout-secs", value_name = "N", default_value_t = 120, value_parser = clap::value_parser!(u64).range(1..), env = "FINAPP_ACK_TIMEOUT_SECS")]
+        ack_timeout_secs: u64,
 
         /// Connect key for authentication
-        #[arg(long = "ck")]
+        #[arg(long = "ck", env = "FINAPP_CONNECT_KEY", hide_env_values = true)]
         connect_key: String,
 
         /// Remote filename (without extension)
         #[arg(short = 's', long = "save-as")]
         save_as: Option<String>,
 
+        /// Optional note sent alongside the file (max 1 KiB)
+        #[arg(long = "note")]
+        note: Option<String>,
+
+        /// Bundle of hardening options; individual flags below override it
+        #[arg(long = "security-level", value_enum, default_value = "default", env = "FINAPP_SECURITY_LEVEL")]
+        security_level: SecurityLevel,
+
+        /// Refuse servers that only accept a weaker authentication method than this [default: set by --security-level]
+        #[arg(long = "min-auth-method", value_enum, env = "FINAPP_MIN_AUTH_METHOD")]
+        min_auth_method: Option<AuthMethod>,
+
+        /// Refuse servers announcing an older wire protocol version than this
+        #[arg(long = "min-protocol-version", value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=PROTOCOL_VERSION as i64), env = "FINAPP_MIN_PROTOCOL_VERSION")]
+        min_protocol_version: u32,
+
+        /// Refuse servers whose strongest cipher suite is weaker than this
+        #[arg(long = "min-crypto-suite", value_enum, default_value = "aes-128-gcm", env = "FINAPP_MIN_CRYPTO_SUITE")]
+        min_crypto_suite: CipherSuite,
+
+        /// Offer to compress data with these algorithms, preferred first (comma separated); bare --compress offers gzip
+        #[arg(long = "compress", value_enum, value_delimiter = ',', value_name = "ALGORITHM", num_args = 0..=1, default_missing_value = "gzip")]
+        compress: Option<Vec<Compression>>,
+
+        /// Stream each file from disk in encrypted chunks of SIZE (max 8MiB) instead of reading it whole; bare --chunk-size uses 1MiB
+        #[arg(long = "chunk-size", value_name = "SIZE", value_parser = parse_size, num_args = 0..=1, default_missing_value = "1MiB", conflicts_with_all = ["compress", "each_file", "manifest", "pipeline"])]
+        chunk_size: Option<u64>,
+
+        /// Informational name announced to the server (max 64 chars, [A-Za-z0-9._-])
+        #[arg(long = "identity", value_name = "NAME", env = "FINAPP_IDENTITY")]
+        identity: Option<String>,
+
+        /// File of pinned server keys, per local key [default: <keys>/known_hosts]
+        #[arg(long = "known-hosts", value_name = "PATH", env = "FINAPP_KNOWN_HOSTS")]
+        known_hosts: Option<String>,
+
         /// Path to keys directory
-        #[arg(short = 'k', long = "keys", default_value = "keys")]
+        #[arg(short = 'k', long = "keys", default_value = "keys", env = "FINAPP_KEYS_DIR")]
         keys_dir: String,
     },
 
     /// Generate new key pair
     Keygen {
         /// Output directory for keys
-        #[arg(short = 'o', long = "output", default_value = "keys")]
+        #[arg(short = 'o', long = "output", default_value = "keys", env = "FINAPP_KEYS_DIR")]
         output: String,
+
+        /// Encrypt the private key with a passphrase (asked for, or FINAPP_KEY_PASSPHRASE)
+        #[arg(long = "encrypt", conflicts_with = "count")]
+        encrypt: bool,
+
+        /// Add this many pre-generated keys to --pool-dir instead of writing a key pair
+        #[arg(long = "count", requires = "pool_dir")]
+        count: Option<usize>,
+
+        /// Key pool directory to fill (see --key-pool)
+        #[arg(long = "pool-dir", requires = "count")]
+        pool_dir: Option<String>,
     },
 
     /// Add a connect key to whitelist
+    #[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
     Whitelist {
         /// Connect key to add
-        #[arg(long = "ck")]
-        connect_key: String,
+        #[arg(long = "ck", required = true)]
+        connect_key: Option<String>,
 
         /// Whitelist file path
-        #[arg(short = 'f', long = "file", default_value = "keys/whitelist.txt")]
+        #[arg(short = 'f', long = "file", default_value = "keys/whitelist.txt", env = "FINAPP_WHITELIST")]
+        file: String,
+
+        /// Store the key as its SHA-256 hash instead of in plaintext
+        #[arg(long = "hashed")]
+        hashed: bool,
+
+        /// Name of the peer, written after the key as `# LABEL`
+        #[arg(long = "label", value_name = "LABEL")]
+        label: Option<String>,
+
+        #[command(subcommand)]
+        action: Option<WhitelistCommand>,
+    },
+
+    /// Print a received file, decrypting it if it was stored encrypted at rest
+    Read {
+        /// Stored message file
+        file: String,
+
+        /// Path to keys directory
+        #[arg(short = 'k', long = "keys", default_value = "keys", env = "FINAPP_KEYS_DIR")]
+        keys_dir: String,
+
+        /// Write the contents to this file instead of stdout
+        #[arg(short = 'o', long = "output")]
+        output: Option<String>,
+    },
+
+    /// Print a stored message's header metadata as JSON, without keys or decrypting it
+    Header {
+        /// Stored message file (or its .meta.json sidecar)
         file: String,
     },
+
+    /// Export a signed proof of receipt for a stored message, or verify one
+    Prove {
+        /// Stored message file to prove
+        #[arg(required_unless_present = "verify", conflicts_with = "verify")]
+        file: Option<String>,
+
+        /// Where to write the proof
+        #[arg(long = "out", default_value = "proof.json", conflicts_with = "verify")]
+        out: String,
+
+        /// Verify this proof instead of creating one; needs no keys or messages
+        #[arg(long = "verify", value_name = "PROOF")]
+        verify: Option<String>,
+
+        /// Only accept proofs signed by this public key (PEM file)
+        #[arg(long = "server-key", value_name = "PEM", requires = "verify")]
+        server_key: Option<String>,
+
+        /// Path to keys directory
+        #[arg(short = 'k', long = "keys", default_value = "keys", env = "FINAPP_KEYS_DIR")]
+        keys_dir: String,
+    },
+
+    /// Write a detached signature over a file with this node's private key
+    Sign {
+        /// File to sign
+        #[arg(long = "file")]
+        file: String,
+
+        /// Where to write the signature [default: <FILE>.sig]
+        #[arg(long = "out")]
+        out: Option<String>,
+
+        /// Path to keys directory
+        #[arg(short = 'k', long = "keys", default_value = "keys", env = "FINAPP_KEYS_DIR")]
+        keys_dir: String,
+    },
+
+    /// Check a detached RSA or Ed25519 signature over a file
+    VerifySig {
+        /// Signed file
+        #[arg(long = "file")]
+        file: String,
+
+        /// Signature file
+        #[arg(long = "sig")]
+        sig: String,
+
+        /// Signer's public key (PEM file)
+        #[arg(long = "pubkey", value_name = "PEM")]
+        pubkey: String,
+    },
+
+    /// Write this node's public key in an encoding other stacks (OpenSSL, Java) expect
+    ExportKey {
+        /// Encoding to write
+        #[arg(long = "format", value_enum)]
+        format: PublicKeyFormat,
+
+        /// File to write the key to
+        #[arg(long = "out")]
+        out: String,
+
+        /// Path to keys directory
+        #[arg(short = 'k', long = "keys", default_value = "keys", env = "FINAPP_KEYS_DIR")]
+        keys_dir: String,
+    },
+
+    /// Re-encrypt messages stored with --encrypt-at-rest to a new key
+    Reencrypt {
+        /// Keys directory holding the key the messages are currently encrypted to
+        #[arg(long = "old-key")]
+        old_key: String,
+
+        /// Keys directory holding the key to re-encrypt to (only the public key is needed)
+        #[arg(long = "new-key")]
+        new_key: String,
+
+        /// Directory the messages are stored in
+        #[arg(long = "messages-dir", default_value = "messages", env = "FINAPP_MESSAGES_DIR")]
+        messages_dir: String,
+    },
+
+    /// Move messages stored with --encrypt-at-rest to a new key by re-wrapping only their AES keys
+    RekeyAtRest {
+        /// Keys directory holding the key the messages are currently encrypted to
+        #[arg(long = "old-key")]
+        old_key: String,
+
+        /// Keys directory holding the key to move to (only the public key is needed)
+        #[arg(long = "new-key")]
+        new_key: String,
+
+        /// Directory the messages are stored in
+        #[arg(long = "messages-dir", default_value = "messages", env = "FINAPP_MESSAGES_DIR")]
+        messages_dir: String,
+    },
+
+    /// Check whether two keys directories hold the same identity
+    KeyMatch {
+        /// First keys directory
+        first: String,
+
+        /// Second keys directory
+        second: String,
+    },
+
+    /// Send a file to an in-process server over loopback and check it arrives intact
+    Selftest,
+
+    /// Print a line for each new message stored in a messages directory
+    Watch {
+        /// Directory to watch
+        #[arg(long = "messages-dir", default_value = "messages", env = "FINAPP_MESSAGES_DIR")]
+        messages_dir: String,
+    },
+
+    /// Inspect the wire protocol
+    Protocol {
+        #[command(subcommand)]
+        action: ProtocolCommand,
+    },
+}
+
+/// Pick out the legacy shorthand send (`--ip`, `--file`, `--ck`)
+///
+/// `Ok(None)` when none of the three were given, so the caller can show help;
+/// an [`AppError::Cli`] naming the missing ones when only some were.
+pub fn shorthand_send<'a>(
+    ip: Option<&'a str>,
+    file: Option<&'a str>,
+    connect_key: Option<&'a str>,
+) -> Result<Option<(&'a str, &'a str, &'a str)>> {
+    match (ip, file, connect_key) {
+        (Some(ip), Some(file), Some(connect_key)) => Ok(Some((ip, file, connect_key))),
+        (None, None, None) => Ok(None),
+        _ => {
+            let missing: Vec<_> = [(ip, "--ip"), (file, "--file"), (connect_key, "--ck")]
+                .into_iter()
+                .filter(|(value, _)| value.is_none())
+                .map(|(_, flag)| flag)
+                .collect();
+            Err(AppError::Cli(format!(
+                "Sending without a subcommand needs --ip, --file and --ck; missing {} (or use `send`)",
+                missing.join(", ")
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_shorthand_send_names_missing_args() {
+        assert!(shorthand_send(None, None, None).unwrap().is_none());
+        assert_eq!(
+            shorthand_send(Some("10.0.0.1"), Some("a.json"), Some("ck")).unwrap(),
+            Some(("10.0.0.1", "a.json", "ck"))
+        );
+
+        let missing = |ip, file, ck| match shorthand_send(ip, file, ck) {
+            Err(AppError::Cli(message)) => message.rsplit_once("missing ").unwrap().1.to_string(),
+            other => panic!("expected a CLI error, got {:?}", other),
+        };
+        assert_eq!(missing(Some("10.0.0.1"), None, None), "--file, --ck (or use `send`)");
+        assert_eq!(missing(None, Some("a.json"), None), "--ip, --ck (or use `send`)");
+        assert_eq!(missing(None, None, Some("ck")), "--ip, --file (or use `send`)");
+        assert_eq!(missing(Some("10.0.0.1"), Some("a.json"), None), "--ck (or use `send`)");
+        assert_eq!(missing(Some("10.0.0.1"), None, Some("ck")), "--file (or use `send`)");
+        assert_eq!(missing(None, Some("a.json"), Some("ck")), "--ip (or use `send`)");
+    }
+
+    #[test]
+    fn test_bare_compress_offers_gzip() {
+        let compress = |extra: &[&str]| {
+            let base = ["stl_finapp", "send", "--ip", "127.0.0.1", "--file", "eod.csv"];
+            let args = Args::try_parse_from(base.iter().chain(extra)).unwrap();
+            match args.command {
+                Some(Commands::Send { compress, connect_key, .. }) => (compress, connect_key),
+                other => panic!("unexpected command: {:?}", other),
+            }
+        };
+
+        assert_eq!(compress(&["--compress", "--ck", "secret"]), (Some(vec![Compression::Gzip]), "secret".to_string()));
+        assert_eq!(compress(&["--compress", "zstd,gzip", "--ck", "secret"]).0, Some(vec![Compression::Zstd, Compression::Gzip]));
+        assert_eq!(compress(&["--ck", "secret"]).0, None);
+    }
+
+    // Environment variables are process-wide, so every env assertion lives in
+    // this single test to avoid races with parallel tests.
+    #[test]
+    fn test_env_vars_picked_up_and_overridden_by_flags() {
+        std::env::set_var("FINAPP_PORT", "9100");
+        std::env::set_var("FINAPP_MESSAGES_DIR", "/srv/finapp/inbox");
+        std::env::set_var("FINAPP_ON_COLLISION", "reject");
+
+        let args = Args::try_parse_from(["stl_finapp", "listen"]).unwrap();
+        match args.command {
+            Some(Commands::Listen { port, messages_dir, on_collision, .. }) => {
+                assert_eq!(port, 9100);
+                assert_eq!(messages_dir, "/srv/finapp/inbox");
+                assert_eq!(on_collision, CollisionPolicy::Reject);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+
+        let args = Args::try_parse_from([
+            "stl_finapp", "listen", "--port", "9200", "--messages-dir", "inbox",
+        ])
+        .unwrap();
+        match args.command {
+            Some(Commands::Listen { port, messages_dir, .. }) => {
+                assert_eq!(port, 9200);
+                assert_eq!(messages_dir, "inbox");
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+
+        std::env::remove_var("FINAPP_PORT");
+        std::env::remove_var("FINAPP_MESSAGES_DIR");
+        std::env::remove_var("FINAPP_ON_COLLISION");
+    }
 }
@@ -1,5 +1,7 @@
 pub mod args;
 pub mod output;
+pub mod prompt;
 
-pub use args::{Args, Commands};
-pub use output::Output;
+pub use args::{Args, Commands, ProtocolCommand, WhitelistCommand, DumpFormat, shorthand_send};
+pub use output::{Output, ColorChoice};
+pub use prompt::{key_passphrase, new_key_passphrase, KEY_PASSPHRASE_ENV};
@@ -1,4 +1,88 @@
+use std::io::IsTerminal;
+use std::net::SocketAddr;
+use std::path::Path;
+use clap::ValueEnum;
 use colored::Colorize;
+use crate::auth::WhitelistEntry;
+use crate::server::{ConnectionId, ShutdownReport};
+
+/// When CLI output should be colored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stdout is a terminal, honouring `NO_COLOR` and `CLICOLOR`
+    #[default]
+    Auto,
+    /// Always emit color codes
+    Always,
+    /// Never emit color codes
+    Never,
+}
+
+impl ColorChoice {
+    /// Make every later `Output` call follow this setting
+    pub fn apply(self) {
+        let enabled = self.enabled(|name| std::env::var(name).ok(), std::io::stdout().is_terminal());
+        colored::control::set_override(enabled);
+    }
+
+    /// Decide whether to color, given an environment lookup and whether stdout is a TTY
+    ///
+    /// `NO_COLOR` (any non-empty value) wins over `CLICOLOR_FORCE`, which
+    /// wins over `CLICOLOR=0`; explicit `always`/`never` ignore all three.
+    fn enabled(self, env: impl Fn(&str) -> Option<String>, is_tty: bool) -> bool {
+        let set = |name: &str| env(name).is_some_and(|v| !v.is_empty());
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto if set("NO_COLOR") => false,
+            ColorChoice::Auto if env("CLICOLOR_FORCE").is_some_and(|v| v != "0") => true,
+            ColorChoice::Auto if env("CLICOLOR").as_deref() == Some("0") => false,
+            ColorChoice::Auto => is_tty,
+        }
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static CAPTURED: std::cell::RefCell<Option<Vec<String>>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Prefix `line` with the id of the connection being handled, if any
+fn tagged(line: String) -> String {
+    let line = match ConnectionId::current() {
+        Some(id) => format!("{} {}", format!("[conn {}]", id).dimmed(), line),
+        None => line,
+    };
+    #[cfg(test)]
+    CAPTURED.with(|captured| {
+        if let Some(lines) = captured.borrow_mut().as_mut() {
+            lines.push(line.clone());
+        }
+    });
+    line
+}
+
+/// Print a line to stdout
+fn emit(line: String) {
+    println!("{}", tagged(line));
+}
+
+/// Print a line to stderr
+fn emit_err(line: String) {
+    eprintln!("{}", tagged(line));
+}
+
+/// Collect the lines `Output` prints on this thread until [`take_captured`] is called
+#[cfg(test)]
+pub(crate) fn start_capture() {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+}
+
+/// Stop collecting and return what was printed since [`start_capture`]
+#[cfg(test)]
+pub(crate) fn take_captured() -> Vec<String> {
+    CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default())
+}
 
 /// Colored CLI output utilities
 pub struct Output;
@@ -6,99 +90,101 @@ pub struct Output;
 impl Output {
     /// Print an info message in cyan
     pub fn info(msg: &str) {
-        println!("{} {}", "[INFO]".cyan().bold(), msg);
+        emit(format!("{} {}", "[INFO]".cyan().bold(), msg));
     }
 
     /// Print a success message in green
     pub fn success(msg: &str) {
-        println!("{} {}", "[SUCCESS]".green().bold(), msg);
+        emit(format!("{} {}", "[SUCCESS]".green().bold(), msg));
     }
 
     /// Print a warning message in yellow
     pub fn warning(msg: &str) {
-        println!("{} {}", "[WARNING]".yellow().bold(), msg);
+        emit(format!("{} {}", "[WARNING]".yellow().bold(), msg));
     }
 
     /// Print an error message in red
     pub fn error(msg: &str) {
-        eprintln!("{} {}", "[ERROR]".red().bold(), msg);
+        emit_err(format!("{} {}", "[ERROR]".red().bold(), msg));
     }
 
     /// Print listening status
-    pub fn listening(ip: &str, port: u16) {
-        println!(
-            "{} Listening on {}:{}",
+    pub fn listening(addr: SocketAddr) {
+        emit(format!(
+            "{} Listening on {}",
             "[-]".blue().bold(),
-            ip.green(),
-            port.to_string().green()
-        );
+            addr.to_string().green()
+        ));
     }
 
     /// Print connecting status
     pub fn connecting(addr: &str) {
-        println!("{} Connecting to {}...", "[*]".yellow().bold(), addr.cyan());
+        emit(format!("{} Connecting to {}...", "[*]".yellow().bold(), addr.cyan()));
     }
 
     /// Print connected status
     pub fn connected(ip: &str) {
-        println!("{} Connected to {}", "[+]".green().bold(), ip.cyan());
+        emit(format!("{} Connected to {}", "[+]".green().bold(), ip.cyan()));
     }
 
     /// Print authenticating status
     pub fn authenticating() {
-        println!("{} Authenticating...", "[*]".yellow().bold());
+        emit(format!("{} Authenticating...", "[*]".yellow().bold()));
     }
 
-    /// Print authenticated status
-    pub fn authenticated() {
-        println!("{} Authentication successful", "[+]".green().bold());
+    /// Print authenticated status, naming the peer's whitelist label if it has one
+    pub fn authenticated(label: Option<&str>) {
+        match label {
+            Some(label) => emit(format!("{} Authenticated as {}", "[+]".green().bold(), label.cyan())),
+            None => emit(format!("{} Authentication successful", "[+]".green().bold())),
+        }
     }
 
     /// Print authentication failed
     pub fn auth_failed(reason: &str) {
-        println!("{} Authentication failed: {}", "[!]".red().bold(), reason);
+        emit(format!("{} Authentication failed: {}", "[!]".red().bold(), reason));
     }
 
     /// Print encrypting status
     pub fn encrypting() {
-        println!("{} Encrypting message...", "[*]".yellow().bold());
+        emit(format!("{} Encrypting message...", "[*]".yellow().bold()));
     }
 
     /// Print decrypting status
     pub fn decrypting() {
-        println!("{} Decrypting message...", "[*]".yellow().bold());
+        emit(format!("{} Decrypting message...", "[*]".yellow().bold()));
     }
 
     /// Print sending status
     pub fn sending(size: usize) {
-        println!("{} Sending {} bytes...", "[*]".yellow().bold(), size);
+        emit(format!("{} Sending {} bytes...", "[*]".yellow().bold(), size));
     }
 
     /// Print receiving status
     pub fn receiving(size: usize) {
-        println!("{} Receiving {} bytes...", "[*]".yellow().bold(), size);
+        emit(format!("{} Receiving {} bytes...", "[*]".yellow().bold(), size));
     }
 
     /// Print message received
     pub fn message_received(from: &str, filename: &str) {
-        println!(
+        emit(format!(
             "{} Message received from {} - saved as {}",
             "[+]".green().bold(),
             from.cyan(),
             filename.magenta()
-        );
+        ));
     }
 
     /// Print helper/tip message
     pub fn helper(msg: &str) {
-        println!("{} {}", "[?]".magenta().bold(), msg.white());
+        emit(format!("{} {}", "[?]".magenta().bold(), msg.white()));
     }
 
     /// Print a section header
     pub fn header(msg: &str) {
-        println!("\n{}", msg.cyan().bold().underline());
-        println!("{}", "Developed by sweetrush".dimmed().italic());
-        println!("{}", "─".repeat(50).dimmed());
+        emit(format!("\n{}", msg.cyan().bold().underline()));
+        emit("Developed by sweetrush".dimmed().italic().to_string());
+        emit("─".repeat(50).dimmed().to_string());
     }
 
     /// Print key generation success
@@ -113,6 +199,34 @@ impl Output {
         Self::success(&format!("Connect key added to whitelist: {}", key));
     }
 
+    /// Print whitelist key removed
+    pub fn whitelist_removed(key: &str) {
+        Self::success(&format!("Connect key removed from whitelist: {}", key));
+    }
+
+    /// Print the entries of a whitelist with the lines they are on
+    pub fn whitelist_entries(path: &Path, entries: &[WhitelistEntry]) {
+        if entries.is_empty() {
+            Self::warning(&format!("{} lists no connect keys; every peer is refused", path.display()));
+            return;
+        }
+        Self::info(&format!("{} connect keys in {}", entries.len(), path.display()));
+        for entry in entries {
+            let line = entry.line.map_or_else(|| "-".to_string(), |line| line.to_string());
+            let mut shown = format!("  {} {}", format!("line {}:", line).dimmed(), entry.display_key().cyan());
+            if let Some(pattern) = &entry.pattern {
+                shown.push_str(&format!("  pattern={}", pattern));
+            }
+            if let Some(quota) = &entry.quota {
+                shown.push_str(&format!("  quota {}", quota));
+            }
+            if let Some(label) = &entry.label {
+                shown.push_str(&format!("  # {}", label));
+            }
+            emit(shown);
+        }
+    }
+
     /// Print server started
     pub fn server_started(port: u16) {
         Self::success(&format!("Server started on port {}", port));
@@ -124,8 +238,41 @@ impl Output {
         Self::info(&format!("Connection from {}", addr));
     }
 
+    /// Print the end-of-run summary of a stopped server
+    pub fn shutdown_report(report: &ShutdownReport) {
+        Self::info(&format!(
+            "Server stopped after {}s: {} connections, {} files received, {} interrupted, {} panicked",
+            report.uptime_secs, report.connections, report.files_received, report.interrupted, report.panicked
+        ));
+        if !report.rejected.is_empty() {
+            let reasons: Vec<String> = report.rejected.iter().map(|(reason, count)| format!("{} {}", reason, count)).collect();
+            Self::info(&format!("Rejected {} connections: {}", report.rejected_total(), reasons.join(", ")));
+        }
+    }
+
     /// Print file saved
     pub fn file_saved(filename: &str) {
         Self::success(&format!("File saved: {}", filename));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |name| vars.iter().find(|(k, _)| *k == name).map(|(_, v)| v.to_string())
+    }
+
+    #[test]
+    fn test_color_choice_respects_env() {
+        assert!(ColorChoice::Auto.enabled(env(&[]), true));
+        assert!(!ColorChoice::Auto.enabled(env(&[]), false));
+        assert!(!ColorChoice::Auto.enabled(env(&[("NO_COLOR", "1")]), true));
+        assert!(!ColorChoice::Auto.enabled(env(&[("CLICOLOR", "0")]), true));
+        assert!(ColorChoice::Auto.enabled(env(&[("CLICOLOR_FORCE", "1")]), false));
+
+        assert!(ColorChoice::Always.enabled(env(&[("NO_COLOR", "1")]), false));
+        assert!(!ColorChoice::Never.enabled(env(&[("CLICOLOR_FORCE", "1")]), true));
+    }
+}
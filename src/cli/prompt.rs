@@ -0,0 +1,55 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::Path;
+use crate::error::{AppError, Result};
+
+/// Environment variable supplying the passphrase of an encrypted private key
+pub const KEY_PASSPHRASE_ENV: &str = "FINAPP_KEY_PASSPHRASE";
+
+/// Passphrase for the encrypted private key in `dir`
+///
+/// Taken from `FINAPP_KEY_PASSPHRASE` when set, so scripts and services can
+/// start unattended; otherwise asked for on the terminal.
+pub fn key_passphrase(dir: &Path) -> Result<String> {
+    if let Ok(passphrase) = std::env::var(KEY_PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    read_passphrase(&format!("Passphrase for the private key in {}: ", dir.display()))
+}
+
+/// Passphrase to encrypt a new private key with, asked for twice unless set in the environment
+pub fn new_key_passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var(KEY_PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = read_passphrase("New private key passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err(AppError::Cli("The passphrase must not be empty".to_string()));
+    }
+    if read_passphrase("Repeat the passphrase: ")? != passphrase {
+        return Err(AppError::Cli("Passphrases do not match".to_string()));
+    }
+    Ok(passphrase)
+}
+
+/// Ask for a passphrase without echoing it when stdin is a terminal
+///
+/// Piped input is read as a plain line, so scripts can still feed one in.
+fn read_passphrase(prompt: &str) -> Result<String> {
+    if !io::stdin().is_terminal() {
+        return read_line(prompt);
+    }
+    rpassword::prompt_password(prompt).map_err(|e| AppError::Cli(format!("Failed to read input: {}", e)))
+}
+
+/// Print `prompt` to stderr and read one line from stdin
+fn read_line(prompt: &str) -> Result<String> {
+    eprint!("{}", prompt);
+    io::stderr().flush().ok();
+
+    let mut input = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut input)
+        .map_err(|e| AppError::Cli(format!("Failed to read input: {}", e)))?;
+    Ok(input.trim_end_matches(['\r', '\n']).to_string())
+}
@@ -0,0 +1,109 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::error::{AppError, Result};
+
+/// Files to send in one session, with per-file options
+///
+/// Written in TOML as one `[[file]]` table per entry:
+///
+/// ```toml
+/// [[file]]
+/// path = "out/ledger.csv"
+/// save_as = "ledger_eod"
+/// note = "EOD settlement batch"
+/// ttl = 86400
+/// ```
+///
+/// Relative paths are resolved against the manifest's own directory, so a
+/// manifest works the same from whatever directory the job runs in.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Entries in the order they are sent
+    #[serde(rename = "file")]
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// One file listed in a [`Manifest`]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    /// File to send
+    pub path: PathBuf,
+    /// Name requested from the server instead of the file's own name
+    pub save_as: Option<String>,
+    /// Note sent alongside the file
+    pub note: Option<String>,
+    /// How long the server should keep the file, in seconds
+    pub ttl: Option<u64>,
+}
+
+impl ManifestEntry {
+    /// Name the file is sent under
+    pub fn filename(&self) -> &str {
+        self.save_as
+            .as_deref()
+            .or_else(|| self.path.file_name().and_then(|n| n.to_str()))
+            .unwrap_or("message")
+    }
+}
+
+impl Manifest {
+    /// Read and validate a manifest file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| AppError::Config(format!("Failed to read manifest {}: {}", path.display(), e)))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&text, base)
+            .map_err(|e| AppError::Config(format!("Invalid manifest {}: {}", path.display(), e)))
+    }
+
+    /// Parse a manifest, resolving relative paths against `base`
+    ///
+    /// Every listed file must exist, so a typo fails the job before anything
+    /// is sent rather than halfway through the batch.
+    pub fn parse(text: &str, base: &Path) -> std::result::Result<Self, String> {
+        let mut manifest: Manifest = toml::from_str(text).map_err(|e| e.to_string())?;
+        if manifest.entries.is_empty() {
+            return Err("no [[file]] entries".to_string());
+        }
+
+        let mut missing = Vec::new();
+        for entry in &mut manifest.entries {
+            if entry.path.is_relative() {
+                entry.path = base.join(&entry.path);
+            }
+            if !entry.path.is_file() {
+                missing.push(entry.path.display().to_string());
+            }
+        }
+        if !missing.is_empty() {
+            return Err(format!("files not found: {}", missing.join(", ")));
+        }
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_resolves_paths_and_rejects_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("ledger.csv"), b"ledger").unwrap();
+
+        let text = "[[file]]\npath = \"ledger.csv\"\nsave_as = \"eod\"\nttl = 60\n";
+        let manifest = Manifest::parse(text, dir.path()).unwrap();
+        assert_eq!(manifest.entries[0].path, dir.path().join("ledger.csv"));
+        assert_eq!(manifest.entries[0].filename(), "eod");
+        assert_eq!(manifest.entries[0].ttl, Some(60));
+
+        let err = Manifest::parse("[[file]]\npath = \"gone.csv\"\n", dir.path()).unwrap_err();
+        assert!(err.contains("gone.csv"), "{}", err);
+        let err = Manifest::parse("[[file]]\npath = \"ledger.csv\"\nsaveas = \"x\"\n", dir.path()).unwrap_err();
+        assert!(err.contains("line 3"), "{}", err);
+        assert!(Manifest::parse("", dir.path()).is_err());
+    }
+}
@@ -1,3 +1,7 @@
 pub mod sender;
+pub mod manifest;
+pub mod pool;
 
-pub use sender::Client;
+pub use sender::{Client, ClientSession, SendTimings, expand_file_patterns};
+pub use manifest::{Manifest, ManifestEntry};
+pub use pool::{ClientPool, SendJob, SendReport};
@@ -0,0 +1,293 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use crate::error::{AppError, Result};
+use crate::crypto::KeyPair;
+use crate::compression::{Compression, CompressionStats};
+use crate::security::SecurityLevel;
+use crate::protocol::HandshakeOptions;
+use super::sender::{Client, SendTimings};
+
+/// One file to deliver to one server
+#[derive(Debug, Clone)]
+pub struct SendJob {
+    /// Server IP address, or `unix:/path/to.sock`
+    pub server_ip: String,
+    /// Server port
+    pub port: u16,
+    /// File to send
+    pub file: PathBuf,
+    /// Connect key for this server
+    pub connect_key: String,
+    /// Name requested from the server instead of the file's own name
+    pub save_as: Option<String>,
+    /// Note sent alongside the file
+    pub note: Option<String>,
+}
+
+impl SendJob {
+    /// Send `file` to `server_ip:port` with `connect_key`
+    pub fn new(server_ip: &str, port: u16, file: PathBuf, connect_key: &str) -> Self {
+        Self {
+            server_ip: server_ip.to_string(),
+            port,
+            file,
+            connect_key: connect_key.to_string(),
+            save_as: None,
+            note: None,
+        }
+    }
+
+    /// Request a different name on the server
+    pub fn with_save_as(mut self, save_as: Option<&str>) -> Self {
+        self.save_as = save_as.map(|s| s.to_string());
+        self
+    }
+
+    /// Attach a note to the transfer
+    pub fn with_note(mut self, note: Option<&str>) -> Self {
+        self.note = note.map(|n| n.to_string());
+        self
+    }
+}
+
+/// Outcome of one [`SendJob`]
+#[derive(Debug)]
+pub struct SendReport {
+    /// The job this report is for
+    pub job: SendJob,
+    /// Name the server stored the file under, or why it was not delivered
+    pub result: Result<String>,
+    /// Time from connecting to the final acknowledgment or error
+    pub elapsed: Duration,
+    /// Handshake and transfer time of a delivered file
+    pub timings: Option<SendTimings>,
+}
+
+impl SendReport {
+    /// Whether the file was delivered
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+
+    /// The delivery error, if any
+    pub fn error(&self) -> Option<&AppError> {
+        self.result.as_ref().err()
+    }
+
+    /// Sizes and time of compressing a delivered file, if it was compressed
+    pub fn compression(&self) -> Option<&CompressionStats> {
+        self.timings.as_ref()?.compression.as_ref()
+    }
+}
+
+/// Sends to many servers at once from one loaded key pair
+///
+/// Every job gets its own connection, but the key pair is shared rather than
+/// loaded per target, and at most `max_concurrent` jobs run at a time.
+pub struct ClientPool {
+    keypair: Arc<KeyPair>,
+    handshake: HandshakeOptions,
+    verify_delivery: bool,
+    known_hosts: Option<PathBuf>,
+    limit: Arc<Semaphore>,
+    max_concurrent: usize,
+    peak: Arc<AtomicUsize>,
+}
+
+impl ClientPool {
+    /// Create a pool running at most `max_concurrent` sends at once
+    pub fn new(keypair: KeyPair, max_concurrent: usize) -> Self {
+        let max_concurrent = max_concurrent.max(1);
+        Self {
+            keypair: Arc::new(keypair),
+            // Compression is opt-in on the sending side, as for `Client`
+            handshake: HandshakeOptions {
+                compression: Vec::new(),
+                ..HandshakeOptions::default()
+            },
+            verify_delivery: false,
+            known_hosts: None,
+            limit: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            peak: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Use the handshake settings of a security level for every send
+    ///
+    /// `strict` only connects to servers pinned in the file given to
+    /// [`ClientPool::with_known_hosts`].
+    pub fn with_security_level(mut self, level: SecurityLevel) -> Self {
+        level.apply(&mut self.handshake);
+        self
+    }
+
+    /// Pin server keys in a known hosts file, as [`Client::with_known_hosts`]
+    pub fn with_known_hosts(mut self, path: Option<&Path>) -> Self {
+        self.known_hosts = path.map(|p| p.to_path_buf());
+        self
+    }
+
+    /// Announce an informational identity to every server
+    pub fn with_identity(mut self, identity: Option<&str>) -> Self {
+        self.handshake.identity = identity.map(|i| i.to_string());
+        self
+    }
+
+    /// Offer to compress transfers, preferred algorithm first
+    pub fn with_compression(mut self, algorithms: &[Compression]) -> Self {
+        self.handshake.compression = algorithms.to_vec();
+        self
+    }
+
+    /// After each ack, have the server read the stored file back and compare checksums
+    pub fn with_verify_delivery(mut self, enabled: bool) -> Self {
+        self.verify_delivery = enabled;
+        self
+    }
+
+    /// Most sends this pool allows at once
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// Most sends that have actually run at once so far
+    pub fn peak_concurrency(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Run every job, returning one report per job in the order given
+    ///
+    /// A failed job does not affect the others.
+    pub async fn send_all(&self, jobs: Vec<SendJob>) -> Vec<SendReport> {
+        let running = Arc::new(AtomicUsize::new(0));
+        let mut tasks = JoinSet::new();
+
+        for (index, job) in jobs.into_iter().enumerate() {
+            let limit = self.limit.clone();
+            let running = running.clone();
+            let peak = self.peak.clone();
+            let client = Client::with_shared_keypair(&job.server_ip, job.port, self.keypair.clone())
+                .with_handshake(self.handshake.clone())
+                .with_verify_delivery(self.verify_delivery)
+                .with_known_hosts(self.known_hosts.as_deref());
+
+            tasks.spawn(async move {
+                // The semaphore is never closed, so acquiring only waits
+                let _permit = limit.acquire_owned().await.expect("pool semaphore closed");
+                let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now_running, Ordering::Relaxed);
+
+                let started = Instant::now();
+                let sent = client
+                    .send_message_timed(&job.file, &job.connect_key, job.save_as.as_deref(), job.note.as_deref())
+                    .await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                let (result, timings) = match sent {
+                    Ok((saved_as, timings)) => (Ok(saved_as), Some(timings)),
+                    Err(e) => (Err(e), None),
+                };
+                (index, SendReport { job, result, elapsed: started.elapsed(), timings })
+            });
+        }
+
+        let mut reports = Vec::with_capacity(tasks.len());
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok(report) => reports.push(report),
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        }
+        reports.sort_by_key(|(index, _)| *index);
+        reports.into_iter().map(|(_, report)| report).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::auth::Whitelist;
+    use crate::server::{Server, ServerHandle};
+
+    async fn spawn_server(dir: &std::path::Path, name: &str) -> ServerHandle {
+        let whitelist_path = dir.join(format!("{}.whitelist", name));
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.join(name);
+        Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap())
+            .unwrap()
+            .spawn()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_sends_stay_within_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let servers = [spawn_server(dir.path(), "a").await, spawn_server(dir.path(), "b").await];
+
+        let mut jobs = Vec::new();
+        for n in 0..6 {
+            let file = dir.path().join(format!("batch_{}.csv", n));
+            fs::write(&file, format!("row,{}\n", n)).unwrap();
+            let port = servers[n % 2].port();
+            jobs.push(SendJob::new("127.0.0.1", port, file, "secret"));
+        }
+        jobs.push(SendJob::new("127.0.0.1", servers[0].port(), dir.path().join("batch_0.csv"), "wrong-key"));
+
+        let pool = ClientPool::new(KeyPair::generate().unwrap(), 2);
+        let reports = tokio::time::timeout(Duration::from_secs(60), pool.send_all(jobs)).await.unwrap();
+
+        assert_eq!(reports.len(), 7);
+        for (n, report) in reports[..6].iter().enumerate() {
+            let saved_as = report.result.as_ref().unwrap();
+            assert!(saved_as.starts_with(&format!("batch_{}.csv_", n)), "{}", saved_as);
+        }
+        assert!(reports[6].error().is_some());
+        assert!(reports[6].timings.is_none());
+
+        // Both phases are timed and together fit within the whole send
+        for report in &reports[..6] {
+            let timings = report.timings.unwrap();
+            assert!(timings.handshake > Duration::ZERO && timings.transfer > Duration::ZERO);
+            assert!(timings.handshake + timings.transfer <= report.elapsed);
+        }
+        assert!(pool.peak_concurrency() >= 1 && pool.peak_concurrency() <= pool.max_concurrent());
+
+        for server in servers {
+            server.shutdown();
+            server.wait().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compression_ratio_reported_by_client_and_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = spawn_server(dir.path(), "ratio").await;
+        let data = b"date,account,amount\n2024-01-01,ACC-001,100.00\n".repeat(500);
+        let file = dir.path().join("ledger.csv");
+        fs::write(&file, &data).unwrap();
+
+        let pool = ClientPool::new(KeyPair::generate().unwrap(), 1).with_compression(&[Compression::Zstd]);
+        let reports = pool.send_all(vec![SendJob::new("127.0.0.1", server.port(), file, "secret")]).await;
+        let sent = *reports[0].compression().unwrap();
+        assert_eq!(sent.algorithm, Compression::Zstd);
+        assert_eq!(sent.original_bytes, data.len() as u64);
+        assert!(sent.compressed_bytes > 0 && sent.compressed_bytes < sent.original_bytes / 10);
+        assert_eq!(sent.ratio, sent.original_bytes as f64 / sent.compressed_bytes as f64);
+
+        // The server records the same sizes in the sidecar
+        let saved_as = reports[0].result.as_ref().unwrap();
+        let meta = crate::server::storage::read_sidecar(&dir.path().join("ratio").join(saved_as)).unwrap();
+        let received = meta.compression.unwrap();
+        assert_eq!((received.original_bytes, received.compressed_bytes), (sent.original_bytes, sent.compressed_bytes));
+        assert_eq!(received.ratio, sent.ratio);
+
+        server.shutdown();
+        server.wait().await.unwrap();
+    }
+}
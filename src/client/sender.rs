@@ -1,93 +1,1620 @@
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::fs;
-use tokio::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use crate::transport::{self, BoxedStream, UNIX_PREFIX};
 use crate::error::{AppError, Result};
-use crate::crypto::{KeyPair, encrypt_large};
-use crate::protocol::{Handshake, Message, MessageType, MessageHeader, calculate_checksum};
+use rsa::RsaPublicKey;
+use sha2::{Digest, Sha256};
+use crate::crypto::{KeyPair, AuthMethod, CipherSuite, ChunkSealer, encrypt_with_suite, fingerprint, sealed_len, DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE};
+use crate::auth::{KnownHosts, HostCheck};
+use crate::compression::{Compression, CompressionStats};
+use crate::security::SecurityLevel;
+use crate::protocol::{HandshakeOptions, HandshakeOutcome};
+use crate::protocol::{AuthenticatedChannel, FrameCodec, Disconnect, Message, MessageType, MessageHeader, MessageChunk, TransferAck, VerifyRequest, VerifyResponse, MessageMeta, ContentKind, MAX_NOTE_BYTES, validate_identity, calculate_checksum};
+use crate::protocol::message::unexpected_message;
 use crate::protocol::handshake::{send_message, receive_message, send_raw_data};
 use crate::cli::Output;
+use super::manifest::Manifest;
+
+/// How long to wait for the server's disconnect notice after a failed send
+const DISCONNECT_NOTICE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait for a reply once a transfer has been fully sent
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long to wait for the server's `Pong`
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest reply frame accepted from the server
+///
+/// Acks, verify responses and disconnect notices are all a few hundred
+/// bytes; anything near this size is not a well-behaved server.
+const MAX_REPLY_FRAME: usize = 64 * 1024;
+
+/// Where the time of one send went
+///
+/// A slow `handshake` points at the server (its RSA work) or the route to
+/// it; a slow `transfer` with a quick handshake points at the link.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SendTimings {
+    /// Connecting, the handshake and authentication
+    pub handshake: Duration,
+    /// Sending the data until the server acknowledged it
+    pub transfer: Duration,
+    /// Sizes and time of compressing the data, if it was compressed
+    pub compression: Option<CompressionStats>,
+}
 
 /// Client for sending messages to a server
 pub struct Client {
     server_addr: String,
-    keypair: KeyPair,
+    keypair: Arc<KeyPair>,
+    handshake: HandshakeOptions,
+    verify_delivery: bool,
+    known_hosts: Option<PathBuf>,
+    ack_timeout: Duration,
+    chunk_size: Option<usize>,
 }
 
 impl Client {
     /// Create a new client instance
+    ///
+    /// `server_ip` may also be `unix:/path/to.sock` to connect over a Unix
+    /// domain socket, in which case `port` is ignored.
     pub fn new(server_ip: &str, port: u16, keypair: KeyPair) -> Self {
+        Self::with_shared_keypair(server_ip, port, Arc::new(keypair))
+    }
+
+    /// Create a client that shares an already loaded key pair with others
+    pub fn with_shared_keypair(server_ip: &str, port: u16, keypair: Arc<KeyPair>) -> Self {
         Self {
-            server_addr: format!("{}:{}", server_ip, port),
+            server_addr: if server_ip.starts_with(UNIX_PREFIX) {
+                server_ip.to_string()
+            } else {
+                format!("{}:{}", server_ip, port)
+            },
             keypair,
+            // Compression is opt-in on the sending side
+            handshake: HandshakeOptions {
+                compression: Vec::new(),
+                ..HandshakeOptions::default()
+            },
+            verify_delivery: false,
+            known_hosts: None,
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            chunk_size: None,
         }
     }
 
+    /// Replace all handshake settings at once
+    pub(crate) fn with_handshake(mut self, handshake: HandshakeOptions) -> Self {
+        self.handshake = handshake;
+        self
+    }
+
+    /// Restrict the cipher suites offered during the handshake
+    pub fn with_cipher_suites(mut self, suites: &[CipherSuite]) -> Self {
+        self.handshake.cipher_suites = suites.to_vec();
+        self
+    }
+
+    /// Announce an informational identity to the server
+    pub fn with_identity(mut self, identity: Option<&str>) -> Self {
+        self.handshake.identity = identity.map(|i| i.to_string());
+        self
+    }
+
+    /// Refuse to authenticate with a method weaker than `min`
+    pub fn with_min_auth_method(mut self, min: AuthMethod) -> Self {
+        self.handshake.min_auth_method = min;
+        self
+    }
+
+    /// Refuse servers announcing a protocol version older than `min`
+    pub fn with_min_protocol_version(mut self, min: u32) -> Self {
+        self.handshake.min_protocol_version = min;
+        self
+    }
+
+    /// Refuse servers whose strongest common cipher suite is weaker than `min`
+    pub fn with_min_cipher_suite(mut self, min: CipherSuite) -> Self {
+        self.handshake.min_cipher_suite = min;
+        self
+    }
+
+    /// Only accept a server key whose public exponent is exactly 65537
+    pub fn with_exact_public_exponent(mut self, exact: bool) -> Self {
+        self.handshake.exact_public_exponent = exact;
+        self
+    }
+
+    /// Use the handshake settings of a security level
+    ///
+    /// Later `with_*` calls override individual settings.
+    pub fn with_security_level(mut self, level: SecurityLevel) -> Self {
+        level.apply(&mut self.handshake);
+        self
+    }
+
+    /// Offer to compress transfers, preferred algorithm first
+    ///
+    /// The server picks one it supports; if it supports none of them the
+    /// data is sent uncompressed.
+    pub fn with_compression(mut self, algorithms: &[Compression]) -> Self {
+        self.handshake.compression = algorithms.to_vec();
+        self
+    }
+
+    /// After each ack, have the server read the stored file back and compare checksums
+    pub fn with_verify_delivery(mut self, enabled: bool) -> Self {
+        self.verify_delivery = enabled;
+        self
+    }
+
+    /// Refuse servers that have no key pinned yet instead of pinning on first use
+    ///
+    /// Needs a known hosts file from [`Client::with_known_hosts`].
+    pub fn with_require_pinned_host(mut self, required: bool) -> Self {
+        self.handshake.require_pinned_host = required;
+        self
+    }
+
+    /// Pin server keys in a known hosts file, refusing servers whose key changed
+    ///
+    /// Pins are kept per local key, so switching identities never reuses
+    /// trust established under another one.
+    pub fn with_known_hosts(mut self, path: Option<&Path>) -> Self {
+        self.known_hosts = path.map(|p| p.to_path_buf());
+        self
+    }
+
+    /// Give up on a reply this long after the data was sent
+    ///
+    /// The data has reached the server by then, so the error says delivery is
+    /// unconfirmed rather than failed.
+    pub fn with_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = timeout;
+        self
+    }
+
+    /// Stream files from disk in encrypted chunks of this many bytes
+    ///
+    /// Only one chunk of the file is held in memory on either side. Chunked
+    /// transfers are never compressed. Applies to files sent with
+    /// [`Client::send_message`] and the calls built on it; directory archives
+    /// are always sent in chunks, of this size if set.
+    pub fn with_chunk_size(mut self, chunk_size: Option<usize>) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
     /// Send a message to the server
     pub async fn send_message(
         &self,
         message_file: &Path,
         connect_key: &str,
         save_as: Option<&str>,
+        note: Option<&str>,
     ) -> Result<String> {
-        Output::connecting(&self.server_addr);
+        let (saved_as, _) = self.send_message_timed(message_file, connect_key, save_as, note).await?;
+        Ok(saved_as)
+    }
 
-        // Connect to server
-        let mut stream = TcpStream::connect(&self.server_addr)
-            .await
-            .map_err(|e| AppError::Client(format!("Failed to connect to {}: {}", self.server_addr, e)))?;
+    /// Send a message to the server, also reporting how long each phase took
+    pub async fn send_message_timed(
+        &self,
+        message_file: &Path,
+        connect_key: &str,
+        save_as: Option<&str>,
+        note: Option<&str>,
+    ) -> Result<(String, SendTimings)> {
+        let filename = message_file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("message");
+        let filename = save_as.unwrap_or(filename);
 
-        // Perform handshake
-        Output::authenticating();
-        let _server_public = Handshake::client_side(&mut stream, connect_key, &self.keypair).await?;
+        if let Some(chunk_size) = self.chunk_size {
+            return self.transfer_chunked(message_file, filename, connect_key, note, chunk_size).await;
+        }
 
         // Read message file
         let message_data = fs::read(message_file)
             .map_err(|e| AppError::Client(format!("Failed to read message file: {}", e)))?;
+        self.transfer(&message_data, filename, connect_key, note, ContentKind::File).await
+    }
 
-        let filename = save_as.unwrap_or_else(|| {
-            message_file
-                .file_name()
+    /// Send an in-memory buffer as if it were a file called `filename`
+    ///
+    /// `save_as` overrides `filename` as the name requested from the server.
+    pub async fn send_bytes(
+        &self,
+        data: &[u8],
+        filename: &str,
+        connect_key: &str,
+        save_as: Option<&str>,
+        note: Option<&str>,
+    ) -> Result<String> {
+        let filename = save_as.unwrap_or(filename);
+        let (saved_as, _) = self.transfer(data, filename, connect_key, note, ContentKind::File).await?;
+        Ok(saved_as)
+    }
+
+    /// Send an in-memory buffer and have the server confirm what it stored
+    ///
+    /// Like [`Client::send_bytes`] with delivery verification always on, but
+    /// returns the metadata the server recorded for the file rather than just
+    /// the name it was saved as.
+    pub async fn send_bytes_verified(
+        &self,
+        data: &[u8],
+        filename: &str,
+        connect_key: &str,
+        save_as: Option<&str>,
+        note: Option<&str>,
+    ) -> Result<MessageMeta> {
+        self.check_limits(note)?;
+        let (mut stream, outcome) = self.connect(connect_key).await?.into_parts();
+        let payload = Payload { data, filename: save_as.unwrap_or(filename), note, content: ContentKind::File, ttl: None };
+        let (saved_as, _) = self.acknowledged(&mut stream, &outcome, &payload, 0).await?;
+        let response = verify_delivery(&mut stream, &saved_as, &calculate_checksum(data), self.ack_timeout).await?;
+        response
+            .meta
+            .ok_or_else(|| AppError::Protocol(format!("Server sent no metadata for {}", saved_as)))
+    }
+
+    /// Send several files, one transfer each, returning the outcome per file
+    ///
+    /// A failed file does not stop the remaining ones from being sent.
+    pub async fn send_files(
+        &self,
+        files: &[PathBuf],
+        connect_key: &str,
+        note: Option<&str>,
+    ) -> Vec<(PathBuf, Result<String>)> {
+        let mut outcomes = Vec::with_capacity(files.len());
+        for file in files {
+            let result = self.send_message(file, connect_key, None, note).await;
+            outcomes.push((file.clone(), result));
+        }
+        outcomes
+    }
+
+    /// Send a whole directory to the server as a single tar archive
+    ///
+    /// The archive is never held whole: it is built once to checksum it and
+    /// again while sending, piped into sealed chunks of the configured chunk
+    /// size (1 MiB by default), so memory use does not grow with the tree.
+    /// Archives are not compressed. A file that changes between the two
+    /// passes makes the server refuse the archive's checksum.
+    pub async fn send_directory_archive(
+        &self,
+        dir: &Path,
+        connect_key: &str,
+        save_as: Option<&str>,
+        note: Option<&str>,
+    ) -> Result<String> {
+        let chunk_size = self.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+        check_chunk_size(chunk_size)?;
+        self.check_limits(note)?;
+        let name = save_as.unwrap_or_else(|| {
+            dir.file_name()
                 .and_then(|n| n.to_str())
-                .unwrap_or("message")
+                .unwrap_or("directory")
         });
 
+        let (len, checksum) = hash_directory_archive(dir).await?;
+        let header = MessageHeader::new(name, sealed_len(len, chunk_size), &checksum)
+            .with_note(note)
+            .with_content(ContentKind::Directory);
+
+        // The archiver blocks on the pipe until the chunks before have been sent
+        let (mut archive, pipe) = tokio::io::duplex(chunk_size);
+        let archiving = {
+            let (dir, runtime) = (dir.to_path_buf(), tokio::runtime::Handle::current());
+            tokio::task::spawn_blocking(move || write_directory_archive(&dir, BlockingWriter { inner: pipe, runtime }).map(drop))
+        };
+        let sent = self.send_chunked(header, &mut archive, len, connect_key, chunk_size).await;
+        // Closing the pipe stops an archiver left waiting by a failed send
+        drop(archive);
+        let archived = archiving
+            .await
+            .unwrap_or_else(|e| Err(AppError::Client(format!("Archive task failed: {}", e))));
+
+        match (sent, archived) {
+            (Ok((saved_as, _)), _) => Ok(saved_as),
+            // A read error on the pipe is explained by what stopped the archiver
+            (Err(_), Err(e)) => Err(e),
+            (Err(e), Ok(())) => Err(e),
+        }
+    }
+
+    /// Send every file under `dir` over one connection, one transfer each
+    ///
+    /// Files are sent in path order, each with its own header, checksum and
+    /// acknowledgment, and the batch ends with `EndOfBatch`. Subdirectories
+    /// are walked too, but only file names reach the server. As with
+    /// [`Client::send_manifest`], once the connection fails the remaining
+    /// files are reported as not sent.
+    pub async fn send_directory(
+        &self,
+        dir: &Path,
+        connect_key: &str,
+        note: Option<&str>,
+    ) -> Result<Vec<(PathBuf, Result<String>)>> {
+        self.check_limits(note)?;
+        let files = directory_files(dir)?;
+        let (mut stream, outcome) = self.connect(connect_key).await?.into_parts();
+
+        let mut outcomes = Vec::with_capacity(files.len());
+        let mut broken: Option<String> = None;
+        for (sequence, file) in files.into_iter().enumerate() {
+            let result = match (&broken, fs::read(&file)) {
+                (Some(reason), _) => Err(AppError::Client(format!("Not sent: {}", reason))),
+                (None, Err(e)) => Err(AppError::Client(format!("Failed to read {}: {}", file.display(), e))),
+                (None, Ok(data)) => {
+                    let filename = file.file_name().and_then(|n| n.to_str()).unwrap_or("message");
+                    let payload = Payload { data: &data, filename, note, content: ContentKind::File, ttl: None };
+                    let result = self
+                        .deliver(&mut stream, &outcome, &payload, sequence as u64)
+                        .await
+                        .map(|(saved_as, _)| saved_as);
+                    if let Err(e) = &result {
+                        broken = Some(format!("connection failed on {}: {}", file.display(), e));
+                    }
+                    result
+                }
+            };
+            outcomes.push((file, result));
+        }
+
+        if broken.is_none() {
+            send_message(&mut stream, &Message::new(MessageType::EndOfBatch, Vec::new())).await?;
+            let _ = stream.shutdown().await;
+        }
+        let sent = outcomes.iter().filter(|(_, result)| result.is_ok()).count();
+        Output::info(&format!("Transferred {} of {} files from {}", sent, outcomes.len(), dir.display()));
+        Ok(outcomes)
+    }
+
+    /// Send several files back-to-back over one connection without waiting
+    /// for each acknowledgment
+    ///
+    /// Acks are matched to files by the sequence id carried in each header, so
+    /// the server may acknowledge them in any order. Connection and handshake
+    /// failures fail the whole call; per-file failures are reported per file.
+    pub async fn send_files_pipelined(
+        &self,
+        files: &[PathBuf],
+        connect_key: &str,
+        note: Option<&str>,
+    ) -> Result<Vec<(PathBuf, Result<String>)>> {
+        self.check_limits(note)?;
+        let (stream, outcome) = self.connect(connect_key).await?.into_parts();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let (sent_tx, sent_rx) = oneshot::channel::<()>();
+
+        let send_all = async {
+            let mut result = Ok(());
+            for (sequence, file) in files.iter().enumerate() {
+                let sent = match fs::read(file) {
+                    Ok(data) => {
+                        let filename = file.file_name().and_then(|n| n.to_str()).unwrap_or("message");
+                        let payload = Payload { data: &data, filename, note, content: ContentKind::File, ttl: None };
+                        self.send_payload(&mut writer, &outcome, &payload, sequence as u64).await
+                    }
+                    Err(e) => Err(AppError::Client(format!("Failed to read {}: {}", file.display(), e))),
+                };
+                if let Err(e) = sent {
+                    result = Err(e);
+                    break;
+                }
+            }
+            // Closing our half tells the server there are no more transfers
+            let _ = writer.shutdown().await;
+            let _ = sent_tx.send(());
+            result
+        };
+
+        let collect_acks = async {
+            // Uploads of later files may outlast the ack timeout, so the
+            // clock only starts once everything has been sent
+            let deadline = async {
+                let _ = sent_rx.await;
+                tokio::time::sleep(self.ack_timeout).await;
+            };
+            tokio::pin!(deadline);
+
+            let mut acks = HashMap::new();
+            while acks.len() < files.len() {
+                let reply = tokio::select! {
+                    reply = read_reply_or_eof(&mut reader) => reply,
+                    _ = &mut deadline => return (acks, Some(reply_timeout(self.ack_timeout).to_string())),
+                };
+                match reply {
+                    Ok(Some(msg)) => match parse_ack(msg) {
+                        Ok(ack) => {
+                            acks.insert(ack.sequence, ack.saved_as);
+                        }
+                        Err(e) => return (acks, Some(e.to_string())),
+                    },
+                    Ok(None) => return (acks, Some("Connection closed by server".to_string())),
+                    Err(e) => return (acks, Some(e.to_string())),
+                }
+            }
+            (acks, None)
+        };
+
+        let (sent, (mut acks, ack_failure)) = tokio::join!(send_all, collect_acks);
+        let failure = ack_failure
+            .or_else(|| sent.err().map(|e| e.to_string()))
+            .unwrap_or_else(|| "No acknowledgment received".to_string());
+
+        Ok(files
+            .iter()
+            .enumerate()
+            .map(|(sequence, file)| {
+                let result = acks
+                    .remove(&(sequence as u64))
+                    .ok_or_else(|| AppError::Client(failure.clone()));
+                (file.clone(), result)
+            })
+            .collect())
+    }
+
+    /// Connect, authenticate and transfer a single payload
+    async fn transfer(
+        &self,
+        message_data: &[u8],
+        filename: &str,
+        connect_key: &str,
+        note: Option<&str>,
+        content: ContentKind,
+    ) -> Result<(String, SendTimings)> {
+        self.check_limits(note)?;
+        let started = Instant::now();
+        let (mut stream, outcome) = self.connect(connect_key).await?.into_parts();
+        let handshake = started.elapsed();
+        Output::info(&format!("Handshake took {} ms", handshake.as_millis()));
+
+        let payload = Payload { data: message_data, filename, note, content, ttl: None };
+        let (saved_as, compression) = self.deliver(&mut stream, &outcome, &payload, 0).await?;
+        let timings = SendTimings { handshake, transfer: started.elapsed() - handshake, compression };
+        Output::info(&format!("Transfer took {} ms", timings.transfer.as_millis()));
+        Ok((saved_as, timings))
+    }
+
+    /// Connect, authenticate and stream a file from disk in chunks
+    ///
+    /// The file is read twice: once for the checksum that goes in the header,
+    /// then again chunk by chunk while sending.
+    async fn transfer_chunked(
+        &self,
+        message_file: &Path,
+        filename: &str,
+        connect_key: &str,
+        note: Option<&str>,
+        chunk_size: usize,
+    ) -> Result<(String, SendTimings)> {
+        check_chunk_size(chunk_size)?;
+        self.check_limits(note)?;
+        let (len, checksum) = hash_file(message_file).await?;
+        let header = MessageHeader::new(filename, sealed_len(len, chunk_size), &checksum).with_note(note);
+        let mut file = tokio::fs::File::open(message_file)
+            .await
+            .map_err(|e| AppError::Client(format!("Failed to read message file: {}", e)))?;
+        self.send_chunked(header, &mut file, len, connect_key, chunk_size).await
+    }
+
+    /// Connect, authenticate and send `len` bytes of `source` in sealed chunks under `header`
+    ///
+    /// `header` already carries the name, sealed size and checksum; the
+    /// stream key and signature are added here once the server's key is known.
+    async fn send_chunked<R: AsyncRead + Unpin>(
+        &self,
+        header: MessageHeader,
+        source: &mut R,
+        len: u64,
+        connect_key: &str,
+        chunk_size: usize,
+    ) -> Result<(String, SendTimings)> {
+        let started = Instant::now();
+        let (mut stream, outcome) = self.connect(connect_key).await?.into_parts();
+        let handshake = started.elapsed();
+        Output::info(&format!("Handshake took {} ms", handshake.as_millis()));
+
+        Output::info(&format!("Sending file: {} ({} bytes)", header.filename, len));
+        Output::encrypting();
+        let (sealer, stream_key) = ChunkSealer::new(&outcome.peer_public_key, outcome.cipher_suite)?;
+        let header = header
+            .with_stream_key(Some(stream_key))
+            .signed_with(&self.keypair.private_key)?;
+        if let Err(e) = send_chunks(&mut stream, &header, sealer, source, len, chunk_size).await {
+            return Err(disconnect_notice(&mut stream).await.unwrap_or(e));
+        }
+        let ack = parse_ack(receive_reply(&mut stream, self.ack_timeout).await?)?;
+        Output::success(&format!("Message delivered, saved as: {}", ack.saved_as));
+        if self.verify_delivery {
+            verify_delivery(&mut stream, &ack.saved_as, &header.checksum, self.ack_timeout).await?;
+        }
+
+        let timings = SendTimings { handshake, transfer: started.elapsed() - handshake, compression: None };
+        Output::info(&format!("Transfer took {} ms", timings.transfer.as_millis()));
+        Ok((ack.saved_as, timings))
+    }
+
+    /// Send every file in a manifest over one connection, in order
+    ///
+    /// Each entry is acknowledged before the next is sent. A file that cannot
+    /// be read fails only its own entry; once the connection fails, the
+    /// entries after it are reported as not sent.
+    pub async fn send_manifest(
+        &self,
+        manifest: &Manifest,
+        connect_key: &str,
+    ) -> Result<Vec<(PathBuf, Result<String>)>> {
+        for entry in &manifest.entries {
+            self.check_limits(entry.note.as_deref())?;
+        }
+        let (mut stream, outcome) = self.connect(connect_key).await?.into_parts();
+
+        let mut outcomes = Vec::with_capacity(manifest.entries.len());
+        let mut broken: Option<String> = None;
+        for (sequence, entry) in manifest.entries.iter().enumerate() {
+            let result = match (&broken, fs::read(&entry.path)) {
+                (Some(reason), _) => Err(AppError::Client(format!("Not sent: {}", reason))),
+                (None, Err(e)) => Err(AppError::Client(format!("Failed to read {}: {}", entry.path.display(), e))),
+                (None, Ok(data)) => {
+                    let payload = Payload {
+                        data: &data,
+                        filename: entry.filename(),
+                        note: entry.note.as_deref(),
+                        content: ContentKind::File,
+                        ttl: entry.ttl,
+                    };
+                    let result = self
+                        .deliver(&mut stream, &outcome, &payload, sequence as u64)
+                        .await
+                        .map(|(saved_as, _)| saved_as);
+                    if let Err(e) = &result {
+                        broken = Some(format!("connection failed on {}: {}", entry.path.display(), e));
+                    }
+                    result
+                }
+            };
+            outcomes.push((entry.path.clone(), result));
+        }
+        Ok(outcomes)
+    }
+
+    /// Send one payload on an established connection and wait for its ack
+    ///
+    /// With delivery verification on, the server is also asked to read the
+    /// stored file back. Returns the stored name and, if the payload was
+    /// compressed, what compression did to it.
+    async fn deliver<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        outcome: &HandshakeOutcome,
+        payload: &Payload<'_>,
+        sequence: u64,
+    ) -> Result<(String, Option<CompressionStats>)> {
+        let (saved_as, stats) = self.acknowledged(stream, outcome, payload, sequence).await?;
+        if self.verify_delivery {
+            verify_delivery(stream, &saved_as, &calculate_checksum(payload.data), self.ack_timeout).await?;
+        }
+        Ok((saved_as, stats))
+    }
+
+    /// Send one payload and wait for the server to acknowledge it
+    async fn acknowledged<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        outcome: &HandshakeOutcome,
+        payload: &Payload<'_>,
+        sequence: u64,
+    ) -> Result<(String, Option<CompressionStats>)> {
+        let stats = match self.send_payload(stream, outcome, payload, sequence).await {
+            Ok(stats) => stats,
+            // A server that refuses mid-upload says why before closing
+            Err(e) => return Err(disconnect_notice(stream).await.unwrap_or(e)),
+        };
+
+        // Wait for acknowledgment
+        let ack = parse_ack(receive_reply(stream, self.ack_timeout).await?)?;
+        if ack.sequence != sequence {
+            return Err(AppError::Protocol(format!("Acknowledgment for unknown transfer {}", ack.sequence)));
+        }
+        Output::success(&format!("Message delivered, saved as: {}", ack.saved_as));
+        Ok((ack.saved_as, stats))
+    }
+
+    /// Connect and authenticate once, keeping the connection for several sends
+    ///
+    /// Each [`ClientSession::send_message`] is acknowledged before it
+    /// returns, as with [`Client::send_message`], but no further handshake
+    /// is needed.
+    pub async fn open_session(self, connect_key: &str) -> Result<ClientSession> {
+        self.check_limits(None)?;
+        let (stream, outcome) = self.connect(connect_key).await?.into_parts();
+        Ok(ClientSession {
+            client: self,
+            connect_key: connect_key.to_string(),
+            stream,
+            outcome,
+            next_sequence: 0,
+            closed: None,
+            auto_reconnect: false,
+            reconnects: 0,
+            last_activity: Instant::now(),
+        })
+    }
+
+    /// Check client-side limits before opening a connection
+    fn check_limits(&self, note: Option<&str>) -> Result<()> {
+        if let Some(identity) = &self.handshake.identity {
+            validate_identity(identity)?;
+        }
+        if let Some(note) = note {
+            if note.len() > MAX_NOTE_BYTES {
+                return Err(AppError::Client(format!(
+                    "Note is {} bytes, maximum is {}",
+                    note.len(),
+                    MAX_NOTE_BYTES
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Connect to the server and run the handshake
+    async fn connect(&self, connect_key: &str) -> Result<AuthenticatedChannel<BoxedStream>> {
+        Output::connecting(&self.server_addr);
+
+        let stream = transport::connect(&self.server_addr).await?;
+
+        Output::authenticating();
+        let channel = AuthenticatedChannel::connect(stream, connect_key, &self.keypair, &self.handshake).await?;
+        self.check_known_host(channel.peer_public_key())?;
+        Ok(channel)
+    }
+
+    /// Compare the server's key with the one pinned for it, if pinning is on
+    fn check_known_host(&self, server_key: &RsaPublicKey) -> Result<()> {
+        let Some(path) = &self.known_hosts else {
+            if self.handshake.require_pinned_host {
+                return Err(AppError::Config("Pinned server keys are required but no known hosts file is set".to_string()));
+            }
+            return Ok(());
+        };
+        let server_fingerprint = fingerprint(server_key)?;
+        let local = self.keypair.fingerprint()?;
+        let mut known_hosts = KnownHosts::load(path)?;
+        if self.handshake.require_pinned_host && known_hosts.pinned(&local, &self.server_addr).is_none() {
+            return Err(AppError::Auth(format!(
+                "No key pinned for {} in {} (server key {})",
+                self.server_addr,
+                path.display(),
+                server_fingerprint
+            )));
+        }
+        if known_hosts.check(&local, &self.server_addr, &server_fingerprint)? == HostCheck::Added {
+            Output::info(&format!(
+                "Pinned server key {} for {} in {}",
+                server_fingerprint,
+                self.server_addr,
+                path.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Encrypt one payload and send its header and data
+    ///
+    /// Returns what compression did to the payload, if it was compressed.
+    async fn send_payload<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        outcome: &HandshakeOutcome,
+        payload: &Payload<'_>,
+        sequence: u64,
+    ) -> Result<Option<CompressionStats>> {
+        let Payload { data: message_data, filename, note, content, ttl } = *payload;
         Output::info(&format!("Sending file: {} ({} bytes)", filename, message_data.len()));
 
-        // Calculate checksum
-        let checksum = calculate_checksum(&message_data);
+        // Calculate checksum over the original data
+        let checksum = calculate_checksum(message_data);
+
+        // Compress with whatever the server agreed to
+        let compressed;
+        let mut stats = None;
+        let body = if outcome.compression == Compression::None {
+            message_data
+        } else {
+            let started = Instant::now();
+            compressed = outcome.compression.compress(message_data)?;
+            let compression = CompressionStats::new(
+                outcome.compression,
+                message_data.len() as u64,
+                compressed.len() as u64,
+                started.elapsed(),
+            );
+            Output::info(&format!("Compressed with {}", compression));
+            stats = Some(compression);
+            &compressed
+        };
 
         // Encrypt message
         Output::encrypting();
-        let encrypted = encrypt_large(&self.keypair.public_key, &message_data)?;
+        let encrypted = encrypt_with_suite(&outcome.peer_public_key, body, outcome.cipher_suite)?;
         let encrypted_bytes = encrypted.to_bytes()?;
 
         // Create header
-        let header = MessageHeader::new(filename, encrypted_bytes.len() as u64, &checksum);
+        let header = MessageHeader::new(filename, encrypted_bytes.len() as u64, &checksum)
+            .with_note(note)
+            .with_content(content)
+            .with_sequence(sequence)
+            .with_compression(outcome.compression)
+            .with_ttl(ttl)
+            .signed_with(&self.keypair.private_key)?;
 
         // Send header
         let header_bytes = header.to_bytes()?;
         let header_msg = Message::new(MessageType::MessageHeader, header_bytes);
-        send_message(&mut stream, &header_msg).await?;
+        send_message(stream, &header_msg).await?;
 
         // Send encrypted data
         Output::sending(encrypted_bytes.len());
-        send_raw_data(&mut stream, &encrypted_bytes).await?;
+        send_raw_data(stream, &encrypted_bytes).await?;
+        Ok(stats)
+    }
+}
 
-        // Wait for acknowledgment
-        let ack_msg = receive_message(&mut stream).await?;
-
-        match ack_msg.msg_type {
-            MessageType::Acknowledgment => {
-                let saved_filename = String::from_utf8(ack_msg.payload)
-                    .unwrap_or_else(|_| filename.to_string());
-                Output::success(&format!("Message delivered, saved as: {}", saved_filename));
-                Ok(saved_filename)
+/// An authenticated connection kept open between sends
+///
+/// The server ends the connection on any failed transfer, so after one
+/// send fails every later send fails without touching the network, unless
+/// [`ClientSession::with_auto_reconnect`] is on. Then a send on a connection
+/// that failed, or that the server has since closed, first connects and
+/// authenticates again. A send that fails after its data went out is never
+/// repeated, since the server may have stored it.
+pub struct ClientSession {
+    client: Client,
+    connect_key: String,
+    stream: BoxedStream,
+    outcome: HandshakeOutcome,
+    next_sequence: u64,
+    closed: Option<String>,
+    auto_reconnect: bool,
+    reconnects: u64,
+    last_activity: Instant,
+}
+
+impl ClientSession {
+    /// Address of the server this session is connected to
+    pub fn server_addr(&self) -> &str {
+        &self.client.server_addr
+    }
+
+    /// Transfers sent over this session so far
+    pub fn sent(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Whether a failed send or ping has ended the current connection
+    pub fn is_closed(&self) -> bool {
+        self.closed.is_some()
+    }
+
+    /// Reconnect before a send if the connection has been lost
+    pub fn with_auto_reconnect(mut self, enabled: bool) -> Self {
+        self.auto_reconnect = enabled;
+        self
+    }
+
+    /// Times the session has connected again after losing its connection
+    pub fn reconnects(&self) -> u64 {
+        self.reconnects
+    }
+
+    /// Time since the last send or ping
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Check the server is still there with a `Ping`
+    ///
+    /// Sent while idle, this also keeps NAT and firewall state for the
+    /// connection from expiring. A ping that fails ends the connection.
+    pub async fn ping(&mut self) -> Result<()> {
+        if let Some(reason) = &self.closed {
+            return Err(AppError::Client(format!("Session closed: {}", reason)));
+        }
+        self.last_activity = Instant::now();
+        let result = ping(&mut self.stream).await;
+        if let Err(e) = &result {
+            self.closed = Some(e.to_string());
+        }
+        result
+    }
+
+    /// Connect and authenticate again, replacing the current connection
+    pub async fn reconnect(&mut self) -> Result<()> {
+        let (stream, outcome) = self.client.connect(&self.connect_key).await?.into_parts();
+        self.stream = stream;
+        self.outcome = outcome;
+        self.closed = None;
+        self.reconnects += 1;
+        self.last_activity = Instant::now();
+        Ok(())
+    }
+
+    /// Ping `session` whenever it has been idle for `interval`, until the task is aborted
+    ///
+    /// A failed ping is reported and closes the connection, so with
+    /// auto-reconnect the next send connects again.
+    pub fn keep_alive(session: &Arc<Mutex<ClientSession>>, interval: Duration) -> JoinHandle<()> {
+        let session = Arc::clone(session);
+        tokio::spawn(async move {
+            loop {
+                let idle = session.lock().await.idle_for();
+                if idle < interval {
+                    tokio::time::sleep(interval - idle).await;
+                    continue;
+                }
+                let mut session = session.lock().await;
+                if session.is_closed() || session.idle_for() < interval {
+                    drop(session);
+                    tokio::time::sleep(interval).await;
+                    continue;
+                }
+                if let Err(e) = session.ping().await {
+                    Output::warning(&format!("Keepalive to {} failed: {}", session.server_addr(), e));
+                }
+            }
+        })
+    }
+
+    /// Send a file over the open connection
+    pub async fn send_message(&mut self, message_file: &Path, save_as: Option<&str>, note: Option<&str>) -> Result<String> {
+        if self.closed.is_none() && peer_closed(&mut self.stream).await {
+            self.closed = Some("server closed the connection".to_string());
+        }
+        if let Some(reason) = &self.closed {
+            if !self.auto_reconnect {
+                return Err(AppError::Client(format!("Session closed: {}", reason)));
+            }
+            Output::warning(&format!("Connection to {} lost ({}), reconnecting", self.server_addr(), reason));
+            self.reconnect().await?;
+        }
+        self.client.check_limits(note)?;
+        let data = fs::read(message_file)
+            .map_err(|e| AppError::Client(format!("Failed to read message file: {}", e)))?;
+        let filename = message_file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("message");
+
+        let payload = Payload {
+            data: &data,
+            filename: save_as.unwrap_or(filename),
+            note,
+            content: ContentKind::File,
+            ttl: None,
+        };
+        let result = self
+            .client
+            .deliver(&mut self.stream, &self.outcome, &payload, self.next_sequence)
+            .await
+            .map(|(saved_as, _)| saved_as);
+        self.next_sequence += 1;
+        self.last_activity = Instant::now();
+        if let Err(e) = &result {
+            self.closed = Some(e.to_string());
+        }
+        result
+    }
+
+    /// Tell the server no more transfers follow and close the connection
+    ///
+    /// Takes `&mut self` so a session shared with its keepalive task can be
+    /// closed through the lock. Closing twice, or after the connection was
+    /// lost, does nothing; a later send fails unless auto-reconnect is on.
+    pub async fn close(&mut self) -> Result<()> {
+        if self.closed.is_some() {
+            return Ok(());
+        }
+        self.closed = Some("session closed by client".to_string());
+        self.stream
+            .shutdown()
+            .await
+            .map_err(|e| AppError::Client(format!("Failed to close session: {}", e)))
+    }
+}
+
+/// One file's worth of data and the header fields sent with it
+#[derive(Clone, Copy)]
+struct Payload<'a> {
+    data: &'a [u8],
+    filename: &'a str,
+    note: Option<&'a str>,
+    content: ContentKind,
+    ttl: Option<u64>,
+}
+
+/// Send the header of a chunked transfer, then the file one sealed chunk at a time
+async fn send_chunks<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    stream: &mut W,
+    header: &MessageHeader,
+    mut sealer: ChunkSealer,
+    source: &mut R,
+    len: u64,
+    chunk_size: usize,
+) -> Result<()> {
+    send_message(stream, &Message::new(MessageType::MessageHeader, header.to_bytes()?)).await?;
+
+    // The count comes from the length checksummed before, so a file that
+    // shrinks in between fails the read rather than ending early
+    Output::sending(header.size as usize);
+    let read_err = |e: std::io::Error| AppError::Client(format!("Failed to read message file: {}", e));
+    let chunks = len.div_ceil(chunk_size as u64).max(1);
+    let mut buf = vec![0u8; chunk_size];
+    let mut remaining = len;
+    for index in 0..chunks {
+        let take = remaining.min(chunk_size as u64) as usize;
+        source.read_exact(&mut buf[..take]).await.map_err(read_err)?;
+        remaining -= take as u64;
+
+        let last = index + 1 == chunks;
+        let chunk = MessageChunk::new(index as u32, last, sealer.seal(&buf[..take], last)?);
+        send_message(stream, &Message::new(MessageType::MessageChunk, chunk.to_bytes()?)).await?;
+    }
+    Ok(())
+}
+
+/// Length and SHA-256 checksum of a file, hashed on the blocking pool
+///
+/// A multi-GB file takes long enough to hash that doing it on the runtime
+/// would hold up every other send sharing it.
+async fn hash_file(path: &Path) -> Result<(u64, String)> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let read_err = |e: std::io::Error| AppError::Client(format!("Failed to read message file: {}", e));
+        let mut hasher = Sha256::new();
+        let len = std::io::copy(&mut fs::File::open(&path).map_err(read_err)?, &mut hasher).map_err(read_err)?;
+        Ok((len, format!("{:x}", hasher.finalize())))
+    })
+    .await
+    .unwrap_or_else(|e| Err(AppError::Client(format!("Checksum task failed: {}", e))))
+}
+
+/// Length and SHA-256 checksum of the archive [`write_directory_archive`] builds for `dir`
+async fn hash_directory_archive(dir: &Path) -> Result<(u64, String)> {
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let hashed = write_directory_archive(&dir, HashingWriter::default())?;
+        Ok((hashed.len, format!("{:x}", hashed.hasher.finalize())))
+    })
+    .await
+    .unwrap_or_else(|e| Err(AppError::Client(format!("Checksum task failed: {}", e))))
+}
+
+/// Refuse a chunk size the server would not accept
+fn check_chunk_size(chunk_size: usize) -> Result<()> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(AppError::Client(format!(
+            "Chunk size must be between 1 and {} bytes, got {}",
+            MAX_CHUNK_SIZE, chunk_size
+        )));
+    }
+    Ok(())
+}
+
+/// Writer that only counts and hashes what is written to it
+#[derive(Default)]
+struct HashingWriter {
+    hasher: Sha256,
+    len: u64,
+}
+
+impl std::io::Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.hasher.update(buf);
+        self.len += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Blocking writer into an async one, for use on the blocking thread pool
+struct BlockingWriter<W> {
+    inner: W,
+    runtime: tokio::runtime::Handle,
+}
+
+impl<W: AsyncWrite + Unpin> std::io::Write for BlockingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.runtime.block_on(self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.runtime.block_on(self.inner.flush())
+    }
+}
+
+/// Interpret the server's reply to a transfer
+fn parse_ack(msg: Message) -> Result<TransferAck> {
+    match msg.msg_type {
+        MessageType::Acknowledgment => TransferAck::from_bytes(&msg.payload),
+        _ => Err(server_refusal(&msg, MessageType::Acknowledgment)),
+    }
+}
+
+/// Error for a reply that is not the `expected` one
+///
+/// Servers explain a refusal with a `Disconnect` notice; older ones send a
+/// plain `Error` message.
+fn server_refusal(msg: &Message, expected: MessageType) -> AppError {
+    match msg.msg_type {
+        MessageType::Disconnect => match Disconnect::from_bytes(&msg.payload) {
+            Ok(notice) => AppError::Client(format!("Server disconnected: {}", notice)),
+            Err(e) => e,
+        },
+        MessageType::Error => {
+            let error_msg = String::from_utf8_lossy(&msg.payload);
+            AppError::Client(format!("Server error: {}", error_msg))
+        }
+        other => unexpected_message(&[expected, MessageType::Disconnect], other),
+    }
+}
+
+/// Send a `Ping` and wait for the matching `Pong`
+async fn ping<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> Result<()> {
+    let token = rand::random::<u64>().to_be_bytes().to_vec();
+    send_message(stream, &Message::new(MessageType::Ping, token.clone())).await?;
+    let reply = match tokio::time::timeout(PING_TIMEOUT, read_reply_or_eof(stream)).await {
+        Ok(reply) => reply?.ok_or_else(|| AppError::Client("Server closed the connection".to_string()))?,
+        Err(_) => {
+            return Err(AppError::Client(format!("No reply to ping within {}s", PING_TIMEOUT.as_secs())));
+        }
+    };
+    if reply.msg_type != MessageType::Pong {
+        return Err(server_refusal(&reply, MessageType::Pong));
+    }
+    if reply.payload != token {
+        return Err(AppError::Protocol("Pong does not match the ping sent".to_string()));
+    }
+    Ok(())
+}
+
+/// Whether an idle connection has been closed or reset by the server
+///
+/// Nothing is expected from the server between transfers, so anything
+/// readable (end of stream, an error or an unsolicited disconnect notice)
+/// means the connection is finished. Does not wait.
+async fn peer_closed<S: AsyncRead + Unpin>(stream: &mut S) -> bool {
+    let mut byte = [0u8; 1];
+    tokio::time::timeout(Duration::ZERO, stream.read(&mut byte)).await.is_ok()
+}
+
+/// Read a disconnect notice the server may have sent before closing
+async fn disconnect_notice<S: AsyncRead + Unpin>(stream: &mut S) -> Option<AppError> {
+    let msg = tokio::time::timeout(DISCONNECT_NOTICE_TIMEOUT, receive_message(stream))
+        .await
+        .ok()?
+        .ok()?;
+    (msg.msg_type != MessageType::Acknowledgment).then(|| server_refusal(&msg, MessageType::Acknowledgment))
+}
+
+/// Wait for the server's reply to data it has already been sent
+async fn receive_reply<S: AsyncRead + Unpin>(stream: &mut S, timeout: Duration) -> Result<Message> {
+    match tokio::time::timeout(timeout, read_reply_or_eof(stream)).await {
+        Ok(reply) => reply?.ok_or_else(|| {
+            AppError::Client("Data sent but delivery unconfirmed: server closed the connection without replying".to_string())
+        }),
+        Err(_) => Err(reply_timeout(timeout)),
+    }
+}
+
+/// Read one size-capped reply, or `None` if the server closed the stream
+async fn read_reply_or_eof<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Message>> {
+    let codec = FrameCodec::control().with_max_len(MAX_REPLY_FRAME);
+    codec.read_frame_or_eof(stream).await?.map(|data| Message::from_bytes(&data)).transpose()
+}
+
+fn reply_timeout(timeout: Duration) -> AppError {
+    AppError::Client(format!(
+        "Data sent but delivery unconfirmed: no reply from the server within {}s",
+        timeout.as_secs()
+    ))
+}
+
+/// Ask the server to read back `saved_as` and check it against `expected` checksum
+async fn verify_delivery<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    saved_as: &str,
+    expected: &str,
+    timeout: Duration,
+) -> Result<VerifyResponse> {
+    let request = Message::new(MessageType::VerifyRequest, VerifyRequest::new(saved_as).to_bytes()?);
+    send_message(stream, &request).await?;
+
+    let msg = receive_reply(stream, timeout).await?;
+    let response = match msg.msg_type {
+        MessageType::VerifyResponse => VerifyResponse::from_bytes(&msg.payload)?,
+        _ => return Err(server_refusal(&msg, MessageType::VerifyResponse)),
+    };
+
+    if response.checksum != expected {
+        return Err(AppError::Client(format!(
+            "Delivery verification failed for {}: stored checksum {} does not match {}",
+            saved_as, response.checksum, expected
+        )));
+    }
+    Output::success(&format!("Delivery verified: {}", saved_as));
+    Ok(response)
+}
+
+/// Expand `--file` arguments into concrete paths
+///
+/// Each argument may be a plain path or a glob such as `out/batch_*.json`.
+/// Plain paths are passed through untouched; a glob that matches no files is
+/// an error rather than being skipped.
+pub fn expand_file_patterns(patterns: &[String]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            files.push(PathBuf::from(pattern));
+            continue;
+        }
+
+        let matches = glob::glob(pattern)
+            .map_err(|e| AppError::Client(format!("Invalid file pattern '{}': {}", pattern, e)))?;
+
+        let mut matched = false;
+        for entry in matches {
+            let path = entry
+                .map_err(|e| AppError::Client(format!("Failed to read {}: {}", e.path().display(), e)))?;
+            if path.is_file() {
+                files.push(path);
+                matched = true;
+            }
+        }
+
+        if !matched {
+            return Err(AppError::Client(format!("No files match '{}'", pattern)));
+        }
+    }
+    Ok(files)
+}
+
+/// Regular files under `dir`, recursively, in path order; symlinks are skipped
+fn directory_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(directory_tree(dir)?
+        .into_iter()
+        .filter(|(_, is_dir)| !is_dir)
+        .map(|(path, _)| path)
+        .collect())
+}
+
+/// Subdirectories and regular files under `dir`, recursively, in path order
+///
+/// Each path comes with whether it is a directory; symlinks are skipped.
+/// A directory sorts before everything in it.
+fn directory_tree(dir: &Path) -> Result<Vec<(PathBuf, bool)>> {
+    if !dir.is_dir() {
+        return Err(AppError::Client(format!("Not a directory: {}", dir.display())));
+    }
+
+    let mut tree = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = fs::read_dir(&current)
+            .map_err(|e| AppError::Client(format!("Failed to read {}: {}", current.display(), e)))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| AppError::Client(format!("Failed to read {}: {}", current.display(), e)))?;
+            let file_type = entry
+                .file_type()
+                .map_err(|e| AppError::Client(format!("Failed to read {}: {}", entry.path().display(), e)))?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+                tree.push((entry.path(), true));
+            } else if file_type.is_file() {
+                tree.push((entry.path(), false));
             }
-            MessageType::Error => {
-                let error_msg = String::from_utf8_lossy(&ack_msg.payload);
-                Err(AppError::Client(format!("Server error: {}", error_msg)))
+        }
+    }
+    tree.sort();
+    Ok(tree)
+}
+
+/// Write `dir` as an uncompressed tar archive into `writer`, returning it
+///
+/// Entries are relative to `dir` and written in path order, so the same
+/// tree always gives the same archive; symlinks are left out. File
+/// contents are copied through, never held whole.
+pub fn write_directory_archive<W: std::io::Write>(dir: &Path, writer: W) -> Result<W> {
+    let tree = directory_tree(dir)?;
+    let failed = |path: &Path, e: std::io::Error| AppError::Client(format!("Failed to archive {}: {}", path.display(), e));
+
+    let mut builder = tar::Builder::new(writer);
+    builder.append_dir(".", dir).map_err(|e| failed(dir, e))?;
+    for (path, is_dir) in tree {
+        let name = path.strip_prefix(dir).unwrap_or(&path);
+        if is_dir {
+            builder.append_dir(name, &path).map_err(|e| failed(&path, e))?;
+        } else {
+            builder.append_path_with_name(&path, name).map_err(|e| failed(&path, e))?;
+        }
+    }
+    builder
+        .into_inner()
+        .map_err(|e| AppError::Client(format!("Failed to finish archive: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use crate::auth::{Whitelist, WhitelistAuthorizer};
+    use crate::server::Server;
+    use crate::server::storage::read_sidecar;
+
+    /// Start a server accepting the connect key "secret", returning its port and inbox
+    async fn start_server(dir: &Path) -> (u16, PathBuf) {
+        start_server_with_entry(dir, "secret").await
+    }
+
+    /// Start a server whose whitelist holds the single `entry`
+    async fn start_server_with_entry(dir: &Path, entry: &str) -> (u16, PathBuf) {
+        let whitelist_path = dir.join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add(entry, None).unwrap();
+        let messages_dir = dir.join("messages");
+        let server = Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap())
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { server.serve(listener).await });
+        (port, messages_dir)
+    }
+
+    #[tokio::test]
+    async fn test_payload_is_sealed_for_the_server_key() {
+        let client_keys = KeyPair::generate().unwrap();
+        let server_keys = KeyPair::generate().unwrap();
+        let outcome = HandshakeOutcome {
+            peer_public_key: server_keys.public_key.clone(),
+            cipher_suite: CipherSuite::default(),
+            auth_method: AuthMethod::PssSha256,
+            compression: Compression::None,
+            session: crate::protocol::SessionEvent { local: String::new(), remote: String::new() },
+            peer_identity: None,
+            whitelist_entry: None,
+        };
+        let payload = Payload { data: b"ACC-001,100.00\n", filename: "ledger.csv", note: None, content: ContentKind::File, ttl: None };
+        let mut wire = Vec::new();
+        Client::new("127.0.0.1", 0, client_keys.clone())
+            .send_payload(&mut wire, &outcome, &payload, 0)
+            .await
+            .unwrap();
+
+        let mut reader = &wire[..];
+        crate::protocol::FrameCodec::control().read_frame(&mut reader).await.unwrap();
+        let data = crate::protocol::FrameCodec::data().read_frame(&mut reader).await.unwrap();
+        let sealed = crate::crypto::EncryptedMessage::from_bytes(&data).unwrap();
+
+        // Only the server can open it; the sender's own key cannot
+        assert_eq!(crate::crypto::decrypt_large(&server_keys.private_key, &sealed).unwrap(), b"ACC-001,100.00\n");
+        assert!(crate::crypto::decrypt_large(&client_keys.private_key, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_pattern_without_matches_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let pattern = dir.path().join("batch_*.json").to_string_lossy().to_string();
+        assert!(expand_file_patterns(&[pattern]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_glob_sends_every_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = dir.path().join("out");
+        fs::create_dir(&outbox).unwrap();
+        for name in ["batch_1.json", "batch_2.json", "batch_3.json", "other.txt"] {
+            fs::write(outbox.join(name), name.as_bytes()).unwrap();
+        }
+
+        let (port, messages_dir) = start_server(dir.path()).await;
+
+        let pattern = outbox.join("batch_*.json").to_string_lossy().to_string();
+        let files = expand_file_patterns(&[pattern]).unwrap();
+        assert_eq!(files.len(), 3);
+
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap());
+        let outcomes = client.send_files(&files, "secret", None).await;
+        assert!(outcomes.iter().all(|(_, result)| result.is_ok()));
+
+        let stored = fs::read_dir(&messages_dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "ftt"))
+            .count();
+        assert_eq!(stored, 3);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_files_matched_by_sequence() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server(dir.path()).await;
+
+        let files: Vec<PathBuf> = (1..=4)
+            .map(|n| {
+                let path = dir.path().join(format!("part_{}.csv", n));
+                fs::write(&path, format!("row {}", n)).unwrap();
+                path
+            })
+            .collect();
+
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap());
+        let outcomes = client.send_files_pipelined(&files, "secret", None).await.unwrap();
+
+        assert_eq!(outcomes.len(), 4);
+        for (n, (file, result)) in outcomes.iter().enumerate() {
+            assert_eq!(file, &files[n]);
+            let saved_as = result.as_ref().unwrap();
+            assert!(saved_as.starts_with(&format!("part_{}.csv_", n + 1)));
+            let stored = fs::read_to_string(messages_dir.join(saved_as)).unwrap();
+            assert_eq!(stored, format!("row {}", n + 1));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compressed_transfer_stored_uncompressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server(dir.path()).await;
+        let payload = b"date,amount\n2024-01-01,100.00\n".repeat(100);
+        let file = dir.path().join("ledger.csv");
+        fs::write(&file, &payload).unwrap();
+
+        for algorithm in Compression::ALL {
+            let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap())
+                .with_compression(&[algorithm])
+                .with_verify_delivery(true);
+            let saved_as = client.send_message(&file, "secret", None, None).await.unwrap();
+            assert_eq!(fs::read(messages_dir.join(saved_as)).unwrap(), payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_and_one_byte_files_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server(dir.path()).await;
+
+        for payload in [&b""[..], b"x"] {
+            let file = dir.path().join(format!("ledger_{}.csv", payload.len()));
+            fs::write(&file, payload).unwrap();
+            for algorithm in [Compression::None, Compression::Gzip, Compression::Zstd] {
+                let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap())
+                    .with_compression(&[algorithm])
+                    .with_verify_delivery(true);
+                let saved_as = client.send_message(&file, "secret", None, None).await.unwrap();
+                assert_eq!(fs::read(messages_dir.join(&saved_as)).unwrap(), payload, "{}", algorithm);
+                assert_eq!(read_sidecar(&messages_dir.join(&saved_as)).unwrap().size, payload.len() as u64);
             }
-            _ => Err(AppError::Protocol("Unexpected response from server".to_string())),
         }
     }
+
+    #[tokio::test]
+    async fn test_send_bytes_from_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server(dir.path()).await;
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap());
+
+        let report = b"account,balance\nACME,1000.00\n";
+        let saved_as = client.send_bytes(report, "balances.csv", "secret", None, None).await.unwrap();
+        assert!(saved_as.starts_with("balances.csv_"));
+        assert_eq!(fs::read(messages_dir.join(&saved_as)).unwrap(), report);
+
+        let renamed = client.send_bytes(report, "balances.csv", "secret", Some("eod"), None).await.unwrap();
+        assert!(renamed.starts_with("eod_"));
+    }
+
+    #[tokio::test]
+    async fn test_manifest_entries_sent_with_their_save_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server(dir.path()).await;
+        fs::write(dir.path().join("ledger.csv"), b"ledger").unwrap();
+        fs::write(dir.path().join("positions.csv"), b"positions").unwrap();
+        let manifest_path = dir.path().join("manifest.toml");
+        fs::write(
+            &manifest_path,
+            "[[file]]\npath = \"ledger.csv\"\nsave_as = \"ledger_eod\"\nnote = \"EOD\"\n\n\
+             [[file]]\npath = \"positions.csv\"\nsave_as = \"positions_eod\"\nttl = 3600\n",
+        )
+        .unwrap();
+
+        let manifest = Manifest::load(&manifest_path).unwrap();
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap());
+        let outcomes = client.send_manifest(&manifest, "secret").await.unwrap();
+
+        assert_eq!(outcomes.len(), 2);
+        let ledger = outcomes[0].1.as_ref().unwrap();
+        let positions = outcomes[1].1.as_ref().unwrap();
+        assert!(ledger.starts_with("ledger_eod_"), "{}", ledger);
+        assert!(positions.starts_with("positions_eod_"), "{}", positions);
+        assert_eq!(fs::read(messages_dir.join(positions)).unwrap(), b"positions");
+
+        let ledger_meta = read_sidecar(&messages_dir.join(ledger)).unwrap();
+        assert_eq!(ledger_meta.note.as_deref(), Some("EOD"));
+        assert!(ledger_meta.expires_at.is_none());
+        assert!(read_sidecar(&messages_dir.join(positions)).unwrap().expires_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_directory_sent_file_by_file_on_one_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server(dir.path()).await;
+        let reports = dir.path().join("reports");
+        fs::create_dir_all(reports.join("archive")).unwrap();
+        fs::write(reports.join("balances.csv"), b"ACC-001,100.00\n").unwrap();
+        fs::write(reports.join("positions.csv"), b"EUR,5\n").unwrap();
+        fs::write(reports.join("archive").join("journal.csv"), b"J-1\n").unwrap();
+
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap());
+        let outcomes = client.send_directory(&reports, "secret", None).await.unwrap();
+
+        let files: Vec<&Path> = outcomes.iter().map(|(file, _)| file.as_path()).collect();
+        assert_eq!(files, [reports.join("archive/journal.csv"), reports.join("balances.csv"), reports.join("positions.csv")]);
+        for (file, result) in &outcomes {
+            let saved_as = result.as_ref().unwrap();
+            let data = fs::read(file).unwrap();
+            assert_eq!(fs::read(messages_dir.join(saved_as)).unwrap(), data);
+            assert_eq!(read_sidecar(&messages_dir.join(saved_as)).unwrap().checksum, calculate_checksum(&data));
+        }
+        assert!(client.send_directory(&reports.join("balances.csv"), "secret", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_transfer_streams_file_to_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server_with_entry(dir.path(), "secret;pattern=ledger*").await;
+        let ledger = dir.path().join("ledger.csv");
+        let data: Vec<u8> = (0..10_000u32).flat_map(|i| format!("ACC-{:05},{}.00\n", i, i).into_bytes()).collect();
+        fs::write(&ledger, &data).unwrap();
+
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap())
+            .with_chunk_size(Some(64 * 1024))
+            .with_verify_delivery(true);
+        let saved_as = client.send_message(&ledger, "secret", None, None).await.unwrap();
+        assert_eq!(fs::read(messages_dir.join(&saved_as)).unwrap(), data);
+        assert_eq!(read_sidecar(&messages_dir.join(&saved_as)).unwrap().size, data.len() as u64);
+
+        // A refused name leaves no staging file behind
+        let denied = dir.path().join("payload.sh");
+        fs::write(&denied, &data).unwrap();
+        let err = client.send_message(&denied, "secret", None, None).await.unwrap_err();
+        assert!(err.to_string().contains("Filename not allowed"), "{}", err);
+        assert_eq!(fs::read_dir(&messages_dir).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_changed_server_key_refused_only_for_pinning_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, _) = start_server(dir.path()).await;
+        let file = dir.path().join("ledger.csv");
+        fs::write(&file, b"ledger").unwrap();
+
+        // Alice pinned a different key for this server earlier
+        let alice = KeyPair::generate().unwrap();
+        let known_hosts = dir.path().join("known_hosts");
+        let host = format!("127.0.0.1:{}", port);
+        fs::write(&known_hosts, format!("{} {} stale-fingerprint\n", alice.fingerprint().unwrap(), host)).unwrap();
+
+        let client = Client::new("127.0.0.1", port, alice).with_known_hosts(Some(&known_hosts));
+        let err = client.send_message(&file, "secret", None, None).await.unwrap_err();
+        assert!(err.to_string().contains("stale-fingerprint"), "{}", err);
+
+        // Bob shares the file but has no pin yet, so he trusts and pins the server
+        let bob = KeyPair::generate().unwrap();
+        let bob_fingerprint = bob.fingerprint().unwrap();
+        let client = Client::new("127.0.0.1", port, bob).with_known_hosts(Some(&known_hosts));
+        client.send_message(&file, "secret", None, None).await.unwrap();
+        assert!(KnownHosts::load(&known_hosts).unwrap().pinned(&bob_fingerprint, &host).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_restricted_peer_filenames() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server_with_entry(dir.path(), "secret;pattern=settlement_*.json").await;
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap());
+
+        let allowed = dir.path().join("settlement_20240101.json");
+        fs::write(&allowed, b"{}").unwrap();
+        let saved_as = client.send_message(&allowed, "secret", None, None).await.unwrap();
+        assert!(messages_dir.join(saved_as).exists());
+
+        let denied = dir.path().join("payload.sh");
+        fs::write(&denied, b"#!/bin/sh").unwrap();
+        let err = client.send_message(&denied, "secret", None, None).await.unwrap_err();
+        assert!(err.to_string().contains("Filename not allowed"));
+        assert_eq!(fs::read_dir(&messages_dir).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unacknowledged_transfer_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let whitelist = Whitelist::load(&whitelist_path).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Authenticates, then swallows everything and never acknowledges
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let keypair = KeyPair::generate().unwrap();
+            let channel = AuthenticatedChannel::accept(stream, &WhitelistAuthorizer::new(whitelist), &keypair, &HandshakeOptions::default())
+                .await
+                .unwrap();
+            let (mut stream, _) = channel.into_parts();
+            let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+        });
+
+        let file = dir.path().join("ledger.csv");
+        fs::write(&file, b"ledger").unwrap();
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap())
+            .with_ack_timeout(Duration::from_secs(1));
+        let err = tokio::time::timeout(Duration::from_secs(30), client.send_message(&file, "secret", None, None))
+            .await
+            .expect("client hung waiting for the ack")
+            .unwrap_err();
+        assert!(err.to_string().contains("delivery unconfirmed"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_verify_delivery_detects_corruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server(dir.path()).await;
+        let payload = b"ledger,2024-01-01,100.00\n";
+        let file = dir.path().join("ledger.csv");
+        fs::write(&file, payload).unwrap();
+
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap()).with_verify_delivery(true);
+        let saved_as = client.send_message(&file, "secret", None, None).await.unwrap();
+        assert_eq!(fs::read(messages_dir.join(saved_as)).unwrap(), payload);
+
+        // Corrupt the stored file between the ack and the read-back
+        let (mut stream, outcome) = client.connect("secret").await.unwrap().into_parts();
+        let file = Payload { data: payload, filename: "ledger.csv", note: None, content: ContentKind::File, ttl: None };
+        client.send_payload(&mut stream, &outcome, &file, 0).await.unwrap();
+        let ack = parse_ack(receive_message(&mut stream).await.unwrap()).unwrap();
+        fs::write(messages_dir.join(&ack.saved_as), b"ledger,2024-01-01,999.00\n").unwrap();
+
+        let err = verify_delivery(&mut stream, &ack.saved_as, &calculate_checksum(payload), DEFAULT_ACK_TIMEOUT)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Delivery verification failed"));
+    }
+
+    #[tokio::test]
+    async fn test_send_bytes_verified_returns_stored_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, messages_dir) = start_server(dir.path()).await;
+        let payload = b"ledger,2024-01-01,100.00\n";
+
+        let client = Client::new("127.0.0.1", port, KeyPair::generate().unwrap());
+        let meta = client.send_bytes_verified(payload, "ledger.csv", "secret", None, Some("EOD")).await.unwrap();
+        assert_eq!(meta.filename, "ledger.csv");
+        assert_eq!(meta.size, payload.len() as u64);
+        assert_eq!(meta.checksum, calculate_checksum(payload));
+        assert_eq!(meta.note.as_deref(), Some("EOD"));
+        assert_eq!(meta, crate::server::storage::read_sidecar(&messages_dir.join(&meta.saved_as)).unwrap());
+    }
 }
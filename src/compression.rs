@@ -0,0 +1,181 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+use clap::ValueEnum;
+use serde::{Serialize, Deserialize};
+use crate::error::{AppError, Result};
+
+/// Compression applied to a message body before it is encrypted
+///
+/// The client offers the algorithms it is willing to use and the server picks
+/// one it supports, or `None`, during the handshake. The chosen algorithm is
+/// recorded in each message header so the server knows how to unpack the
+/// body; a body is never compressed with something the server did not accept.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, ValueEnum)]
+pub enum Compression {
+    /// Body sent as is
+    #[default]
+    None,
+    /// gzip (DEFLATE)
+    Gzip,
+    /// Zstandard
+    Zstd,
+}
+
+impl Compression {
+    /// All algorithms this build can compress and decompress
+    pub const ALL: [Compression; 2] = [Compression::Zstd, Compression::Gzip];
+
+    /// Name as used on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Compress `data` with this algorithm
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let compress_err = |e: std::io::Error| AppError::Client(format!("Failed to compress with {}: {}", self, e));
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).map_err(compress_err)?;
+                encoder.finish().map_err(compress_err)
+            }
+            Compression::Zstd => zstd::encode_all(data, 0).map_err(compress_err),
+        }
+    }
+
+    /// Reverse [`Compression::compress`], refusing output larger than `limit` bytes
+    ///
+    /// The output is read through a `take`, so a tiny body that would expand
+    /// to gigabytes fails once it passes `limit` instead of filling memory.
+    pub fn decompress(self, data: &[u8], limit: u64) -> Result<Vec<u8>> {
+        let decompress_err =
+            |e: std::io::Error| AppError::Protocol(format!("Failed to decompress {} body: {}", self, e));
+        let decoder: Box<dyn Read + '_> = match self {
+            Compression::None => Box::new(data),
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(data).map_err(decompress_err)?),
+        };
+        let mut out = Vec::new();
+        decoder.take(limit.saturating_add(1)).read_to_end(&mut out).map_err(decompress_err)?;
+        if out.len() as u64 > limit {
+            return Err(AppError::Protocol(format!(
+                "{} body decompresses to more than the {} byte limit",
+                self, limit
+            )));
+        }
+        Ok(out)
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What compressing one transfer saved, and what it cost
+///
+/// The client records the time spent compressing, the server the time spent
+/// decompressing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct CompressionStats {
+    /// Algorithm the body was compressed with
+    pub algorithm: Compression,
+    /// Size of the data before compression
+    pub original_bytes: u64,
+    /// Size of the compressed body
+    pub compressed_bytes: u64,
+    /// `original_bytes / compressed_bytes`; below 1 means compression made the body larger
+    pub ratio: f64,
+    /// Time spent compressing or decompressing, in microseconds
+    pub elapsed_us: u64,
+}
+
+impl CompressionStats {
+    /// Record compressing `original_bytes` into `compressed_bytes` in `elapsed`
+    pub fn new(algorithm: Compression, original_bytes: u64, compressed_bytes: u64, elapsed: Duration) -> Self {
+        Self {
+            algorithm,
+            original_bytes,
+            compressed_bytes,
+            ratio: original_bytes as f64 / compressed_bytes.max(1) as f64,
+            elapsed_us: elapsed.as_micros() as u64,
+        }
+    }
+
+    /// Time spent compressing or decompressing
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_micros(self.elapsed_us)
+    }
+}
+
+impl std::fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} -> {} bytes ({:.2}x) in {:.1} ms",
+            self.algorithm,
+            self.original_bytes,
+            self.compressed_bytes,
+            self.ratio,
+            self.elapsed_us as f64 / 1000.0
+        )
+    }
+}
+
+/// Pick the first algorithm offered by the client that the server supports
+///
+/// Falls back to `None` when the sets are disjoint, so compression can never
+/// make a transfer fail.
+pub fn negotiate(offered: &[Compression], supported: &[Compression]) -> Compression {
+    offered
+        .iter()
+        .copied()
+        .find(|c| *c != Compression::None && supported.contains(c))
+        .unwrap_or(Compression::None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_overlapping_and_disjoint() {
+        let server = [Compression::Gzip];
+        assert_eq!(negotiate(&[Compression::Zstd, Compression::Gzip], &server), Compression::Gzip);
+        assert_eq!(negotiate(&Compression::ALL, &Compression::ALL), Compression::Zstd);
+
+        assert_eq!(negotiate(&[Compression::Zstd], &server), Compression::None);
+        assert_eq!(negotiate(&[], &Compression::ALL), Compression::None);
+        assert_eq!(negotiate(&[Compression::None], &[Compression::None]), Compression::None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"date,amount\n2024-01-01,100.00\n".repeat(200);
+        for algorithm in Compression::ALL {
+            let packed = algorithm.compress(&data).unwrap();
+            assert!(packed.len() < data.len());
+            assert_eq!(algorithm.decompress(&packed, data.len() as u64).unwrap(), data);
+        }
+        assert!(Compression::Gzip.decompress(&data, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn test_decompression_bomb_stops_at_limit() {
+        let expanded = vec![0u8; 64 * 1024 * 1024];
+        for algorithm in Compression::ALL {
+            let bomb = algorithm.compress(&expanded).unwrap();
+            assert!(bomb.len() < 128 * 1024, "{} packed to {} bytes", algorithm, bomb.len());
+
+            let err = algorithm.decompress(&bomb, 1024 * 1024).unwrap_err();
+            assert!(matches!(err, AppError::Protocol(_)), "{}", err);
+            assert!(err.to_string().contains("more than the 1048576 byte limit"), "{}", err);
+        }
+    }
+}
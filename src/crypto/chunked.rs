@@ -0,0 +1,188 @@
+use aes_gcm::aead::OsRng;
+use rand::RngCore;
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Serialize, Deserialize};
+use crate::error::{AppError, Result};
+use crate::crypto::encryption::{aead_decrypt, aead_encrypt, RsaPadding, NONCE_LEN};
+use crate::crypto::suite::CipherSuite;
+
+/// Plaintext carried by each chunk unless the sender picks another size
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Largest plaintext a single chunk may carry
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Bytes the AEAD tag adds to every chunk, the same for all supported suites
+pub const CHUNK_TAG_LEN: usize = 16;
+
+/// Random part of each chunk nonce; the rest is the chunk index and last-chunk flag
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 5;
+
+/// Symmetric key of a chunked stream, wrapped for the receiver
+///
+/// Sent once ahead of the chunks; every chunk is then sealed under the same
+/// key with a nonce made of `nonce_prefix`, the chunk's index and whether it
+/// is the last one, so chunks cannot be reordered, dropped or cut short
+/// without failing authentication.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StreamKey {
+    /// Cipher suite the chunks are sealed with
+    pub suite: CipherSuite,
+    /// Padding the symmetric key was wrapped with
+    pub padding: RsaPadding,
+    /// Symmetric key encrypted with RSA
+    pub encrypted_key: Vec<u8>,
+    /// Random nonce prefix shared by every chunk
+    pub nonce_prefix: Vec<u8>,
+}
+
+/// Total sealed size of `len` bytes of plaintext cut into `chunk_size` chunks
+///
+/// An empty stream still has one (empty) final chunk.
+pub fn sealed_len(len: u64, chunk_size: usize) -> u64 {
+    let chunks = len.div_ceil(chunk_size as u64).max(1);
+    len + chunks * CHUNK_TAG_LEN as u64
+}
+
+/// Nonce of the chunk at `index`
+fn chunk_nonce(prefix: &[u8], index: u32, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..NONCE_LEN - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce
+}
+
+/// Seals a stream of chunks for one receiver
+pub struct ChunkSealer {
+    suite: CipherSuite,
+    key: Vec<u8>,
+    nonce_prefix: Vec<u8>,
+    next: u32,
+    finished: bool,
+}
+
+impl ChunkSealer {
+    /// Start a stream to `public_key`, returning the key to send ahead of it
+    pub fn new(public_key: &RsaPublicKey, suite: CipherSuite) -> Result<(Self, StreamKey)> {
+        let mut key = vec![0u8; suite.key_len()];
+        let mut nonce_prefix = vec![0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut key);
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        let padding = RsaPadding::default();
+        let stream_key = StreamKey {
+            suite,
+            padding,
+            encrypted_key: padding.encrypt(public_key, &key)?,
+            nonce_prefix: nonce_prefix.clone(),
+        };
+        Ok((Self { suite, key, nonce_prefix, next: 0, finished: false }, stream_key))
+    }
+
+    /// Seal the next chunk; `last` marks the end of the stream
+    pub fn seal(&mut self, data: &[u8], last: bool) -> Result<Vec<u8>> {
+        if self.finished {
+            return Err(AppError::Crypto("Chunk sealed after the last one".to_string()));
+        }
+        let nonce = chunk_nonce(&self.nonce_prefix, self.next, last);
+        let sealed = aead_encrypt(self.suite, &self.key, &nonce, data)?;
+        self.next = self
+            .next
+            .checked_add(1)
+            .ok_or_else(|| AppError::Crypto("Too many chunks in one stream".to_string()))?;
+        self.finished = last;
+        Ok(sealed)
+    }
+}
+
+/// Opens the chunks of a stream in the order they were sealed
+pub struct ChunkOpener {
+    suite: CipherSuite,
+    key: Vec<u8>,
+    nonce_prefix: Vec<u8>,
+    next: u32,
+    finished: bool,
+}
+
+impl ChunkOpener {
+    /// Unwrap the stream key with `private_key`
+    pub fn new(private_key: &RsaPrivateKey, stream_key: &StreamKey) -> Result<Self> {
+        let key = stream_key.padding.decrypt(private_key, &stream_key.encrypted_key)?;
+        if key.len() != stream_key.suite.key_len() {
+            return Err(AppError::Crypto(format!(
+                "Wrapped key length {} does not match {}",
+                key.len(),
+                stream_key.suite
+            )));
+        }
+        if stream_key.nonce_prefix.len() != NONCE_PREFIX_LEN {
+            return Err(AppError::Crypto(format!("Invalid nonce prefix length: {}", stream_key.nonce_prefix.len())));
+        }
+        Ok(Self {
+            suite: stream_key.suite,
+            key,
+            nonce_prefix: stream_key.nonce_prefix.clone(),
+            next: 0,
+            finished: false,
+        })
+    }
+
+    /// Authenticate and decrypt the next chunk
+    ///
+    /// A chunk out of order, or one whose `last` flag differs from what the
+    /// sender sealed, fails authentication like tampered data.
+    pub fn open(&mut self, sealed: &[u8], last: bool) -> Result<Vec<u8>> {
+        if self.finished {
+            return Err(AppError::Crypto("Chunk received after the last one".to_string()));
+        }
+        let nonce = chunk_nonce(&self.nonce_prefix, self.next, last);
+        let data = aead_decrypt(self.suite, &self.key, &nonce, sealed)
+            .map_err(|_| AppError::Crypto(format!("Chunk {} failed authentication", self.next)))?;
+        self.next += 1;
+        self.finished = last;
+        Ok(data)
+    }
+
+    /// Whether the last chunk has been opened
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::KeyPair;
+
+    #[test]
+    fn test_chunks_round_trip_only_in_order() {
+        let keypair = KeyPair::generate().unwrap();
+        for suite in CipherSuite::ALL {
+            let (mut sealer, stream_key) = ChunkSealer::new(&keypair.public_key, suite).unwrap();
+            let first = sealer.seal(b"ACC-001,100.00\n", false).unwrap();
+            let second = sealer.seal(b"ACC-002,250.00\n", true).unwrap();
+            assert!(sealer.seal(b"more", true).is_err());
+            assert_eq!((first.len() + second.len()) as u64, sealed_len(30, 15));
+
+            let mut opener = ChunkOpener::new(&keypair.private_key, &stream_key).unwrap();
+            assert_eq!(opener.open(&first, false).unwrap(), b"ACC-001,100.00\n");
+            assert!(!opener.is_finished());
+            assert_eq!(opener.open(&second, true).unwrap(), b"ACC-002,250.00\n");
+            assert!(opener.is_finished());
+
+            // Swapped, or cut short by claiming an earlier chunk was the last
+            let mut opener = ChunkOpener::new(&keypair.private_key, &stream_key).unwrap();
+            assert!(opener.open(&second, false).is_err());
+            let mut opener = ChunkOpener::new(&keypair.private_key, &stream_key).unwrap();
+            assert!(opener.open(&first, true).is_err());
+        }
+    }
+
+    #[test]
+    fn test_sealed_len_counts_a_tag_per_chunk() {
+        assert_eq!(sealed_len(0, DEFAULT_CHUNK_SIZE), CHUNK_TAG_LEN as u64);
+        assert_eq!(sealed_len(10, 10), 10 + CHUNK_TAG_LEN as u64);
+        assert_eq!(sealed_len(11, 10), 11 + 2 * CHUNK_TAG_LEN as u64);
+    }
+}
@@ -0,0 +1,206 @@
+use std::io::{self, Read, Write};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use crate::error::{AppError, Result};
+use crate::crypto::chunked::{ChunkOpener, ChunkSealer, StreamKey, CHUNK_TAG_LEN, MAX_CHUNK_SIZE};
+use crate::crypto::encryption::RsaPadding;
+use crate::crypto::suite::CipherSuite;
+
+/// Marker opening a file sealed in chunks
+///
+/// A message sealed whole as an [`EncryptedMessage`](crate::crypto::EncryptedMessage)
+/// starts with its own marker or, from before that, a little-endian suite
+/// index, so never with these bytes.
+const MAGIC: [u8; 4] = *b"FEC1";
+
+/// Largest serialized stream key accepted when opening a file
+const MAX_KEY_LEN: usize = 4096;
+
+/// Bytes framing each sealed chunk: the last-chunk flag and the sealed length
+const FRAME_LEN: usize = 5;
+
+/// Whether `head`, the start of a stored file, is in the chunked format
+pub fn is_chunked_file(head: &[u8]) -> bool {
+    head.starts_with(&MAGIC)
+}
+
+/// Seals plaintext into a file for [`open_chunked_file`], one chunk at a time
+///
+/// The file is the bytes returned by [`new`](Self::new) followed by those of
+/// every [`seal`](Self::seal) in order. Chunks may be of any size up to
+/// [`MAX_CHUNK_SIZE`], and the last one must be marked so.
+pub struct ChunkedFileSealer {
+    sealer: ChunkSealer,
+}
+
+impl ChunkedFileSealer {
+    /// Start a file for `public_key`, returning the bytes it opens with
+    pub fn new(public_key: &RsaPublicKey, suite: CipherSuite) -> Result<(Self, Vec<u8>)> {
+        let (sealer, stream_key) = ChunkSealer::new(public_key, suite)?;
+        Ok((Self { sealer }, file_header(&stream_key)?))
+    }
+
+    /// Seal the next chunk, returning the bytes to append to the file
+    pub fn seal(&mut self, data: &[u8], last: bool) -> Result<Vec<u8>> {
+        if data.len() > MAX_CHUNK_SIZE {
+            return Err(AppError::Crypto(format!(
+                "Chunk of {} bytes exceeds the {} byte limit",
+                data.len(),
+                MAX_CHUNK_SIZE
+            )));
+        }
+        let sealed = self.sealer.seal(data, last)?;
+        let mut frame = Vec::with_capacity(FRAME_LEN + sealed.len());
+        frame.push(last as u8);
+        frame.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&sealed);
+        Ok(frame)
+    }
+}
+
+/// Seal `data` whole into the chunked file format, in chunks of `chunk_size`
+pub fn seal_chunked_file(public_key: &RsaPublicKey, suite: CipherSuite, data: &[u8], chunk_size: usize) -> Result<Vec<u8>> {
+    let (mut sealer, mut file) = ChunkedFileSealer::new(public_key, suite)?;
+    if data.is_empty() {
+        file.extend_from_slice(&sealer.seal(data, true)?);
+    }
+    let mut chunks = data.chunks(chunk_size).peekable();
+    while let Some(chunk) = chunks.next() {
+        file.extend_from_slice(&sealer.seal(chunk, chunks.peek().is_none())?);
+    }
+    Ok(file)
+}
+
+/// Decrypt a chunked file from `reader` into `writer`, returning the number of bytes written
+///
+/// Only one chunk is held in memory at a time. Each chunk is authenticated
+/// before it is written, but a file that was cut short or tampered with is
+/// only caught at the damaged chunk, after the ones before it were written.
+pub fn open_chunked_file<R: Read, W: Write>(private_key: &RsaPrivateKey, mut reader: R, writer: &mut W) -> Result<u64> {
+    let stream_key = read_header(&mut reader)?;
+    let mut opener = ChunkOpener::new(private_key, &stream_key)?;
+
+    let mut sealed = Vec::new();
+    let mut written = 0u64;
+    while !opener.is_finished() {
+        let mut frame = [0u8; FRAME_LEN];
+        reader.read_exact(&mut frame).map_err(read_err)?;
+        let len = u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]) as usize;
+        if frame[0] > 1 || len > MAX_CHUNK_SIZE + CHUNK_TAG_LEN {
+            return Err(AppError::Crypto("Corrupt chunk frame in sealed file".to_string()));
+        }
+        sealed.resize(len, 0);
+        reader.read_exact(&mut sealed).map_err(read_err)?;
+
+        let data = opener.open(&sealed, frame[0] == 1)?;
+        writer.write_all(&data).map_err(|e| AppError::Crypto(format!("Failed to write decrypted data: {}", e)))?;
+        written += data.len() as u64;
+    }
+
+    if reader.read(&mut [0u8; 1]).map_err(read_err)? != 0 {
+        return Err(AppError::Crypto("Data after the last chunk of sealed file".to_string()));
+    }
+    Ok(written)
+}
+
+/// Re-wrap a chunked file's key for `new_key`
+///
+/// Like [`rewrap_key`](crate::crypto::rewrap_key), the sealed chunks are
+/// carried over byte for byte and the new key is wrapped with the default
+/// padding.
+pub fn rewrap_chunked_file(private_key: &RsaPrivateKey, new_key: &RsaPublicKey, stored: &[u8]) -> Result<Vec<u8>> {
+    let mut chunks = stored;
+    let stream_key = read_header(&mut chunks)?;
+    let key = stream_key.padding.decrypt(private_key, &stream_key.encrypted_key)?;
+    if key.len() != stream_key.suite.key_len() {
+        return Err(AppError::Crypto(format!(
+            "Wrapped key length {} does not match {}",
+            key.len(),
+            stream_key.suite
+        )));
+    }
+
+    let padding = RsaPadding::default();
+    let mut file = file_header(&StreamKey {
+        padding,
+        encrypted_key: padding.encrypt(new_key, &key)?,
+        ..stream_key
+    })?;
+    file.extend_from_slice(chunks);
+    Ok(file)
+}
+
+/// Bytes opening a chunked file: the marker and the length-prefixed stream key
+fn file_header(stream_key: &StreamKey) -> Result<Vec<u8>> {
+    let key = bincode::serialize(stream_key)
+        .map_err(|e| AppError::Serialization(format!("Failed to serialize stream key: {}", e)))?;
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    header.extend_from_slice(&key);
+    Ok(header)
+}
+
+/// Read the bytes written by [`file_header`]
+fn read_header<R: Read>(reader: &mut R) -> Result<StreamKey> {
+    let mut start = [0u8; MAGIC.len() + 4];
+    reader.read_exact(&mut start).map_err(read_err)?;
+    if !is_chunked_file(&start) {
+        return Err(AppError::Serialization("Not a chunked sealed file".to_string()));
+    }
+    let len = u32::from_le_bytes([start[4], start[5], start[6], start[7]]) as usize;
+    if len > MAX_KEY_LEN {
+        return Err(AppError::Serialization(format!("Stream key of {} bytes is too long", len)));
+    }
+    let mut key = vec![0u8; len];
+    reader.read_exact(&mut key).map_err(read_err)?;
+    bincode::deserialize(&key).map_err(|e| AppError::Serialization(format!("Failed to deserialize stream key: {}", e)))
+}
+
+fn read_err(e: io::Error) -> AppError {
+    match e.kind() {
+        io::ErrorKind::UnexpectedEof => AppError::Crypto("Sealed file ends before its last chunk".to_string()),
+        _ => AppError::Crypto(format!("Failed to read sealed file: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::KeyPair;
+
+    #[test]
+    fn test_chunked_file_round_trip_and_truncation() {
+        let keypair = KeyPair::generate().unwrap();
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+
+        for len in [0, 1, 4096, data.len()] {
+            let file = seal_chunked_file(&keypair.public_key, CipherSuite::default(), &data[..len], 4096).unwrap();
+            assert!(is_chunked_file(&file));
+            let mut out = Vec::new();
+            assert_eq!(open_chunked_file(&keypair.private_key, file.as_slice(), &mut out).unwrap(), len as u64);
+            assert_eq!(out, &data[..len]);
+        }
+
+        // Dropping the last chunk, or adding bytes after it, is refused
+        let file = seal_chunked_file(&keypair.public_key, CipherSuite::default(), &data, 4096).unwrap();
+        let last_frame = FRAME_LEN + data.len() % 4096 + CHUNK_TAG_LEN;
+        let err = open_chunked_file(&keypair.private_key, &file[..file.len() - last_frame], &mut io::sink()).unwrap_err();
+        assert!(err.to_string().contains("ends before its last chunk"), "{}", err);
+        let padded = [file.as_slice(), b"x"].concat();
+        assert!(open_chunked_file(&keypair.private_key, padded.as_slice(), &mut io::sink()).is_err());
+    }
+
+    #[test]
+    fn test_rewrap_chunked_file_keeps_chunks() {
+        let (old, new) = (KeyPair::generate().unwrap(), KeyPair::generate().unwrap());
+        let file = seal_chunked_file(&old.public_key, CipherSuite::default(), b"ACC-001,100.00\n", 8).unwrap();
+
+        let rewrapped = rewrap_chunked_file(&old.private_key, &new.public_key, &file).unwrap();
+        let chunks = |file: &[u8]| file[file.len() - 2 * (FRAME_LEN + CHUNK_TAG_LEN) - 15..].to_vec();
+        assert_eq!(chunks(&rewrapped), chunks(&file));
+
+        let mut out = Vec::new();
+        open_chunked_file(&new.private_key, rewrapped.as_slice(), &mut out).unwrap();
+        assert_eq!(out, b"ACC-001,100.00\n");
+        assert!(open_chunked_file(&old.private_key, rewrapped.as_slice(), &mut io::sink()).is_err());
+    }
+}
@@ -1,15 +1,69 @@
 use aes_gcm::{
     aead::{Aead, KeyInit, OsRng},
-    Aes256Gcm, Nonce, AeadCore,
+    Aes128Gcm, Aes256Gcm,
 };
-use rsa::{RsaPublicKey, RsaPrivateKey, Pkcs1v15Encrypt};
+use aes_gcm_siv::Aes256GcmSiv;
+use rand::RngCore;
+use std::io::{Read, Write};
+use rsa::{Oaep, RsaPublicKey, RsaPrivateKey, Pkcs1v15Encrypt};
+use sha2::Sha256;
 use serde::{Serialize, Deserialize};
 use crate::error::{AppError, Result};
+use crate::crypto::suite::CipherSuite;
 
-/// Maximum data size that can be encrypted directly with RSA 2048 (PKCS1v15 padding)
+/// Nonce length shared by all supported AEAD suites
+pub(crate) const NONCE_LEN: usize = 12;
+
+/// Size of each write when decrypting to a writer
+const WRITE_CHUNK: usize = 256 * 1024;
+
+/// Maximum data size that can be encrypted directly with RSA 2048 (OAEP with SHA-256)
 pub const RSA_MAX_ENCRYPT_SIZE: usize = 190;
 
-/// Encrypt data using RSA public key (for small data only)
+/// Marker opening a serialized [`EncryptedMessage`] that records its padding
+///
+/// Messages written before the padding was recorded start with the suite's
+/// variant index as a little-endian `u32`, so they never begin with these bytes.
+const TAGGED_MAGIC: [u8; 4] = *b"FEM2";
+
+/// RSA padding used to wrap a hybrid message's symmetric key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RsaPadding {
+    /// PKCS#1 v1.5, used by messages from before padding was recorded
+    Pkcs1v15,
+    /// OAEP with SHA-256 and MGF1-SHA-256
+    #[default]
+    OaepSha256,
+}
+
+impl RsaPadding {
+    /// Largest plaintext this padding can wrap under a key of `modulus_bytes`
+    pub fn max_plaintext_len(self, modulus_bytes: usize) -> usize {
+        match self {
+            RsaPadding::Pkcs1v15 => modulus_bytes.saturating_sub(11),
+            RsaPadding::OaepSha256 => modulus_bytes.saturating_sub(2 * 32 + 2),
+        }
+    }
+
+    pub(crate) fn encrypt(self, public_key: &RsaPublicKey, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            RsaPadding::Pkcs1v15 => encrypt(public_key, data),
+            RsaPadding::OaepSha256 => encrypt_oaep(public_key, data),
+        }
+    }
+
+    pub(crate) fn decrypt(self, private_key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            RsaPadding::Pkcs1v15 => decrypt(private_key, data),
+            RsaPadding::OaepSha256 => decrypt_oaep(private_key, data),
+        }
+    }
+}
+
+/// Encrypt data using RSA public key with PKCS#1 v1.5 padding (for small data only)
+///
+/// Kept for peers and stored messages that predate OAEP; new code should use
+/// [`encrypt_oaep`].
 pub fn encrypt(public_key: &RsaPublicKey, data: &[u8]) -> Result<Vec<u8>> {
     let mut rng = OsRng;
     public_key
@@ -17,16 +71,35 @@ pub fn encrypt(public_key: &RsaPublicKey, data: &[u8]) -> Result<Vec<u8>> {
         .map_err(|e| AppError::Crypto(format!("RSA encryption failed: {}", e)))
 }
 
-/// Decrypt data using RSA private key
+/// Decrypt data using RSA private key with PKCS#1 v1.5 padding
 pub fn decrypt(private_key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
     private_key
         .decrypt(Pkcs1v15Encrypt, data)
         .map_err(|e| AppError::Crypto(format!("RSA decryption failed: {}", e)))
 }
 
+/// Encrypt data using RSA public key with OAEP-SHA256 padding (at most [`RSA_MAX_ENCRYPT_SIZE`] bytes)
+pub fn encrypt_oaep(public_key: &RsaPublicKey, data: &[u8]) -> Result<Vec<u8>> {
+    let mut rng = OsRng;
+    public_key
+        .encrypt(&mut rng, Oaep::new::<Sha256>(), data)
+        .map_err(|e| AppError::Crypto(format!("RSA-OAEP encryption failed: {}", e)))
+}
+
+/// Decrypt data using RSA private key with OAEP-SHA256 padding
+pub fn decrypt_oaep(private_key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+    private_key
+        .decrypt(Oaep::new::<Sha256>(), data)
+        .map_err(|e| AppError::Crypto(format!("RSA-OAEP decryption failed: {}", e)))
+}
+
 /// Hybrid encrypted message (RSA + AES)
 #[derive(Serialize, Deserialize)]
 pub struct EncryptedMessage {
+    /// Cipher suite the data was encrypted with
+    pub suite: CipherSuite,
+    /// Padding the symmetric key was wrapped with
+    pub padding: RsaPadding,
     /// AES key encrypted with RSA
     pub encrypted_key: Vec<u8>,
     /// Nonce for AES-GCM
@@ -35,56 +108,221 @@ pub struct EncryptedMessage {
     pub encrypted_data: Vec<u8>,
 }
 
+/// Layout of [`EncryptedMessage`] before the padding was recorded; always PKCS#1 v1.5
+#[derive(Serialize, Deserialize)]
+struct UntaggedMessage {
+    suite: CipherSuite,
+    encrypted_key: Vec<u8>,
+    nonce: Vec<u8>,
+    encrypted_data: Vec<u8>,
+}
+
+impl From<UntaggedMessage> for EncryptedMessage {
+    fn from(message: UntaggedMessage) -> Self {
+        Self {
+            suite: message.suite,
+            padding: RsaPadding::Pkcs1v15,
+            encrypted_key: message.encrypted_key,
+            nonce: message.nonce,
+            encrypted_data: message.encrypted_data,
+        }
+    }
+}
+
 impl EncryptedMessage {
     /// Serialize to bytes
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self)
-            .map_err(|e| AppError::Serialization(format!("Failed to serialize message: {}", e)))
+        let mut bytes = TAGGED_MAGIC.to_vec();
+        bincode::serialize_into(&mut bytes, self)
+            .map_err(|e| AppError::Serialization(format!("Failed to serialize message: {}", e)))?;
+        Ok(bytes)
     }
 
-    /// Deserialize from bytes
+    /// Deserialize from bytes, accepting messages written before the padding was recorded
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        bincode::deserialize(data)
-            .map_err(|e| AppError::Serialization(format!("Failed to deserialize message: {}", e)))
+        let decoded = match data.strip_prefix(&TAGGED_MAGIC) {
+            Some(tagged) => bincode::deserialize(tagged),
+            None => bincode::deserialize::<UntaggedMessage>(data).map(Self::from),
+        };
+        decoded.map_err(|e| AppError::Serialization(format!("Failed to deserialize message: {}", e)))
+    }
+
+    /// Deserialize a message received from a peer
+    ///
+    /// Unlike [`from_bytes`](Self::from_bytes) there is no fallback to the
+    /// untagged layout, and a key wrapped with PKCS#1 v1.5 is refused: every
+    /// sender records its padding and uses OAEP, and accepting v1.5 from the
+    /// network would hand peers a padding oracle on the server's key. Only
+    /// a server at `--security-level compat` falls back to
+    /// [`from_bytes`](Self::from_bytes) for peers from before OAEP.
+    pub fn from_bytes_tagged(data: &[u8]) -> Result<Self> {
+        let tagged = data
+            .strip_prefix(&TAGGED_MAGIC)
+            .ok_or_else(|| AppError::Serialization("Message does not record its key padding".to_string()))?;
+        let message: Self = bincode::deserialize(tagged)
+            .map_err(|e| AppError::Serialization(format!("Failed to deserialize message: {}", e)))?;
+        if message.padding != RsaPadding::OaepSha256 {
+            return Err(AppError::Crypto("Message key not wrapped with OAEP".to_string()));
+        }
+        Ok(message)
+    }
+
+    /// Deserialize from a reader, without first reading it into memory whole
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
+        let mut start = [0u8; TAGGED_MAGIC.len()];
+        reader
+            .read_exact(&mut start)
+            .map_err(|e| AppError::Serialization(format!("Failed to deserialize message: {}", e)))?;
+        let decoded = if start == TAGGED_MAGIC {
+            bincode::deserialize_from(reader)
+        } else {
+            bincode::deserialize_from::<_, UntaggedMessage>(start.chain(reader)).map(Self::from)
+        };
+        decoded.map_err(|e| AppError::Serialization(format!("Failed to deserialize message: {}", e)))
     }
 }
 
 /// Encrypt large data using hybrid encryption (RSA + AES-256-GCM)
 pub fn encrypt_large(public_key: &RsaPublicKey, data: &[u8]) -> Result<EncryptedMessage> {
-    // Generate random AES-256 key
-    let aes_key = Aes256Gcm::generate_key(&mut OsRng);
-    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    encrypt_with_suite(public_key, data, CipherSuite::default())
+}
+
+/// Encrypt large data using hybrid encryption with the given cipher suite
+pub fn encrypt_with_suite(
+    public_key: &RsaPublicKey,
+    data: &[u8],
+    suite: CipherSuite,
+) -> Result<EncryptedMessage> {
+    // Generate random symmetric key and nonce
+    let mut key = vec![0u8; suite.key_len()];
+    let mut nonce = vec![0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut key);
+    OsRng.fill_bytes(&mut nonce);
 
-    // Encrypt data with AES-GCM
-    let cipher = Aes256Gcm::new(&aes_key);
-    let encrypted_data = cipher
-        .encrypt(&nonce, data)
-        .map_err(|e| AppError::Crypto(format!("AES encryption failed: {}", e)))?;
+    // Encrypt data with the suite's AEAD
+    let encrypted_data = aead_encrypt(suite, &key, &nonce, data)?;
 
-    // Encrypt AES key with RSA
-    let encrypted_key = encrypt(public_key, &aes_key)?;
+    // Encrypt symmetric key with RSA
+    let padding = RsaPadding::default();
+    let encrypted_key = padding.encrypt(public_key, &key)?;
 
     Ok(EncryptedMessage {
+        suite,
+        padding,
         encrypted_key,
-        nonce: nonce.to_vec(),
+        nonce,
         encrypted_data,
     })
 }
 
 /// Decrypt hybrid encrypted message
 pub fn decrypt_large(private_key: &RsaPrivateKey, message: &EncryptedMessage) -> Result<Vec<u8>> {
-    // Decrypt AES key with RSA
-    let aes_key = decrypt(private_key, &message.encrypted_key)?;
+    let key = unwrap_key(private_key, message)?;
+    aead_decrypt(message.suite, &key, &message.nonce, &message.encrypted_data)
+}
 
-    // Create AES cipher
-    let aes_key = aes_gcm::Key::<Aes256Gcm>::from_slice(&aes_key);
-    let cipher = Aes256Gcm::new(aes_key);
-    let nonce = Nonce::from_slice(&message.nonce);
+/// Decrypt a hybrid message into `writer`, returning the number of bytes written
+///
+/// The message is sealed as a single AEAD unit, so it is authenticated in
+/// full before anything is written and `writer` never sees unverified data.
+/// That also means the whole plaintext is decrypted into memory first; this
+/// only saves the caller from holding on to it, and memory use still grows
+/// with the message. The plaintext is handed over in chunks. Data that is too
+/// large for that is better sealed with
+/// [`ChunkedFileSealer`](crate::crypto::ChunkedFileSealer) in the first place.
+pub fn decrypt_large_to_writer<W: Write>(
+    private_key: &RsaPrivateKey,
+    message: &EncryptedMessage,
+    writer: &mut W,
+) -> Result<u64> {
+    let key = unwrap_key(private_key, message)?;
+    let data = aead_decrypt(message.suite, &key, &message.nonce, &message.encrypted_data)?;
+    for chunk in data.chunks(WRITE_CHUNK) {
+        writer.write_all(chunk)?;
+    }
+    Ok(data.len() as u64)
+}
+
+/// Decrypt a hybrid message's symmetric key and check it fits the message's suite
+fn unwrap_key(private_key: &RsaPrivateKey, message: &EncryptedMessage) -> Result<Vec<u8>> {
+    // Decrypt symmetric key with RSA
+    let key = message.padding.decrypt(private_key, &message.encrypted_key)?;
 
-    // Decrypt data
-    cipher
-        .decrypt(nonce, message.encrypted_data.as_slice())
-        .map_err(|e| AppError::Crypto(format!("AES decryption failed: {}", e)))
+    if key.len() != message.suite.key_len() {
+        return Err(AppError::Crypto(format!(
+            "Wrapped key length {} does not match {}",
+            key.len(),
+            message.suite
+        )));
+    }
+    if message.nonce.len() != NONCE_LEN {
+        return Err(AppError::Crypto(format!("Invalid nonce length: {}", message.nonce.len())));
+    }
+    Ok(key)
+}
+
+/// Re-wrap a hybrid message's symmetric key for `new_key`
+///
+/// Only the RSA-wrapped key changes; the nonce and bulk ciphertext are
+/// carried over byte for byte, so this costs one RSA operation each way
+/// however large the message is. The new key is always wrapped with the
+/// default padding, so re-wrapping also upgrades PKCS#1 v1.5 messages.
+pub fn rewrap_key(
+    private_key: &RsaPrivateKey,
+    new_key: &RsaPublicKey,
+    message: &EncryptedMessage,
+) -> Result<EncryptedMessage> {
+    let key = message.padding.decrypt(private_key, &message.encrypted_key)?;
+    if key.len() != message.suite.key_len() {
+        return Err(AppError::Crypto(format!(
+            "Wrapped key length {} does not match {}",
+            key.len(),
+            message.suite
+        )));
+    }
+
+    let padding = RsaPadding::default();
+    Ok(EncryptedMessage {
+        suite: message.suite,
+        padding,
+        encrypted_key: padding.encrypt(new_key, &key)?,
+        nonce: message.nonce.clone(),
+        encrypted_data: message.encrypted_data.clone(),
+    })
+}
+
+/// Encrypt with the AEAD selected by `suite`
+pub(crate) fn aead_encrypt(suite: CipherSuite, key: &[u8], nonce: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let nonce = aes_gcm::Nonce::from_slice(nonce);
+    let result = match suite {
+        CipherSuite::Aes128Gcm => Aes128Gcm::new_from_slice(key)
+            .map_err(|e| AppError::Crypto(format!("Invalid key: {}", e)))?
+            .encrypt(nonce, data),
+        CipherSuite::Aes256Gcm => Aes256Gcm::new_from_slice(key)
+            .map_err(|e| AppError::Crypto(format!("Invalid key: {}", e)))?
+            .encrypt(nonce, data),
+        CipherSuite::Aes256GcmSiv => Aes256GcmSiv::new_from_slice(key)
+            .map_err(|e| AppError::Crypto(format!("Invalid key: {}", e)))?
+            .encrypt(nonce, data),
+    };
+    result.map_err(|e| AppError::Crypto(format!("{} encryption failed: {}", suite, e)))
+}
+
+/// Decrypt with the AEAD selected by `suite`
+pub(crate) fn aead_decrypt(suite: CipherSuite, key: &[u8], nonce: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let nonce = aes_gcm::Nonce::from_slice(nonce);
+    let result = match suite {
+        CipherSuite::Aes128Gcm => Aes128Gcm::new_from_slice(key)
+            .map_err(|e| AppError::Crypto(format!("Invalid key: {}", e)))?
+            .decrypt(nonce, data),
+        CipherSuite::Aes256Gcm => Aes256Gcm::new_from_slice(key)
+            .map_err(|e| AppError::Crypto(format!("Invalid key: {}", e)))?
+            .decrypt(nonce, data),
+        CipherSuite::Aes256GcmSiv => Aes256GcmSiv::new_from_slice(key)
+            .map_err(|e| AppError::Crypto(format!("Invalid key: {}", e)))?
+            .decrypt(nonce, data),
+    };
+    result.map_err(|e| AppError::Crypto(format!("{} decryption failed: {}", suite, e)))
 }
 
 #[cfg(test)]
@@ -103,6 +341,64 @@ mod tests {
         assert_eq!(data.to_vec(), decrypted);
     }
 
+    #[test]
+    fn test_encrypt_decrypt_oaep() {
+        let keypair = KeyPair::generate().unwrap();
+        let data = [7u8; RSA_MAX_ENCRYPT_SIZE];
+
+        let encrypted = encrypt_oaep(&keypair.public_key, &data).unwrap();
+        assert_eq!(decrypt_oaep(&keypair.private_key, &encrypted).unwrap(), data);
+        assert!(decrypt(&keypair.private_key, &encrypted).is_err());
+        assert!(encrypt_oaep(&keypair.public_key, &[0u8; RSA_MAX_ENCRYPT_SIZE + 1]).is_err());
+        assert_eq!(RsaPadding::OaepSha256.max_plaintext_len(256), RSA_MAX_ENCRYPT_SIZE);
+    }
+
+    #[test]
+    fn test_both_paddings_round_trip() {
+        let keypair = KeyPair::generate().unwrap();
+        let data = b"settlement batch".to_vec();
+
+        let message = encrypt_large(&keypair.public_key, &data).unwrap();
+        assert_eq!(message.padding, RsaPadding::OaepSha256);
+        let bytes = message.to_bytes().unwrap();
+        assert!(bytes.starts_with(&TAGGED_MAGIC));
+        for restored in [EncryptedMessage::from_bytes(&bytes), EncryptedMessage::from_reader(&bytes[..])] {
+            let restored = restored.unwrap();
+            assert_eq!(restored.padding, RsaPadding::OaepSha256);
+            assert_eq!(decrypt_large(&keypair.private_key, &restored).unwrap(), data);
+        }
+
+        // A message written before the padding was recorded: PKCS#1 v1.5, no marker
+        let mut key = vec![0u8; CipherSuite::Aes256Gcm.key_len()];
+        let mut nonce = vec![0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut key);
+        OsRng.fill_bytes(&mut nonce);
+        let old = UntaggedMessage {
+            suite: CipherSuite::Aes256Gcm,
+            encrypted_key: encrypt(&keypair.public_key, &key).unwrap(),
+            encrypted_data: aead_encrypt(CipherSuite::Aes256Gcm, &key, &nonce, &data).unwrap(),
+            nonce,
+        };
+        let bytes = bincode::serialize(&old).unwrap();
+        for restored in [EncryptedMessage::from_bytes(&bytes), EncryptedMessage::from_reader(&bytes[..])] {
+            let restored = restored.unwrap();
+            assert_eq!(restored.padding, RsaPadding::Pkcs1v15);
+            assert_eq!(decrypt_large(&keypair.private_key, &restored).unwrap(), data);
+
+            // Re-wrapping moves it to OAEP
+            let rewrapped = rewrap_key(&keypair.private_key, &keypair.public_key, &restored).unwrap();
+            assert_eq!(rewrapped.padding, RsaPadding::OaepSha256);
+            assert_eq!(decrypt_large(&keypair.private_key, &rewrapped).unwrap(), data);
+        }
+
+        // Neither the old layout nor a tagged v1.5 key is accepted from a peer
+        assert!(EncryptedMessage::from_bytes_tagged(&bytes).is_err());
+        let tagged_v15 = EncryptedMessage::from(old).to_bytes().unwrap();
+        assert!(EncryptedMessage::from_bytes(&tagged_v15).is_ok());
+        assert!(EncryptedMessage::from_bytes_tagged(&tagged_v15).is_err());
+        assert!(EncryptedMessage::from_bytes_tagged(&message.to_bytes().unwrap()).is_ok());
+    }
+
     #[test]
     fn test_encrypt_decrypt_large() {
         let keypair = crate::crypto::keys::KeyPair::generate().unwrap();
@@ -113,4 +409,50 @@ mod tests {
 
         assert_eq!(data, decrypted);
     }
+
+    #[test]
+    fn test_encrypt_decrypt_every_suite() {
+        let keypair = crate::crypto::keys::KeyPair::generate().unwrap();
+        let data = b"settlement batch".to_vec();
+
+        for suite in CipherSuite::ALL {
+            let encrypted = encrypt_with_suite(&keypair.public_key, &data, suite).unwrap();
+            assert_eq!(encrypted.suite, suite);
+
+            let bytes = encrypted.to_bytes().unwrap();
+            let restored = EncryptedMessage::from_bytes(&bytes).unwrap();
+            assert_eq!(decrypt_large(&keypair.private_key, &restored).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_decrypt_large_to_writer_writes_in_chunks() {
+        /// Records the largest single write
+        struct Recorder(Vec<u8>, usize);
+        impl Write for Recorder {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.1 = self.1.max(buf.len());
+                self.0.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let keypair = KeyPair::generate().unwrap();
+        let data: Vec<u8> = (0..3 * WRITE_CHUNK + 17).map(|i| (i % 251) as u8).collect();
+        let mut message = encrypt_large(&keypair.public_key, &data).unwrap();
+
+        let mut out = Recorder(Vec::new(), 0);
+        assert_eq!(decrypt_large_to_writer(&keypair.private_key, &message, &mut out).unwrap(), data.len() as u64);
+        assert_eq!(out.0, data);
+        assert_eq!(out.1, WRITE_CHUNK);
+
+        // Tampered ciphertext fails before anything is written
+        message.encrypted_data[0] ^= 1;
+        let mut out = Recorder(Vec::new(), 0);
+        assert!(decrypt_large_to_writer(&keypair.private_key, &message, &mut out).is_err());
+        assert!(out.0.is_empty());
+    }
 }
@@ -1,5 +1,15 @@
 pub mod keys;
 pub mod encryption;
+pub mod chunked;
+pub mod chunked_file;
+pub mod suite;
+pub mod signing;
+pub mod pool;
 
-pub use keys::KeyPair;
-pub use encryption::{encrypt, decrypt, encrypt_large, decrypt_large, EncryptedMessage};
+pub use keys::{KeyPair, fingerprint, public_key_pem, public_key_from_pem, encode_public_key, decode_public_key, check_public_exponent, PublicKeyFormat, PUBLIC_EXPONENT};
+pub use encryption::{encrypt, decrypt, encrypt_oaep, decrypt_oaep, encrypt_large, encrypt_with_suite, decrypt_large, decrypt_large_to_writer, rewrap_key, EncryptedMessage, RsaPadding, RSA_MAX_ENCRYPT_SIZE};
+pub use chunked::{ChunkSealer, ChunkOpener, StreamKey, sealed_len, DEFAULT_CHUNK_SIZE, MAX_CHUNK_SIZE, CHUNK_TAG_LEN};
+pub use chunked_file::{ChunkedFileSealer, seal_chunked_file, open_chunked_file, rewrap_chunked_file, is_chunked_file};
+pub use suite::{CipherSuite, negotiate};
+pub use pool::KeyPool;
+pub use signing::{sign, verify, sign_with, verify_with, sign_detached, verify_detached, AuthMethod, VerifyKey};
@@ -0,0 +1,189 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
+use crate::cli::output::Output;
+use crate::error::{AppError, Result};
+use super::keys::KeyPair;
+
+/// Extension of a ready pool entry; anything else in the directory is ignored
+const ENTRY_EXTENSION: &str = "pem";
+
+/// Directory of pre-generated key pairs, so a fresh identity need not wait for keygen
+///
+/// Each key is one PKCS#8 private key file. A key is claimed by renaming it
+/// before it is read, so two processes drawing from the same pool never get
+/// the same key, and new keys only appear under their final name once fully
+/// written.
+#[derive(Debug, Clone)]
+pub struct KeyPool {
+    dir: PathBuf,
+    /// Background refills started by [`KeyPool::take_or_generate`], shared by clones
+    refills: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl KeyPool {
+    /// Use the pool in `dir`; it is created when keys are first added
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            refills: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Directory holding the pool
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of keys ready to be taken
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.entries()?.len())
+    }
+
+    /// Whether no keys are ready to be taken
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Generate `count` keys in parallel and add them to the pool
+    pub async fn fill(&self, count: usize) -> Result<()> {
+        let mut generating = JoinSet::new();
+        for _ in 0..count {
+            generating.spawn(KeyPair::generate_async());
+        }
+        while let Some(generated) = generating.join_next().await {
+            let keypair = generated.map_err(|e| AppError::Crypto(format!("Key generation task failed: {}", e)))??;
+            self.add(&keypair)?;
+        }
+        Ok(())
+    }
+
+    /// Claim one key, removing it from the pool; `None` if the pool is empty
+    pub fn take(&self) -> Result<Option<KeyPair>> {
+        for entry in self.entries()? {
+            let claimed = entry.with_extension(format!("taken-{}-{:08x}", std::process::id(), rand::random::<u32>()));
+            match fs::rename(&entry, &claimed) {
+                Ok(()) => {
+                    let private_key = KeyPair::load_private(&claimed);
+                    let _ = fs::remove_file(&claimed);
+                    return Ok(Some(KeyPair::from_private(private_key?)));
+                }
+                // Another process claimed it first
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(pool_error(&self.dir, e)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Take a key from the pool, or generate one directly if it is empty
+    ///
+    /// A key taken from the pool is replaced by a new one generated in the
+    /// background; call [`KeyPool::finish_refills`] before the runtime shuts
+    /// down, or a short-lived process drops the refill and the pool shrinks.
+    /// A refill that fails is reported as a warning.
+    pub async fn take_or_generate(&self) -> Result<KeyPair> {
+        match self.take()? {
+            Some(keypair) => {
+                let pool = self.clone();
+                let refill = tokio::spawn(async move {
+                    if let Err(e) = pool.fill(1).await {
+                        Output::warning(&format!("Failed to refill key pool {}: {}", pool.dir().display(), e));
+                    }
+                });
+                self.refills.lock().unwrap_or_else(|e| e.into_inner()).push(refill);
+                Ok(keypair)
+            }
+            None => KeyPair::generate_async().await,
+        }
+    }
+
+    /// Wait for the keys taken so far to be replaced
+    pub async fn finish_refills(&self) {
+        let refills = std::mem::take(&mut *self.refills.lock().unwrap_or_else(|e| e.into_inner()));
+        for refill in refills {
+            let _ = refill.await;
+        }
+    }
+
+    /// Write one key under a temporary name, then publish it
+    fn add(&self, keypair: &KeyPair) -> Result<()> {
+        let name = format!("key-{}-{:016x}", chrono::Utc::now().timestamp_micros(), rand::random::<u64>());
+        let partial = self.dir.join(format!("{}.partial", name));
+        keypair.save_private(&partial)?;
+        fs::rename(&partial, self.dir.join(name).with_extension(ENTRY_EXTENSION))
+            .map_err(|e| pool_error(&self.dir, e))
+    }
+
+    /// Ready entries, oldest first
+    fn entries(&self) -> Result<Vec<PathBuf>> {
+        let read_dir = match fs::read_dir(&self.dir) {
+            Ok(read_dir) => read_dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(pool_error(&self.dir, e)),
+        };
+        let mut entries = Vec::new();
+        for entry in read_dir {
+            let path = entry.map_err(|e| pool_error(&self.dir, e))?.path();
+            if path.extension().is_some_and(|ext| ext == ENTRY_EXTENSION) {
+                entries.push(path);
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+}
+
+fn pool_error(dir: &Path, e: io::Error) -> AppError {
+    AppError::Crypto(format!("Key pool {}: {}", dir.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_take_from_pool_and_refill() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = KeyPool::new(&dir.path().join("pool"));
+        pool.fill(2).await.unwrap();
+        assert_eq!(pool.len().unwrap(), 2);
+
+        let first = pool.take().unwrap().unwrap();
+        let second = pool.take().unwrap().unwrap();
+        assert!(first.is_consistent() && second.is_consistent());
+        assert_ne!(first.fingerprint().unwrap(), second.fingerprint().unwrap());
+        assert!(pool.take().unwrap().is_none());
+
+        // An empty pool falls back to generating directly
+        let generated = pool.take_or_generate().await.unwrap();
+        assert!(generated.is_consistent());
+        assert!(pool.is_empty().unwrap());
+
+        // A key drawn from the pool is replaced in the background
+        pool.fill(1).await.unwrap();
+        let drawn = pool.take_or_generate().await.unwrap();
+        assert_ne!(drawn.fingerprint().unwrap(), generated.fingerprint().unwrap());
+        pool.finish_refills().await;
+        assert_eq!(pool.len().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_refill_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let pool = KeyPool::new(&dir.path().join("pool"));
+        pool.fill(1).await.unwrap();
+
+        crate::cli::output::start_capture();
+        pool.take_or_generate().await.unwrap();
+        // The refill has not run yet; leave it nowhere to write
+        fs::remove_dir_all(pool.dir()).unwrap();
+        fs::write(pool.dir(), b"not a directory").unwrap();
+
+        pool.finish_refills().await;
+        let lines = crate::cli::output::take_captured();
+        assert!(lines.iter().any(|line| line.contains("Failed to refill key pool")), "{:#?}", lines);
+    }
+}
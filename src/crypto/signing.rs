@@ -0,0 +1,197 @@
+use clap::ValueEnum;
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use rsa::pkcs8::DecodePublicKey;
+use rsa::pss::{BlindedSigningKey, Signature, VerifyingKey};
+use rsa::signature::{RandomizedSigner, SignatureEncoding, Verifier};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use crate::error::{AppError, Result};
+use super::keys::check_public_exponent;
+
+/// How a client proves possession of its private key during the handshake
+///
+/// `LegacyDecrypt` is the original scheme: a raw, unhashed PKCS#1 v1.5
+/// private-key operation over the challenge bytes alone. It is kept only so
+/// peers can be migrated one at a time; `PssSha256` signs the challenge bound
+/// to the connection's nonce and timestamp.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+pub enum AuthMethod {
+    /// Raw PKCS#1 v1.5 over the challenge (insecure, for old peers)
+    #[value(name = "legacy-decrypt")]
+    LegacyDecrypt,
+    /// RSA-PSS with SHA-256 over the bound challenge material
+    #[value(name = "pss-sha256")]
+    PssSha256,
+}
+
+impl AuthMethod {
+    /// All methods this build supports, strongest first
+    pub const ALL: [AuthMethod; 2] = [AuthMethod::PssSha256, AuthMethod::LegacyDecrypt];
+
+    /// Relative strength used for negotiation and minimums
+    pub fn strength(self) -> u8 {
+        match self {
+            AuthMethod::LegacyDecrypt => 1,
+            AuthMethod::PssSha256 => 2,
+        }
+    }
+
+    /// Name as used on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            AuthMethod::LegacyDecrypt => "legacy-decrypt",
+            AuthMethod::PssSha256 => "pss-sha256",
+        }
+    }
+
+    /// Whether this method is at least as strong as `min`
+    pub fn meets(self, min: AuthMethod) -> bool {
+        self.strength() >= min.strength()
+    }
+}
+
+impl std::fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Produce a challenge response with the given method
+pub fn sign_with(method: AuthMethod, private_key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+    match method {
+        AuthMethod::PssSha256 => sign(private_key, data),
+        AuthMethod::LegacyDecrypt => {
+            let mut rng = rand::thread_rng();
+            private_key
+                .sign_with_rng(&mut rng, Pkcs1v15Sign::new_unprefixed(), data)
+                .map_err(|e| AppError::Crypto(format!("Signing failed: {}", e)))
+        }
+    }
+}
+
+/// Check a challenge response produced by [`sign_with`]
+pub fn verify_with(method: AuthMethod, public_key: &RsaPublicKey, data: &[u8], signature: &[u8]) -> Result<()> {
+    match method {
+        AuthMethod::PssSha256 => verify(public_key, data, signature),
+        AuthMethod::LegacyDecrypt => public_key
+            .verify(Pkcs1v15Sign::new_unprefixed(), data, signature)
+            .map_err(|_| AppError::Crypto("Signature verification failed".to_string())),
+    }
+}
+
+/// Sign data with RSA-PSS (SHA-256)
+pub fn sign(private_key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+    let signing_key = BlindedSigningKey::<Sha256>::new(private_key.clone());
+    let mut rng = rand::thread_rng();
+    let signature = signing_key
+        .try_sign_with_rng(&mut rng, data)
+        .map_err(|e| AppError::Crypto(format!("Signing failed: {}", e)))?;
+    Ok(signature.to_vec())
+}
+
+/// Verify an RSA-PSS (SHA-256) signature over data
+pub fn verify(public_key: &RsaPublicKey, data: &[u8], signature: &[u8]) -> Result<()> {
+    let verifying_key = VerifyingKey::<Sha256>::new(public_key.clone());
+    let signature = Signature::try_from(signature)
+        .map_err(|e| AppError::Crypto(format!("Malformed signature: {}", e)))?;
+    verifying_key
+        .verify(data, &signature)
+        .map_err(|_| AppError::Crypto("Signature verification failed".to_string()))
+}
+
+/// Public key a detached signature is checked against
+#[derive(Debug, Clone)]
+pub enum VerifyKey {
+    /// RSA key, as used by finapp nodes
+    Rsa(RsaPublicKey),
+    /// Ed25519 key from an external signing tool
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl VerifyKey {
+    /// Parse a PEM `PUBLIC KEY` holding either an RSA or an Ed25519 key
+    pub fn from_pem(pem: &str) -> Result<Self> {
+        if let Ok(key) = RsaPublicKey::from_public_key_pem(pem) {
+            check_public_exponent(&key, false)?;
+            return Ok(VerifyKey::Rsa(key));
+        }
+        <ed25519_dalek::VerifyingKey as ed25519_dalek::pkcs8::DecodePublicKey>::from_public_key_pem(pem)
+            .map(VerifyKey::Ed25519)
+            .map_err(|_| AppError::Crypto("Public key is neither an RSA nor an Ed25519 key".to_string()))
+    }
+}
+
+/// Sign a whole file's contents, for a signature kept next to it
+///
+/// Uses RSA-PSS with SHA-256, like [`sign`].
+pub fn sign_detached(private_key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+    sign(private_key, data)
+}
+
+/// Check a detached signature over `data`
+///
+/// RSA signatures may be RSA-PSS or PKCS#1 v1.5, both over SHA-256, so
+/// signatures made with `openssl dgst -sha256 -sign` verify as well as those
+/// from [`sign_detached`]. Ed25519 signatures are checked strictly.
+pub fn verify_detached(key: &VerifyKey, data: &[u8], signature: &[u8]) -> Result<()> {
+    let valid = match key {
+        VerifyKey::Rsa(public_key) => {
+            verify(public_key, data, signature).is_ok()
+                || public_key
+                    .verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(data), signature)
+                    .is_ok()
+        }
+        VerifyKey::Ed25519(public_key) => ed25519_dalek::Signature::from_slice(signature)
+            .is_ok_and(|signature| public_key.verify_strict(data, &signature).is_ok()),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::Crypto("Signature verification failed".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyPair;
+
+    #[test]
+    fn test_sign_verify() {
+        let keypair = KeyPair::generate().unwrap();
+        let other = KeyPair::generate().unwrap();
+        let signature = sign(&keypair.private_key, b"challenge").unwrap();
+
+        assert!(verify(&keypair.public_key, b"challenge", &signature).is_ok());
+        assert!(verify(&keypair.public_key, b"tampered", &signature).is_err());
+        assert!(verify(&other.public_key, b"challenge", &signature).is_err());
+    }
+
+    #[test]
+    fn test_detached_signatures() {
+        let keypair = KeyPair::generate().unwrap();
+        let other = KeyPair::generate().unwrap();
+        let data = b"id,amount\n1,100.00\n";
+        let key = VerifyKey::from_pem(&keypair.public_key_pem().unwrap()).unwrap();
+
+        let signature = sign_detached(&keypair.private_key, data).unwrap();
+        assert!(verify_detached(&key, data, &signature).is_ok());
+        assert!(verify_detached(&key, b"id,amount\n1,900.00\n", &signature).is_err());
+        let wrong_key = VerifyKey::from_pem(&other.public_key_pem().unwrap()).unwrap();
+        assert!(verify_detached(&wrong_key, data, &signature).is_err());
+
+        // PKCS#1 v1.5, as produced by `openssl dgst -sha256 -sign`
+        let pkcs1 = keypair.private_key.sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(data)).unwrap();
+        assert!(verify_detached(&key, data, &pkcs1).is_ok());
+
+        use ed25519_dalek::pkcs8::EncodePublicKey;
+        use ed25519_dalek::Signer;
+        let ed_key = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+        let ed_pem = ed_key.verifying_key().to_public_key_pem(Default::default()).unwrap();
+        let ed_verify = VerifyKey::from_pem(&ed_pem).unwrap();
+        let ed_signature = ed_key.sign(data).to_bytes();
+        assert!(verify_detached(&ed_verify, data, &ed_signature).is_ok());
+        assert!(verify_detached(&ed_verify, b"tampered", &ed_signature).is_err());
+        assert!(verify_detached(&ed_verify, data, &signature).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use serde::{Serialize, Deserialize};
+use clap::ValueEnum;
+
+/// Symmetric cipher used to encrypt a message body
+///
+/// The per-message key is always wrapped with the recipient's RSA key; the
+/// suite selects the AEAD used for the body. Suites are negotiated during the
+/// handshake so both peers agree on one before any data is sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default, ValueEnum)]
+pub enum CipherSuite {
+    /// AES-128 in GCM mode
+    #[value(name = "aes-128-gcm")]
+    Aes128Gcm,
+    /// AES-256 in GCM mode
+    #[default]
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    /// AES-256 in GCM-SIV mode (nonce-misuse resistant)
+    #[value(name = "aes-256-gcm-siv")]
+    Aes256GcmSiv,
+}
+
+impl CipherSuite {
+    /// All suites this build supports, strongest first
+    pub const ALL: [CipherSuite; 3] = [
+        CipherSuite::Aes256GcmSiv,
+        CipherSuite::Aes256Gcm,
+        CipherSuite::Aes128Gcm,
+    ];
+
+    /// Relative strength used to pick between mutually supported suites
+    pub fn strength(self) -> u8 {
+        match self {
+            CipherSuite::Aes128Gcm => 1,
+            CipherSuite::Aes256Gcm => 2,
+            CipherSuite::Aes256GcmSiv => 3,
+        }
+    }
+
+    /// Whether this suite is at least as strong as `min`
+    pub fn meets(self, min: CipherSuite) -> bool {
+        self.strength() >= min.strength()
+    }
+
+    /// Length of the symmetric key in bytes
+    pub fn key_len(self) -> usize {
+        match self {
+            CipherSuite::Aes128Gcm => 16,
+            CipherSuite::Aes256Gcm | CipherSuite::Aes256GcmSiv => 32,
+        }
+    }
+
+    /// Human-readable name
+    pub fn name(self) -> &'static str {
+        match self {
+            CipherSuite::Aes128Gcm => "AES-128-GCM",
+            CipherSuite::Aes256Gcm => "AES-256-GCM",
+            CipherSuite::Aes256GcmSiv => "AES-256-GCM-SIV",
+        }
+    }
+}
+
+impl std::fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Pick the strongest suite offered by the client that the server supports
+pub fn negotiate(offered: &[CipherSuite], supported: &[CipherSuite]) -> Option<CipherSuite> {
+    offered
+        .iter()
+        .copied()
+        .filter(|suite| supported.contains(suite))
+        .max_by_key(|suite| suite.strength())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_overlapping() {
+        let client = [CipherSuite::Aes128Gcm, CipherSuite::Aes256Gcm];
+        let server = [CipherSuite::Aes256Gcm, CipherSuite::Aes256GcmSiv, CipherSuite::Aes128Gcm];
+        assert_eq!(negotiate(&client, &server), Some(CipherSuite::Aes256Gcm));
+
+        assert_eq!(negotiate(&CipherSuite::ALL, &CipherSuite::ALL), Some(CipherSuite::Aes256GcmSiv));
+    }
+
+    #[test]
+    fn test_negotiate_disjoint() {
+        let client = [CipherSuite::Aes128Gcm];
+        let server = [CipherSuite::Aes256Gcm, CipherSuite::Aes256GcmSiv];
+        assert_eq!(negotiate(&client, &server), None);
+        assert_eq!(negotiate(&[], &server), None);
+    }
+}
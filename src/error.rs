@@ -1,3 +1,4 @@
+use serde::ser::{Serialize, Serializer, SerializeStruct};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -44,6 +45,51 @@ impl AppError {
             AppError::Serialization(_) => 9,
         }
     }
+
+    /// Name of the error variant, used as a stable machine-readable kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AppError::Io(_) => "Io",
+            AppError::Cli(_) => "Cli",
+            AppError::Crypto(_) => "Crypto",
+            AppError::Auth(_) => "Auth",
+            AppError::Protocol(_) => "Protocol",
+            AppError::Server(_) => "Server",
+            AppError::Client(_) => "Client",
+            AppError::Config(_) => "Config",
+            AppError::Serialization(_) => "Serialization",
+        }
+    }
+
+    /// Render as `{"error":{"kind":...,"code":...,"message":...}}`
+    pub fn to_json(&self) -> String {
+        serde_json::json!({ "error": self }).to_string()
+    }
+}
+
+impl Serialize for AppError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("AppError", 3)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("code", &self.exit_code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
 }
 
 pub type Result<T> = std::result::Result<T, AppError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_rendering() {
+        let err = AppError::Auth("Invalid connect key".to_string());
+        let value: serde_json::Value = serde_json::from_str(&err.to_json()).unwrap();
+
+        assert_eq!(value["error"]["kind"], "Auth");
+        assert_eq!(value["error"]["code"], 4);
+        assert_eq!(value["error"]["message"], "Authentication error: Invalid connect key");
+    }
+}
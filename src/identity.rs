@@ -0,0 +1,297 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+use crate::error::{AppError, Result};
+use crate::crypto::{KeyPair, KeyPool, fingerprint};
+use crate::auth::Whitelist;
+
+/// File name of the private key inside an identity directory
+pub const PRIVATE_KEY_FILE: &str = "private_key.pem";
+/// File name of the public key inside an identity directory
+pub const PUBLIC_KEY_FILE: &str = "public_key.pem";
+/// File name of the whitelist inside an identity directory
+pub const WHITELIST_FILE: &str = "whitelist.txt";
+
+/// A node's keypair together with the directory it lives in
+pub struct NodeIdentity {
+    dir: PathBuf,
+    keypair: KeyPair,
+    fingerprint: String,
+}
+
+impl NodeIdentity {
+    /// Generate a new identity in `dir`, refusing to replace an existing one
+    pub fn create(dir: &Path) -> Result<Self> {
+        Self::ensure_absent(dir)?;
+        Self::regenerate(dir)
+    }
+
+    /// Generate a new keypair in `dir`, replacing any existing keys
+    ///
+    /// An existing whitelist is left untouched; a missing one is initialized.
+    pub fn regenerate(dir: &Path) -> Result<Self> {
+        Self::install(dir, KeyPair::generate()?)
+    }
+
+    /// Like [`NodeIdentity::regenerate`], generating the keypair off the async runtime
+    pub async fn regenerate_async(dir: &Path) -> Result<Self> {
+        Self::install(dir, KeyPair::generate_async().await?)
+    }
+
+    /// Like [`NodeIdentity::regenerate_async`], encrypting the private key with `passphrase`
+    pub async fn regenerate_encrypted_async(dir: &Path, passphrase: &str) -> Result<Self> {
+        let keypair = KeyPair::generate_async().await?;
+        Self::prepare_dir(dir)?;
+        keypair.save_encrypted(&dir.join(PRIVATE_KEY_FILE), &dir.join(PUBLIC_KEY_FILE), passphrase)?;
+        Self::from_keypair(dir, keypair)
+    }
+
+    /// Like [`NodeIdentity::load_or_create`], generating the keypair off the async runtime
+    pub async fn load_or_create_async(dir: &Path) -> Result<Self> {
+        if Self::exists(dir) {
+            return Self::load(dir);
+        }
+        Self::ensure_absent(dir)?;
+        Self::regenerate_async(dir).await
+    }
+
+    /// Like [`NodeIdentity::load_or_create_async`], taking a new keypair from `pool`
+    ///
+    /// Falls back to generating one if the pool is empty.
+    pub async fn load_or_create_from_pool(dir: &Path, pool: &KeyPool) -> Result<Self> {
+        if Self::exists(dir) {
+            return Self::load(dir);
+        }
+        Self::ensure_absent(dir)?;
+        Self::install(dir, pool.take_or_generate().await?)
+    }
+
+    /// Refuse to continue if either key file is already present
+    fn ensure_absent(dir: &Path) -> Result<()> {
+        if dir.join(PRIVATE_KEY_FILE).exists() || dir.join(PUBLIC_KEY_FILE).exists() {
+            return Err(AppError::Crypto(format!(
+                "An identity already exists in {}",
+                dir.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Write `keypair` into `dir` and initialize a missing whitelist
+    fn install(dir: &Path, keypair: KeyPair) -> Result<Self> {
+        Self::prepare_dir(dir)?;
+        keypair.save(&dir.join(PRIVATE_KEY_FILE), &dir.join(PUBLIC_KEY_FILE))?;
+        Self::load(dir)
+    }
+
+    /// Create `dir` and initialize a missing whitelist in it
+    fn prepare_dir(dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)
+            .map_err(|e| AppError::Crypto(format!("Failed to create directory: {}", e)))?;
+
+        let whitelist_path = dir.join(WHITELIST_FILE);
+        if !whitelist_path.exists() {
+            Whitelist::create(&whitelist_path)?;
+        }
+        Ok(())
+    }
+
+    /// Load an existing identity from `dir`
+    ///
+    /// A passphrase-protected private key is an error; see [`NodeIdentity::load_with`].
+    pub fn load(dir: &Path) -> Result<Self> {
+        let keypair = KeyPair::load(&dir.join(PRIVATE_KEY_FILE), &dir.join(PUBLIC_KEY_FILE))?;
+        Self::from_keypair(dir, keypair)
+    }
+
+    /// Load an existing identity from `dir`, calling `passphrase` only if its private key is encrypted
+    pub fn load_with(dir: &Path, passphrase: impl FnOnce() -> Result<String>) -> Result<Self> {
+        let (private_path, public_path) = (dir.join(PRIVATE_KEY_FILE), dir.join(PUBLIC_KEY_FILE));
+        if !KeyPair::private_key_is_encrypted(&private_path)? {
+            return Self::load(dir);
+        }
+        let keypair = KeyPair::load_encrypted(&private_path, &public_path, &passphrase()?)?;
+        Self::from_keypair(dir, keypair)
+    }
+
+    /// Whether the private key in `dir` is protected by a passphrase
+    pub fn is_encrypted(dir: &Path) -> bool {
+        KeyPair::private_key_is_encrypted(&dir.join(PRIVATE_KEY_FILE)).unwrap_or(false)
+    }
+
+    fn from_keypair(dir: &Path, keypair: KeyPair) -> Result<Self> {
+        let fingerprint = keypair.fingerprint()?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            keypair,
+            fingerprint,
+        })
+    }
+
+    /// Load the identity in `dir`, creating one if no keys exist yet
+    pub fn load_or_create(dir: &Path) -> Result<Self> {
+        if Self::exists(dir) {
+            Self::load(dir)
+        } else {
+            Self::create(dir)
+        }
+    }
+
+    /// Check whether `dir` holds both halves of a keypair
+    pub fn exists(dir: &Path) -> bool {
+        dir.join(PRIVATE_KEY_FILE).exists() && dir.join(PUBLIC_KEY_FILE).exists()
+    }
+
+    /// Directory holding the identity
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the private key
+    pub fn private_key_path(&self) -> PathBuf {
+        self.dir.join(PRIVATE_KEY_FILE)
+    }
+
+    /// Path of the public key
+    pub fn public_key_path(&self) -> PathBuf {
+        self.dir.join(PUBLIC_KEY_FILE)
+    }
+
+    /// Path of the whitelist
+    pub fn whitelist_path(&self) -> PathBuf {
+        self.dir.join(WHITELIST_FILE)
+    }
+
+    /// SHA-256 fingerprint of the public key
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// The identity's keypair
+    pub fn keypair(&self) -> &KeyPair {
+        &self.keypair
+    }
+
+    /// Consume the identity, returning its keypair
+    pub fn into_keypair(self) -> KeyPair {
+        self.keypair
+    }
+}
+
+/// Which identity a key directory holds, and whether its halves belong together
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyDirCheck {
+    /// Directory that was checked
+    pub dir: PathBuf,
+    /// Fingerprint of the public key file
+    pub fingerprint: String,
+    /// Whether the private key derives the public key; `None` if there is no private key
+    pub private_key_matches: Option<bool>,
+}
+
+impl KeyDirCheck {
+    /// Load the public key in `dir` and, if present, check the private key against it
+    pub fn inspect(dir: &Path) -> Result<Self> {
+        let public_key = KeyPair::load_public(&dir.join(PUBLIC_KEY_FILE))?;
+        let private_path = dir.join(PRIVATE_KEY_FILE);
+        let private_key_matches = if private_path.exists() {
+            let private_key = KeyPair::load_private(&private_path)?;
+            Some(KeyPair { private_key, public_key: public_key.clone() }.is_consistent())
+        } else {
+            None
+        };
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            fingerprint: fingerprint(&public_key)?,
+            private_key_matches,
+        })
+    }
+
+    /// Whether both directories hold the same public key (same modulus and exponent)
+    pub fn same_identity(&self, other: &KeyDirCheck) -> bool {
+        self.fingerprint == other.fingerprint
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_then_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let created = NodeIdentity::create(dir.path()).unwrap();
+
+        assert!(created.private_key_path().exists());
+        assert!(created.public_key_path().exists());
+        assert!(created.whitelist_path().exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(created.private_key_path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let loaded = NodeIdentity::load(dir.path()).unwrap();
+        assert_eq!(created.fingerprint(), loaded.fingerprint());
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_identity_asks_for_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let created = NodeIdentity::regenerate_encrypted_async(dir.path(), "hunter22").await.unwrap();
+        assert!(NodeIdentity::is_encrypted(dir.path()));
+        assert!(created.whitelist_path().exists());
+
+        let loaded = NodeIdentity::load_with(dir.path(), || Ok("hunter22".to_string())).unwrap();
+        assert_eq!(loaded.fingerprint(), created.fingerprint());
+        let err = NodeIdentity::load_with(dir.path(), || Ok("hunter2".to_string())).err().unwrap();
+        assert!(err.to_string().contains("incorrect passphrase"));
+        assert!(NodeIdentity::load(dir.path()).is_err());
+
+        // Plaintext keys never ask
+        let plain = tempfile::tempdir().unwrap();
+        NodeIdentity::create(plain.path()).unwrap();
+        assert!(!NodeIdentity::is_encrypted(plain.path()));
+        NodeIdentity::load_with(plain.path(), || panic!("asked for a passphrase")).unwrap();
+    }
+
+    #[test]
+    fn test_create_refuses_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = NodeIdentity::create(dir.path()).unwrap();
+
+        assert!(NodeIdentity::create(dir.path()).is_err());
+
+        let loaded = NodeIdentity::load(dir.path()).unwrap();
+        assert_eq!(first.fingerprint(), loaded.fingerprint());
+    }
+
+    #[test]
+    fn test_key_dirs_compared_and_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, copy, b) = (dir.path().join("a"), dir.path().join("copy"), dir.path().join("b"));
+        NodeIdentity::create(&a).unwrap();
+        NodeIdentity::create(&b).unwrap();
+        fs::create_dir(&copy).unwrap();
+        fs::copy(a.join(PUBLIC_KEY_FILE), copy.join(PUBLIC_KEY_FILE)).unwrap();
+
+        let (check_a, check_copy, check_b) = (
+            KeyDirCheck::inspect(&a).unwrap(),
+            KeyDirCheck::inspect(&copy).unwrap(),
+            KeyDirCheck::inspect(&b).unwrap(),
+        );
+        assert!(check_a.same_identity(&check_copy));
+        assert!(!check_a.same_identity(&check_b));
+        assert_eq!(check_a.private_key_matches, Some(true));
+        assert_eq!(check_copy.private_key_matches, None);
+
+        // b's private key next to a's public key is a misplaced file
+        fs::copy(b.join(PRIVATE_KEY_FILE), a.join(PRIVATE_KEY_FILE)).unwrap();
+        let mixed = KeyDirCheck::inspect(&a).unwrap();
+        assert_eq!(mixed.fingerprint, check_a.fingerprint);
+        assert_eq!(mixed.private_key_matches, Some(false));
+    }
+}
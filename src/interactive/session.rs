@@ -1,19 +1,73 @@
+use colored::Colorize;
+use std::collections::HashMap;
 use std::io::{self, Write};
 use std::path::Path;
-use tokio::sync::broadcast;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
 use crate::error::{AppError, Result};
 use crate::crypto::KeyPair;
+use crate::identity::NodeIdentity;
 use crate::auth::Whitelist;
-use crate::server::Server;
-use crate::client::Client;
-use crate::cli::Output;
+use crate::server::{Server, LastAccept, MessageWatcher};
+use crate::client::{Client, ClientSession};
+use crate::cli::{key_passphrase, Output};
 
 /// Interactive session for REPL mode
 pub struct InteractiveSession {
     keypair: Option<KeyPair>,
     keys_dir: String,
     server_shutdown: Option<broadcast::Sender<()>>,
+    server_drain: Option<broadcast::Sender<()>>,
     listening_port: Option<u16>,
+    draining: bool,
+    /// Connect keys per peer set with `set-key`; kept in memory only
+    connect_keys: HashMap<String, String>,
+    /// Connection opened with `connect`, used by `send` until `disconnect`
+    connection: Option<KeptConnection>,
+    /// Stop the server after this long without accepting a connection
+    auto_stop_idle: Option<Duration>,
+    /// Watches the running server for `auto_stop_idle`
+    idle_monitor: Option<IdleMonitor>,
+}
+
+/// How often the idle monitor looks at the server's last accept
+const AUTO_STOP_CHECK: Duration = Duration::from_secs(1);
+
+/// Background task that drains an idle server and flags that it did
+struct IdleMonitor {
+    stopped: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+/// Default idle time before `connect`'s keepalive pings the server
+const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// A connection opened with `connect`, pinged while idle and reopened when lost
+struct KeptConnection {
+    session: Arc<Mutex<ClientSession>>,
+    keepalive: Option<JoinHandle<()>>,
+}
+
+/// A parsed `send` command line
+#[derive(Debug, PartialEq, Eq)]
+struct SendArgs<'a> {
+    ip: &'a str,
+    file: &'a str,
+    save_as: Option<&'a str>,
+    connect_key: Option<&'a str>,
+}
+
+/// A parsed `connect` command line
+#[derive(Debug, PartialEq, Eq)]
+struct ConnectArgs<'a> {
+    ip: &'a str,
+    port: u16,
+    connect_key: Option<&'a str>,
+    /// Idle time before pinging; `None` turns keepalive off
+    keepalive: Option<Duration>,
 }
 
 impl InteractiveSession {
@@ -23,10 +77,23 @@ impl InteractiveSession {
             keypair: None,
             keys_dir: keys_dir.to_string(),
             server_shutdown: None,
+            server_drain: None,
             listening_port: None,
+            draining: false,
+            connect_keys: HashMap::new(),
+            connection: None,
+            auto_stop_idle: None,
+            idle_monitor: None,
         }
     }
 
+    /// Stop a server started with `listen` once it has gone `secs` seconds
+    /// without accepting a connection; `None` keeps it running
+    pub fn with_auto_stop_idle_secs(mut self, secs: Option<u64>) -> Self {
+        self.auto_stop_idle = secs.map(Duration::from_secs);
+        self
+    }
+
     /// Run the interactive session
     pub async fn run(&mut self) -> Result<()> {
         Output::header("Secure Finance Messaging Application");
@@ -45,6 +112,8 @@ impl InteractiveSession {
                 continue;
             }
 
+            self.check_auto_stop();
+
             let parts: Vec<&str> = input.split_whitespace().collect();
             let command = parts[0];
 
@@ -52,11 +121,17 @@ impl InteractiveSession {
                 "help" | "h" | "?" => self.show_help(),
                 "listen" | "l" => self.start_server(&parts[1..]).await?,
                 "send" | "s" => self.send_message(&parts[1..]).await?,
-                "status" => self.show_status(),
-                "keygen" | "k" => self.generate_keys(&parts[1..])?,
+                "connect" => self.connect(&parts[1..]).await?,
+                "disconnect" => self.disconnect().await,
+                "set-key" => self.set_connect_key(&parts[1..]),
+                "status" => self.show_status().await,
+                "keygen" | "k" => self.generate_keys(&parts[1..]).await?,
                 "whitelist" | "w" => self.manage_whitelist(&parts[1..])?,
                 "stop" => self.stop_server()?,
+                "drain" => self.drain_server(),
+                "watch" => self.watch_messages(&parts[1..]).await?,
                 "exit" | "quit" | "q" => {
+                    self.disconnect().await;
                     self.stop_server()?;
                     Output::info("Goodbye!");
                     break;
@@ -72,14 +147,22 @@ impl InteractiveSession {
     fn show_help(&self) {
         Output::header("Available Commands");
 
-        println!("  {:<20} {}", "listen [port]", "Start listening server (default: 8080)");
-        println!("  {:<20} {}", "stop", "Stop the listening server");
-        println!("  {:<20} {}", "send <ip> <file> [name]", "Send message to server");
-        println!("  {:<20} {}", "status", "Show current status");
-        println!("  {:<20} {}", "keygen [dir]", "Generate new key pair");
-        println!("  {:<20} {}", "whitelist <key>", "Add key to whitelist");
-        println!("  {:<20} {}", "help", "Show this help message");
-        println!("  {:<20} {}", "exit / quit", "Exit interactive mode");
+        help_line("listen [port]", "Start listening server (default: 8080)");
+        help_line("stop", "Stop the listening server");
+        help_line("drain", "Stop accepting, let in-flight transfers finish");
+        help_line("send <ip> <file> [name] [--ck KEY]", "Send message to server");
+        help_line("connect <ip> [port] [--ck KEY]", "Keep one authenticated connection for later sends (--keepalive SECS, 0 off)");
+        help_line("send <file> [name]", "Send over the connection opened with 'connect'");
+        help_line("disconnect", "Close the connection opened with 'connect'");
+        help_line("set-key <ip> [key]", "Remember a connect key for this session (no key forgets it)");
+        help_line("watch [dir]", "Show new messages as they arrive (Ctrl+C to stop)");
+        help_line("status", "Show current status");
+        help_line("keygen [dir]", "Generate new key pair");
+        help_line("whitelist", "List whitelisted keys");
+        help_line("whitelist <key> [label]", "Add key to whitelist");
+        help_line("whitelist remove <key>", "Remove key from whitelist");
+        help_line("help", "Show this help message");
+        help_line("exit / quit", "Exit interactive mode");
         println!();
     }
 
@@ -90,90 +173,254 @@ impl InteractiveSession {
             return Ok(());
         }
 
-        let port = args.get(0)
+        let port = args.first()
             .and_then(|s| s.parse::<u16>().ok())
             .unwrap_or(8080);
 
-        let keypair = self.get_or_create_keypair()?;
+        let keypair = self.get_or_create_keypair().await?;
         let whitelist_path = Path::new(&self.keys_dir).join("whitelist.txt");
+        // The REPL manages its own keys directory, so a missing whitelist there is
+        // first use rather than a mistyped path
+        Whitelist::load_or_create(&whitelist_path)?;
 
         let server = Server::new(port, &whitelist_path, keypair, "messages")?;
         let shutdown_tx = server.shutdown_channel();
         self.server_shutdown = Some(shutdown_tx);
+        self.server_drain = Some(server.drain_channel());
         self.listening_port = Some(port);
+        self.draining = false;
+        self.idle_monitor = self.auto_stop_idle.map(|idle| {
+            IdleMonitor::spawn(server.last_accept(), server.drain_channel(), idle)
+        });
 
         // Run server in background
         tokio::spawn(async move {
-            if let Err(e) = server.start().await {
-                Output::error(&format!("Server error: {}", e));
+            match server.start().await {
+                Ok(report) => Output::shutdown_report(&report),
+                Err(e) => Output::error(&format!("Server error: {}", e)),
             }
         });
 
         Ok(())
     }
 
+    /// Forget a server the idle monitor stopped; true if it did
+    fn check_auto_stop(&mut self) -> bool {
+        if !self.idle_monitor.as_ref().is_some_and(|monitor| monitor.stopped.load(Ordering::SeqCst)) {
+            return false;
+        }
+        self.idle_monitor = None;
+        self.server_shutdown = None;
+        self.server_drain = None;
+        self.listening_port = None;
+        self.draining = false;
+        true
+    }
+
     /// Stop the server
     fn stop_server(&mut self) -> Result<()> {
+        if let Some(monitor) = self.idle_monitor.take() {
+            monitor.task.abort();
+        }
         if let Some(shutdown) = self.server_shutdown.take() {
             let _ = shutdown.send(());
+            self.server_drain = None;
             self.listening_port = None;
+            self.draining = false;
             Output::info("Server stopped");
         }
         Ok(())
     }
 
+    /// Drain the server: refuse new connections, keep serving in-flight ones
+    fn drain_server(&mut self) {
+        match &self.server_drain {
+            Some(drain) if !self.draining => {
+                let _ = drain.send(());
+                self.draining = true;
+                Output::info("Server draining; use 'stop' to abandon remaining transfers");
+            }
+            Some(_) => Output::warning("Server is already draining"),
+            None => Output::warning("Server is not running"),
+        }
+    }
+
     /// Send a message
     async fn send_message(&mut self, args: &[&str]) -> Result<()> {
-        if args.len() < 2 {
-            Output::error("Usage: send <ip> <file> [save_as]");
-            return Ok(());
+        if self.connection.is_some() {
+            return self.send_over_connection(args).await;
         }
 
-        let ip = args[0];
-        let file = args[1];
-        let save_as = args.get(2).copied();
+        let send = match parse_send_args(args) {
+            Ok(send) => send,
+            Err(e) => {
+                Output::error(&e.to_string());
+                return Ok(());
+            }
+        };
 
-        // Prompt for connect key
-        let connect_key = prompt_password("Enter connect key: ")?;
+        // Prompt only when the key was neither given nor set for this peer
+        let connect_key = match self.connect_key_for(send.ip, send.connect_key) {
+            Some(key) => key,
+            None => prompt_password("Enter connect key: ")?,
+        };
 
-        let keypair = self.get_or_create_keypair()?;
-        let client = Client::new(ip, 8080, keypair);
+        let keypair = self.get_or_create_keypair().await?;
+        let client = Client::new(send.ip, 8080, keypair);
 
-        client.send_message(Path::new(file), &connect_key, save_as).await?;
+        client.send_message(Path::new(send.file), &connect_key, send.save_as, None).await?;
 
         Ok(())
     }
 
+    /// Send a file over the connection opened with `connect`
+    async fn send_over_connection(&mut self, args: &[&str]) -> Result<()> {
+        let (file, save_as) = match args {
+            [file] => (*file, None),
+            [file, save_as] => (*file, Some(*save_as)),
+            _ => {
+                Output::error("Usage while connected: send <file> [save_as] ('disconnect' to send elsewhere)");
+                return Ok(());
+            }
+        };
+        let Some(connection) = &self.connection else {
+            return Ok(());
+        };
+
+        let mut session = connection.session.lock().await;
+        let result = session.send_message(Path::new(file), save_as, None).await;
+        if session.is_closed() {
+            Output::warning(&format!("Connection to {} closed; the next send reconnects", session.server_addr()));
+        }
+        result.map(|_| ())
+    }
+
+    /// Open an authenticated connection that later sends reuse
+    async fn connect(&mut self, args: &[&str]) -> Result<()> {
+        if let Some(connection) = &self.connection {
+            Output::warning(&format!(
+                "Already connected to {}. Use 'disconnect' first.",
+                connection.session.lock().await.server_addr()
+            ));
+            return Ok(());
+        }
+        let connect = match parse_connect_args(args) {
+            Ok(connect) => connect,
+            Err(e) => {
+                Output::error(&e.to_string());
+                return Ok(());
+            }
+        };
+
+        let connect_key = match self.connect_key_for(connect.ip, connect.connect_key) {
+            Some(key) => key,
+            None => prompt_password("Enter connect key: ")?,
+        };
+
+        let keypair = self.get_or_create_keypair().await?;
+        let session = Client::new(connect.ip, connect.port, keypair)
+            .open_session(&connect_key)
+            .await?
+            .with_auto_reconnect(true);
+        Output::info(&format!(
+            "Connected to {}; 'send <file>' uses this connection until 'disconnect'",
+            session.server_addr()
+        ));
+        let session = Arc::new(Mutex::new(session));
+        let keepalive = connect.keepalive.map(|interval| ClientSession::keep_alive(&session, interval));
+        self.connection = Some(KeptConnection { session, keepalive });
+        Ok(())
+    }
+
+    /// Close the connection opened with `connect`, if any
+    async fn disconnect(&mut self) {
+        let Some(KeptConnection { session, keepalive }) = self.connection.take() else {
+            return;
+        };
+        if let Some(keepalive) = keepalive {
+            keepalive.abort();
+            let _ = keepalive.await;
+        }
+        // Close through the lock: a send still holding the session finishes first
+        let mut session = session.lock().await;
+        let (addr, sent) = (session.server_addr().to_string(), session.sent());
+        match session.close().await {
+            Ok(()) => Output::info(&format!("Disconnected from {} after {} transfer(s)", addr, sent)),
+            Err(e) => Output::warning(&e.to_string()),
+        }
+    }
+
+    /// Remember, or with no key forget, the connect key for a peer
+    fn set_connect_key(&mut self, args: &[&str]) {
+        match args {
+            [peer, key] => {
+                self.connect_keys.insert(peer.to_string(), key.to_string());
+                Output::info(&format!("Connect key set for {} (this session only)", peer));
+            }
+            [peer] => {
+                if self.connect_keys.remove(*peer).is_some() {
+                    Output::info(&format!("Connect key for {} forgotten", peer));
+                } else {
+                    Output::warning(&format!("No connect key set for {}", peer));
+                }
+            }
+            _ => Output::error("Usage: set-key <ip> [connect_key]"),
+        }
+    }
+
+    /// Key to use for `peer`: the one supplied on the command, else the one set for it
+    fn connect_key_for(&self, peer: &str, supplied: Option<&str>) -> Option<String> {
+        supplied
+            .map(|key| key.to_string())
+            .or_else(|| self.connect_keys.get(peer).cloned())
+    }
+
     /// Show current status
-    fn show_status(&self) {
+    async fn show_status(&self) {
         Output::header("Current Status");
 
         if let Some(port) = self.listening_port {
-            println!("  Server: Listening on port {}", port);
+            if self.draining {
+                println!("  Server: Draining on port {}", port);
+            } else {
+                println!("  Server: Listening on port {}", port);
+            }
         } else {
             println!("  Server: Not running");
         }
 
+        if let Some(connection) = &self.connection {
+            let session = connection.session.lock().await;
+            println!(
+                "  Connected to: {} ({} sent, {} reconnects)",
+                session.server_addr(),
+                session.sent(),
+                session.reconnects()
+            );
+        }
+
         if self.keypair.is_some() {
             println!("  Keys: Loaded from {}", self.keys_dir);
         } else {
             println!("  Keys: Not loaded");
         }
 
+        if !self.connect_keys.is_empty() {
+            let mut peers: Vec<_> = self.connect_keys.keys().map(String::as_str).collect();
+            peers.sort_unstable();
+            println!("  Connect keys set for: {}", peers.join(", "));
+        }
+
         println!();
     }
 
     /// Generate new keys
-    fn generate_keys(&mut self, args: &[&str]) -> Result<()> {
-        let output_dir = args.get(0).map(|s| s.to_string()).unwrap_or_else(|| self.keys_dir.clone());
-
-        let keypair = KeyPair::generate()?;
-        let private_path = Path::new(&output_dir).join("private_key.pem");
-        let public_path = Path::new(&output_dir).join("public_key.pem");
+    async fn generate_keys(&mut self, args: &[&str]) -> Result<()> {
+        let output_dir = args.first().map(|s| s.to_string()).unwrap_or_else(|| self.keys_dir.clone());
 
-        keypair.save(&private_path, &public_path)?;
+        let identity = NodeIdentity::regenerate_async(Path::new(&output_dir)).await?;
 
-        self.keypair = Some(keypair);
+        self.keypair = Some(identity.into_keypair());
         self.keys_dir = output_dir.clone();
 
         Output::keys_generated(&output_dir);
@@ -181,33 +428,52 @@ impl InteractiveSession {
         Ok(())
     }
 
+    /// Follow a messages directory until Ctrl+C, then return to the prompt
+    async fn watch_messages(&self, args: &[&str]) -> Result<()> {
+        let dir = args.first().copied().unwrap_or("messages");
+        MessageWatcher::new(Path::new(dir))?
+            .run(async {
+                tokio::signal::ctrl_c().await.ok();
+            })
+            .await
+    }
+
     /// Manage whitelist
     fn manage_whitelist(&mut self, args: &[&str]) -> Result<()> {
-        if args.is_empty() {
-            Output::error("Usage: whitelist <connect_key>");
-            return Ok(());
-        }
-
-        let connect_key = args[0];
         let whitelist_path = Path::new(&self.keys_dir).join("whitelist.txt");
 
-        let mut whitelist = Whitelist::load(&whitelist_path)?;
-        whitelist.add(connect_key)?;
-
-        Output::whitelist_updated(connect_key);
+        match args {
+            ["remove", connect_key] => {
+                let mut whitelist = Whitelist::load_or_create(&whitelist_path)?;
+                if whitelist.remove(connect_key)? {
+                    Output::whitelist_removed(connect_key);
+                } else {
+                    Output::warning(&format!("Connect key not in whitelist: {}", connect_key));
+                }
+            }
+            [] => {
+                let whitelist = Whitelist::load_or_create(&whitelist_path)?;
+                Output::whitelist_entries(&whitelist_path, whitelist.list());
+            }
+            ["remove", ..] => Output::error("Usage: whitelist remove <connect_key>"),
+            [connect_key, label @ ..] => {
+                let label = label.join(" ");
+                let mut whitelist = Whitelist::load_or_create(&whitelist_path)?;
+                whitelist.add(connect_key, Some(label.as_str()).filter(|label| !label.is_empty()))?;
+                Output::whitelist_updated(connect_key);
+            }
+        }
 
         Ok(())
     }
 
     /// Load existing keys
     fn load_keys(&mut self) -> Result<()> {
-        let private_path = Path::new(&self.keys_dir).join("private_key.pem");
-        let public_path = Path::new(&self.keys_dir).join("public_key.pem");
-
-        if private_path.exists() && public_path.exists() {
-            match KeyPair::load(&private_path, &public_path) {
-                Ok(kp) => {
-                    self.keypair = Some(kp);
+        let dir = Path::new(&self.keys_dir);
+        if NodeIdentity::exists(dir) {
+            match NodeIdentity::load_with(dir, || key_passphrase(dir)) {
+                Ok(identity) => {
+                    self.keypair = Some(identity.into_keypair());
                     Output::info(&format!("Loaded keys from {}", self.keys_dir));
                 }
                 Err(e) => {
@@ -220,31 +486,114 @@ impl InteractiveSession {
     }
 
     /// Get existing keypair or create new one
-    fn get_or_create_keypair(&mut self) -> Result<KeyPair> {
-        let private_path = Path::new(&self.keys_dir).join("private_key.pem");
-        let public_path = Path::new(&self.keys_dir).join("public_key.pem");
+    async fn get_or_create_keypair(&mut self) -> Result<KeyPair> {
+        if let Some(keypair) = &self.keypair {
+            return Ok(keypair.clone());
+        }
 
-        if self.keypair.is_some() {
-            KeyPair::load(&private_path, &public_path)
+        // Keys that failed to load at startup, e.g. after a mistyped passphrase
+        let dir = Path::new(&self.keys_dir);
+        if NodeIdentity::exists(dir) {
+            let keypair = NodeIdentity::load_with(dir, || key_passphrase(dir))?.into_keypair();
+            self.keypair = Some(keypair.clone());
+            return Ok(keypair);
+        }
+
+        let identity = NodeIdentity::load_or_create_async(dir).await?;
+        let keypair = identity.into_keypair();
+        self.keypair = Some(keypair.clone());
+
+        Output::keys_generated(&self.keys_dir);
+        Ok(keypair)
+    }
+}
+
+impl IdleMonitor {
+    /// Drain the server once it has gone `idle` without accepting a connection
+    ///
+    /// Draining rather than shutting down frees the port but lets a transfer
+    /// accepted just before the deadline finish.
+    fn spawn(last_accept: LastAccept, drain: broadcast::Sender<()>, idle: Duration) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stopped);
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(AUTO_STOP_CHECK.min(idle)).await;
+                let quiet = last_accept.elapsed();
+                if quiet >= idle {
+                    flag.store(true, Ordering::SeqCst);
+                    Output::info(&format!("No connection for {}s; stopping the server", quiet.as_secs()));
+                    let _ = drain.send(());
+                    return;
+                }
+            }
+        });
+        Self { stopped, task }
+    }
+}
+
+/// Split arguments into positionals and an optional `--ck KEY`
+fn split_connect_key<'a>(args: &[&'a str], usage: impl Fn() -> AppError) -> Result<(Vec<&'a str>, Option<&'a str>)> {
+    let mut positional = Vec::new();
+    let mut connect_key = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if *arg == "--ck" {
+            connect_key = Some(*iter.next().ok_or_else(&usage)?);
         } else {
-            let keypair = KeyPair::generate()?;
-            keypair.save(&private_path, &public_path)?;
+            positional.push(*arg);
+        }
+    }
+    Ok((positional, connect_key))
+}
 
-            let loaded = KeyPair::load(&private_path, &public_path)?;
-            // Note: We can't easily store it and return a clone, so we store a dummy
-            // or just reload it. RsaPrivateKey usually isn't Clone.
-            self.keypair = Some(KeyPair::load(&private_path, &public_path)?);
+/// Parse `send <ip> <file> [save_as] [--ck KEY]`
+fn parse_send_args<'a>(args: &[&'a str]) -> Result<SendArgs<'a>> {
+    let usage = || AppError::Cli("Usage: send <ip> <file> [save_as] [--ck connect_key]".to_string());
+    let (positional, connect_key) = split_connect_key(args, usage)?;
 
-            Output::keys_generated(&self.keys_dir);
-            Ok(loaded)
+    match positional[..] {
+        [ip, file] => Ok(SendArgs { ip, file, save_as: None, connect_key }),
+        [ip, file, save_as] => Ok(SendArgs { ip, file, save_as: Some(save_as), connect_key }),
+        _ => Err(usage()),
+    }
+}
+
+/// Parse `connect <ip> [port] [--ck KEY] [--keepalive SECS]`
+fn parse_connect_args<'a>(args: &[&'a str]) -> Result<ConnectArgs<'a>> {
+    let usage = || AppError::Cli("Usage: connect <ip> [port] [--ck connect_key] [--keepalive secs, 0 for off]".to_string());
+
+    let mut rest = Vec::new();
+    let mut keepalive = Some(DEFAULT_KEEPALIVE);
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if *arg == "--keepalive" {
+            let secs: u64 = iter.next().and_then(|secs| secs.parse().ok()).ok_or_else(usage)?;
+            keepalive = (secs > 0).then(|| Duration::from_secs(secs));
+        } else {
+            rest.push(*arg);
         }
     }
+    let (positional, connect_key) = split_connect_key(&rest, usage)?;
+
+    match positional[..] {
+        [ip] => Ok(ConnectArgs { ip, port: 8080, connect_key, keepalive }),
+        [ip, port] => {
+            let port = port.parse().map_err(|_| usage())?;
+            Ok(ConnectArgs { ip, port, connect_key, keepalive })
+        }
+        _ => Err(usage()),
+    }
+}
+
+/// Print a single row of the help table
+fn help_line(usage: &str, description: &str) {
+    println!("  {:<36} {}", usage, description);
 }
 
 /// Print the prompt
 fn print_prompt() {
-    use std::io::Write;
-    use colored::Colorize;
     print!("{} ", "finapp>".green().bold());
     io::stdout().flush().ok();
 }
@@ -270,3 +619,174 @@ fn prompt_password(prompt: &str) -> Result<String> {
 
     Ok(input.trim().to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_supplied_connect_key() {
+        let send = parse_send_args(&["10.0.0.5", "ledger.csv", "--ck", "secret", "eod"]).unwrap();
+        assert_eq!(
+            send,
+            SendArgs { ip: "10.0.0.5", file: "ledger.csv", save_as: Some("eod"), connect_key: Some("secret") }
+        );
+        assert!(parse_send_args(&["10.0.0.5", "ledger.csv", "--ck"]).is_err());
+        assert!(parse_send_args(&["10.0.0.5"]).is_err());
+
+        // A supplied key wins over one set for the peer
+        let mut session = InteractiveSession::new("keys");
+        session.set_connect_key(&["10.0.0.5", "cached"]);
+        assert_eq!(session.connect_key_for("10.0.0.5", Some("secret")).as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn test_cached_connect_key() {
+        let mut session = InteractiveSession::new("keys");
+        assert_eq!(session.connect_key_for("10.0.0.5", None), None);
+
+        session.set_connect_key(&["10.0.0.5", "cached"]);
+        assert_eq!(session.connect_key_for("10.0.0.5", None).as_deref(), Some("cached"));
+        assert_eq!(session.connect_key_for("10.0.0.6", None), None);
+
+        session.set_connect_key(&["10.0.0.5"]);
+        assert_eq!(session.connect_key_for("10.0.0.5", None), None);
+    }
+
+    #[tokio::test]
+    async fn test_sends_after_connect_share_one_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let server = Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap())
+            .unwrap()
+            .spawn()
+            .await
+            .unwrap();
+        let file = dir.path().join("ledger.csv");
+        std::fs::write(&file, "id,amount\n1,100\n").unwrap();
+        let file = file.to_str().unwrap();
+        let port = server.port().to_string();
+
+        let mut session = InteractiveSession::new(dir.path().join("keys").to_str().unwrap());
+        assert!(parse_connect_args(&["127.0.0.1", "not-a-port"]).is_err());
+        session.connect(&["127.0.0.1", &port, "--ck", "secret"]).await.unwrap();
+        session.send_message(&[file]).await.unwrap();
+        session.send_message(&[file, "eod"]).await.unwrap();
+        assert_eq!(session.connection.as_ref().unwrap().session.lock().await.sent(), 2);
+        session.disconnect().await;
+        assert!(session.connection.is_none());
+
+        let stored = std::fs::read_dir(&messages_dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "ftt"))
+            .count();
+        assert_eq!(stored, 2);
+
+        server.shutdown();
+        assert_eq!(server.wait().await.unwrap().connections, 1);
+    }
+
+    /// Forward connections to `target`; aborting the returned handles drops
+    /// every forwarded connection, as a NAT timeout would
+    async fn severable_proxy(target: u16) -> (u16, Arc<std::sync::Mutex<Vec<JoinHandle<()>>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let links = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let accepted = Arc::clone(&links);
+        tokio::spawn(async move {
+            while let Ok((mut inbound, _)) = listener.accept().await {
+                let link = tokio::spawn(async move {
+                    let mut outbound = tokio::net::TcpStream::connect(("127.0.0.1", target)).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+                accepted.lock().unwrap().push(link);
+            }
+        });
+        (port, links)
+    }
+
+    #[tokio::test]
+    async fn test_send_after_dropped_connection_reconnects() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let server = Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap())
+            .unwrap()
+            .spawn()
+            .await
+            .unwrap();
+        let (proxy_port, links) = severable_proxy(server.port()).await;
+        let file = dir.path().join("ledger.csv");
+        std::fs::write(&file, "id,amount\n1,100\n").unwrap();
+        let file = file.to_str().unwrap();
+
+        let mut session = InteractiveSession::new(dir.path().join("keys").to_str().unwrap());
+        session.connect(&["127.0.0.1", &proxy_port.to_string(), "--ck", "secret", "--keepalive", "1"]).await.unwrap();
+        session.send_message(&[file]).await.unwrap();
+        let kept = Arc::clone(&session.connection.as_ref().unwrap().session);
+        kept.lock().await.ping().await.unwrap();
+
+        // The keepalive notices the connection is gone without anything being sent
+        links.lock().unwrap().drain(..).for_each(|link| link.abort());
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !kept.lock().await.is_closed() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        crate::cli::output::start_capture();
+        session.send_message(&[file]).await.unwrap();
+        let lines = crate::cli::output::take_captured();
+        assert!(lines.iter().any(|line| line.contains("reconnecting")), "{:#?}", lines);
+        assert_eq!(kept.lock().await.reconnects(), 1);
+        assert_eq!(kept.lock().await.sent(), 2);
+
+        // Disconnect closes the session even while another handle to it is alive
+        crate::cli::output::start_capture();
+        session.disconnect().await;
+        let lines = crate::cli::output::take_captured();
+        assert!(lines.iter().any(|line| line.contains("Disconnected")), "{:#?}", lines);
+        assert!(kept.lock().await.is_closed());
+
+        let stored = std::fs::read_dir(&messages_dir)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|ext| ext == "ftt"))
+            .count();
+        assert_eq!(stored, 2);
+        server.shutdown();
+        assert_eq!(server.wait().await.unwrap().connections, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_server_auto_stops() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut session = InteractiveSession::new(dir.path().join("keys").to_str().unwrap())
+            .with_auto_stop_idle_secs(Some(60));
+        session.start_server(&["0"]).await.unwrap();
+
+        // Paused time only moves on once every task is waiting, so the server
+        // has taken its start time before the first second passes
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(!session.check_auto_stop());
+        assert!(session.listening_port.is_some());
+
+        tokio::time::sleep(AUTO_STOP_CHECK * 2).await;
+        assert!(session.check_auto_stop());
+        assert!(session.listening_port.is_none());
+        assert!(session.server_drain.is_none());
+    }
+
+    #[test]
+    fn test_connect_keepalive_option() {
+        let parsed = parse_connect_args(&["10.0.0.5", "--keepalive", "0", "--ck", "k"]).unwrap();
+        assert_eq!(parsed.keepalive, None);
+        assert_eq!(parsed.connect_key, Some("k"));
+        assert_eq!(parse_connect_args(&["10.0.0.5"]).unwrap().keepalive, Some(DEFAULT_KEEPALIVE));
+        assert!(parse_connect_args(&["10.0.0.5", "--keepalive"]).is_err());
+    }
+}
@@ -6,5 +6,13 @@ pub mod client;
 pub mod protocol;
 pub mod interactive;
 pub mod error;
+pub mod identity;
+pub mod selftest;
+pub mod clock;
+pub mod transport;
+pub mod compression;
+pub mod security;
+#[cfg(any(test, feature = "testing"))]
+pub mod test_support;
 
 pub use error::AppError;
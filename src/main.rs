@@ -1,42 +1,256 @@
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use clap::Parser;
-use stl_finapp::cli::{Args, Commands, Output};
+use stl_finapp::cli::{key_passphrase, new_key_passphrase, shorthand_send, Args, Commands, ProtocolCommand, WhitelistCommand, DumpFormat, Output};
 use stl_finapp::error::{AppError, Result};
-use stl_finapp::crypto::KeyPair;
-use stl_finapp::server::Server;
-use stl_finapp::client::Client;
+use stl_finapp::crypto::{encode_public_key, sign_detached, verify_detached, KeyPair, KeyPool, PublicKeyFormat, VerifyKey};
+use stl_finapp::auth::{Whitelist, KNOWN_HOSTS_FILE};
+use stl_finapp::identity::{KeyDirCheck, NodeIdentity, PUBLIC_KEY_FILE};
+use stl_finapp::server::{Server, ServerConfig, MemoryBudget, parse_bind_addr, MessageWatcher, Proof, QuotaUsage, TransferLog};
+use stl_finapp::server::storage::{read_message_to_writer, read_sidecar, reencrypt_dir, rewrap_dir, SIDECAR_EXTENSION};
+use stl_finapp::client::{Client, Manifest, expand_file_patterns};
 use stl_finapp::interactive::InteractiveSession;
+use stl_finapp::selftest::run_selftest;
+use stl_finapp::protocol::{describe, HandshakeOptions};
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
     let args = Args::parse();
+    args.color.apply();
+    let json_errors = args.json_errors;
 
+    if let Err(e) = run(args).await {
+        if json_errors {
+            eprintln!("{}", e.to_json());
+        } else {
+            Output::error(&e.to_string());
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    let key_pool = args.key_pool.as_deref().map(|dir| KeyPool::new(Path::new(dir)));
+    let result = run_command(args, key_pool.as_ref()).await;
+
+    // Replace any key taken from the pool before the runtime is dropped,
+    // so one-off commands do not use the pool up
+    if let Some(pool) = &key_pool {
+        pool.finish_refills().await;
+    }
+    result
+}
+
+async fn run_command(args: Args, key_pool: Option<&KeyPool>) -> Result<()> {
     // Set default keys directory
     let keys_dir = "keys";
-    let messages_dir = "messages";
 
     match args.command {
-        Some(Commands::Listen { port, whitelist, keys_dir }) => {
-            run_server(port, &whitelist, &keys_dir, messages_dir).await?;
+        Some(Commands::Listen {
+            bind,
+            port,
+            unix_socket,
+            whitelist,
+            create_whitelist,
+            whitelist_reload_secs,
+            transfer_log,
+            transfer_log_max_mb,
+            transfer_log_keep,
+            quota_usage,
+            keys_dir,
+            messages_dir,
+            temp_dir,
+            require_empty_messages_dir,
+            extract_dirs,
+            encrypt_at_rest,
+            allowed_ext,
+            decrypt_memory_budget,
+            max_message_size,
+            read_timeout_secs,
+            drain_timeout_secs,
+            max_connections,
+            connection_rate_limit,
+            detect_type,
+            on_type_mismatch,
+            on_collision,
+            security_level,
+            min_auth_method,
+            min_protocol_version,
+            min_crypto_suite,
+            compression,
+        }) => {
+            let bind_addr = parse_bind_addr(&bind)?;
+            let handshake = HandshakeOptions {
+                min_protocol_version,
+                min_cipher_suite: min_crypto_suite,
+                ..security_level.resolve(HandshakeOptions::default(), min_auth_method, compression)?
+            };
+            let config = ServerConfig {
+                messages_dir,
+                temp_dir,
+                require_empty_messages_dir,
+                extract_dirs,
+                encrypt_at_rest,
+                allowed_extensions: allowed_ext,
+                on_collision,
+                detect_type,
+                on_type_mismatch,
+                handshake,
+                quota_usage: Arc::new(QuotaUsage::load(Path::new(&quota_usage))?),
+                memory_budget: decrypt_memory_budget.map(|bytes| Arc::new(MemoryBudget::new(bytes))),
+                max_message_bytes: Some(max_message_size).filter(|bytes| *bytes > 0),
+                read_timeout: Some(Duration::from_secs(read_timeout_secs)).filter(|t| !t.is_zero()),
+                ..ServerConfig::default()
+            };
+            let whitelist_reload = whitelist_reload_secs.map(Duration::from_secs);
+            let transfer_log = match transfer_log {
+                Some(path) => Some(
+                    TransferLog::open(Path::new(&path))?
+                        .with_max_bytes(transfer_log_max_mb.map(|mb| mb * 1024 * 1024))
+                        .with_keep(transfer_log_keep),
+                ),
+                None => None,
+            };
+            ensure_whitelist(Path::new(&whitelist), create_whitelist)?;
+            let server = Server::new(port, Path::new(&whitelist), load_or_generate_keypair(&keys_dir, key_pool).await?, &config.messages_dir)?
+                .with_bind_addr(bind_addr)
+                .with_config(config)
+                .with_whitelist_reload(whitelist_reload)
+                .with_drain_timeout(Some(Duration::from_secs(drain_timeout_secs)))
+                .with_max_connections(max_connections.map(|max| usize::try_from(max).unwrap_or(usize::MAX)))
+                .with_connection_rate_limit(connection_rate_limit)
+                .with_transfer_log(transfer_log);
+            run_server(server, unix_socket.as_deref(), args.json_errors).await?;
+        }
+        Some(Commands::Send {
+            ip,
+            port,
+            file,
+            dir,
+            each_file,
+            manifest,
+            pipeline,
+            verify_delivery,
+            ack_timeout_secs,
+            connect_key,
+            save_as,
+            note,
+            security_level,
+            min_auth_method,
+            min_protocol_version,
+            min_crypto_suite,
+            compress,
+            chunk_size,
+            identity,
+            known_hosts,
+            keys_dir,
+        }) => {
+            // Nothing is offered for compression unless asked for
+            let base = HandshakeOptions { compression: Vec::new(), ..HandshakeOptions::default() };
+            let handshake = security_level.resolve(base, min_auth_method, compress)?;
+            let client = build_client(&ip, port, &keys_dir, key_pool, identity.as_deref())
+                .await?
+                .with_verify_delivery(verify_delivery)
+                .with_ack_timeout(Duration::from_secs(ack_timeout_secs))
+                .with_chunk_size(chunk_size.map(|size| usize::try_from(size).unwrap_or(usize::MAX)))
+                .with_known_hosts(Some(&known_hosts_path(known_hosts.as_deref(), &keys_dir)))
+                .with_require_pinned_host(handshake.require_pinned_host)
+                .with_min_auth_method(handshake.min_auth_method)
+                .with_min_protocol_version(min_protocol_version)
+                .with_min_cipher_suite(min_crypto_suite)
+                .with_cipher_suites(&handshake.cipher_suites)
+                .with_compression(&handshake.compression)
+                .with_exact_public_exponent(handshake.exact_public_exponent);
+            if let Some(manifest) = manifest {
+                run_client_manifest(&client, &manifest, &connect_key).await?;
+            } else if let Some(dir) = dir {
+                run_client_dir(&client, &dir, &connect_key, save_as.as_deref(), note.as_deref(), each_file).await?;
+            } else {
+                run_client_files(&client, &file, &connect_key, save_as.as_deref(), note.as_deref(), pipeline).await?;
+            }
+        }
+        Some(Commands::Keygen { output, encrypt, count, pool_dir }) => match (count, pool_dir) {
+            (Some(count), Some(pool_dir)) => fill_key_pool(&pool_dir, count).await?,
+            _ => generate_keys(&output, encrypt).await?,
+        },
+        Some(Commands::Whitelist { action: Some(WhitelistCommand::Restore { file }), .. }) => {
+            restore_whitelist(&file)?;
+        }
+        Some(Commands::Whitelist { action: Some(WhitelistCommand::List { file }), .. }) => {
+            let whitelist = Whitelist::load(Path::new(&file))?;
+            Output::whitelist_entries(whitelist.path(), whitelist.list());
+        }
+        Some(Commands::Whitelist { action: Some(WhitelistCommand::Remove { connect_key, file }), .. }) => {
+            remove_from_whitelist(&connect_key, &file)?;
         }
-        Some(Commands::Send { ip, port, file, connect_key, save_as, keys_dir }) => {
-            run_client(&ip, port, &file, &connect_key, save_as.as_deref(), &keys_dir).await?;
+        Some(Commands::Whitelist { action: Some(WhitelistCommand::Lint { file }), .. }) => {
+            lint_whitelist(&file)?;
         }
-        Some(Commands::Keygen { output }) => {
-            generate_keys(&output)?;
+        Some(Commands::Whitelist { connect_key, file, hashed, label, action: None }) => {
+            let connect_key = connect_key.expect("clap requires --ck without a subcommand");
+            add_to_whitelist(&connect_key, &file, hashed, label.as_deref())?;
+        }
+        Some(Commands::Read { file, keys_dir, output }) => {
+            read_stored_message(&file, &keys_dir, output.as_deref())?;
+        }
+        Some(Commands::Header { file }) => {
+            print_header(Path::new(&file))?;
+        }
+        Some(Commands::Prove { file, out, verify, server_key, keys_dir }) => {
+            match (verify, file) {
+                (Some(proof), _) => verify_proof(&proof, server_key.as_deref())?,
+                (None, Some(file)) => export_proof(&file, &out, &keys_dir)?,
+                (None, None) => unreachable!("clap requires a file unless --verify is given"),
+            }
         }
-        Some(Commands::Whitelist { connect_key, file }) => {
-            add_to_whitelist(&connect_key, &file)?;
+        Some(Commands::Sign { file, out, keys_dir }) => {
+            let out = out.unwrap_or_else(|| format!("{}.sig", file));
+            sign_file(&file, &out, &keys_dir)?;
+        }
+        Some(Commands::ExportKey { format, out, keys_dir }) => {
+            export_public_key(&keys_dir, format, &out)?;
+        }
+        Some(Commands::VerifySig { file, sig, pubkey }) => {
+            verify_file_signature(&file, &sig, &pubkey)?;
+        }
+        Some(Commands::Reencrypt { old_key, new_key, messages_dir }) => {
+            reencrypt_messages(&old_key, &new_key, &messages_dir)?;
+        }
+        Some(Commands::RekeyAtRest { old_key, new_key, messages_dir }) => {
+            rekey_messages(&old_key, &new_key, &messages_dir)?;
+        }
+        Some(Commands::KeyMatch { first, second }) => {
+            match_keys(Path::new(&first), Path::new(&second))?;
+        }
+        Some(Commands::Selftest) => {
+            run_selftest().await?;
+        }
+        Some(Commands::Watch { messages_dir }) => {
+            MessageWatcher::new(Path::new(&messages_dir))?
+                .run(async {
+                    shutdown_signal().await;
+                })
+                .await?;
+        }
+        Some(Commands::Protocol { action: ProtocolCommand::Dump { format } }) => {
+            let description = describe()?;
+            match format {
+                DumpFormat::Json => println!("{}", description.to_json()?),
+                DumpFormat::Text => print!("{}", description.to_text()),
+            }
         }
         None => {
             if args.interactive {
-                let mut session = InteractiveSession::new(keys_dir);
+                let mut session = InteractiveSession::new(keys_dir).with_auto_stop_idle_secs(args.auto_stop_idle_secs);
                 session.run().await?;
-            } else if let (Some(ip), Some(file), Some(ck)) =
-                (args.ip, args.file, args.connect_key) {
-                run_client(&ip, args.port, &file, &ck, args.save_as.as_deref(), keys_dir).await?;
+            } else if let Some((ip, file, ck)) =
+                shorthand_send(args.ip.as_deref(), args.file.as_deref(), args.connect_key.as_deref())? {
+                let client = build_client(ip, args.port, keys_dir, key_pool, None).await?;
+                run_client(&client, file, ck, args.save_as.as_deref(), args.note.as_deref()).await?;
             } else {
-                // Show help if no valid combination and not interactive
+                // Nothing asked for: show help
                 use clap::CommandFactory;
                 let mut cmd = Args::command();
                 cmd.print_help().unwrap();
@@ -48,71 +262,385 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn run_server(port: u16, whitelist_path: &str, keys_dir: &str, messages_dir: &str) -> Result<()> {
-    let keypair = load_or_generate_keypair(keys_dir)?;
-    let server = Server::new(port, Path::new(whitelist_path), keypair, messages_dir)?;
-
-    // Handle Ctrl+C gracefully
+async fn run_server(server: Server, unix_socket: Option<&str>, json: bool) -> Result<()> {
+    // Handle Ctrl+C and SIGTERM (systemd, Kubernetes) gracefully: the first
+    // signal drains in-flight transfers, a second one stops at once
+    let drain_tx = server.drain_channel();
     let shutdown_tx = server.shutdown_channel();
+    let signal = shutdown_signal();
     tokio::spawn(async move {
-        tokio::signal::ctrl_c().await.ok();
+        let signal = signal.await;
+        Output::info(&format!("Received {}, finishing transfers in progress (again to stop now)", signal));
+        let _ = drain_tx.send(());
+        let signal = shutdown_signal().await;
+        Output::info(&format!("Received {}", signal));
         let _ = shutdown_tx.send(());
     });
 
-    server.start().await?;
+    let report = match unix_socket {
+        #[cfg(unix)]
+        Some(path) => server.start_unix(Path::new(path)).await?,
+        #[cfg(not(unix))]
+        Some(_) => return Err(AppError::Server("Unix domain sockets are not supported on this platform".to_string())),
+        None => server.start().await?,
+    };
+    if json {
+        println!("{}", report.to_json()?);
+    } else {
+        Output::shutdown_report(&report);
+    }
     Ok(())
 }
 
-async fn run_client(
+/// Wait for Ctrl+C or, on Unix, SIGTERM, resolving to the name of the signal
+///
+/// The SIGTERM handler is installed when this is called rather than when the
+/// future is first polled, so a signal arriving in between is not lost.
+fn shutdown_signal() -> impl std::future::Future<Output = &'static str> {
+    #[cfg(unix)]
+    let sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .map_err(|e| Output::warning(&format!("Cannot handle SIGTERM, only Ctrl+C stops gracefully: {}", e)))
+        .ok();
+    async move {
+        #[cfg(unix)]
+        if let Some(mut sigterm) = sigterm {
+            return tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = sigterm.recv() => "SIGTERM",
+            };
+        }
+        tokio::signal::ctrl_c().await.ok();
+        "SIGINT"
+    }
+}
+
+/// Create a missing whitelist only when asked to, so a mistyped path is caught
+fn ensure_whitelist(path: &Path, create: bool) -> Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+    if !create {
+        return Err(AppError::Config(format!(
+            "Whitelist {} does not exist; check --whitelist, or pass --create-whitelist to start with an empty one",
+            path.display()
+        )));
+    }
+    Whitelist::create(path)?;
+    Output::info(&format!("Created empty whitelist {}", path.display()));
+    Ok(())
+}
+
+/// Known hosts file to use, defaulting to one inside the keys directory
+fn known_hosts_path(known_hosts: Option<&str>, keys_dir: &str) -> PathBuf {
+    known_hosts.map_or_else(|| Path::new(keys_dir).join(KNOWN_HOSTS_FILE), PathBuf::from)
+}
+
+async fn build_client(
     ip: &str,
     port: u16,
+    keys_dir: &str,
+    key_pool: Option<&KeyPool>,
+    identity: Option<&str>,
+) -> Result<Client> {
+    let keypair = load_or_generate_keypair(keys_dir, key_pool).await?;
+    Ok(Client::new(ip, port, keypair).with_identity(identity))
+}
+
+async fn run_client(
+    client: &Client,
     file: &str,
     connect_key: &str,
     save_as: Option<&str>,
-    keys_dir: &str,
+    note: Option<&str>,
 ) -> Result<()> {
-    let keypair = load_or_generate_keypair(keys_dir)?;
-    let client = Client::new(ip, port, keypair);
+    client.send_message(Path::new(file), connect_key, save_as, note).await?;
+    Ok(())
+}
+
+async fn run_client_dir(
+    client: &Client,
+    dir: &str,
+    connect_key: &str,
+    save_as: Option<&str>,
+    note: Option<&str>,
+    each_file: bool,
+) -> Result<()> {
+    if each_file {
+        let outcomes = client.send_directory(Path::new(dir), connect_key, note).await?;
+        return report_outcomes(&outcomes);
+    }
+    client.send_directory_archive(Path::new(dir), connect_key, save_as, note).await?;
+    Ok(())
+}
+
+async fn run_client_files(
+    client: &Client,
+    patterns: &[String],
+    connect_key: &str,
+    save_as: Option<&str>,
+    note: Option<&str>,
+    pipeline: bool,
+) -> Result<()> {
+    let files = expand_file_patterns(patterns)?;
+
+    // A single file keeps the original behaviour, including --save-as
+    if let [file] = files.as_slice() {
+        client.send_message(file, connect_key, save_as, note).await?;
+        return Ok(());
+    }
+    if save_as.is_some() {
+        return Err(AppError::Cli("--save-as cannot be used when sending several files".to_string()));
+    }
+
+    let outcomes = if pipeline {
+        client.send_files_pipelined(&files, connect_key, note).await?
+    } else {
+        client.send_files(&files, connect_key, note).await
+    };
+    report_outcomes(&outcomes)
+}
+
+async fn run_client_manifest(client: &Client, manifest: &str, connect_key: &str) -> Result<()> {
+    let manifest = Manifest::load(Path::new(manifest))?;
+    let outcomes = client.send_manifest(&manifest, connect_key).await?;
+    report_outcomes(&outcomes)
+}
 
-    client.send_message(Path::new(file), connect_key, save_as).await?;
+/// Print one line per file and fail if any of them was not delivered
+fn report_outcomes(outcomes: &[(PathBuf, Result<String>)]) -> Result<()> {
+    let failed = outcomes.iter().filter(|(_, result)| result.is_err()).count();
+    for (file, result) in outcomes {
+        match result {
+            Ok(saved_as) => Output::success(&format!("{} -> {}", file.display(), saved_as)),
+            Err(e) => Output::error(&format!("{}: {}", file.display(), e)),
+        }
+    }
+
+    if failed > 0 {
+        return Err(AppError::Client(format!("{} of {} files failed to send", failed, outcomes.len())));
+    }
+    Output::success(&format!("Sent {} files", outcomes.len()));
+    Ok(())
+}
+
+fn read_stored_message(file: &str, keys_dir: &str, output: Option<&str>) -> Result<()> {
+    let identity = load_identity(Path::new(keys_dir))?;
+
+    match output {
+        Some(path) => {
+            let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+            let result = read_message_to_writer(Path::new(file), identity.keypair(), &mut out)
+                .and_then(|_| Ok(out.flush()?));
+            drop(out);
+            // Do not leave a half-written or unverified copy behind
+            if result.is_err() {
+                let _ = std::fs::remove_file(path);
+            }
+            result?;
+        }
+        None => {
+            read_message_to_writer(Path::new(file), identity.keypair(), &mut std::io::stdout().lock())?;
+        }
+    }
+    Ok(())
+}
+
+/// Print the sidecar of a stored message; works on encrypted-at-rest messages without the key
+fn print_header(file: &Path) -> Result<()> {
+    let sidecar_suffix = format!(".{}", SIDECAR_EXTENSION);
+    let message = match file.to_str().and_then(|name| name.strip_suffix(&sidecar_suffix)) {
+        Some(message) => Path::new(message),
+        None => file,
+    };
+    let meta = read_sidecar(message)?;
+    let json = serde_json::to_string_pretty(&meta)
+        .map_err(|e| AppError::Serialization(format!("Failed to serialize metadata: {}", e)))?;
+    println!("{}", json);
+    Ok(())
+}
+
+fn export_proof(file: &str, out: &str, keys_dir: &str) -> Result<()> {
+    let identity = load_identity(Path::new(keys_dir))?;
+    Proof::create(Path::new(file), identity.keypair())?.save(Path::new(out))?;
+    Output::success(&format!("Proof for {} written to {}", file, out));
+    Ok(())
+}
+
+fn verify_proof(proof: &str, server_key: Option<&str>) -> Result<()> {
+    let trusted = server_key.map(|path| KeyPair::load_public(Path::new(path))).transpose()?;
+    let verified = Proof::load(Path::new(proof))?.verify(trusted.as_ref())?;
+    Output::success(&format!("Proof valid for {}", verified.saved_as));
+    Output::info(&format!("Signed by server key {}", verified.server_fingerprint));
+    if let Some(sender) = &verified.sender_fingerprint {
+        Output::info(&format!("Sent with key {}", sender));
+    }
+    Ok(())
+}
+
+fn sign_file(file: &str, out: &str, keys_dir: &str) -> Result<()> {
+    let identity = load_identity(Path::new(keys_dir))?;
+    let signature = sign_detached(&identity.keypair().private_key, &read_file(file)?)?;
+    write_file(out, &signature)?;
+    Output::success(&format!("Signature for {} written to {}", file, out));
+    Ok(())
+}
+
+fn export_public_key(keys_dir: &str, format: PublicKeyFormat, out: &str) -> Result<()> {
+    let public_key = KeyPair::load_public(&Path::new(keys_dir).join(PUBLIC_KEY_FILE))?;
+    write_file(out, &encode_public_key(&public_key, format)?)?;
+    Output::success(&format!("Public key written to {}", out));
+    Ok(())
+}
+
+fn verify_file_signature(file: &str, sig: &str, pubkey: &str) -> Result<()> {
+    let pem = String::from_utf8(read_file(pubkey)?)
+        .map_err(|_| AppError::Cli(format!("{} is not a PEM public key", pubkey)))?;
+    let key = VerifyKey::from_pem(&pem)?;
+    verify_detached(&key, &read_file(file)?, &read_file(sig)?)?;
+    Output::success(&format!("Signature valid for {}", file));
+    Ok(())
+}
+
+/// Read a file named on the command line, saying which one failed
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| AppError::Cli(format!("Failed to read {}: {}", path, e)))
+}
+
+/// Write a file named on the command line, saying which one failed
+fn write_file(path: &str, contents: &[u8]) -> Result<()> {
+    std::fs::write(path, contents).map_err(|e| AppError::Cli(format!("Failed to write {}: {}", path, e)))
+}
+
+fn reencrypt_messages(old_keys_dir: &str, new_keys_dir: &str, messages_dir: &str) -> Result<()> {
+    let old = load_identity(Path::new(old_keys_dir))?;
+    let new_key = KeyPair::load_public(&Path::new(new_keys_dir).join(PUBLIC_KEY_FILE))?;
+
+    let report = reencrypt_dir(Path::new(messages_dir), old.keypair(), &new_key)?;
+    Output::success(&format!(
+        "Re-encrypted {} messages ({} plaintext skipped)",
+        report.reencrypted, report.skipped
+    ));
     Ok(())
 }
 
-fn generate_keys(output_dir: &str) -> Result<()> {
-    std::fs::create_dir_all(output_dir)
-        .map_err(|e| AppError::Io(e))?;
+fn rekey_messages(old_keys_dir: &str, new_keys_dir: &str, messages_dir: &str) -> Result<()> {
+    let old = load_identity(Path::new(old_keys_dir))?;
+    let new_key = KeyPair::load_public(&Path::new(new_keys_dir).join(PUBLIC_KEY_FILE))?;
 
-    let keypair = KeyPair::generate()?;
-    let private_path = Path::new(output_dir).join("private_key.pem");
-    let public_path = Path::new(output_dir).join("public_key.pem");
+    let report = rewrap_dir(Path::new(messages_dir), old.keypair(), &new_key)?;
+    Output::success(&format!(
+        "Re-wrapped keys of {} messages ({} plaintext skipped)",
+        report.reencrypted, report.skipped
+    ));
+    Ok(())
+}
 
-    keypair.save(&private_path, &public_path)?;
+async fn fill_key_pool(pool_dir: &str, count: usize) -> Result<()> {
+    let pool = KeyPool::new(Path::new(pool_dir));
+    pool.fill(count).await?;
+    Output::success(&format!("Added {} keys to {} ({} ready)", count, pool_dir, pool.len()?));
+    Ok(())
+}
 
+async fn generate_keys(output_dir: &str, encrypt: bool) -> Result<()> {
+    if encrypt {
+        let passphrase = new_key_passphrase()?;
+        NodeIdentity::regenerate_encrypted_async(Path::new(output_dir), &passphrase).await?;
+    } else {
+        NodeIdentity::regenerate_async(Path::new(output_dir)).await?;
+    }
     Output::keys_generated(output_dir);
     Ok(())
 }
 
-fn add_to_whitelist(connect_key: &str, whitelist_path: &str) -> Result<()> {
-    use stl_finapp::auth::Whitelist;
-    let mut whitelist = Whitelist::load(Path::new(whitelist_path))?;
-    whitelist.add(connect_key)?;
-    Output::whitelist_updated(connect_key);
+/// Load the identity in `dir`, asking for the passphrase if its private key is encrypted
+fn load_identity(dir: &Path) -> Result<NodeIdentity> {
+    NodeIdentity::load_with(dir, || key_passphrase(dir))
+}
+
+/// Print both fingerprints, failing unless the directories hold one consistent identity
+fn match_keys(first: &Path, second: &Path) -> Result<()> {
+    let checks = [KeyDirCheck::inspect(first)?, KeyDirCheck::inspect(second)?];
+    for check in &checks {
+        Output::info(&format!("{}: {}", check.dir.display(), check.fingerprint));
+    }
+
+    let mismatched: Vec<_> = checks
+        .iter()
+        .filter(|check| check.private_key_matches == Some(false))
+        .map(|check| check.dir.display().to_string())
+        .collect();
+    if !mismatched.is_empty() {
+        return Err(AppError::Crypto(format!(
+            "Private key does not belong to the public key in {}",
+            mismatched.join(", ")
+        )));
+    }
+    if !checks[0].same_identity(&checks[1]) {
+        return Err(AppError::Crypto("The directories hold different identities".to_string()));
+    }
+    Output::success("Same identity");
     Ok(())
 }
 
-fn load_or_generate_keypair(keys_dir: &str) -> Result<KeyPair> {
-    let private_path = Path::new(keys_dir).join("private_key.pem");
-    let public_path = Path::new(keys_dir).join("public_key.pem");
+fn add_to_whitelist(connect_key: &str, whitelist_path: &str, hashed: bool, label: Option<&str>) -> Result<()> {
+    let mut whitelist = Whitelist::load_or_create(Path::new(whitelist_path))?;
+    if hashed {
+        let line = whitelist.add_hashed(connect_key, label)?;
+        Output::whitelist_updated(&line);
+    } else {
+        whitelist.add(connect_key, label)?;
+        Output::whitelist_updated(connect_key);
+    }
+    Ok(())
+}
 
-    if private_path.exists() && public_path.exists() {
-        KeyPair::load(&private_path, &public_path)
+fn remove_from_whitelist(connect_key: &str, whitelist_path: &str) -> Result<()> {
+    let mut whitelist = Whitelist::load(Path::new(whitelist_path))?;
+    if whitelist.remove(connect_key)? {
+        Output::whitelist_removed(connect_key);
     } else {
-        Output::info("Keys not found, generating new key pair...");
-        std::fs::create_dir_all(keys_dir)
-            .map_err(|e| AppError::Io(e))?;
-        let keypair = KeyPair::generate()?;
-        keypair.save(&private_path, &public_path)?;
-        // Reload to be sure
-        KeyPair::load(&private_path, &public_path)
+        Output::warning(&format!("Connect key not in whitelist {}: {}", whitelist_path, connect_key));
     }
+    Ok(())
+}
+
+fn restore_whitelist(whitelist_path: &str) -> Result<()> {
+    let path = Path::new(whitelist_path);
+    let whitelist = Whitelist::restore(path)?;
+    Output::success(&format!(
+        "Restored {} from backup ({} keys); the replaced version is now the backup",
+        path.display(),
+        whitelist.keys().count()
+    ));
+    Ok(())
+}
+
+fn lint_whitelist(whitelist_path: &str) -> Result<()> {
+    let path = Path::new(whitelist_path);
+    let issues = Whitelist::lint(path)?;
+    if issues.is_empty() {
+        Output::success(&format!("{}: no problems found", path.display()));
+        return Ok(());
+    }
+    for issue in &issues {
+        Output::error(&format!("{}:{}", path.display(), issue));
+    }
+    Err(AppError::Config(format!("{} problem(s) in whitelist {}", issues.len(), path.display())))
+}
+
+async fn load_or_generate_keypair(keys_dir: &str, key_pool: Option<&KeyPool>) -> Result<KeyPair> {
+    let dir = Path::new(keys_dir);
+    let identity = match key_pool {
+        Some(pool) if !NodeIdentity::exists(dir) => {
+            Output::info(&format!("Keys not found, taking a key pair from {}...", pool.dir().display()));
+            NodeIdentity::load_or_create_from_pool(dir, pool).await?
+        }
+        _ if NodeIdentity::exists(dir) => load_identity(dir)?,
+        _ => {
+            Output::info("Keys not found, generating new key pair...");
+            NodeIdentity::load_or_create_async(dir).await?
+        }
+    };
+    Ok(identity.into_keypair())
 }
@@ -0,0 +1,125 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+use rsa::RsaPublicKey;
+use crate::error::Result;
+use crate::crypto::{CipherSuite, KeyPair};
+use crate::auth::Authorizer;
+use crate::protocol::message::Message;
+use crate::protocol::handshake::{Handshake, HandshakeOptions, HandshakeOutcome, send_message, receive_message};
+
+/// A stream that has completed the finapp handshake
+///
+/// Carries the negotiated peer key and cipher suite alongside the stream so
+/// callers can run their own message exchange over the authenticated
+/// connection. The file transfer protocol is built on the same type.
+pub struct AuthenticatedChannel<S> {
+    stream: S,
+    outcome: HandshakeOutcome,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AuthenticatedChannel<S> {
+    /// Run the client side of the handshake on `stream`
+    pub async fn connect(
+        mut stream: S,
+        connect_key: &str,
+        keypair: &KeyPair,
+        options: &HandshakeOptions,
+    ) -> Result<Self> {
+        let outcome = Handshake::client_side(&mut stream, connect_key, keypair, options).await?;
+        Ok(Self { stream, outcome })
+    }
+
+    /// Run the server side of the handshake on `stream`
+    pub async fn accept(
+        mut stream: S,
+        authorizer: &dyn Authorizer,
+        keypair: &KeyPair,
+        options: &HandshakeOptions,
+    ) -> Result<Self> {
+        let outcome = Handshake::server_side(&mut stream, authorizer, keypair, options).await?;
+        Ok(Self { stream, outcome })
+    }
+
+    /// The peer's RSA public key
+    pub fn peer_public_key(&self) -> &RsaPublicKey {
+        &self.outcome.peer_public_key
+    }
+
+    /// Cipher suite both sides agreed on
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.outcome.cipher_suite
+    }
+
+    /// Everything the handshake negotiated
+    pub fn outcome(&self) -> &HandshakeOutcome {
+        &self.outcome
+    }
+
+    /// Send a framed message to the peer
+    pub async fn send(&mut self, msg: &Message) -> Result<()> {
+        send_message(&mut self.stream, msg).await
+    }
+
+    /// Receive the next framed message from the peer
+    pub async fn receive(&mut self) -> Result<Message> {
+        receive_message(&mut self.stream).await
+    }
+
+    /// Direct access to the underlying stream
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Split into the stream and the handshake outcome
+    pub fn into_parts(self) -> (S, HandshakeOutcome) {
+        (self.stream, self.outcome)
+    }
+}
+
+/// Authenticate to a finapp server over `stream` with the default handshake options
+///
+/// The returned channel is ready for custom message exchange.
+pub async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    connect_key: &str,
+    keypair: &KeyPair,
+) -> Result<AuthenticatedChannel<S>> {
+    AuthenticatedChannel::connect(stream, connect_key, keypair, &HandshakeOptions::default()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use crate::protocol::MessageType;
+    use crate::auth::{Whitelist, WhitelistAuthorizer};
+
+    #[tokio::test]
+    async fn test_custom_message_over_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add("secret", None).unwrap();
+        let server_keys = KeyPair::generate().unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+        let server_public = server_keys.public_key.clone();
+        let client_public = client_keys.public_key.clone();
+
+        let (client_stream, server_stream) = duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut channel = AuthenticatedChannel::accept(server_stream, &WhitelistAuthorizer::new(whitelist), &server_keys, &HandshakeOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(channel.peer_public_key(), &client_public);
+            let ping = channel.receive().await.unwrap();
+            channel.send(&Message::new(MessageType::Custom, [b"pong:".as_slice(), &ping.payload].concat())).await.unwrap();
+        });
+
+        let mut channel = authenticate(client_stream, "secret", &client_keys).await.unwrap();
+        assert_eq!(channel.peer_public_key(), &server_public);
+        channel.send(&Message::new(MessageType::Custom, b"ping".to_vec())).await.unwrap();
+        let reply = channel.receive().await.unwrap();
+        reply.expect_type(MessageType::Custom).unwrap();
+        assert_eq!(reply.payload, b"pong:ping");
+
+        server.await.unwrap();
+    }
+}
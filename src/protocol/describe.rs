@@ -0,0 +1,247 @@
+use serde::{Serialize, Deserialize};
+use serde::de::{self, Deserializer, Visitor};
+use crate::error::{AppError, Result};
+use crate::crypto::StreamKey;
+use crate::protocol::handshake::{PROTOCOL_MAGIC, PROTOCOL_VERSION};
+use crate::protocol::frame::FrameCodec;
+use crate::protocol::message::{
+    AuthAccepted, AuthChallenge, AuthResponse, Disconnect, DisconnectReason, MessageChunk, MessageHeader, MessageType, TransferAck, VerifyRequest, VerifyResponse,
+};
+
+/// Machine-readable description of the wire protocol
+///
+/// Built from the protocol types themselves, so it follows them as they
+/// change rather than drifting like hand-written docs.
+#[derive(Debug, Serialize)]
+pub struct ProtocolDescription {
+    /// `PROTOCOL_VERSION`
+    pub version: u32,
+    /// Bytes the client sends before anything else
+    pub magic: String,
+    /// How messages are delimited on the stream
+    pub framing: Framing,
+    /// Message types with the tag they are encoded as
+    pub message_types: Vec<MessageTypeInfo>,
+    /// Payload structures, fields in encoding order
+    pub structures: Vec<StructureInfo>,
+}
+
+/// Framing rules shared by every message
+#[derive(Debug, Serialize)]
+pub struct Framing {
+    /// Serialization format of control messages and their payloads
+    pub encoding: &'static str,
+    /// Serialization format of `MessageHeader`, which differs so it can grow
+    pub header_encoding: &'static str,
+    /// Byte order of the length prefixes
+    pub length_byte_order: &'static str,
+    /// Length prefix of a control message
+    pub control_length_prefix_bytes: usize,
+    /// Length prefix of raw transfer data
+    pub data_length_prefix_bytes: usize,
+}
+
+/// One `MessageType` variant
+#[derive(Debug, Serialize)]
+pub struct MessageTypeInfo {
+    /// Variant name
+    pub name: String,
+    /// Enum tag as encoded by bincode
+    pub tag: u32,
+}
+
+/// Field layout of one payload structure
+#[derive(Debug, Serialize)]
+pub struct StructureInfo {
+    /// Type name
+    pub name: &'static str,
+    /// Fields in the order they are encoded
+    pub fields: Vec<FieldInfo>,
+}
+
+/// One field of a payload structure
+#[derive(Debug, Serialize)]
+pub struct FieldInfo {
+    /// Field name
+    pub name: &'static str,
+    /// Shape of the field's value: string, integer, bytes, list, struct or bool
+    pub kind: &'static str,
+}
+
+/// Describe the protocol spoken by this build
+pub fn describe() -> Result<ProtocolDescription> {
+    let message_types = MessageType::ALL
+        .iter()
+        .map(|t| Ok(MessageTypeInfo { name: t.to_string(), tag: wire_tag(t)? }))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Samples have every optional field filled in so each one shows a kind
+    let response = AuthResponse::new(String::new(), Vec::new()).with_identity(Some("identity"));
+    let stream_key = StreamKey {
+        suite: Default::default(),
+        padding: Default::default(),
+        encrypted_key: Vec::new(),
+        nonce_prefix: Vec::new(),
+    };
+    let header = MessageHeader::new("file", 0, "")
+        .with_note(Some("note"))
+        .with_ttl(Some(0))
+        .with_stream_key(Some(stream_key));
+    let structures = vec![
+        structure(&AuthChallenge::new())?,
+        structure(&response)?,
+        structure(&AuthAccepted::new(Default::default(), Default::default()))?,
+        structure(&header)?,
+        structure(&MessageChunk::new(0, false, Vec::new()))?,
+        structure(&TransferAck::new(0, ""))?,
+        structure(&VerifyRequest::new(""))?,
+        structure(&VerifyResponse::new("", ""))?,
+        structure(&Disconnect::new(DisconnectReason::ProtocolError, ""))?,
+    ];
+
+    Ok(ProtocolDescription {
+        version: PROTOCOL_VERSION,
+        magic: String::from_utf8_lossy(&PROTOCOL_MAGIC).to_string(),
+        framing: Framing {
+            encoding: "bincode",
+            header_encoding: "msgpack (map keyed by field name; unknown fields ignored)",
+            length_byte_order: "big-endian",
+            control_length_prefix_bytes: FrameCodec::control().prefix().width(),
+            data_length_prefix_bytes: FrameCodec::data().prefix().width(),
+        },
+        message_types,
+        structures,
+    })
+}
+
+impl ProtocolDescription {
+    /// Render as pretty-printed JSON
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| AppError::Serialization(format!("Failed to serialize protocol description: {}", e)))
+    }
+
+    /// Render as plain text
+    pub fn to_text(&self) -> String {
+        let mut out = format!("Protocol version {} (magic \"{}\")\n", self.version, self.magic);
+        out.push_str(&format!(
+            "Framing: {} messages with a {}-byte {} length prefix; raw data with a {}-byte prefix\n",
+            self.framing.encoding,
+            self.framing.control_length_prefix_bytes,
+            self.framing.length_byte_order,
+            self.framing.data_length_prefix_bytes
+        ));
+        out.push_str(&format!("Headers: {}\n", self.framing.header_encoding));
+        out.push_str("\nMessage types:\n");
+        for t in &self.message_types {
+            out.push_str(&format!("  {:>3}  {}\n", t.tag, t.name));
+        }
+        for s in &self.structures {
+            out.push_str(&format!("\n{}:\n", s.name));
+            for f in &s.fields {
+                out.push_str(&format!("  {:<20} {}\n", f.name, f.kind));
+            }
+        }
+        out
+    }
+}
+
+/// Enum tag bincode writes for a message type
+fn wire_tag(t: &MessageType) -> Result<u32> {
+    let bytes = bincode::serialize(t)
+        .map_err(|e| AppError::Serialization(format!("Failed to encode {}: {}", t, e)))?;
+    let tag: [u8; 4] = bytes
+        .get(..4)
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| AppError::Serialization(format!("Unexpected encoding for {}", t)))?;
+    Ok(u32::from_le_bytes(tag))
+}
+
+/// Field layout of `T`, with field order taken from its `Deserialize` impl
+/// and kinds from a serialized `sample`
+fn structure<T: Serialize + for<'de> Deserialize<'de>>(sample: &T) -> Result<StructureInfo> {
+    let (name, names) = struct_fields::<T>()?;
+    let value = serde_json::to_value(sample)
+        .map_err(|e| AppError::Serialization(format!("Failed to describe {}: {}", name, e)))?;
+
+    let fields = names
+        .iter()
+        .map(|field| FieldInfo { name: field, kind: value.get(field).map_or("unknown", kind_of) })
+        .collect();
+    Ok(StructureInfo { name, fields })
+}
+
+fn kind_of(value: &serde_json::Value) -> &'static str {
+    use serde_json::Value;
+    match value {
+        Value::Null => "optional",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(items) if items.iter().all(Value::is_u64) => "bytes",
+        Value::Array(_) => "list",
+        Value::Object(_) => "struct",
+    }
+}
+
+/// Name and declared field order of a derived struct
+///
+/// Serde hands both to `deserialize_struct`, so a deserializer that stops
+/// there recovers them without any input.
+fn struct_fields<T: for<'de> Deserialize<'de>>() -> Result<(&'static str, &'static [&'static str])> {
+    let mut found = None;
+    let _ = T::deserialize(FieldProbe(&mut found));
+    found.ok_or_else(|| AppError::Serialization("Type is not a struct".to_string()))
+}
+
+struct FieldProbe<'a>(&'a mut Option<(&'static str, &'static [&'static str])>);
+
+impl<'de> Deserializer<'de> for FieldProbe<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> std::result::Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> std::result::Result<V::Value, Self::Error> {
+        *self.0 = Some((name, fields));
+        Err(de::Error::custom("probe finished"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_lists_every_message_type() {
+        let description = describe().unwrap();
+        let json: serde_json::Value = serde_json::from_str(&description.to_json().unwrap()).unwrap();
+        let types = json["message_types"].as_array().unwrap();
+
+        // Tags are contiguous from zero and nothing decodes past the last one,
+        // so `MessageType::ALL` cannot have missed a variant
+        for (tag, t) in types.iter().enumerate() {
+            assert_eq!(t["tag"], tag as u64);
+            assert_eq!(t["name"], MessageType::ALL[tag].to_string());
+        }
+        let past_end = (types.len() as u32).to_le_bytes();
+        assert!(bincode::deserialize::<MessageType>(&past_end).is_err());
+
+        let header = description.structures.iter().find(|s| s.name == "MessageHeader").unwrap();
+        let names: Vec<_> = header.fields.iter().map(|f| f.name).collect();
+        assert_eq!(names[..3], ["filename", "size", "timestamp"]);
+        assert_eq!(header.fields[1].kind, "integer");
+    }
+}
@@ -0,0 +1,233 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::error::{AppError, Result};
+
+/// Largest control message a peer may announce
+///
+/// Control messages carry headers, keys and acknowledgments, all far
+/// smaller than this; the cap stops a bogus length from allocating gigabytes.
+pub const MAX_CONTROL_FRAME: usize = 16 * 1024 * 1024;
+
+/// Width of the big-endian length prefix in front of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthPrefix {
+    /// 4 bytes, used for control messages
+    U32,
+    /// 8 bytes, used for raw transfer data
+    U64,
+}
+
+impl LengthPrefix {
+    /// Number of bytes the prefix occupies
+    pub fn width(self) -> usize {
+        match self {
+            LengthPrefix::U32 => 4,
+            LengthPrefix::U64 => 8,
+        }
+    }
+
+    /// Largest length the prefix can express
+    fn max_value(self) -> u64 {
+        match self {
+            LengthPrefix::U32 => u32::MAX as u64,
+            LengthPrefix::U64 => u64::MAX,
+        }
+    }
+}
+
+/// Length-prefixed framing shared by every message on the wire
+///
+/// All length handling lives here: announced lengths are checked against
+/// `max_len` before anything is allocated, and converted to `usize` without
+/// truncating on 32-bit targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCodec {
+    prefix: LengthPrefix,
+    max_len: usize,
+    what: &'static str,
+}
+
+impl FrameCodec {
+    /// Codec for control messages: 4-byte prefix, at most [`MAX_CONTROL_FRAME`]
+    pub const fn control() -> Self {
+        Self {
+            prefix: LengthPrefix::U32,
+            max_len: MAX_CONTROL_FRAME,
+            what: "message",
+        }
+    }
+
+    /// Codec for raw transfer data: 8-byte prefix, bounded only by memory
+    pub const fn data() -> Self {
+        Self {
+            prefix: LengthPrefix::U64,
+            max_len: usize::MAX,
+            what: "data",
+        }
+    }
+
+    /// Refuse frames longer than `max_len` bytes
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Width of this codec's length prefix
+    pub fn prefix(&self) -> LengthPrefix {
+        self.prefix
+    }
+
+    /// Largest frame this codec accepts
+    pub fn max_len(&self) -> usize {
+        self.max_len
+    }
+
+    /// Encode the length prefix for a payload of `len` bytes
+    pub fn encode_length(&self, len: usize) -> Result<Vec<u8>> {
+        let wide = u64::try_from(len).ok().filter(|l| *l <= self.prefix.max_value());
+        match wide {
+            Some(l) if len <= self.max_len => Ok(match self.prefix {
+                LengthPrefix::U32 => (l as u32).to_be_bytes().to_vec(),
+                LengthPrefix::U64 => l.to_be_bytes().to_vec(),
+            }),
+            _ => Err(self.too_long(len as u128)),
+        }
+    }
+
+    /// Decode and bound-check a length prefix
+    pub fn decode_length(&self, prefix: &[u8]) -> Result<usize> {
+        let len = match (self.prefix, prefix) {
+            (LengthPrefix::U32, &[a, b, c, d]) => u32::from_be_bytes([a, b, c, d]) as u64,
+            (LengthPrefix::U64, bytes) if bytes.len() == 8 => {
+                let mut buf = [0u8; 8];
+                buf.copy_from_slice(bytes);
+                u64::from_be_bytes(buf)
+            }
+            _ => {
+                return Err(AppError::Protocol(format!(
+                    "Length prefix must be {} bytes, got {}",
+                    self.prefix.width(),
+                    prefix.len()
+                )));
+            }
+        };
+        usize::try_from(len)
+            .ok()
+            .filter(|l| *l <= self.max_len)
+            .ok_or_else(|| self.too_long(len as u128))
+    }
+
+    /// Write `payload` preceded by its length
+    pub async fn write_frame<W: AsyncWrite + Unpin>(&self, writer: &mut W, payload: &[u8]) -> Result<()> {
+        let prefix = self.encode_length(payload.len())?;
+        writer.write_all(&prefix)
+            .await
+            .map_err(|e| AppError::Protocol(format!("Failed to send {} length: {}", self.what, e)))?;
+        writer.write_all(payload)
+            .await
+            .map_err(|e| AppError::Protocol(format!("Failed to send {}: {}", self.what, e)))
+    }
+
+    /// Read one frame
+    pub async fn read_frame<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<Vec<u8>> {
+        self.read_frame_or_eof(reader).await?.ok_or_else(|| {
+            AppError::Protocol(format!("Failed to read {} length: connection closed", self.what))
+        })
+    }
+
+    /// Read one frame, or `None` if the stream ended cleanly before it started
+    pub async fn read_frame_or_eof<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<Option<Vec<u8>>> {
+        match self.read_length_or_eof(reader).await? {
+            Some(len) => self.read_body(reader, len).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Read just the length prefix, telling a clean close apart from a truncated prefix
+    pub async fn read_length_or_eof<R: AsyncRead + Unpin>(&self, reader: &mut R) -> Result<Option<usize>> {
+        let read_err = |e: std::io::Error| AppError::Protocol(format!("Failed to read {} length: {}", self.what, e));
+        let mut prefix = [0u8; 8];
+        let prefix = &mut prefix[..self.prefix.width()];
+
+        let first = reader.read(&mut prefix[..1]).await.map_err(read_err)?;
+        if first == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut prefix[1..]).await.map_err(read_err)?;
+        self.decode_length(prefix).map(Some)
+    }
+
+    /// Read a frame body of `len` bytes whose prefix was already read
+    pub async fn read_body<R: AsyncRead + Unpin>(&self, reader: &mut R, len: usize) -> Result<Vec<u8>> {
+        if len > self.max_len {
+            return Err(self.too_long(len as u128));
+        }
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body)
+            .await
+            .map_err(|e| AppError::Protocol(format!("Failed to read {}: {}", self.what, e)))?;
+        Ok(body)
+    }
+
+    fn too_long(&self, len: u128) -> AppError {
+        AppError::Protocol(format!(
+            "Frame of {} bytes exceeds the {} limit of {} bytes",
+            len, self.what, self.max_len
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, RngCore};
+
+    #[tokio::test]
+    async fn test_round_trip_and_truncation() {
+        for codec in [FrameCodec::control(), FrameCodec::data()] {
+            let mut wire = Vec::new();
+            codec.write_frame(&mut wire, b"first").await.unwrap();
+            codec.write_frame(&mut wire, b"").await.unwrap();
+            assert_eq!(wire.len(), 2 * codec.prefix().width() + 5);
+
+            let mut reader = wire.as_slice();
+            assert_eq!(codec.read_frame(&mut reader).await.unwrap(), b"first");
+            assert_eq!(codec.read_frame(&mut reader).await.unwrap(), b"");
+            assert!(codec.read_frame_or_eof(&mut reader).await.unwrap().is_none());
+
+            // Cut anywhere inside a frame is an error, never a short frame
+            for cut in 1..codec.prefix().width() + 5 {
+                assert!(codec.read_frame(&mut &wire[..cut]).await.is_err(), "cut at {}", cut);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_lengths_rejected_before_allocating() {
+        let codec = FrameCodec::control().with_max_len(8);
+        assert!(codec.write_frame(&mut Vec::new(), &[0u8; 9]).await.is_err());
+
+        let announced = u32::MAX.to_be_bytes();
+        let err = FrameCodec::control().read_frame(&mut announced.as_slice()).await.unwrap_err();
+        assert!(err.to_string().contains("exceeds"), "{}", err);
+
+        let announced = u64::MAX.to_be_bytes();
+        let codec = FrameCodec::data().with_max_len(1024);
+        assert!(codec.read_frame(&mut announced.as_slice()).await.is_err());
+        assert!(codec.decode_length(&[0u8; 4]).is_err());
+    }
+
+    /// Same property as the `frame_reader` fuzz target, on a fixed budget
+    #[tokio::test]
+    async fn test_arbitrary_bytes_never_panic() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..2000 {
+            let mut input = vec![0u8; rng.gen_range(0..64)];
+            rng.fill_bytes(&mut input);
+            let codec = FrameCodec::control().with_max_len(32);
+            let mut reader = input.as_slice();
+            while let Ok(Some(frame)) = codec.read_frame_or_eof(&mut reader).await {
+                assert!(frame.len() <= 32);
+            }
+        }
+    }
+}
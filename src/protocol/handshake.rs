@@ -1,25 +1,160 @@
-use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use rsa::RsaPublicKey;
 use rsa::pkcs8::DecodePublicKey;
 use crate::error::{AppError, Result};
-use crate::crypto::{decrypt, KeyPair};
-use crate::auth::{Whitelist, hash_connect_key};
-use crate::protocol::message::{Message, MessageType, AuthChallenge, AuthResponse};
+use crate::crypto::{sign_with, verify_with, negotiate, fingerprint, check_public_exponent, AuthMethod, CipherSuite, KeyPair};
+use crate::auth::{Authorizer, WhitelistEntry, hash_connect_key};
+use crate::compression::{self, Compression};
+use crate::protocol::message::{Message, MessageType, AuthChallenge, AuthResponse, AuthAccepted, validate_identity, unexpected_message};
+use crate::protocol::frame::FrameCodec;
 use crate::cli::Output;
+use crate::clock::{SharedClock, system_clock};
+
+/// Magic bytes a client sends first so stray connections are rejected early
+pub const PROTOCOL_MAGIC: [u8; 4] = *b"FTT1";
+
+/// Version of the wire protocol spoken after the magic
+///
+/// Version 2 encodes `MessageHeader` as a field-named MessagePack map.
+/// Version 3 announces the version at the end of the challenge and response;
+/// a peer that leaves it off is taken to speak version 2.
+pub const PROTOCOL_VERSION: u32 = 3;
 
 /// Handshake protocol handler
 pub struct Handshake;
 
+/// Settings for one side of the handshake
+#[derive(Clone, Debug)]
+pub struct HandshakeOptions {
+    /// Cipher suites offered (client) or accepted (server)
+    pub cipher_suites: Vec<CipherSuite>,
+    /// Informational identity announced by the client
+    pub identity: Option<String>,
+    /// Authentication methods this side can use
+    pub auth_methods: Vec<AuthMethod>,
+    /// Weakest authentication method this side accepts
+    pub min_auth_method: AuthMethod,
+    /// Compression offered (client, preferred first) or accepted (server)
+    pub compression: Vec<Compression>,
+    /// Refuse a peer key whose public exponent is not exactly 65537
+    pub exact_public_exponent: bool,
+    /// Oldest protocol version this side accepts from its peer
+    pub min_protocol_version: u32,
+    /// Weakest cipher suite this side agrees to, even if both support a weaker one
+    pub min_cipher_suite: CipherSuite,
+    /// Time source for stamping challenges and checking they have not expired (server)
+    pub clock: SharedClock,
+    /// Accept payload keys wrapped with PKCS#1 v1.5, as sent by peers from before OAEP (server)
+    pub accept_pkcs1v15_payloads: bool,
+    /// Refuse transfers whose header the client did not sign (server)
+    pub require_signed_payloads: bool,
+    /// Refuse a server whose key is not already pinned in the known hosts file (client)
+    pub require_pinned_host: bool,
+}
+
+impl Default for HandshakeOptions {
+    fn default() -> Self {
+        Self {
+            cipher_suites: CipherSuite::ALL.to_vec(),
+            identity: None,
+            auth_methods: AuthMethod::ALL.to_vec(),
+            min_auth_method: AuthMethod::LegacyDecrypt,
+            compression: Compression::ALL.to_vec(),
+            exact_public_exponent: false,
+            min_protocol_version: 1,
+            min_cipher_suite: CipherSuite::Aes128Gcm,
+            clock: system_clock(),
+            accept_pkcs1v15_payloads: false,
+            require_signed_payloads: false,
+            require_pinned_host: false,
+        }
+    }
+}
+
+impl HandshakeOptions {
+    /// Methods this side supports that also meet its minimum, strongest first
+    pub fn acceptable_auth_methods(&self) -> Vec<AuthMethod> {
+        let mut methods: Vec<_> = self
+            .auth_methods
+            .iter()
+            .copied()
+            .filter(|m| m.meets(self.min_auth_method))
+            .collect();
+        methods.sort_by_key(|m| std::cmp::Reverse(m.strength()));
+        methods
+    }
+
+    /// Fail if the peer announced a protocol version below this side's minimum
+    pub fn check_protocol_version(&self, peer_version: u32) -> Result<()> {
+        if peer_version < self.min_protocol_version {
+            return Err(AppError::Protocol(format!(
+                "Peer speaks protocol version {}, below the minimum {}",
+                peer_version, self.min_protocol_version
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Both ends of an authenticated session, identified by key fingerprint
+///
+/// Logged once per handshake on each host; the server's `local` is the
+/// client's `remote` and vice versa, so the two logs can be correlated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionEvent {
+    /// Fingerprint of this node's public key
+    pub local: String,
+    /// Fingerprint of the peer's public key
+    pub remote: String,
+}
+
+impl SessionEvent {
+    fn new(local: &RsaPublicKey, remote: &RsaPublicKey) -> Result<Self> {
+        Ok(Self {
+            local: fingerprint(local)?,
+            remote: fingerprint(remote)?,
+        })
+    }
+}
+
+impl std::fmt::Display for SessionEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "session: local={} remote={}", self.local, self.remote)
+    }
+}
+
+/// What both peers agreed on during a successful handshake
+#[derive(Debug)]
+pub struct HandshakeOutcome {
+    /// The peer's RSA public key
+    pub peer_public_key: RsaPublicKey,
+    /// Cipher suite to use for the transfer
+    pub cipher_suite: CipherSuite,
+    /// How the client proved possession of its key
+    pub auth_method: AuthMethod,
+    /// Compression to apply to transfer data
+    pub compression: Compression,
+    /// Fingerprints of both peers, as logged
+    pub session: SessionEvent,
+    /// Identity the client announced (server side only, informational)
+    pub peer_identity: Option<String>,
+    /// Whitelist entry the client authenticated with, if the authorizer has one (server side only)
+    pub whitelist_entry: Option<WhitelistEntry>,
+}
+
 impl Handshake {
     /// Server-side handshake
-    pub async fn server_side(
-        stream: &mut TcpStream,
-        whitelist: &Whitelist,
+    pub async fn server_side<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: &mut S,
+        authorizer: &dyn Authorizer,
         keypair: &KeyPair,
-    ) -> Result<RsaPublicKey> {
-        // 1. Send challenge
-        let challenge = AuthChallenge::new();
+        options: &HandshakeOptions,
+    ) -> Result<HandshakeOutcome> {
+        // 0. Make sure the peer speaks our protocol at all
+        expect_magic(stream).await?;
+
+        // 1. Send challenge, advertising the authentication methods we accept
+        let challenge = AuthChallenge::new_at(options.clock.as_ref()).with_auth_methods(&options.acceptable_auth_methods());
         let challenge_bytes = challenge.to_bytes()
             .map_err(|e| AppError::Protocol(format!("Failed to serialize challenge: {}", e)))?;
 
@@ -28,144 +163,275 @@ impl Handshake {
 
         Output::info("Challenge sent to client");
 
-        // 2. Receive and verify response
+        // 2. Receive the response followed by the client's public key
         let response_msg = receive_message(stream).await?;
 
-        if !matches!(response_msg.msg_type, MessageType::AuthResponse) {
-            return Err(AppError::Auth("Expected AuthResponse".to_string()));
-        }
+        response_msg.expect_type(MessageType::AuthResponse)?;
 
         let response: AuthResponse = AuthResponse::from_bytes(&response_msg.payload)?;
 
-        // Check if connect key is whitelisted
-        let key_valid = whitelist.keys().iter().any(|k| {
-            hash_connect_key(k) == response.connect_key_hash
-        });
-
-        if !key_valid {
-            let fail_msg = Message::new(MessageType::AuthFailure, b"Invalid connect key".to_vec());
+        // Refuse old clients before looking at anything they sent
+        if let Err(e) = options.check_protocol_version(response.protocol_version) {
+            let fail_msg = Message::new(MessageType::AuthFailure, b"Protocol version below minimum".to_vec());
             send_message(stream, &fail_msg).await?;
-            return Err(AppError::Auth("Invalid connect key".to_string()));
+            return Err(e);
         }
 
-        // 3. Send success
-        let success_msg = Message::new(MessageType::AuthSuccess, vec![]);
-        send_message(stream, &success_msg).await?;
+        let client_public_pem = receive_public_key(stream).await?;
 
-        Output::authenticated();
+        // The signature is checked against this connection's challenge, so a
+        // response captured elsewhere cannot pass and no set of issued nonces
+        // is kept; only refuse one that took longer than a token's lifetime.
+        // The public key is read first so the refusal is not lost to a reset.
+        if !challenge.is_fresh_at(options.clock.as_ref()) {
+            let fail_msg = Message::new(MessageType::AuthFailure, b"Challenge expired".to_vec());
+            send_message(stream, &fail_msg).await?;
+            return Err(AppError::Auth("Response arrived after the challenge expired".to_string()));
+        }
 
-        // 4. Exchange public keys
-        let client_public_pem = receive_public_key(stream).await?;
         let client_public = RsaPublicKey::from_public_key_pem(&client_public_pem)
             .map_err(|e| AppError::Crypto(format!("Failed to parse client public key: {}", e)))?;
+        check_public_exponent(&client_public, options.exact_public_exponent)?;
+
+        // The client must have used one of the methods we advertised
+        if !challenge.auth_methods.contains(&response.auth_method) {
+            let fail_msg = Message::new(MessageType::AuthFailure, b"Authentication method not allowed".to_vec());
+            send_message(stream, &fail_msg).await?;
+            return Err(AppError::Auth(format!("Authentication method not allowed: {}", response.auth_method)));
+        }
+
+        // The signature must cover the challenge issued on this connection
+        let material = challenge.material_for(response.auth_method);
+        if verify_with(response.auth_method, &client_public, &material, &response.challenge_response).is_err() {
+            let fail_msg = Message::new(MessageType::AuthFailure, b"Invalid challenge signature".to_vec());
+            send_message(stream, &fail_msg).await?;
+            return Err(AppError::Auth("Invalid challenge signature".to_string()));
+        }
+
+        // Only a client proven to hold its key reaches the authorizer, so
+        // unauthenticated peers cannot make it call out to a directory
+        match authorizer.is_authorized(&response.connect_key_hash, &client_public).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let fail_msg = Message::new(MessageType::AuthFailure, b"Invalid connect key".to_vec());
+                send_message(stream, &fail_msg).await?;
+                return Err(AppError::Auth("Invalid connect key".to_string()));
+            }
+            Err(e) => {
+                let fail_msg = Message::new(MessageType::AuthFailure, b"Authorization unavailable".to_vec());
+                send_message(stream, &fail_msg).await?;
+                return Err(AppError::Auth(format!("Authorizer failed: {}", e)));
+            }
+        }
+        let whitelist_entry = authorizer.entry(&response.connect_key_hash);
+
+        // The identity is informational only, but must still be well-formed
+        if let Some(identity) = &response.identity {
+            if let Err(e) = validate_identity(identity) {
+                let fail_msg = Message::new(MessageType::AuthFailure, b"Invalid client identity".to_vec());
+                send_message(stream, &fail_msg).await?;
+                return Err(e);
+            }
+            Output::info(&format!("Client identity: {}", identity));
+        }
+
+        if response.auth_method == AuthMethod::LegacyDecrypt {
+            Output::warning("Client authenticated with legacy-decrypt; use --min-auth-method pss-sha256 once all peers are upgraded");
+        }
+
+        // Pick the strongest cipher suite both sides support
+        let cipher_suite = match negotiate(&response.cipher_suites, &options.cipher_suites) {
+            Some(suite) => suite,
+            None => {
+                let fail_msg = Message::new(MessageType::AuthFailure, b"No common cipher suite".to_vec());
+                send_message(stream, &fail_msg).await?;
+                return Err(AppError::Auth("No common cipher suite".to_string()));
+            }
+        };
+        if !cipher_suite.meets(options.min_cipher_suite) {
+            let fail_msg = Message::new(MessageType::AuthFailure, b"Cipher suite below minimum".to_vec());
+            send_message(stream, &fail_msg).await?;
+            return Err(AppError::Auth(format!(
+                "Client's strongest cipher suite {} is below the minimum {}",
+                cipher_suite, options.min_cipher_suite
+            )));
+        }
+
+        // Compression is optional, so disjoint sets fall back to none
+        let compression = compression::negotiate(&response.compression, &options.compression);
 
+        // 3. Send success with the chosen suite and compression
+        let accepted = AuthAccepted::new(cipher_suite, compression);
+        let success_msg = Message::new(MessageType::AuthSuccess, accepted.to_bytes()?);
+        send_message(stream, &success_msg).await?;
+
+        Output::authenticated(whitelist_entry.as_ref().and_then(|entry| entry.label.as_deref()));
+
+        // 4. Send our public key
         send_public_key(stream, &keypair.public_key).await?;
 
-        Output::info("Public keys exchanged");
+        Output::info(&format!("Public keys exchanged, using {}", cipher_suite));
+        let session = SessionEvent::new(&keypair.public_key, &client_public)?;
+        Output::info(&session.to_string());
 
-        Ok(client_public)
+        Ok(HandshakeOutcome {
+            session,
+            peer_public_key: client_public,
+            cipher_suite,
+            auth_method: response.auth_method,
+            compression,
+            peer_identity: response.identity,
+            whitelist_entry,
+        })
     }
 
     /// Client-side handshake
-    pub async fn client_side(
-        stream: &mut TcpStream,
+    pub async fn client_side<S: AsyncRead + AsyncWrite + Unpin>(
+        stream: &mut S,
         connect_key: &str,
         keypair: &KeyPair,
-    ) -> Result<RsaPublicKey> {
+        options: &HandshakeOptions,
+    ) -> Result<HandshakeOutcome> {
+        // 0. Announce the protocol
+        stream.write_all(&PROTOCOL_MAGIC)
+            .await
+            .map_err(|e| AppError::Protocol(format!("Failed to send protocol magic: {}", e)))?;
+
         // 1. Receive challenge
         let challenge_msg = receive_message(stream).await?;
 
-        if !matches!(challenge_msg.msg_type, MessageType::AuthChallenge) {
-            return Err(AppError::Protocol("Expected AuthChallenge".to_string()));
-        }
+        challenge_msg.expect_type(MessageType::AuthChallenge)?;
 
         let challenge: AuthChallenge = AuthChallenge::from_bytes(&challenge_msg.payload)?;
 
         Output::info("Received challenge from server");
+        options.check_protocol_version(challenge.protocol_version)?;
 
-        // 2. Sign challenge and send response
-        // In RSA, "signing" with PKCS1-v15 without a hash is technically decrypting the challenge
-        let challenge_response = decrypt(&keypair.private_key, &challenge.challenge)
-            .map_err(|e| AppError::Auth(format!("Failed to sign challenge: {}", e)))?;
-
-        let connect_key_hash = hash_connect_key(connect_key);
-        let response = AuthResponse::new(connect_key_hash, challenge_response);
+        // 2. Sign challenge and send response, followed by our public key
+        let response = build_auth_response(&challenge, connect_key, keypair, options)?;
 
         let response_bytes = response.to_bytes()
             .map_err(|e| AppError::Protocol(format!("Failed to serialize response: {}", e)))?;
 
         let msg = Message::new(MessageType::AuthResponse, response_bytes);
         send_message(stream, &msg).await?;
+        send_public_key(stream, &keypair.public_key).await?;
 
         Output::info("Sent authentication response");
 
         // 3. Receive success/failure
         let result_msg = receive_message(stream).await?;
 
-        match result_msg.msg_type {
+        let AuthAccepted { cipher_suite, compression } = match result_msg.msg_type {
             MessageType::AuthSuccess => {
-                Output::authenticated();
+                Output::authenticated(None);
+                AuthAccepted::from_bytes(&result_msg.payload)?
             }
             MessageType::AuthFailure => {
                 let reason = String::from_utf8_lossy(&result_msg.payload);
                 return Err(AppError::Auth(format!("Authentication failed: {}", reason)));
             }
-            _ => {
-                return Err(AppError::Protocol("Unexpected message type".to_string()));
+            other => {
+                return Err(unexpected_message(&[MessageType::AuthSuccess, MessageType::AuthFailure], other));
             }
+        };
+
+        if !options.cipher_suites.contains(&cipher_suite) {
+            return Err(AppError::Protocol(format!("Server chose unoffered cipher suite {}", cipher_suite)));
+        }
+        if !cipher_suite.meets(options.min_cipher_suite) {
+            return Err(AppError::Auth(format!(
+                "Server's strongest cipher suite {} is below the minimum {}",
+                cipher_suite, options.min_cipher_suite
+            )));
+        }
+        if compression != Compression::None && !options.compression.contains(&compression) {
+            return Err(AppError::Protocol(format!("Server chose unoffered compression {}", compression)));
         }
 
-        // 4. Exchange public keys
-        send_public_key(stream, &keypair.public_key).await?;
+        // 4. Receive the server's public key
         let server_public_pem = receive_public_key(stream).await?;
         let server_public = RsaPublicKey::from_public_key_pem(&server_public_pem)
             .map_err(|e| AppError::Crypto(format!("Failed to parse server public key: {}", e)))?;
+        check_public_exponent(&server_public, options.exact_public_exponent)?;
 
-        Output::info("Public keys exchanged");
+        Output::info(&format!("Public keys exchanged, using {}", cipher_suite));
+        let session = SessionEvent::new(&keypair.public_key, &server_public)?;
+        Output::info(&session.to_string());
 
-        Ok(server_public)
+        Ok(HandshakeOutcome {
+            session,
+            peer_public_key: server_public,
+            cipher_suite,
+            auth_method: response.auth_method,
+            compression,
+            peer_identity: None,
+            whitelist_entry: None,
+        })
     }
 }
 
-/// Send a message over the stream
-pub async fn send_message(stream: &mut TcpStream, msg: &Message) -> Result<()> {
-    let data = msg.to_bytes()?;
-    let len = data.len() as u32;
+/// Build the client's response to a challenge, signing it with the client key
+pub fn build_auth_response(
+    challenge: &AuthChallenge,
+    connect_key: &str,
+    keypair: &KeyPair,
+    options: &HandshakeOptions,
+) -> Result<AuthResponse> {
+    if let Some(identity) = &options.identity {
+        validate_identity(identity)?;
+    }
 
-    // Send length prefix
-    stream.write_all(&len.to_be_bytes())
-        .await
-        .map_err(|e| AppError::Protocol(format!("Failed to send length: {}", e)))?;
+    // Strongest method we accept that the server advertised
+    let method = options
+        .acceptable_auth_methods()
+        .into_iter()
+        .find(|m| challenge.auth_methods.contains(m))
+        .ok_or_else(|| AppError::Auth("No common authentication method with the server".to_string()))?;
 
-    // Send data
-    stream.write_all(&data)
-        .await
-        .map_err(|e| AppError::Protocol(format!("Failed to send message: {}", e)))?;
+    let signature = sign_with(method, &keypair.private_key, &challenge.material_for(method))
+        .map_err(|e| AppError::Auth(format!("Failed to sign challenge: {}", e)))?;
 
-    Ok(())
+    Ok(AuthResponse::new(hash_connect_key(connect_key), signature)
+        .with_cipher_suites(&options.cipher_suites)
+        .with_identity(options.identity.as_deref())
+        .with_auth_method(method)
+        .with_compression(&options.compression))
 }
 
-/// Receive a message from the stream
-pub async fn receive_message(stream: &mut TcpStream) -> Result<Message> {
-    // Read length prefix
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf)
+/// Read the protocol magic and reject anything else before parsing further
+pub async fn expect_magic<S: AsyncRead + Unpin>(stream: &mut S) -> Result<()> {
+    let mut magic = [0u8; 4];
+    stream.read_exact(&mut magic)
         .await
-        .map_err(|e| AppError::Protocol(format!("Failed to read length: {}", e)))?;
+        .map_err(|e| AppError::Protocol(format!("Failed to read protocol magic: {}", e)))?;
 
-    let len = u32::from_be_bytes(len_buf) as usize;
+    if magic != PROTOCOL_MAGIC {
+        return Err(AppError::Protocol("not a finapp connection".to_string()));
+    }
+    Ok(())
+}
 
-    // Read data
-    let mut data = vec![0u8; len];
-    stream.read_exact(&mut data)
-        .await
-        .map_err(|e| AppError::Protocol(format!("Failed to read message: {}", e)))?;
+/// Send a message over the stream
+pub async fn send_message<S: AsyncWrite + Unpin>(stream: &mut S, msg: &Message) -> Result<()> {
+    FrameCodec::control().write_frame(stream, &msg.to_bytes()?).await
+}
+
+/// Receive a message from the stream
+pub async fn receive_message<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Message> {
+    Message::from_bytes(&FrameCodec::control().read_frame(stream).await?)
+}
 
-    Message::from_bytes(&data)
+/// Receive a message, or `None` if the peer closed the stream before sending one
+pub async fn receive_message_or_eof<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Message>> {
+    match FrameCodec::control().read_frame_or_eof(stream).await? {
+        Some(data) => Message::from_bytes(&data).map(Some),
+        None => Ok(None),
+    }
 }
 
 /// Send public key
-async fn send_public_key(stream: &mut TcpStream, public_key: &RsaPublicKey) -> Result<()> {
+pub async fn send_public_key<S: AsyncWrite + Unpin>(stream: &mut S, public_key: &RsaPublicKey) -> Result<()> {
     use rsa::pkcs8::EncodePublicKey;
     use rsa::pkcs8::LineEnding;
 
@@ -177,39 +443,598 @@ async fn send_public_key(stream: &mut TcpStream, public_key: &RsaPublicKey) -> R
 }
 
 /// Receive public key
-async fn receive_public_key(stream: &mut TcpStream) -> Result<String> {
+pub async fn receive_public_key<S: AsyncRead + Unpin>(stream: &mut S) -> Result<String> {
     let msg = receive_message(stream).await?;
 
-    if !matches!(msg.msg_type, MessageType::PublicKeyExchange) {
-        return Err(AppError::Protocol("Expected PublicKeyExchange".to_string()));
-    }
+    msg.expect_type(MessageType::PublicKeyExchange)?;
 
     String::from_utf8(msg.payload)
         .map_err(|e| AppError::Protocol(format!("Invalid public key encoding: {}", e)))
 }
 
 /// Send raw data
-pub async fn send_raw_data(stream: &mut TcpStream, data: &[u8]) -> Result<()> {
-    let len = data.len() as u64;
+pub async fn send_raw_data<S: AsyncWrite + Unpin>(stream: &mut S, data: &[u8]) -> Result<()> {
+    FrameCodec::data().write_frame(stream, data).await
+}
 
-    // Send length prefix (8 bytes for large data)
-    stream.write_all(&len.to_be_bytes())
-        .await
-        .map_err(|e| AppError::Protocol(format!("Failed to send data length: {}", e)))?;
+/// Receive raw data whose length prefix was already read
+pub async fn receive_raw_data<S: AsyncRead + Unpin>(stream: &mut S, size: usize) -> Result<Vec<u8>> {
+    FrameCodec::data().read_body(stream, size).await
+}
 
-    // Send data
-    stream.write_all(data)
-        .await
-        .map_err(|e| AppError::Protocol(format!("Failed to send data: {}", e)))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use std::sync::Arc;
+    use crate::auth::{Whitelist, WhitelistAuthorizer};
 
-    Ok(())
-}
+    fn whitelist_with(dir: &std::path::Path, key: &str) -> WhitelistAuthorizer {
+        let mut whitelist = Whitelist::load_or_create(&dir.join("whitelist.txt")).unwrap();
+        whitelist.add(key, None).unwrap();
+        WhitelistAuthorizer::new(whitelist)
+    }
 
-/// Receive raw data
-pub async fn receive_raw_data(stream: &mut TcpStream, size: usize) -> Result<Vec<u8>> {
-    let mut data = vec![0u8; size];
-    stream.read_exact(&mut data)
-        .await
-        .map_err(|e| AppError::Protocol(format!("Failed to read data: {}", e)))?;
-    Ok(data)
+    #[tokio::test]
+    async fn test_handshake_succeeds() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+        let expected_server_key = server_keys.public_key.clone();
+        let expected_client_key = client_keys.public_key.clone();
+
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &whitelist, &server_keys, &HandshakeOptions::default()).await
+        });
+
+        let client_outcome = Handshake::client_side(&mut client, "secret", &client_keys, &HandshakeOptions::default())
+            .await
+            .unwrap();
+        let server_outcome = server_task.await.unwrap().unwrap();
+
+        assert_eq!(client_outcome.peer_public_key, expected_server_key);
+        assert_eq!(server_outcome.peer_public_key, expected_client_key);
+        assert_eq!(client_outcome.cipher_suite, server_outcome.cipher_suite);
+    }
+
+    #[tokio::test]
+    async fn test_closure_authorizer_decides_on_key_hash_and_peer_key() {
+        let server_keys = Arc::new(KeyPair::generate().unwrap());
+        let allowed = KeyPair::generate().unwrap();
+        let allowed_fingerprint = allowed.fingerprint().unwrap();
+        let authorizer: Arc<dyn Authorizer> = Arc::new(move |hash: &str, peer: &RsaPublicKey| -> Result<bool> {
+            if hash != hash_connect_key("directory") {
+                return Err(AppError::Auth("directory unreachable".to_string()));
+            }
+            Ok(fingerprint(peer)? == allowed_fingerprint)
+        });
+
+        let attempt = |connect_key: &'static str, client_keys: KeyPair| {
+            let (authorizer, server_keys) = (Arc::clone(&authorizer), Arc::clone(&server_keys));
+            async move {
+                let (mut client, mut server) = duplex(64 * 1024);
+                let server_task = tokio::spawn(async move {
+                    Handshake::server_side(&mut server, authorizer.as_ref(), &server_keys, &HandshakeOptions::default()).await
+                });
+                let client_result = Handshake::client_side(&mut client, connect_key, &client_keys, &HandshakeOptions::default()).await;
+                (client_result, server_task.await.unwrap())
+            }
+        };
+
+        let (client, server) = attempt("directory", allowed).await;
+        client.unwrap();
+        assert!(server.unwrap().whitelist_entry.is_none());
+
+        let (client, server) = attempt("directory", KeyPair::generate().unwrap()).await;
+        assert!(client.unwrap_err().to_string().contains("Invalid connect key"));
+        assert!(server.is_err());
+
+        let (client, server) = attempt("other", KeyPair::generate().unwrap()).await;
+        assert!(client.unwrap_err().to_string().contains("Authorization unavailable"));
+        assert!(server.unwrap_err().to_string().contains("directory unreachable"));
+    }
+
+    #[tokio::test]
+    async fn test_authorizer_not_asked_before_signature_checks_out() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let asked = Arc::new(AtomicUsize::new(0));
+        let authorizer = {
+            let asked = Arc::clone(&asked);
+            move |_: &str, _: &RsaPublicKey| -> Result<bool> {
+                asked.fetch_add(1, Ordering::SeqCst);
+                Ok(true)
+            }
+        };
+        let signer = KeyPair::generate().unwrap();
+        let mismatched = KeyPair { private_key: signer.private_key, public_key: KeyPair::generate().unwrap().public_key };
+
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_keys = KeyPair::generate().unwrap();
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &authorizer, &server_keys, &HandshakeOptions::default()).await
+        });
+        let client_result = Handshake::client_side(&mut client, "secret", &mismatched, &HandshakeOptions::default()).await;
+        assert!(client_result.unwrap_err().to_string().contains("Invalid challenge signature"));
+        assert!(server_task.await.unwrap().is_err());
+        assert_eq!(asked.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_keypair_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = Arc::new(whitelist_with(dir.path(), "secret"));
+        let server_keys = Arc::new(KeyPair::generate().unwrap());
+        let signer = KeyPair::generate().unwrap();
+        let claimed = KeyPair::generate().unwrap();
+        // Signs with one private key while presenting another key's public half
+        let mismatched = KeyPair { private_key: signer.private_key, public_key: claimed.public_key };
+
+        for method in AuthMethod::ALL {
+            let options = HandshakeOptions { auth_methods: vec![method], ..HandshakeOptions::default() };
+            let (mut client, mut server) = duplex(64 * 1024);
+            let server_task = tokio::spawn({
+                let (whitelist, server_keys, options) = (Arc::clone(&whitelist), Arc::clone(&server_keys), options.clone());
+                async move { Handshake::server_side(&mut server, whitelist.as_ref(), &server_keys, &options).await }
+            });
+
+            let client_result = Handshake::client_side(&mut client, "secret", &mismatched, &options).await;
+            assert!(client_result.unwrap_err().to_string().contains("Invalid challenge signature"), "{}", method);
+            let server_err = server_task.await.unwrap().unwrap_err();
+            assert!(matches!(&server_err, AppError::Auth(msg) if msg == "Invalid challenge signature"), "{}", method);
+        }
+    }
+
+    /// Run both sides of a handshake with the given options
+    async fn handshake_with(
+        client_options: HandshakeOptions,
+        server_options: HandshakeOptions,
+    ) -> (Result<HandshakeOutcome>, Result<HandshakeOutcome>) {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &whitelist, &server_keys, &server_options).await
+        });
+        let client_outcome = Handshake::client_side(&mut client, "secret", &client_keys, &client_options).await;
+        drop(client);
+        (client_outcome, server_task.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_exact_public_exponent_refuses_nonstandard_peer_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let private_key = rsa::RsaPrivateKey::new_with_exp(&mut rand::thread_rng(), 1024, &rsa::BigUint::from(65539u32)).unwrap();
+        let client_keys = KeyPair { public_key: RsaPublicKey::from(&private_key), private_key };
+
+        for (exact, accepted) in [(false, true), (true, false)] {
+            let (wl, server_keys) = (whitelist.clone(), KeyPair::generate().unwrap());
+            let options = HandshakeOptions { exact_public_exponent: exact, ..HandshakeOptions::default() };
+            let (mut client, mut server) = duplex(64 * 1024);
+            let server_task = tokio::spawn(async move {
+                Handshake::server_side(&mut server, &wl, &server_keys, &options).await
+            });
+            let client_result = Handshake::client_side(&mut client, "secret", &client_keys, &HandshakeOptions::default()).await;
+            drop(client);
+            let server_result = server_task.await.unwrap();
+            assert_eq!(server_result.is_ok(), accepted);
+            if !accepted {
+                assert!(server_result.unwrap_err().to_string().contains("is not 65537"));
+                assert!(client_result.is_err());
+            }
+        }
+    }
+
+    fn legacy_only() -> HandshakeOptions {
+        HandshakeOptions {
+            auth_methods: vec![AuthMethod::LegacyDecrypt],
+            ..HandshakeOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_method_negotiation() {
+        // Both sides upgraded: PSS is chosen
+        let (client, server) = handshake_with(HandshakeOptions::default(), HandshakeOptions::default()).await;
+        assert_eq!(client.unwrap().auth_method, AuthMethod::PssSha256);
+        assert_eq!(server.unwrap().auth_method, AuthMethod::PssSha256);
+
+        // Old client against a new server, and new client against an old server
+        let (client, server) = handshake_with(legacy_only(), HandshakeOptions::default()).await;
+        assert_eq!(client.unwrap().auth_method, AuthMethod::LegacyDecrypt);
+        assert_eq!(server.unwrap().auth_method, AuthMethod::LegacyDecrypt);
+        let (client, server) = handshake_with(HandshakeOptions::default(), legacy_only()).await;
+        assert_eq!(client.unwrap().auth_method, AuthMethod::LegacyDecrypt);
+        assert!(server.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_min_auth_method_rejects_legacy_peer() {
+        let strict = HandshakeOptions {
+            min_auth_method: AuthMethod::PssSha256,
+            ..HandshakeOptions::default()
+        };
+
+        // A legacy-only client finds nothing acceptable in the server's offer
+        let (client, server) = handshake_with(legacy_only(), strict.clone()).await;
+        assert!(client.unwrap_err().to_string().contains("No common authentication method"));
+        assert!(server.is_err());
+
+        // A client that forces legacy anyway is refused by the server
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &whitelist, &server_keys, &strict).await
+        });
+
+        client.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let challenge_msg = receive_message(&mut client).await.unwrap();
+        let challenge = AuthChallenge::from_bytes(&challenge_msg.payload).unwrap();
+        assert_eq!(challenge.auth_methods, [AuthMethod::PssSha256]);
+        let signature = sign_with(AuthMethod::LegacyDecrypt, &client_keys.private_key, &challenge.challenge).unwrap();
+        let response = AuthResponse::new(hash_connect_key("secret"), signature).with_auth_method(AuthMethod::LegacyDecrypt);
+        send_message(&mut client, &Message::new(MessageType::AuthResponse, response.to_bytes().unwrap())).await.unwrap();
+        send_public_key(&mut client, &client_keys.public_key).await.unwrap();
+
+        let result = receive_message(&mut client).await.unwrap();
+        assert!(matches!(result.msg_type, MessageType::AuthFailure));
+        assert!(server_task.await.unwrap().unwrap_err().to_string().contains("legacy-decrypt"));
+    }
+
+    #[tokio::test]
+    async fn test_min_protocol_version_floor() {
+        let floor = |min| HandshakeOptions { min_protocol_version: min, ..HandshakeOptions::default() };
+
+        // Peers at and above the floor are accepted
+        let (client, server) = handshake_with(HandshakeOptions::default(), floor(PROTOCOL_VERSION)).await;
+        assert!(client.is_ok() && server.is_ok());
+        let (client, server) = handshake_with(floor(PROTOCOL_VERSION - 1), floor(PROTOCOL_VERSION - 1)).await;
+        assert!(client.is_ok() && server.is_ok());
+
+        // A client refuses a server below its floor as soon as the challenge arrives
+        let (client, _) = handshake_with(floor(PROTOCOL_VERSION + 1), HandshakeOptions::default()).await;
+        assert!(client.unwrap_err().to_string().contains(&format!("below the minimum {}", PROTOCOL_VERSION + 1)));
+
+        // A server refuses an older client before looking at its key
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &whitelist, &server_keys, &floor(PROTOCOL_VERSION)).await
+        });
+
+        client.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let challenge_msg = receive_message(&mut client).await.unwrap();
+        assert_eq!(AuthChallenge::from_bytes(&challenge_msg.payload).unwrap().protocol_version, PROTOCOL_VERSION);
+        let response = AuthResponse { protocol_version: PROTOCOL_VERSION - 1, ..AuthResponse::new(hash_connect_key("secret"), Vec::new()) };
+        send_message(&mut client, &Message::new(MessageType::AuthResponse, response.to_bytes().unwrap())).await.unwrap();
+
+        let result = receive_message(&mut client).await.unwrap();
+        assert!(matches!(result.msg_type, MessageType::AuthFailure));
+        assert_eq!(result.payload, b"Protocol version below minimum");
+        let error = server_task.await.unwrap().unwrap_err();
+        assert!(matches!(error, AppError::Protocol(_)));
+        assert!(error.to_string().contains(&format!("protocol version {}", PROTOCOL_VERSION - 1)));
+    }
+
+    #[tokio::test]
+    async fn test_min_cipher_suite_floor() {
+        let offering = |suites: &[CipherSuite]| HandshakeOptions { cipher_suites: suites.to_vec(), ..HandshakeOptions::default() };
+        let floor = |min| HandshakeOptions { min_cipher_suite: min, ..HandshakeOptions::default() };
+
+        // At and above the floor
+        let (client, server) = handshake_with(offering(&[CipherSuite::Aes256Gcm]), floor(CipherSuite::Aes256Gcm)).await;
+        assert_eq!(client.unwrap().cipher_suite, CipherSuite::Aes256Gcm);
+        assert!(server.is_ok());
+        let (client, server) = handshake_with(HandshakeOptions::default(), floor(CipherSuite::Aes256Gcm)).await;
+        assert_eq!(client.unwrap().cipher_suite, CipherSuite::Aes256GcmSiv);
+        assert!(server.is_ok());
+
+        // Below: refused even though both sides support AES-128-GCM
+        let (client, server) = handshake_with(offering(&[CipherSuite::Aes128Gcm]), floor(CipherSuite::Aes256Gcm)).await;
+        assert!(client.unwrap_err().to_string().contains("Cipher suite below minimum"));
+        assert!(server.unwrap_err().to_string().contains("AES-128-GCM is below the minimum AES-256-GCM"));
+
+        // The client applies its own floor to the suite the server picks
+        let client_floor = HandshakeOptions { min_cipher_suite: CipherSuite::Aes256GcmSiv, ..HandshakeOptions::default() };
+        let (client, _) = handshake_with(client_floor, offering(&[CipherSuite::Aes256Gcm])).await;
+        assert!(client.unwrap_err().to_string().contains("AES-256-GCM is below the minimum AES-256-GCM-SIV"));
+    }
+
+    #[tokio::test]
+    async fn test_compression_negotiation() {
+        let with_compression = |algorithms: &[Compression]| HandshakeOptions {
+            compression: algorithms.to_vec(),
+            ..HandshakeOptions::default()
+        };
+
+        // Overlapping: the client's first choice the server supports wins
+        let (client, server) = handshake_with(
+            with_compression(&[Compression::Zstd, Compression::Gzip]),
+            with_compression(&[Compression::Gzip]),
+        )
+        .await;
+        assert_eq!(client.unwrap().compression, Compression::Gzip);
+        assert_eq!(server.unwrap().compression, Compression::Gzip);
+
+        // Disjoint: the handshake still succeeds, without compression
+        let (client, server) = handshake_with(
+            with_compression(&[Compression::Zstd]),
+            with_compression(&[Compression::Gzip]),
+        )
+        .await;
+        assert_eq!(client.unwrap().compression, Compression::None);
+        assert_eq!(server.unwrap().compression, Compression::None);
+    }
+
+    #[tokio::test]
+    async fn test_session_event_names_both_fingerprints() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+        let server_fp = server_keys.fingerprint().unwrap();
+        let client_fp = client_keys.fingerprint().unwrap();
+
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &whitelist, &server_keys, &HandshakeOptions::default()).await
+        });
+        let client_outcome = Handshake::client_side(&mut client, "secret", &client_keys, &HandshakeOptions::default())
+            .await
+            .unwrap();
+        let server_outcome = server_task.await.unwrap().unwrap();
+
+        assert_eq!(
+            server_outcome.session.to_string(),
+            format!("session: local={} remote={}", server_fp, client_fp)
+        );
+        assert_eq!(
+            client_outcome.session.to_string(),
+            format!("session: local={} remote={}", client_fp, server_fp)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replayed_response_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+
+        // First connection: an honest client whose response gets captured
+        let (mut client, mut server) = duplex(64 * 1024);
+        let (wl, keys) = (whitelist.clone(), server_keys.clone());
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &wl, &keys, &HandshakeOptions::default()).await
+        });
+
+        client.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let challenge_msg = receive_message(&mut client).await.unwrap();
+        let challenge = AuthChallenge::from_bytes(&challenge_msg.payload).unwrap();
+        let response = build_auth_response(&challenge, "secret", &client_keys, &HandshakeOptions::default()).unwrap();
+        let captured = Message::new(MessageType::AuthResponse, response.to_bytes().unwrap());
+        send_message(&mut client, &captured).await.unwrap();
+        send_public_key(&mut client, &client_keys.public_key).await.unwrap();
+
+        let result = receive_message(&mut client).await.unwrap();
+        assert!(matches!(result.msg_type, MessageType::AuthSuccess));
+        receive_public_key(&mut client).await.unwrap();
+        server_task.await.unwrap().unwrap();
+
+        // Second connection: replay the captured response against a fresh challenge
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &whitelist, &server_keys, &HandshakeOptions::default()).await
+        });
+
+        client.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        receive_message(&mut client).await.unwrap();
+        send_message(&mut client, &captured).await.unwrap();
+        send_public_key(&mut client, &client_keys.public_key).await.unwrap();
+
+        let result = receive_message(&mut client).await.unwrap();
+        assert!(matches!(result.msg_type, MessageType::AuthFailure));
+        assert!(matches!(server_task.await.unwrap(), Err(AppError::Auth(_))));
+    }
+
+    #[tokio::test]
+    async fn test_rewritten_challenge_timestamp_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &whitelist, &server_keys, &HandshakeOptions::default()).await
+        });
+
+        // A man in the middle shifts the timestamp before the client signs it
+        client.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let challenge_msg = receive_message(&mut client).await.unwrap();
+        let issued = AuthChallenge::from_bytes(&challenge_msg.payload).unwrap();
+        let mut rewritten = issued.clone();
+        rewritten.timestamp = "2000-01-01T00:00:00+00:00".to_string();
+
+        let response = build_auth_response(&rewritten, "secret", &client_keys, &HandshakeOptions::default()).unwrap();
+        let (pss, key, signature) = (AuthMethod::PssSha256, &client_keys.public_key, &response.challenge_response);
+        assert!(verify_with(pss, key, &issued.signed_material(), signature).is_err());
+        assert!(verify_with(pss, key, &rewritten.signed_material(), signature).is_ok());
+
+        let msg = Message::new(MessageType::AuthResponse, response.to_bytes().unwrap());
+        send_message(&mut client, &msg).await.unwrap();
+        send_public_key(&mut client, &client_keys.public_key).await.unwrap();
+
+        let result = receive_message(&mut client).await.unwrap();
+        assert!(matches!(result.msg_type, MessageType::AuthFailure));
+        assert!(matches!(server_task.await.unwrap(), Err(AppError::Auth(_))));
+    }
+
+    #[tokio::test]
+    async fn test_identity_is_informational() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+
+        for identity in [Some("acme-prod-1"), None] {
+            let (mut client, mut server) = duplex(64 * 1024);
+            let (wl, keys) = (whitelist.clone(), server_keys.clone());
+            let server_task = tokio::spawn(async move {
+                Handshake::server_side(&mut server, &wl, &keys, &HandshakeOptions::default()).await
+            });
+
+            let options = HandshakeOptions {
+                identity: identity.map(|i| i.to_string()),
+                ..HandshakeOptions::default()
+            };
+            Handshake::client_side(&mut client, "secret", &client_keys, &options).await.unwrap();
+
+            let outcome = server_task.await.unwrap().unwrap();
+            assert_eq!(outcome.peer_identity.as_deref(), identity);
+        }
+    }
+
+    #[test]
+    fn test_identity_validation() {
+        assert!(validate_identity("acme-prod-1").is_ok());
+        assert!(validate_identity("").is_err());
+        assert!(validate_identity("acme prod").is_err());
+        assert!(validate_identity(&"a".repeat(crate::protocol::MAX_IDENTITY_LEN + 1)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_http_request_rejected_quickly() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let (mut client, mut server) = duplex(64 * 1024);
+
+        client.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+        let result = Handshake::server_side(&mut server, &whitelist, &server_keys, &HandshakeOptions::default()).await;
+        match result {
+            Err(AppError::Protocol(msg)) => assert_eq!(msg, "not a finapp connection"),
+            _ => panic!("expected a protocol error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_message_type_named_in_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+
+        // Server expects an AuthResponse but the "client" echoes a challenge
+        let (mut client, mut server) = duplex(64 * 1024);
+        client.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &whitelist, &server_keys, &HandshakeOptions::default()).await
+        });
+        let challenge = receive_message(&mut client).await.unwrap();
+        send_message(&mut client, &challenge).await.unwrap();
+
+        match server_task.await.unwrap() {
+            Err(AppError::Protocol(msg)) => assert_eq!(msg, "expected AuthResponse, got AuthChallenge"),
+            _ => panic!("expected a protocol error"),
+        }
+
+        // Client expects a challenge but receives an acknowledgment
+        let (mut client, mut server) = duplex(64 * 1024);
+        let client_keys = KeyPair::generate().unwrap();
+        let client_task = tokio::spawn(async move {
+            Handshake::client_side(&mut client, "secret", &client_keys, &HandshakeOptions::default()).await
+        });
+        expect_magic(&mut server).await.unwrap();
+        send_message(&mut server, &Message::new(MessageType::Acknowledgment, Vec::new())).await.unwrap();
+
+        match client_task.await.unwrap() {
+            Err(AppError::Protocol(msg)) => assert_eq!(msg, "expected AuthChallenge, got Acknowledgment"),
+            _ => panic!("expected a protocol error"),
+        }
+    }
+
+    /// Play the client up to its response (a fresh one unless `response` is given) and return what was sent
+    async fn respond_to_challenge(
+        client: &mut tokio::io::DuplexStream,
+        client_keys: &KeyPair,
+        response: Option<AuthResponse>,
+    ) -> AuthResponse {
+        client.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let challenge_msg = receive_message(client).await.unwrap();
+        let challenge = AuthChallenge::from_bytes(&challenge_msg.payload).unwrap();
+        let response = match response {
+            Some(response) => response,
+            None => build_auth_response(&challenge, "secret", client_keys, &HandshakeOptions::default()).unwrap(),
+        };
+        send_message(client, &Message::new(MessageType::AuthResponse, response.to_bytes().unwrap())).await.unwrap();
+        send_public_key(client, &client_keys.public_key).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_replayed_response_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = Arc::new(whitelist_with(dir.path(), "secret"));
+        let server_keys = Arc::new(KeyPair::generate().unwrap());
+        let client_keys = KeyPair::generate().unwrap();
+        let serve = |mut server: tokio::io::DuplexStream| {
+            let (whitelist, server_keys) = (Arc::clone(&whitelist), Arc::clone(&server_keys));
+            tokio::spawn(async move {
+                Handshake::server_side(&mut server, whitelist.as_ref(), &server_keys, &HandshakeOptions::default()).await
+            })
+        };
+
+        let (mut client, server) = duplex(64 * 1024);
+        let server_task = serve(server);
+        let captured = respond_to_challenge(&mut client, &client_keys, None).await;
+        assert!(server_task.await.unwrap().is_ok());
+
+        // The same response sent on a new connection answers a challenge that
+        // server never issued there
+        let (mut client, server) = duplex(64 * 1024);
+        let server_task = serve(server);
+        respond_to_challenge(&mut client, &client_keys, Some(captured)).await;
+        assert!(matches!(receive_message(&mut client).await.unwrap().msg_type, MessageType::AuthFailure));
+        assert!(matches!(server_task.await.unwrap(), Err(AppError::Auth(_))));
+    }
+
+    #[tokio::test]
+    async fn test_response_to_expired_challenge_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = whitelist_with(dir.path(), "secret");
+        let server_keys = KeyPair::generate().unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+        let clock = crate::clock::MockClock::new(chrono::Utc::now());
+        let options = HandshakeOptions { clock: Arc::new(clock.clone()), ..HandshakeOptions::default() };
+
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &whitelist, &server_keys, &options).await
+        });
+        client.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let challenge_msg = receive_message(&mut client).await.unwrap();
+        let challenge = AuthChallenge::from_bytes(&challenge_msg.payload).unwrap();
+        let response = build_auth_response(&challenge, "secret", &client_keys, &HandshakeOptions::default()).unwrap();
+
+        clock.advance(crate::auth::TOKEN_LIFETIME);
+        send_message(&mut client, &Message::new(MessageType::AuthResponse, response.to_bytes().unwrap())).await.unwrap();
+        send_public_key(&mut client, &client_keys.public_key).await.unwrap();
+
+        let result = receive_message(&mut client).await.unwrap();
+        assert!(matches!(result.msg_type, MessageType::AuthFailure));
+        assert_eq!(result.payload, b"Challenge expired");
+        assert!(matches!(server_task.await.unwrap(), Err(AppError::Auth(_))));
+    }
 }
@@ -1,8 +1,21 @@
 use serde::{Serialize, Deserialize};
 use crate::error::{AppError, Result};
+use crate::auth::TOKEN_LIFETIME;
+use crate::clock::{Clock, SystemClock};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use crate::crypto::{AuthMethod, CipherSuite, StreamKey, sign, verify};
+use crate::compression::Compression;
+use crate::protocol::handshake::PROTOCOL_VERSION;
+use crate::protocol::received::MessageMeta;
+
+/// Maximum length in bytes of the optional note attached to a message
+pub const MAX_NOTE_BYTES: usize = 1024;
+
+/// Maximum length of the informational client identity
+pub const MAX_IDENTITY_LEN: usize = 64;
 
 /// Message types for protocol communication
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
     /// Authentication challenge from server
     AuthChallenge,
@@ -22,6 +35,45 @@ pub enum MessageType {
     Acknowledgment,
     /// Error message
     Error,
+    /// Application-defined payload exchanged over an `AuthenticatedChannel`
+    Custom,
+    /// Ask for the checksum of the file just delivered
+    VerifyRequest,
+    /// Checksum of the stored file, read back from disk
+    VerifyResponse,
+    /// Why the sender is about to close the connection
+    Disconnect,
+    /// Liveness check on an idle connection, echoed back as `Pong`
+    Ping,
+    /// Reply to `Ping`, carrying the same payload
+    Pong,
+    /// The sender has no more transfers on this connection
+    EndOfBatch,
+    /// One sealed chunk of a chunked transfer
+    MessageChunk,
+}
+
+impl MessageType {
+    /// Every message type, in wire order
+    pub const ALL: [MessageType; 17] = [
+        MessageType::AuthChallenge,
+        MessageType::AuthResponse,
+        MessageType::AuthSuccess,
+        MessageType::AuthFailure,
+        MessageType::PublicKeyExchange,
+        MessageType::MessageHeader,
+        MessageType::MessageData,
+        MessageType::Acknowledgment,
+        MessageType::Error,
+        MessageType::Custom,
+        MessageType::VerifyRequest,
+        MessageType::VerifyResponse,
+        MessageType::Disconnect,
+        MessageType::Ping,
+        MessageType::Pong,
+        MessageType::EndOfBatch,
+        MessageType::MessageChunk,
+    ];
 }
 
 /// Main message structure
@@ -33,12 +85,26 @@ pub struct Message {
     pub payload: Vec<u8>,
 }
 
+impl std::fmt::Display for MessageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+
 impl Message {
     /// Create a new message
     pub fn new(msg_type: MessageType, payload: Vec<u8>) -> Self {
         Self { msg_type, payload }
     }
 
+    /// Fail with an error naming both types unless this is an `expected` message
+    pub fn expect_type(&self, expected: MessageType) -> Result<()> {
+        if self.msg_type != expected {
+            return Err(unexpected_message(&[expected], self.msg_type));
+        }
+        Ok(())
+    }
+
     /// Serialize message to bytes
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
         bincode::serialize(self)
@@ -52,17 +118,64 @@ impl Message {
     }
 }
 
+/// Error for a message of type `got` where one of `expected` was required
+pub fn unexpected_message(expected: &[MessageType], got: MessageType) -> AppError {
+    let expected = expected
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>()
+        .join(" or ");
+    AppError::Protocol(format!("expected {}, got {}", expected, got))
+}
+
+/// Kind of content carried by a transfer
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentKind {
+    /// A single file
+    #[default]
+    File,
+    /// A directory packed as a tar archive
+    Directory,
+}
+
 /// Message header with metadata
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// Unlike the other payloads, the header is encoded as a MessagePack map
+/// keyed by field name, so it can grow without breaking older peers: a
+/// receiver ignores fields it does not know, and fields missing from an
+/// older sender take their defaults. Only the first four fields are
+/// required; anything added later must be `#[serde(default)]`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MessageHeader {
     /// Original filename
     pub filename: String,
-    /// Size of the encrypted data
+    /// Size of the encrypted data; for a chunked transfer, of all sealed chunks together
     pub size: u64,
     /// Timestamp when message was sent
     pub timestamp: String,
     /// SHA-256 checksum of original data
     pub checksum: String,
+    /// Optional human-readable note sent alongside the file
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Kind of content carried by the transfer
+    #[serde(default)]
+    pub content: ContentKind,
+    /// Position of this transfer within the connection, echoed in its ack
+    #[serde(default)]
+    pub sequence: u64,
+    /// Compression applied to the data before encryption
+    #[serde(default)]
+    pub compression: Compression,
+    /// How long the receiver should keep the message, in seconds
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Key of a chunked transfer, whose data then follows as `MessageChunk`s
+    #[serde(default)]
+    pub stream_key: Option<StreamKey>,
+    /// Hex RSA-PSS signature by the sender over [`MessageHeader::signed_material`]
+    #[serde(default)]
+    pub signature: Option<String>,
 }
 
 impl MessageHeader {
@@ -73,57 +186,483 @@ impl MessageHeader {
             size,
             timestamp: chrono::Utc::now().to_rfc3339(),
             checksum: checksum.to_string(),
+            note: None,
+            content: ContentKind::File,
+            sequence: 0,
+            compression: Compression::None,
+            ttl_secs: None,
+            stream_key: None,
+            signature: None,
+        }
+    }
+
+    /// Announce a chunked transfer sealed under `stream_key`
+    pub fn with_stream_key(mut self, stream_key: Option<StreamKey>) -> Self {
+        self.stream_key = stream_key;
+        self
+    }
+
+    /// Ask the receiver to keep the message for `ttl_secs` seconds
+    pub fn with_ttl(mut self, ttl_secs: Option<u64>) -> Self {
+        self.ttl_secs = ttl_secs;
+        self
+    }
+
+    /// Record the compression applied to the data
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Set the sequence id echoed back in the acknowledgment
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        self.sequence = sequence;
+        self
+    }
+
+    /// Set the kind of content carried by the transfer
+    pub fn with_content(mut self, content: ContentKind) -> Self {
+        self.content = content;
+        self
+    }
+
+    /// Attach a note to the header
+    pub fn with_note(mut self, note: Option<&str>) -> Self {
+        self.note = note.map(|n| n.to_string());
+        self
+    }
+
+    /// `filename || "\n" || checksum`, what a sender signs to vouch for the content
+    pub fn signed_material(&self) -> Vec<u8> {
+        format!("{}\n{}", self.filename, self.checksum).into_bytes()
+    }
+
+    /// Sign the filename and checksum with the sender's key
+    pub fn signed_with(mut self, private_key: &RsaPrivateKey) -> Result<Self> {
+        self.signature = Some(hex::encode(sign(private_key, &self.signed_material())?));
+        Ok(self)
+    }
+
+    /// Check the sender's signature, failing if there is none
+    pub fn verify_signature(&self, public_key: &RsaPublicKey) -> Result<()> {
+        let Some(signature) = &self.signature else {
+            return Err(AppError::Auth(format!("Message header for {} is not signed", self.filename)));
+        };
+        let signature = hex::decode(signature)
+            .map_err(|e| AppError::Protocol(format!("Malformed header signature: {}", e)))?;
+        verify(public_key, &self.signed_material(), &signature)
+    }
+
+    /// Validate header fields that are bounded by the protocol
+    pub fn validate(&self) -> Result<()> {
+        if let Some(note) = &self.note {
+            if note.len() > MAX_NOTE_BYTES {
+                return Err(AppError::Protocol(format!(
+                    "Note is {} bytes, maximum is {}",
+                    note.len(),
+                    MAX_NOTE_BYTES
+                )));
+            }
         }
+        Ok(())
     }
 
     /// Serialize to bytes
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self)
+        rmp_serde::to_vec_named(self)
             .map_err(|e| AppError::Protocol(format!("Failed to serialize header: {}", e)))
     }
 
     /// Deserialize from bytes
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        bincode::deserialize(data)
+        rmp_serde::from_slice(data)
             .map_err(|e| AppError::Protocol(format!("Failed to deserialize header: {}", e)))
     }
 }
 
+/// Category of a [`Disconnect`], so the peer can tell failures apart
+/// without parsing the message text
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Connect key, signature or identity was refused
+    AuthFailed,
+    /// The peer took too long
+    Timeout,
+    /// A message exceeded a size limit
+    TooLarge,
+    /// The peer broke the protocol
+    ProtocolError,
+    /// The request was understood but refused by policy
+    Rejected,
+    /// Something failed on the closing side itself
+    Internal,
+    /// The closing side is shutting down
+    Shutdown,
+    /// The peer has used up its quota for the current window
+    QuotaExceeded,
+}
+
+impl DisconnectReason {
+    /// Reason that best describes closing because of `err`
+    pub fn for_error(err: &AppError) -> Self {
+        match err {
+            AppError::Auth(_) => DisconnectReason::AuthFailed,
+            AppError::Protocol(_) => DisconnectReason::ProtocolError,
+            _ => DisconnectReason::Internal,
+        }
+    }
+
+    /// Stable code as logged and shown to users
+    pub fn code(self) -> &'static str {
+        match self {
+            DisconnectReason::AuthFailed => "auth-failed",
+            DisconnectReason::Timeout => "timeout",
+            DisconnectReason::TooLarge => "too-large",
+            DisconnectReason::ProtocolError => "protocol-error",
+            DisconnectReason::Rejected => "rejected",
+            DisconnectReason::Internal => "internal",
+            DisconnectReason::Shutdown => "shutdown",
+            DisconnectReason::QuotaExceeded => "quota-exceeded",
+        }
+    }
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Payload of a `Disconnect` message, sent best-effort before closing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Disconnect {
+    /// Category of the failure
+    pub reason: DisconnectReason,
+    /// Human-readable detail
+    pub message: String,
+}
+
+impl Disconnect {
+    /// Create a new disconnect notice
+    pub fn new(reason: DisconnectReason, message: &str) -> Self {
+        Self {
+            reason,
+            message: message.to_string(),
+        }
+    }
+
+    /// Notice for closing because of `err`
+    pub fn for_error(err: &AppError) -> Self {
+        Self::new(DisconnectReason::for_error(err), &err.to_string())
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self)
+            .map_err(|e| AppError::Protocol(format!("Failed to serialize disconnect: {}", e)))
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data)
+            .map_err(|e| AppError::Protocol(format!("Failed to deserialize disconnect: {}", e)))
+    }
+}
+
+impl std::fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.reason, self.message)
+    }
+}
+
+/// Payload of an `Acknowledgment` message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TransferAck {
+    /// Sequence id of the acknowledged transfer
+    pub sequence: u64,
+    /// Name the server stored the transfer under
+    pub saved_as: String,
+}
+
+impl TransferAck {
+    /// Create a new acknowledgment
+    pub fn new(sequence: u64, saved_as: &str) -> Self {
+        Self {
+            sequence,
+            saved_as: saved_as.to_string(),
+        }
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self)
+            .map_err(|e| AppError::Protocol(format!("Failed to serialize acknowledgment: {}", e)))
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data)
+            .map_err(|e| AppError::Protocol(format!("Failed to deserialize acknowledgment: {}", e)))
+    }
+}
+
+/// Payload of a `MessageChunk` message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MessageChunk {
+    /// Position of the chunk within its transfer, starting at 0
+    pub index: u32,
+    /// Whether this is the transfer's final chunk
+    pub last: bool,
+    /// Sealed chunk data
+    pub data: Vec<u8>,
+}
+
+impl MessageChunk {
+    /// Create a new chunk
+    pub fn new(index: u32, last: bool, data: Vec<u8>) -> Self {
+        Self { index, last, data }
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self)
+            .map_err(|e| AppError::Protocol(format!("Failed to serialize chunk: {}", e)))
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data)
+            .map_err(|e| AppError::Protocol(format!("Failed to deserialize chunk: {}", e)))
+    }
+}
+
+/// Payload of a `VerifyRequest` message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct VerifyRequest {
+    /// Name the server acknowledged the transfer under
+    pub saved_as: String,
+}
+
+impl VerifyRequest {
+    /// Create a new verification request
+    pub fn new(saved_as: &str) -> Self {
+        Self {
+            saved_as: saved_as.to_string(),
+        }
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self)
+            .map_err(|e| AppError::Protocol(format!("Failed to serialize verify request: {}", e)))
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data)
+            .map_err(|e| AppError::Protocol(format!("Failed to deserialize verify request: {}", e)))
+    }
+}
+
+/// Payload of a `VerifyResponse` message
+///
+/// Servers before protocol version 3 sent only the name and checksum; such a
+/// response decodes with no `meta`. The metadata travels as JSON, so fields
+/// later added to the sidecar do not change the binary layout again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VerifyResponse {
+    /// Name of the stored file
+    pub saved_as: String,
+    /// SHA-256 of the file's plaintext as read back from storage
+    pub checksum: String,
+    /// Metadata the server recorded for the file
+    #[serde(with = "meta_as_json")]
+    pub meta: Option<MessageMeta>,
+}
+
+impl VerifyResponse {
+    /// Create a new verification response
+    pub fn new(saved_as: &str, checksum: &str) -> Self {
+        Self {
+            saved_as: saved_as.to_string(),
+            checksum: checksum.to_string(),
+            meta: None,
+        }
+    }
+
+    /// Include the metadata the server recorded for the file
+    pub fn with_meta(mut self, meta: Option<MessageMeta>) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self)
+            .map_err(|e| AppError::Protocol(format!("Failed to serialize verify response: {}", e)))
+    }
+
+    /// Deserialize from bytes, accepting a response without metadata from an older server
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data)
+            .or_else(|_| bincode::deserialize::<VerifyResponseV2>(data).map(Self::from))
+            .map_err(|e| AppError::Protocol(format!("Failed to deserialize verify response: {}", e)))
+    }
+}
+
+/// Layout of [`VerifyResponse`] before it carried metadata
+#[derive(Serialize, Deserialize)]
+struct VerifyResponseV2 {
+    saved_as: String,
+    checksum: String,
+}
+
+impl From<VerifyResponseV2> for VerifyResponse {
+    fn from(response: VerifyResponseV2) -> Self {
+        Self::new(&response.saved_as, &response.checksum)
+    }
+}
+
+/// Encodes [`VerifyResponse::meta`] as a JSON string inside the bincode payload
+mod meta_as_json {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Error as _;
+    use serde::ser::Error as _;
+    use crate::protocol::received::MessageMeta;
+
+    pub fn serialize<S: Serializer>(meta: &Option<MessageMeta>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        meta.as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(S::Error::custom)?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Option<MessageMeta>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|json| serde_json::from_str(&json))
+            .transpose()
+            .map_err(D::Error::custom)
+    }
+}
+
 /// Authentication challenge
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AuthChallenge {
     /// Random challenge bytes
     pub challenge: Vec<u8>,
-    /// Timestamp
+    /// When the challenge was issued; covered by the `pss-sha256` signature
     pub timestamp: String,
+    /// Ephemeral nonce identifying the server side of this connection
+    pub server_nonce: Vec<u8>,
+    /// Authentication methods the server accepts
+    pub auth_methods: Vec<AuthMethod>,
+    /// Wire protocol version the server speaks
+    pub protocol_version: u32,
 }
 
 impl AuthChallenge {
     /// Create a new challenge
     pub fn new() -> Self {
+        Self::new_at(&SystemClock)
+    }
+
+    /// Create a new challenge stamped with `clock`'s time
+    pub fn new_at(clock: &dyn Clock) -> Self {
         use rand::Rng;
         let mut rng = rand::thread_rng();
         let challenge: Vec<u8> = (0..32).map(|_| rng.gen::<u8>()).collect();
+        let server_nonce: Vec<u8> = (0..16).map(|_| rng.gen::<u8>()).collect();
 
         Self {
             challenge,
-            timestamp: chrono::Utc::now().to_rfc3339(),
+            timestamp: clock.now().to_rfc3339(),
+            server_nonce,
+            auth_methods: AuthMethod::ALL.to_vec(),
+            protocol_version: PROTOCOL_VERSION,
         }
     }
 
+    /// Advertise the authentication methods the server accepts
+    pub fn with_auth_methods(mut self, methods: &[AuthMethod]) -> Self {
+        self.auth_methods = methods.to_vec();
+        self
+    }
+
+    /// Whether a response to this challenge may still be accepted at `clock`'s time
+    ///
+    /// A challenge is good for [`TOKEN_LIFETIME`] after it was issued, like an
+    /// [`AuthToken`](crate::auth::AuthToken); an unreadable timestamp never is.
+    pub fn is_fresh_at(&self, clock: &dyn Clock) -> bool {
+        chrono::DateTime::parse_from_rfc3339(&self.timestamp)
+            .is_ok_and(|issued| clock.now().signed_duration_since(issued) < TOKEN_LIFETIME)
+    }
+
+    /// Bytes the client signs with `method`
+    ///
+    /// The legacy scheme only ever covered the raw challenge bytes.
+    pub fn material_for(&self, method: AuthMethod) -> Vec<u8> {
+        match method {
+            AuthMethod::LegacyDecrypt => self.challenge.clone(),
+            AuthMethod::PssSha256 => self.signed_material(),
+        }
+    }
+
+    /// Bytes the client signs to prove possession of its private key
+    ///
+    /// `challenge || server_nonce || timestamp`, so a signature is only valid
+    /// for the exact challenge issued on one connection.
+    pub fn signed_material(&self) -> Vec<u8> {
+        let mut material = Vec::with_capacity(
+            self.challenge.len() + self.server_nonce.len() + self.timestamp.len(),
+        );
+        material.extend_from_slice(&self.challenge);
+        material.extend_from_slice(&self.server_nonce);
+        material.extend_from_slice(self.timestamp.as_bytes());
+        material
+    }
+
     /// Serialize to bytes
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
         bincode::serialize(self)
             .map_err(|e| AppError::Protocol(format!("Failed to serialize challenge: {}", e)))
     }
 
-    /// Deserialize from bytes
+    /// Deserialize from bytes, accepting the version 2 layout without `protocol_version`
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
         bincode::deserialize(data)
+            .or_else(|_| bincode::deserialize::<AuthChallengeV2>(data).map(Self::from))
             .map_err(|e| AppError::Protocol(format!("Failed to deserialize challenge: {}", e)))
     }
 }
 
+/// Layout of [`AuthChallenge`] before peers announced their protocol version
+#[derive(Serialize, Deserialize)]
+struct AuthChallengeV2 {
+    challenge: Vec<u8>,
+    timestamp: String,
+    server_nonce: Vec<u8>,
+    auth_methods: Vec<AuthMethod>,
+}
+
+impl From<AuthChallengeV2> for AuthChallenge {
+    fn from(challenge: AuthChallengeV2) -> Self {
+        Self {
+            challenge: challenge.challenge,
+            timestamp: challenge.timestamp,
+            server_nonce: challenge.server_nonce,
+            auth_methods: challenge.auth_methods,
+            protocol_version: 2,
+        }
+    }
+}
+
+impl Default for AuthChallenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Authentication response
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AuthResponse {
@@ -131,8 +670,19 @@ pub struct AuthResponse {
     pub connect_key_hash: String,
     /// Signed challenge
     pub challenge_response: Vec<u8>,
-    /// Timestamp
+    /// When the response was built; informational only, since it is not
+    /// signed. Freshness comes from the signed challenge timestamp.
     pub timestamp: String,
+    /// Cipher suites the client is able to use
+    pub cipher_suites: Vec<CipherSuite>,
+    /// Informational client identity; never used for authorization
+    pub identity: Option<String>,
+    /// How `challenge_response` was produced
+    pub auth_method: AuthMethod,
+    /// Compression algorithms the client is willing to use, preferred first
+    pub compression: Vec<Compression>,
+    /// Wire protocol version the client speaks
+    pub protocol_version: u32,
 }
 
 impl AuthResponse {
@@ -142,22 +692,123 @@ impl AuthResponse {
             connect_key_hash,
             challenge_response,
             timestamp: chrono::Utc::now().to_rfc3339(),
+            cipher_suites: CipherSuite::ALL.to_vec(),
+            identity: None,
+            auth_method: AuthMethod::PssSha256,
+            compression: Vec::new(),
+            protocol_version: PROTOCOL_VERSION,
         }
     }
 
+    /// Set the compression algorithms offered to the server
+    pub fn with_compression(mut self, algorithms: &[Compression]) -> Self {
+        self.compression = algorithms.to_vec();
+        self
+    }
+
+    /// Record the method used to answer the challenge
+    pub fn with_auth_method(mut self, method: AuthMethod) -> Self {
+        self.auth_method = method;
+        self
+    }
+
+    /// Set the informational client identity
+    pub fn with_identity(mut self, identity: Option<&str>) -> Self {
+        self.identity = identity.map(|i| i.to_string());
+        self
+    }
+
+    /// Set the cipher suites offered to the server
+    pub fn with_cipher_suites(mut self, suites: &[CipherSuite]) -> Self {
+        self.cipher_suites = suites.to_vec();
+        self
+    }
+
     /// Serialize to bytes
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
         bincode::serialize(self)
             .map_err(|e| AppError::Protocol(format!("Failed to serialize response: {}", e)))
     }
 
-    /// Deserialize from bytes
+    /// Deserialize from bytes, accepting the version 2 layout without `protocol_version`
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
         bincode::deserialize(data)
+            .or_else(|_| bincode::deserialize::<AuthResponseV2>(data).map(Self::from))
             .map_err(|e| AppError::Protocol(format!("Failed to deserialize response: {}", e)))
     }
 }
 
+/// Layout of [`AuthResponse`] before peers announced their protocol version
+#[derive(Serialize, Deserialize)]
+struct AuthResponseV2 {
+    connect_key_hash: String,
+    challenge_response: Vec<u8>,
+    timestamp: String,
+    cipher_suites: Vec<CipherSuite>,
+    identity: Option<String>,
+    auth_method: AuthMethod,
+    compression: Vec<Compression>,
+}
+
+impl From<AuthResponseV2> for AuthResponse {
+    fn from(response: AuthResponseV2) -> Self {
+        Self {
+            connect_key_hash: response.connect_key_hash,
+            challenge_response: response.challenge_response,
+            timestamp: response.timestamp,
+            cipher_suites: response.cipher_suites,
+            identity: response.identity,
+            auth_method: response.auth_method,
+            compression: response.compression,
+            protocol_version: 2,
+        }
+    }
+}
+
+/// Payload of an `AuthSuccess` message: what the server chose
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthAccepted {
+    /// Cipher suite for the transfers
+    pub cipher_suite: CipherSuite,
+    /// Compression for the transfers, `None` if nothing offered is supported
+    pub compression: Compression,
+}
+
+impl AuthAccepted {
+    /// Create a new acceptance
+    pub fn new(cipher_suite: CipherSuite, compression: Compression) -> Self {
+        Self { cipher_suite, compression }
+    }
+
+    /// Serialize to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        bincode::serialize(self)
+            .map_err(|e| AppError::Protocol(format!("Failed to serialize acceptance: {}", e)))
+    }
+
+    /// Deserialize from bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        bincode::deserialize(data)
+            .map_err(|e| AppError::Protocol(format!("Invalid acceptance from server: {}", e)))
+    }
+}
+
+/// Check that a client identity is short and uses only `[A-Za-z0-9._-]`
+pub fn validate_identity(identity: &str) -> Result<()> {
+    if identity.is_empty() || identity.len() > MAX_IDENTITY_LEN {
+        return Err(AppError::Protocol(format!(
+            "Identity must be 1 to {} characters",
+            MAX_IDENTITY_LEN
+        )));
+    }
+    if !identity.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')) {
+        return Err(AppError::Protocol(
+            "Identity may only contain letters, digits, '.', '_' and '-'".to_string(),
+        ));
+    }
+    Ok(())
+}
+
 /// Calculate SHA-256 checksum
 pub fn calculate_checksum(data: &[u8]) -> String {
     use sha2::{Sha256, Digest};
@@ -171,3 +822,106 @@ pub fn verify_checksum(data: &[u8], expected: &str) -> Result<bool> {
     let actual = calculate_checksum(data);
     Ok(actual == expected)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The header as the first senders knew it
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct HeaderV1 {
+        filename: String,
+        size: u64,
+        timestamp: String,
+        checksum: String,
+    }
+
+    #[test]
+    fn test_header_compatible_across_versions() {
+        // An older sender's header leaves every newer field at its default
+        let old = HeaderV1 {
+            filename: "ledger.csv".to_string(),
+            size: 42,
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            checksum: "abc".to_string(),
+        };
+        let header = MessageHeader::from_bytes(&rmp_serde::to_vec_named(&old).unwrap()).unwrap();
+        assert_eq!((header.filename.as_str(), header.size, header.checksum.as_str()), ("ledger.csv", 42, "abc"));
+        assert_eq!(header.note, None);
+        assert_eq!(header.content, ContentKind::File);
+        assert_eq!(header.compression, Compression::None);
+
+        // An older receiver skips the fields it does not know
+        let new = MessageHeader::new("ledger.csv", 42, "abc")
+            .with_note(Some("EOD"))
+            .with_sequence(7)
+            .with_ttl(Some(60));
+        let decoded: HeaderV1 = rmp_serde::from_slice(&new.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.filename, "ledger.csv");
+        assert_eq!(decoded.timestamp, new.timestamp);
+
+        let roundtrip = MessageHeader::from_bytes(&new.to_bytes().unwrap()).unwrap();
+        assert_eq!((roundtrip.note.as_deref(), roundtrip.sequence, roundtrip.ttl_secs), (Some("EOD"), 7, Some(60)));
+    }
+
+    #[test]
+    fn test_auth_messages_compatible_with_version_2_peers() {
+        // A version 2 peer sends the layouts without a protocol version
+        let old_challenge = AuthChallengeV2 {
+            challenge: vec![7; 32],
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            server_nonce: vec![9; 16],
+            auth_methods: vec![AuthMethod::PssSha256],
+        };
+        let challenge = AuthChallenge::from_bytes(&bincode::serialize(&old_challenge).unwrap()).unwrap();
+        assert_eq!((challenge.challenge, challenge.protocol_version), (vec![7; 32], 2));
+        let old_response = AuthResponseV2 {
+            connect_key_hash: "hash".to_string(),
+            challenge_response: vec![1, 2, 3],
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            cipher_suites: vec![CipherSuite::Aes256Gcm],
+            identity: Some("acme".to_string()),
+            auth_method: AuthMethod::PssSha256,
+            compression: vec![Compression::Zstd],
+        };
+        let response = AuthResponse::from_bytes(&bincode::serialize(&old_response).unwrap()).unwrap();
+        assert_eq!((response.identity.as_deref(), response.protocol_version), (Some("acme"), 2));
+        assert_eq!(response.compression, vec![Compression::Zstd]);
+
+        // and still reads ours, ignoring the version it does not know about
+        let new = AuthChallenge::new().to_bytes().unwrap();
+        assert_eq!(AuthChallenge::from_bytes(&new).unwrap().protocol_version, PROTOCOL_VERSION);
+        assert!(bincode::deserialize::<AuthChallengeV2>(&new).is_ok());
+        let new = AuthResponse::new("hash".to_string(), Vec::new()).to_bytes().unwrap();
+        assert_eq!(AuthResponse::from_bytes(&new).unwrap().protocol_version, PROTOCOL_VERSION);
+        assert!(bincode::deserialize::<AuthResponseV2>(&new).is_ok());
+    }
+
+    #[test]
+    fn test_verify_response_compatible_with_version_2_peers() {
+        let old = VerifyResponseV2 { saved_as: "ledger.csv".to_string(), checksum: "abc".to_string() };
+        let response = VerifyResponse::from_bytes(&bincode::serialize(&old).unwrap()).unwrap();
+        assert_eq!(response, VerifyResponse::new("ledger.csv", "abc"));
+
+        let header = MessageHeader::new("ledger.csv", 3, "abc");
+        let meta = MessageMeta::new(&header, "ledger.csv", 3, "127.0.0.1:5000");
+        let new = VerifyResponse::new("ledger.csv", "abc").with_meta(Some(meta.clone()));
+        let bytes = new.to_bytes().unwrap();
+        assert_eq!(VerifyResponse::from_bytes(&bytes).unwrap().meta, Some(meta));
+        let decoded: VerifyResponseV2 = bincode::deserialize(&bytes).unwrap();
+        assert_eq!((decoded.saved_as.as_str(), decoded.checksum.as_str()), ("ledger.csv", "abc"));
+    }
+
+    #[test]
+    fn test_challenge_expires_after_token_lifetime() {
+        let clock = crate::clock::MockClock::new(chrono::Utc::now());
+        let challenge = AuthChallenge::new_at(&clock);
+        clock.advance(TOKEN_LIFETIME - chrono::Duration::milliseconds(1));
+        assert!(challenge.is_fresh_at(&clock));
+        clock.advance(chrono::Duration::milliseconds(1));
+        assert!(!challenge.is_fresh_at(&clock));
+
+        let garbled = AuthChallenge { timestamp: "yesterday".to_string(), ..AuthChallenge::new() };
+        assert!(!garbled.is_fresh_at(&SystemClock));
+    }
+}
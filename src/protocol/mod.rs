@@ -1,5 +1,15 @@
 pub mod message;
 pub mod handshake;
+pub mod session;
+pub mod channel;
+pub mod describe;
+pub mod frame;
+pub mod received;
 
-pub use message::{Message, MessageType, MessageHeader, calculate_checksum, verify_checksum};
-pub use handshake::Handshake;
+pub use message::{Message, MessageType, MessageHeader, MessageChunk, TransferAck, AuthAccepted, Disconnect, DisconnectReason, VerifyRequest, VerifyResponse, ContentKind, MAX_NOTE_BYTES, MAX_IDENTITY_LEN, validate_identity, calculate_checksum, verify_checksum};
+pub use handshake::{PROTOCOL_VERSION, Handshake, HandshakeOptions, HandshakeOutcome, SessionEvent};
+pub use session::{ReceiveSession, ClientRequest};
+pub use channel::{AuthenticatedChannel, authenticate};
+pub use describe::{ProtocolDescription, describe};
+pub use frame::{FrameCodec, LengthPrefix, MAX_CONTROL_FRAME};
+pub use received::{MessageMeta, ReceivedMessage, AcceptedMessage};
@@ -0,0 +1,179 @@
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+use crate::compression::CompressionStats;
+use crate::protocol::message::MessageHeader;
+
+/// Metadata the server records for every stored message
+///
+/// Written as a JSON sidecar next to the message, and sent back to a client
+/// that asks to verify its delivery.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageMeta {
+    /// Filename requested by the sender
+    pub filename: String,
+    /// Name the message was stored under
+    pub saved_as: String,
+    /// Size of the decrypted data in bytes
+    pub size: u64,
+    /// SHA-256 checksum of the decrypted data
+    pub checksum: String,
+    /// Timestamp set by the sender
+    pub sent_at: String,
+    /// Timestamp when the message was stored
+    pub received_at: String,
+    /// Address of the sending peer
+    pub peer: String,
+    /// Optional note sent alongside the file
+    pub note: Option<String>,
+    /// Identity the sender announced during the handshake (informational)
+    pub identity: Option<String>,
+    /// Whether the stored file is an `EncryptedMessage` rather than plaintext
+    #[serde(default)]
+    pub encrypted_at_rest: bool,
+    /// When the sender asked for the message to expire
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    /// PEM public key the sender authenticated with
+    #[serde(default)]
+    pub sender_key: Option<String>,
+    /// How the body was compressed in transit, and how long unpacking it took
+    #[serde(default)]
+    pub compression: Option<CompressionStats>,
+}
+
+impl MessageMeta {
+    /// Build metadata for a received message
+    pub fn new(header: &MessageHeader, saved_as: &str, size: u64, peer: &str) -> Self {
+        Self {
+            filename: header.filename.clone(),
+            saved_as: saved_as.to_string(),
+            size,
+            checksum: header.checksum.clone(),
+            sent_at: header.timestamp.clone(),
+            received_at: chrono::Utc::now().to_rfc3339(),
+            peer: peer.to_string(),
+            note: header.note.clone(),
+            identity: None,
+            encrypted_at_rest: false,
+            expires_at: None,
+            sender_key: None,
+            compression: None,
+        }
+    }
+
+    /// Record when the message was received
+    pub fn with_received_at(mut self, received_at: DateTime<Utc>) -> Self {
+        self.received_at = received_at.to_rfc3339();
+        self
+    }
+
+    /// Record the public key the sender authenticated with
+    pub fn with_sender_key(mut self, pem: Option<String>) -> Self {
+        self.sender_key = pem;
+        self
+    }
+
+    /// Record when the message expires
+    pub fn with_expires_at(mut self, expires_at: Option<DateTime<Utc>>) -> Self {
+        self.expires_at = expires_at.map(|t| t.to_rfc3339());
+        self
+    }
+
+    /// Record whether the stored file is encrypted at rest
+    pub fn with_encrypted_at_rest(mut self, encrypted: bool) -> Self {
+        self.encrypted_at_rest = encrypted;
+        self
+    }
+
+    /// Record what compression did to the body in transit
+    pub fn with_compression(mut self, stats: Option<CompressionStats>) -> Self {
+        self.compression = stats;
+        self
+    }
+
+    /// Record the identity the sender announced
+    pub fn with_identity(mut self, identity: Option<&str>) -> Self {
+        self.identity = identity.map(|i| i.to_string());
+        self
+    }
+}
+
+/// What was received and stored for one transfer
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReceivedMessage {
+    /// Address of the sending peer
+    pub peer: String,
+    /// Fingerprint of the sender's public key
+    pub fingerprint: String,
+    /// Filename requested by the sender
+    pub filename: String,
+    /// Path the message was stored at; `None` when a message handler took it
+    pub path: Option<PathBuf>,
+    /// Size of the decrypted data in bytes
+    pub bytes_written: u64,
+    /// SHA-256 checksum of the decrypted data
+    pub checksum: String,
+    /// Time from reading the header to storing the message
+    pub elapsed: Duration,
+    /// Metadata recorded in the message's sidecar
+    pub meta: MessageMeta,
+}
+
+/// A single transfer received without being stored
+///
+/// Returned by [`Server::accept_one`](crate::server::Server::accept_one) for
+/// callers that store the data themselves.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AcceptedMessage {
+    /// Address of the sending peer
+    pub peer: String,
+    /// Fingerprint of the sender's public key
+    pub fingerprint: String,
+    /// Filename requested by the sender
+    pub filename: String,
+    /// SHA-256 checksum of the decrypted data, already verified
+    pub checksum: String,
+    /// Decrypted (and decompressed) data
+    pub data: Vec<u8>,
+    /// Header the sender sent with the data
+    pub header: MessageHeader,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::VerifyResponse;
+
+    #[test]
+    fn test_shared_types_round_trip() {
+        let header = MessageHeader::new("ledger.csv", 6, "abc123").with_note(Some("EOD"));
+        let meta = MessageMeta::new(&header, "ledger.csv_20240101_120000.ftt", 6, "10.0.0.2:5000")
+            .with_identity(Some("acme-treasury"))
+            .with_encrypted_at_rest(true);
+        let received = ReceivedMessage {
+            peer: "10.0.0.2:5000".to_string(),
+            fingerprint: "SHA256:abc".to_string(),
+            filename: header.filename.clone(),
+            path: Some(PathBuf::from("messages/ledger.csv_20240101_120000.ftt")),
+            bytes_written: 6,
+            checksum: header.checksum.clone(),
+            elapsed: Duration::from_millis(42),
+            meta: meta.clone(),
+        };
+
+        // JSON, as in the sidecar, and bincode, as on the wire
+        let json = serde_json::to_string(&received).unwrap();
+        assert_eq!(serde_json::from_str::<ReceivedMessage>(&json).unwrap(), received);
+        let wire = VerifyResponse::new(&meta.saved_as, &meta.checksum).with_meta(Some(meta.clone()));
+        assert_eq!(VerifyResponse::from_bytes(&wire.to_bytes().unwrap()).unwrap().meta, Some(meta));
+
+        // Sidecars written before a field existed still load
+        let old: MessageMeta = serde_json::from_str(
+            r#"{"filename":"a.csv","saved_as":"a.ftt","size":1,"checksum":"00","sent_at":"t","received_at":"t","peer":"p","note":null,"identity":null}"#,
+        )
+        .unwrap();
+        assert!(!old.encrypted_at_rest && old.sender_key.is_none());
+    }
+}
@@ -0,0 +1,391 @@
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::error::{AppError, Result};
+use crate::protocol::message::{Message, MessageType, MessageHeader, MessageChunk, TransferAck, VerifyRequest, VerifyResponse, Disconnect, DisconnectReason, unexpected_message};
+use crate::cli::Output;
+use crate::protocol::handshake::{send_message, receive_message, receive_message_or_eof};
+use crate::protocol::frame::FrameCodec;
+
+/// Where a [`ReceiveSession`] is in the header-then-data sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReceiveState {
+    AwaitingHeader,
+    AwaitingData,
+    Complete,
+}
+
+/// What the client asked for next on an authenticated connection
+#[derive(Debug, Clone)]
+pub enum ClientRequest {
+    /// A new transfer, starting with its header
+    Transfer(MessageHeader),
+    /// Read back the file just delivered and report its checksum
+    Verify(VerifyRequest),
+}
+
+/// Receiving half of the transfers on a connection after the handshake
+///
+/// Reads the message header and then the length-prefixed payload, enforcing
+/// that they arrive in that order and that the payload length matches the
+/// size announced in the header. Once a transfer is acknowledged the session
+/// is ready for the next one, so a client may pipeline several files.
+pub struct ReceiveSession<'a, S> {
+    stream: &'a mut S,
+    state: ReceiveState,
+    header: Option<MessageHeader>,
+    next_chunk: u32,
+    disconnected: Option<DisconnectReason>,
+}
+
+/// How long [`ReceiveSession::linger`] keeps reading after a disconnect
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> ReceiveSession<'a, S> {
+    /// Start a session on an authenticated stream
+    pub fn new(stream: &'a mut S) -> Self {
+        Self {
+            stream,
+            state: ReceiveState::AwaitingHeader,
+            header: None,
+            next_chunk: 0,
+            disconnected: None,
+        }
+    }
+
+    /// Read and validate the message header
+    pub async fn read_header(&mut self) -> Result<MessageHeader> {
+        self.next_header()
+            .await?
+            .ok_or_else(|| AppError::Protocol("Connection closed before message header".to_string()))
+    }
+
+    /// Read the next message header, or `None` once the client has closed the stream
+    pub async fn next_header(&mut self) -> Result<Option<MessageHeader>> {
+        match self.next_request().await? {
+            Some(ClientRequest::Transfer(header)) => Ok(Some(header)),
+            Some(ClientRequest::Verify(_)) => {
+                Err(unexpected_message(&[MessageType::MessageHeader], MessageType::VerifyRequest))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read the client's next request, or `None` once it has closed the stream
+    /// or sent `EndOfBatch`
+    ///
+    /// Between transfers the client may also ask to verify the file it just
+    /// delivered. Keepalive pings are answered here and never returned.
+    pub async fn next_request(&mut self) -> Result<Option<ClientRequest>> {
+        match self.state {
+            ReceiveState::AwaitingHeader => {}
+            ReceiveState::AwaitingData => {
+                return Err(AppError::Protocol("Message header already received".to_string()));
+            }
+            ReceiveState::Complete => {
+                return Err(AppError::Protocol("Previous transfer not acknowledged".to_string()));
+            }
+        }
+
+        let msg = loop {
+            match receive_message_or_eof(self.stream).await? {
+                Some(msg) if msg.msg_type == MessageType::Ping => {
+                    send_message(self.stream, &Message::new(MessageType::Pong, msg.payload)).await?;
+                }
+                Some(msg) => break msg,
+                None => return Ok(None),
+            }
+        };
+        match msg.msg_type {
+            MessageType::VerifyRequest => {
+                return Ok(Some(ClientRequest::Verify(VerifyRequest::from_bytes(&msg.payload)?)));
+            }
+            // Same as closing the stream, but said explicitly
+            MessageType::EndOfBatch => return Ok(None),
+            // The client gave up; treat it as a close once its reason is logged
+            MessageType::Disconnect => {
+                let notice = Disconnect::from_bytes(&msg.payload)?;
+                Output::warning(&format!("Client disconnected: {}", notice));
+                return Ok(None);
+            }
+            _ => {}
+        }
+        msg.expect_type(MessageType::MessageHeader)?;
+
+        let header = MessageHeader::from_bytes(&msg.payload)?;
+        header.validate()?;
+
+        self.state = ReceiveState::AwaitingData;
+        self.header = Some(header.clone());
+        self.next_chunk = 0;
+        Ok(Some(ClientRequest::Transfer(header)))
+    }
+
+    /// Read the encrypted payload announced by the header
+    pub async fn read_data(&mut self) -> Result<Vec<u8>> {
+        let expected = match (&self.state, &self.header) {
+            (ReceiveState::AwaitingData, Some(header)) if header.stream_key.is_some() => {
+                return Err(AppError::Protocol("Chunked transfer expects MessageChunk, not raw data".to_string()));
+            }
+            (ReceiveState::AwaitingData, Some(header)) => header.size,
+            (ReceiveState::AwaitingHeader, _) => {
+                return Err(AppError::Protocol("Data received before message header".to_string()));
+            }
+            _ => return Err(AppError::Protocol("Message data already received".to_string())),
+        };
+
+        let codec = FrameCodec::data();
+        let data_len = codec
+            .read_length_or_eof(self.stream)
+            .await?
+            .ok_or_else(|| AppError::Protocol("Failed to read data length: connection closed".to_string()))?;
+
+        if data_len as u64 != expected {
+            return Err(AppError::Protocol(format!(
+                "Data length {} does not match header size {}",
+                data_len, expected
+            )));
+        }
+
+        let data = codec.read_body(self.stream, data_len).await?;
+        self.state = ReceiveState::Complete;
+        Ok(data)
+    }
+
+    /// Read the next sealed chunk of a chunked transfer
+    ///
+    /// Chunks must arrive in index order; once the last one is read the
+    /// transfer is complete.
+    pub async fn read_chunk(&mut self) -> Result<MessageChunk> {
+        match (&self.state, &self.header) {
+            (ReceiveState::AwaitingData, Some(header)) if header.stream_key.is_some() => {}
+            (ReceiveState::AwaitingData, _) => {
+                return Err(AppError::Protocol("Transfer is not chunked".to_string()));
+            }
+            (ReceiveState::AwaitingHeader, _) => {
+                return Err(AppError::Protocol("Chunk received before message header".to_string()));
+            }
+            _ => return Err(AppError::Protocol("Message data already received".to_string())),
+        }
+
+        let msg = receive_message(self.stream).await?;
+        if msg.msg_type == MessageType::Disconnect {
+            let notice = Disconnect::from_bytes(&msg.payload)?;
+            return Err(AppError::Protocol(format!("Client disconnected: {}", notice)));
+        }
+        msg.expect_type(MessageType::MessageChunk)?;
+        let chunk = MessageChunk::from_bytes(&msg.payload)?;
+        if chunk.index != self.next_chunk {
+            return Err(AppError::Protocol(format!(
+                "Chunk {} received, expected chunk {}",
+                chunk.index, self.next_chunk
+            )));
+        }
+
+        self.next_chunk += 1;
+        if chunk.last {
+            self.state = ReceiveState::Complete;
+        }
+        Ok(chunk)
+    }
+
+    /// Header received so far, if any
+    pub fn header(&self) -> Option<&MessageHeader> {
+        self.header.as_ref()
+    }
+
+    /// Report an error to the sender before the connection is closed
+    pub async fn reject(&mut self, err: &AppError) -> Result<()> {
+        self.disconnect(&Disconnect::for_error(err)).await
+    }
+
+    /// Tell the sender why the connection is about to close
+    ///
+    /// Only the first notice is sent, so a specific reason given where the
+    /// failure happened is not replaced by a generic one further up.
+    pub async fn disconnect(&mut self, notice: &Disconnect) -> Result<()> {
+        if self.disconnected.is_some() {
+            return Ok(());
+        }
+        self.disconnected = Some(notice.reason);
+        let msg = Message::new(MessageType::Disconnect, notice.to_bytes()?);
+        send_message(self.stream, &msg).await
+    }
+
+    /// Reason given in the disconnect notice, once one has been sent
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnected
+    }
+
+    /// Close our half and discard what the sender still has in flight
+    ///
+    /// Closing with unread data makes TCP reset the connection, which can
+    /// destroy the disconnect notice before the sender reads it. Reading
+    /// briefly lets a sender that is mid-upload finish and see the notice.
+    pub async fn linger(&mut self) {
+        let _ = self.stream.shutdown().await;
+        let mut buf = [0u8; 8192];
+        let _ = tokio::time::timeout(LINGER_TIMEOUT, async {
+            while let Ok(n) = self.stream.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+
+    /// Answer a verification request with the checksum read back from storage
+    pub async fn answer_verify(&mut self, response: &VerifyResponse) -> Result<()> {
+        let msg = Message::new(MessageType::VerifyResponse, response.to_bytes()?);
+        send_message(self.stream, &msg).await
+    }
+
+    /// Acknowledge the transfer with the name it was stored under
+    ///
+    /// The ack echoes the header's sequence id and readies the session for
+    /// the next transfer.
+    pub async fn acknowledge(&mut self, saved_as: &str) -> Result<()> {
+        let sequence = match (&self.state, &self.header) {
+            (ReceiveState::Complete, Some(header)) => header.sequence,
+            _ => return Err(AppError::Protocol("Cannot acknowledge an incomplete transfer".to_string())),
+        };
+
+        let ack = TransferAck::new(sequence, saved_as);
+        let msg = Message::new(MessageType::Acknowledgment, ack.to_bytes()?);
+        send_message(self.stream, &msg).await?;
+
+        self.state = ReceiveState::AwaitingHeader;
+        self.header = None;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+    use crate::protocol::handshake::{receive_message, send_raw_data};
+
+    async fn send_header<S: AsyncWrite + Unpin>(stream: &mut S, size: u64) {
+        send_header_seq(stream, size, 0).await;
+    }
+
+    async fn send_header_seq<S: AsyncWrite + Unpin>(stream: &mut S, size: u64, sequence: u64) {
+        let header = MessageHeader::new("batch", size, "abc").with_sequence(sequence);
+        let msg = Message::new(MessageType::MessageHeader, header.to_bytes().unwrap());
+        send_message(stream, &msg).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_header_then_data() {
+        let (mut client, mut server) = duplex(64 * 1024);
+        send_header(&mut client, 4).await;
+        send_raw_data(&mut client, b"data").await.unwrap();
+
+        let mut session = ReceiveSession::new(&mut server);
+        assert_eq!(session.read_header().await.unwrap().filename, "batch");
+        assert_eq!(session.read_data().await.unwrap(), b"data");
+        session.acknowledge("batch.ftt").await.unwrap();
+
+        let ack = receive_message(&mut client).await.unwrap();
+        assert!(matches!(ack.msg_type, MessageType::Acknowledgment));
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_rejected() {
+        let (mut client, mut server) = duplex(64 * 1024);
+
+        let mut session = ReceiveSession::new(&mut server);
+        assert!(session.read_data().await.is_err());
+        assert!(session.acknowledge("batch.ftt").await.is_err());
+
+        // Something other than a header arrives first
+        let msg = Message::new(MessageType::Acknowledgment, Vec::new());
+        send_message(&mut client, &msg).await.unwrap();
+        match session.read_header().await {
+            Err(AppError::Protocol(msg)) => assert_eq!(msg, "expected MessageHeader, got Acknowledgment"),
+            _ => panic!("expected a protocol error"),
+        }
+
+        send_header(&mut client, 4).await;
+        session.read_header().await.unwrap();
+        assert!(session.read_header().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_size_mismatch_rejected() {
+        let (mut client, mut server) = duplex(64 * 1024);
+        send_header(&mut client, 10).await;
+        send_raw_data(&mut client, b"data").await.unwrap();
+
+        let mut session = ReceiveSession::new(&mut server);
+        session.read_header().await.unwrap();
+        assert!(session.read_data().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_transfers_acked_by_sequence() {
+        let (mut client, mut server) = duplex(64 * 1024);
+        for sequence in [7, 8, 9] {
+            send_header_seq(&mut client, 4, sequence).await;
+            send_raw_data(&mut client, b"data").await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+
+        let mut session = ReceiveSession::new(&mut server);
+        let mut sequences = Vec::new();
+        while let Some(header) = session.next_header().await.unwrap() {
+            session.read_data().await.unwrap();
+            session.acknowledge(&format!("batch_{}.ftt", header.sequence)).await.unwrap();
+            sequences.push(header.sequence);
+        }
+        assert_eq!(sequences, [7, 8, 9]);
+
+        for sequence in [7, 8, 9] {
+            let msg = receive_message(&mut client).await.unwrap();
+            let ack = TransferAck::from_bytes(&msg.payload).unwrap();
+            assert_eq!(ack, TransferAck::new(sequence, &format!("batch_{}.ftt", sequence)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_end_of_batch_ends_session_on_open_stream() {
+        let (mut client, mut server) = duplex(64 * 1024);
+        send_header(&mut client, 4).await;
+        send_raw_data(&mut client, b"data").await.unwrap();
+        send_message(&mut client, &Message::new(MessageType::EndOfBatch, Vec::new())).await.unwrap();
+
+        let mut session = ReceiveSession::new(&mut server);
+        assert!(session.next_header().await.unwrap().is_some());
+        session.read_data().await.unwrap();
+        session.acknowledge("batch.ftt").await.unwrap();
+        // The client has not closed its half, yet the batch is over
+        assert!(session.next_header().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_chunks_must_arrive_in_order() {
+        use crate::crypto::{CipherSuite, RsaPadding, StreamKey};
+
+        let (mut client, mut server) = duplex(64 * 1024);
+        let stream_key = StreamKey {
+            suite: CipherSuite::ALL[0],
+            padding: RsaPadding::default(),
+            encrypted_key: vec![1; 256],
+            nonce_prefix: vec![2; 7],
+        };
+        let header = MessageHeader::new("batch", 64, "abc").with_stream_key(Some(stream_key));
+        send_message(&mut client, &Message::new(MessageType::MessageHeader, header.to_bytes().unwrap())).await.unwrap();
+        for index in [0, 2] {
+            let chunk = MessageChunk::new(index, false, vec![0; 16]);
+            send_message(&mut client, &Message::new(MessageType::MessageChunk, chunk.to_bytes().unwrap())).await.unwrap();
+        }
+
+        let mut session = ReceiveSession::new(&mut server);
+        session.read_header().await.unwrap();
+        assert!(session.read_data().await.is_err());
+        assert_eq!(session.read_chunk().await.unwrap().index, 0);
+        let err = session.read_chunk().await.unwrap_err();
+        assert!(err.to_string().contains("Chunk 2 received, expected chunk 1"), "{}", err);
+    }
+}
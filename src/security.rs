@@ -0,0 +1,252 @@
+use clap::ValueEnum;
+use crate::error::{AppError, Result};
+use crate::crypto::{AuthMethod, CipherSuite};
+use crate::compression::Compression;
+use crate::protocol::HandshakeOptions;
+
+/// One knob bundling the individual hardening options
+///
+/// A level is resolved into concrete handshake settings with
+/// [`SecurityLevel::apply`]; explicit options may then be layered on top,
+/// and [`SecurityLevel::check`] refuses any that fall below the level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, ValueEnum)]
+pub enum SecurityLevel {
+    /// Accept every mode old peers may still use, including payload keys
+    /// wrapped with PKCS#1 v1.5
+    Compat,
+    /// Secure defaults that still interoperate with old peers: legacy
+    /// authentication is allowed, but payload keys must be wrapped with OAEP
+    #[default]
+    Default,
+    /// Refuse legacy authentication, 128-bit ciphers, compression, peer keys
+    /// with a public exponent other than 65537, unsigned payloads and servers
+    /// whose key is not already pinned
+    Strict,
+}
+
+/// Cipher suites `strict` allows
+const STRICT_SUITES: [CipherSuite; 2] = [CipherSuite::Aes256GcmSiv, CipherSuite::Aes256Gcm];
+
+impl SecurityLevel {
+    /// Set the handshake fields this level controls
+    ///
+    /// Compression is left alone except under `strict`, which turns it off:
+    /// compressing before encrypting lets an observer learn about the
+    /// content from the ciphertext length.
+    pub fn apply(self, options: &mut HandshakeOptions) {
+        match self {
+            SecurityLevel::Compat | SecurityLevel::Default => {
+                options.min_auth_method = AuthMethod::LegacyDecrypt;
+                options.cipher_suites = CipherSuite::ALL.to_vec();
+                options.exact_public_exponent = false;
+                options.accept_pkcs1v15_payloads = self == SecurityLevel::Compat;
+                options.require_signed_payloads = false;
+                options.require_pinned_host = false;
+            }
+            SecurityLevel::Strict => {
+                options.min_auth_method = AuthMethod::PssSha256;
+                options.cipher_suites = STRICT_SUITES.to_vec();
+                options.compression = Vec::new();
+                options.exact_public_exponent = true;
+                options.accept_pkcs1v15_payloads = false;
+                options.require_signed_payloads = true;
+                options.require_pinned_host = true;
+            }
+        }
+    }
+
+    /// Fail if `options` are weaker than this level allows
+    pub fn check(self, options: &HandshakeOptions) -> Result<()> {
+        if self != SecurityLevel::Strict {
+            return Ok(());
+        }
+        if !options.min_auth_method.meets(AuthMethod::PssSha256) {
+            return Err(AppError::Config(format!(
+                "Security level strict requires auth method {} or stronger, not {}",
+                AuthMethod::PssSha256,
+                options.min_auth_method
+            )));
+        }
+        if let Some(suite) = options.cipher_suites.iter().find(|s| !STRICT_SUITES.contains(s)) {
+            return Err(AppError::Config(format!("Security level strict does not allow {}", suite)));
+        }
+        if options.compression.iter().any(|c| *c != Compression::None) {
+            return Err(AppError::Config("Security level strict does not allow compression".to_string()));
+        }
+        if options.accept_pkcs1v15_payloads {
+            return Err(AppError::Config("Security level strict requires OAEP-wrapped payload keys".to_string()));
+        }
+        if !options.require_signed_payloads || !options.require_pinned_host {
+            return Err(AppError::Config("Security level strict requires signed payloads and pinned server keys".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Apply this level, then the explicit overrides, and check the result
+    pub fn resolve(
+        self,
+        mut options: HandshakeOptions,
+        min_auth_method: Option<AuthMethod>,
+        compression: Option<Vec<Compression>>,
+    ) -> Result<HandshakeOptions> {
+        self.apply(&mut options);
+        if let Some(min) = min_auth_method {
+            options.min_auth_method = min;
+        }
+        if let Some(compression) = compression {
+            options.compression = compression;
+        }
+        self.check(&options)?;
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+    use crate::auth::{Whitelist, WhitelistAuthorizer};
+    use crate::crypto::KeyPair;
+    use rsa::RsaPublicKey;
+    use crate::client::Client;
+    use crate::crypto::{encrypt, encrypt_with_suite, EncryptedMessage, RsaPadding};
+    use crate::crypto::encryption::aead_encrypt;
+    use crate::protocol::{Handshake, Disconnect, DisconnectReason, Message, MessageHeader, MessageType, authenticate, calculate_checksum};
+    use crate::protocol::handshake::send_raw_data;
+    use crate::server::ServerConfig;
+    use crate::server::handler::handle_connection;
+    use crate::test_support::{spawn_test_server, TEST_CONNECT_KEY};
+
+    /// Authenticate a legacy-only client against a server at `level`
+    async fn legacy_client_against(level: SecurityLevel) -> Result<()> {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add("secret", None).unwrap();
+        let server_keys = KeyPair::generate().unwrap();
+        let server_options = level.resolve(HandshakeOptions::default(), None, None).unwrap();
+
+        let (mut client, mut server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            Handshake::server_side(&mut server, &WhitelistAuthorizer::new(whitelist), &server_keys, &server_options).await
+        });
+        let client_options = HandshakeOptions {
+            auth_methods: vec![AuthMethod::LegacyDecrypt],
+            ..HandshakeOptions::default()
+        };
+        let client_result = Handshake::client_side(&mut client, "secret", &KeyPair::generate().unwrap(), &client_options).await;
+        drop(client);
+        let server_result = server_task.await.unwrap();
+        client_result.and(server_result).map(|_| ())
+    }
+
+    /// Send one hand-built transfer to a server at `level` and return its reply
+    ///
+    /// `build` gets the server's key and the negotiated suite and returns
+    /// the header and the data frame to send.
+    async fn raw_transfer_against(
+        level: SecurityLevel,
+        build: impl FnOnce(&RsaPublicKey, CipherSuite) -> (MessageHeader, Vec<u8>),
+    ) -> Message {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add("secret", None).unwrap();
+        let server_keys = std::sync::Arc::new(KeyPair::generate().unwrap());
+        let config = ServerConfig {
+            messages_dir: dir.path().join("messages").to_string_lossy().to_string(),
+            handshake: level.resolve(HandshakeOptions::default(), None, None).unwrap(),
+            ..ServerConfig::default()
+        };
+
+        let (client, server) = duplex(64 * 1024);
+        let server_task = tokio::spawn(async move {
+            handle_connection(server, "peer", &WhitelistAuthorizer::new(whitelist), &server_keys, &config).await
+        });
+        let mut channel = authenticate(client, "secret", &KeyPair::generate().unwrap()).await.unwrap();
+        let (header, data) = build(channel.peer_public_key(), channel.cipher_suite());
+        channel.send(&Message::new(MessageType::MessageHeader, header.to_bytes().unwrap())).await.unwrap();
+        send_raw_data(channel.stream_mut(), &data).await.unwrap();
+        let reply = channel.receive().await.unwrap();
+        drop(channel);
+        let _ = server_task.await.unwrap();
+        reply
+    }
+
+    /// A payload whose key is wrapped with PKCS#1 v1.5, as peers from before OAEP send
+    fn pkcs1v15_payload(server_key: &RsaPublicKey, suite: CipherSuite) -> (MessageHeader, Vec<u8>) {
+        let data = b"ACC-001,100.00\n";
+        let (key, nonce) = (vec![7u8; suite.key_len()], vec![9u8; 12]);
+        let sealed = EncryptedMessage {
+            suite,
+            padding: RsaPadding::Pkcs1v15,
+            encrypted_key: encrypt(server_key, &key).unwrap(),
+            encrypted_data: aead_encrypt(suite, &key, &nonce, data).unwrap(),
+            nonce,
+        };
+        let bytes = sealed.to_bytes().unwrap();
+        (MessageHeader::new("ledger.csv", bytes.len() as u64, &calculate_checksum(data)), bytes)
+    }
+
+    #[tokio::test]
+    async fn test_compat_accepts_pkcs1v15_payloads() {
+        let reply = raw_transfer_against(SecurityLevel::Compat, pkcs1v15_payload).await;
+        reply.expect_type(MessageType::Acknowledgment).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_default_requires_oaep_payloads_but_allows_legacy_auth() {
+        let reply = raw_transfer_against(SecurityLevel::Default, pkcs1v15_payload).await;
+        assert!(reply.expect_type(MessageType::Acknowledgment).is_err());
+        legacy_client_against(SecurityLevel::Default).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_strict_requires_signed_payloads_and_pinned_hosts() {
+        // An unsigned header is refused before any data is stored
+        let reply = raw_transfer_against(SecurityLevel::Strict, |server_key, suite| {
+            let data = b"ACC-001,100.00\n";
+            let bytes = encrypt_with_suite(server_key, data, suite).unwrap().to_bytes().unwrap();
+            (MessageHeader::new("ledger.csv", bytes.len() as u64, &calculate_checksum(data)), bytes)
+        })
+        .await;
+        reply.expect_type(MessageType::Disconnect).unwrap();
+        assert_eq!(Disconnect::from_bytes(&reply.payload).unwrap().reason, DisconnectReason::Rejected);
+
+        // A strict client only talks to a server it has already pinned
+        let (addr, server) = spawn_test_server(|server| server.with_security_level(SecurityLevel::Strict)).await.unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let known_hosts = dir.path().join("known_hosts");
+        let keys = KeyPair::generate().unwrap();
+        let client = |level| {
+            Client::new(&addr.ip().to_string(), addr.port(), keys.clone())
+                .with_security_level(level)
+                .with_known_hosts(Some(&known_hosts))
+        };
+        let err = client(SecurityLevel::Strict).send_bytes(b"x", "a.csv", TEST_CONNECT_KEY, None, None).await.unwrap_err();
+        assert!(err.to_string().contains("No key pinned"), "{}", err);
+        assert!(!known_hosts.exists());
+
+        client(SecurityLevel::Default).send_bytes(b"x", "a.csv", TEST_CONNECT_KEY, None, None).await.unwrap();
+        client(SecurityLevel::Strict).send_bytes(b"x", "b.csv", TEST_CONNECT_KEY, None, None).await.unwrap();
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_strict_rejects_legacy_peer_and_compat_allows_it() {
+        assert!(legacy_client_against(SecurityLevel::Strict).await.is_err());
+        legacy_client_against(SecurityLevel::Compat).await.unwrap();
+    }
+
+    #[test]
+    fn test_strict_refuses_weaker_overrides() {
+        let strict = SecurityLevel::Strict;
+        assert!(strict.resolve(HandshakeOptions::default(), Some(AuthMethod::LegacyDecrypt), None).is_err());
+        assert!(strict.resolve(HandshakeOptions::default(), None, Some(vec![Compression::Gzip])).is_err());
+
+        let options = strict.resolve(HandshakeOptions::default(), None, None).unwrap();
+        assert_eq!(options.min_auth_method, AuthMethod::PssSha256);
+        assert!(options.compression.is_empty());
+
+        let compat = SecurityLevel::Compat.resolve(HandshakeOptions::default(), None, Some(vec![Compression::Gzip]));
+        assert_eq!(compat.unwrap().compression, [Compression::Gzip]);
+    }
+}
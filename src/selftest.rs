@@ -0,0 +1,60 @@
+use std::fs;
+use tokio::net::TcpListener;
+use crate::error::{AppError, Result};
+use crate::crypto::KeyPair;
+use crate::auth::Whitelist;
+use crate::server::Server;
+use crate::client::Client;
+use crate::cli::Output;
+
+/// Payload sent through the loopback transfer
+const SELFTEST_PAYLOAD: &[u8] = b"finapp selftest payload\n";
+
+/// Run a full send/receive cycle against an in-process server on loopback
+///
+/// Keys, whitelist and received files live in a temporary directory that is
+/// removed when the test finishes, so nothing on disk is touched.
+pub async fn run_selftest() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let messages_dir = dir.path().join("messages");
+    let whitelist_path = dir.path().join("whitelist.txt");
+    let payload_path = dir.path().join("payload.txt");
+
+    Output::info("Generating temporary keys");
+    let server_keys = KeyPair::generate_async().await?;
+    let client_keys = KeyPair::generate_async().await?;
+
+    let connect_key = format!("selftest-{:016x}", rand::random::<u64>());
+    Whitelist::load_or_create(&whitelist_path)?.add(&connect_key, None)?;
+    fs::write(&payload_path, SELFTEST_PAYLOAD)?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .map_err(|e| AppError::Server(format!("Failed to bind loopback listener: {}", e)))?;
+    let port = listener.local_addr()?.port();
+
+    let messages = messages_dir
+        .to_str()
+        .ok_or_else(|| AppError::Server("Temporary directory path is not valid UTF-8".to_string()))?;
+    let server = Server::new(port, &whitelist_path, server_keys, messages)?;
+    let shutdown = server.shutdown_channel();
+    let serving = tokio::spawn(async move { server.serve(listener).await });
+
+    let client = Client::new("127.0.0.1", port, client_keys);
+    let sent = client.send_message(&payload_path, &connect_key, Some("selftest"), None).await;
+
+    let _ = shutdown.send(());
+    serving
+        .await
+        .map_err(|e| AppError::Server(format!("Self-test server task failed: {}", e)))??;
+
+    let saved_as = sent?;
+    let received = fs::read(messages_dir.join(&saved_as))
+        .map_err(|e| AppError::Server(format!("Received file {} not found: {}", saved_as, e)))?;
+    if received != SELFTEST_PAYLOAD {
+        return Err(AppError::Protocol("Received file does not match the payload sent".to_string()));
+    }
+
+    Output::success("Self-test passed");
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use crate::cli::Output;
+
+/// Granularity of reservations; permits are counted in these units
+const UNIT: u64 = 1024;
+
+/// Bytes of message data all connections together may buffer at once
+///
+/// A transfer reserves the most memory it will need (its working set, a
+/// multiple of its size) before its data is read and keeps the reservation
+/// until the message is stored, so large transfers queue instead of all
+/// holding full buffers at the same time. A message bigger than the
+/// whole budget waits until it can have all of it, and then runs alone.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    units: u32,
+    permits: Semaphore,
+    reserved: AtomicU64,
+    peak: AtomicU64,
+}
+
+/// Bytes held for one transfer, returned to the budget on drop
+#[derive(Debug)]
+pub struct Reservation<'a> {
+    budget: &'a MemoryBudget,
+    units: u32,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl MemoryBudget {
+    /// Allow up to `bytes` of message data in memory at once, rounded up to whole KiB
+    pub fn new(bytes: u64) -> Self {
+        let units = bytes.div_ceil(UNIT).clamp(1, u32::MAX as u64) as u32;
+        Self {
+            units,
+            permits: Semaphore::new(units as usize),
+            reserved: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+        }
+    }
+
+    /// Size of the whole budget in bytes
+    pub fn bytes(&self) -> u64 {
+        self.units as u64 * UNIT
+    }
+
+    /// Most bytes that have been reserved at once so far
+    pub fn peak_reserved(&self) -> u64 {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// Wait until `size` bytes are free and hold them until the reservation drops
+    pub async fn reserve(&self, size: u64) -> Reservation<'_> {
+        let units = size.div_ceil(UNIT).clamp(1, self.units as u64) as u32;
+        let permit = match self.permits.try_acquire_many(units) {
+            Ok(permit) => permit,
+            Err(_) => {
+                Output::info(&format!("Waiting for {} bytes of the decrypt memory budget", size));
+                // The semaphore is never closed, so acquiring only waits
+                self.permits.acquire_many(units).await.expect("memory budget semaphore closed")
+            }
+        };
+        let reserved = self.reserved.fetch_add(units as u64 * UNIT, Ordering::SeqCst) + units as u64 * UNIT;
+        self.peak.fetch_max(reserved, Ordering::Relaxed);
+        Reservation { budget: self, units, _permit: permit }
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.reserved.fetch_sub(self.units as u64 * UNIT, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_reservations_wait_for_room() {
+        let budget = MemoryBudget::new(1024 * 1024);
+        let first = budget.reserve(700 * 1024).await;
+
+        // A second large transfer has to wait for the first
+        assert!(tokio::time::timeout(Duration::from_millis(100), budget.reserve(700 * 1024)).await.is_err());
+        drop(first);
+        let second = budget.reserve(700 * 1024).await;
+
+        // One larger than the whole budget takes all of it once free
+        drop(second);
+        let huge = budget.reserve(10 * 1024 * 1024).await;
+        assert!(budget.permits.try_acquire().is_err());
+        drop(huge);
+        assert_eq!(budget.peak_reserved(), budget.bytes());
+    }
+}
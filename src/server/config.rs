@@ -0,0 +1,136 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use clap::ValueEnum;
+use crate::protocol::HandshakeOptions;
+use crate::clock::{SharedClock, system_clock};
+use super::usage::QuotaUsage;
+use super::budget::MemoryBudget;
+use super::message_handler::MessageHandler;
+
+/// How long a server read waits for data before dropping the connection
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest encrypted message a server accepts unless configured otherwise
+pub const DEFAULT_MAX_MESSAGE_BYTES: u64 = 100 * 1024 * 1024;
+
+/// What to do when a received message would overwrite an existing file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, ValueEnum)]
+pub enum CollisionPolicy {
+    /// Store under a new name with a numbered suffix
+    #[default]
+    Suffix,
+    /// Replace the existing file
+    Overwrite,
+    /// Refuse the message
+    Reject,
+}
+
+/// What to do when a file's content contradicts its extension
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, ValueEnum)]
+pub enum TypeMismatchPolicy {
+    /// Store the file and log a warning
+    #[default]
+    Warn,
+    /// Refuse the file
+    Reject,
+}
+
+/// Settings that control how a server handles its connections
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Directory received messages are stored in
+    pub messages_dir: String,
+    /// Directory in-progress files are written to before being moved into
+    /// `messages_dir`; the messages directory itself if `None`
+    pub temp_dir: Option<String>,
+    /// Refuse to start if the messages directory already holds anything
+    pub require_empty_messages_dir: bool,
+    /// Unpack received directory archives instead of storing the tar file
+    pub extract_dirs: bool,
+    /// Behaviour when the target filename already exists
+    pub on_collision: CollisionPolicy,
+    /// Store received files encrypted to the server's own public key
+    pub encrypt_at_rest: bool,
+    /// File extensions the server accepts; empty accepts everything
+    pub allowed_extensions: Vec<String>,
+    /// Compare each received file's content with its extension
+    pub detect_type: bool,
+    /// What to do when `detect_type` finds a mismatch
+    pub on_type_mismatch: TypeMismatchPolicy,
+    /// Largest encrypted message accepted, checked against the header's size
+    /// before any of its data is read; unlimited if `None`. Chunked transfers
+    /// streamed to disk are exempt
+    pub max_message_bytes: Option<u64>,
+    /// Bytes all connections together may hold for message data, counting
+    /// every copy made while decrypting and storing; unlimited if `None`
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// Longest a read may wait without receiving any data; no limit if `None`
+    pub read_timeout: Option<Duration>,
+    /// Handshake settings (accepted cipher suites, ...)
+    pub handshake: HandshakeOptions,
+    /// Time source for received-at timestamps and stored filenames
+    pub clock: SharedClock,
+    /// Usage counted against the quotas in whitelist entries
+    pub quota_usage: Arc<QuotaUsage>,
+    /// Receives each verified message instead of it being written to
+    /// `messages_dir`; `None` stores messages as `.ftt` files
+    pub message_handler: Option<Arc<dyn MessageHandler>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            messages_dir: "messages".to_string(),
+            temp_dir: None,
+            require_empty_messages_dir: false,
+            extract_dirs: false,
+            on_collision: CollisionPolicy::default(),
+            encrypt_at_rest: false,
+            allowed_extensions: Vec::new(),
+            detect_type: false,
+            on_type_mismatch: TypeMismatchPolicy::default(),
+            max_message_bytes: Some(DEFAULT_MAX_MESSAGE_BYTES),
+            memory_budget: None,
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+            handshake: HandshakeOptions::default(),
+            clock: system_clock(),
+            quota_usage: Arc::new(QuotaUsage::in_memory()),
+            message_handler: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Check a sanitized filename's extension against `allowed_extensions`
+    pub fn allows_extension(&self, filename: &str) -> bool {
+        if self.allowed_extensions.is_empty() {
+            return true;
+        }
+        let Some(ext) = Path::new(filename).extension().and_then(|e| e.to_str()) else {
+            return false;
+        };
+        self.allowed_extensions
+            .iter()
+            .any(|allowed| allowed.trim_start_matches('.').eq_ignore_ascii_case(ext))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_extensions() {
+        let config = ServerConfig {
+            allowed_extensions: vec!["json".to_string(), ".csv".to_string()],
+            ..ServerConfig::default()
+        };
+        assert!(config.allows_extension("settlement.json"));
+        assert!(config.allows_extension("ledger.CSV"));
+        assert!(!config.allows_extension("payload.sh"));
+        assert!(!config.allows_extension("README"));
+
+        assert!(ServerConfig::default().allows_extension("payload.sh"));
+    }
+}
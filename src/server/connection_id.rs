@@ -0,0 +1,42 @@
+use std::fmt;
+use std::future::Future;
+
+tokio::task_local! {
+    static CURRENT: ConnectionId;
+}
+
+/// Short random id naming one accepted connection in log lines
+///
+/// The accept loop runs each connection's handler inside [`ConnectionId::scope`],
+/// and [`Output`](crate::cli::Output) prefixes every line printed from within
+/// it with `[conn <id>]`, so interleaved connections can be told apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ConnectionId(u32);
+
+impl ConnectionId {
+    /// Pick a fresh id
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// Id of the connection the calling task is handling, if any
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|id| *id).ok()
+    }
+
+    /// Run `future` as part of this connection
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+
+    /// Run `f` as part of this connection
+    pub fn sync_scope<R>(self, f: impl FnOnce() -> R) -> R {
+        CURRENT.sync_scope(self, f)
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}", self.0)
+    }
+}
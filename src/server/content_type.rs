@@ -0,0 +1,147 @@
+use std::path::Path;
+
+/// How many leading bytes are inspected when sniffing
+const SNIFF_LEN: usize = 8 * 1024;
+
+/// Broad kind of data, as far as its leading bytes tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentType {
+    /// Text starting with `{` or `[`
+    Json,
+    /// Text starting with `<`
+    Xml,
+    /// Any other text
+    Text,
+    /// `%PDF`
+    Pdf,
+    /// Zip container (also xlsx, docx, ...)
+    Zip,
+    /// gzip stream
+    Gzip,
+    /// Zstandard frame
+    Zstd,
+    /// PNG image
+    Png,
+    /// JPEG image
+    Jpeg,
+    /// Anything else
+    Binary,
+}
+
+impl ContentType {
+    /// Whether this is one of the text kinds
+    pub fn is_text(self) -> bool {
+        matches!(self, ContentType::Json | ContentType::Xml | ContentType::Text)
+    }
+}
+
+impl std::fmt::Display for ContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ContentType::Json => "json",
+            ContentType::Xml => "xml",
+            ContentType::Text => "text",
+            ContentType::Pdf => "pdf",
+            ContentType::Zip => "zip",
+            ContentType::Gzip => "gzip",
+            ContentType::Zstd => "zstd",
+            ContentType::Png => "png",
+            ContentType::Jpeg => "jpeg",
+            ContentType::Binary => "binary",
+        })
+    }
+}
+
+/// Guess what `data` is from its magic bytes, or failing that whether it reads as text
+pub fn sniff(data: &[u8]) -> ContentType {
+    const SIGNATURES: [(&[u8], ContentType); 6] = [
+        (b"%PDF", ContentType::Pdf),
+        (b"PK\x03\x04", ContentType::Zip),
+        (b"\x1f\x8b", ContentType::Gzip),
+        (b"\x28\xb5\x2f\xfd", ContentType::Zstd),
+        (b"\x89PNG", ContentType::Png),
+        (b"\xff\xd8\xff", ContentType::Jpeg),
+    ];
+    if let Some((_, kind)) = SIGNATURES.iter().find(|(magic, _)| data.starts_with(magic)) {
+        return *kind;
+    }
+
+    let sample = &data[..data.len().min(SNIFF_LEN)];
+    if !looks_like_text(sample) {
+        return ContentType::Binary;
+    }
+    let sample = sample.strip_prefix(b"\xef\xbb\xbf").unwrap_or(sample);
+    match sample.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{' | b'[') => ContentType::Json,
+        Some(b'<') => ContentType::Xml,
+        _ => ContentType::Text,
+    }
+}
+
+/// Text is UTF-8 (a sequence cut at the sample end is fine) with no NULs and
+/// almost no other control characters
+fn looks_like_text(sample: &[u8]) -> bool {
+    let valid = match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    let controls = sample
+        .iter()
+        .filter(|&&b| b < 0x20 && !matches!(b, b'\n' | b'\r' | b'\t' | 0x0c))
+        .count();
+    valid && !sample.contains(&0) && controls * 100 <= sample.len()
+}
+
+/// Kinds a file with extension `ext` may contain; `None` for extensions not checked
+pub fn expected_types(ext: &str) -> Option<&'static [ContentType]> {
+    use ContentType::*;
+    // Text formats tolerate each other: a CSV export that happens to start
+    // with `[` is still plausibly a CSV
+    Some(match ext.to_ascii_lowercase().as_str() {
+        "json" => &[Json],
+        "xml" => &[Xml],
+        "csv" | "tsv" | "txt" | "log" | "md" => &[Text, Json, Xml],
+        "pdf" => &[Pdf],
+        "zip" | "xlsx" | "docx" | "pptx" | "jar" => &[Zip],
+        "gz" | "tgz" => &[Gzip],
+        "zst" => &[Zstd],
+        "png" => &[Png],
+        "jpg" | "jpeg" => &[Jpeg],
+        _ => return None,
+    })
+}
+
+/// Why `data` does not fit the extension of `filename`, if it does not
+///
+/// Empty data and unknown extensions are never a mismatch.
+pub fn type_mismatch(filename: &str, data: &[u8]) -> Option<String> {
+    let ext = Path::new(filename).extension()?.to_str()?;
+    let expected = expected_types(ext)?;
+    if data.is_empty() {
+        return None;
+    }
+    let found = sniff(data);
+    (!expected.contains(&found)).then(|| format!("{} is declared .{} but contains {} data", filename, ext, found))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_and_mismatch() {
+        assert_eq!(sniff(b"  {\"amount\": 1}"), ContentType::Json);
+        assert_eq!(sniff(b"date,amount\n2024-01-01,100\n"), ContentType::Text);
+        assert_eq!(sniff(b"<?xml version=\"1.0\"?>"), ContentType::Xml);
+        assert_eq!(sniff(b"\x1f\x8b\x08\x00"), ContentType::Gzip);
+        assert_eq!(sniff(&[0u8, 1, 2, 3, 255, 254]), ContentType::Binary);
+
+        assert_eq!(type_mismatch("trades.json", b"[{\"id\": 1}]"), None);
+        assert_eq!(type_mismatch("ledger.csv", b"id,amount\n1,2\n"), None);
+        assert_eq!(type_mismatch("payload.bin", &[0u8, 159, 146, 150]), None);
+        assert_eq!(type_mismatch("empty.json", b""), None);
+        let mismatch = type_mismatch("trades.json", &[0u8, 159, 146, 150]).unwrap();
+        assert!(mismatch.contains("binary"), "{}", mismatch);
+        assert!(type_mismatch("report.pdf", b"not a pdf").is_some());
+    }
+}
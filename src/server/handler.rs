@@ -1,84 +1,1259 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::io::AsyncReadExt;
+use std::time::Instant;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter};
 use crate::error::{AppError, Result};
-use crate::crypto::{KeyPair, decrypt_large};
-use crate::auth::Whitelist;
-use crate::protocol::{Handshake, Message, MessageType, MessageHeader, verify_checksum};
-use crate::protocol::handshake::{send_message, receive_message, receive_raw_data};
+use rsa::RsaPublicKey;
+use crate::crypto::{KeyPair, ChunkOpener, ChunkedFileSealer, CipherSuite, RsaPadding, StreamKey, decrypt_large, public_key_pem, sealed_len, DEFAULT_CHUNK_SIZE};
+use crate::compression::{Compression, CompressionStats};
+use crate::auth::Authorizer;
+pub use crate::protocol::ReceivedMessage;
+use crate::protocol::{AuthenticatedChannel, HandshakeOutcome, ReceiveSession, ClientRequest, Disconnect, DisconnectReason, VerifyRequest, VerifyResponse, MessageHeader, MessageMeta, AcceptedMessage, ContentKind, verify_checksum};
+use crate::server::budget::Reservation;
+use crate::server::config::ServerConfig;
+use crate::server::config::{CollisionPolicy, TypeMismatchPolicy};
+use crate::server::content_type::type_mismatch;
+use crate::server::read_timeout::{ReadTimeout, READ_TIMEOUT};
+use crate::server::storage::{write_sidecar, write_message_async, staging_path, move_into_place_async, extract_archive, resolve_target, stored_filename, stored_checksum};
+use crate::server::usage::QuotaReservation;
 use crate::cli::Output;
 use std::fs;
 
-/// Handle an incoming connection
-pub async fn handle_connection(
-    mut stream: TcpStream,
-    whitelist: &Whitelist,
+/// Handle an incoming connection, returning every transfer it stored
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: &str,
+    authorizer: &dyn Authorizer,
     keypair: &Arc<KeyPair>,
-    messages_dir: &str,
-) -> Result<()> {
+    config: &ServerConfig,
+) -> Result<Vec<ReceivedMessage>> {
+    handle_connection_with_reason(stream, peer, authorizer, keypair, config)
+        .await
+        .map_err(|(_, e)| e)
+}
+
+/// Like [`handle_connection`], but a failed connection also says why it was refused
+pub(crate) async fn handle_connection_with_reason<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: &str,
+    authorizer: &dyn Authorizer,
+    keypair: &Arc<KeyPair>,
+    config: &ServerConfig,
+) -> std::result::Result<Vec<ReceivedMessage>, (DisconnectReason, AppError)> {
+    let handled = serve_connection(stream, peer, authorizer, keypair, config, Destination::Disk).await?;
+    Ok(handled.into_iter().filter_map(Handled::stored).collect())
+}
+
+/// Handle an incoming connection that delivers a single message, keeping it in memory
+///
+/// Nothing is written to the messages directory. The transfer is
+/// acknowledged under its sanitized filename and the connection then ends,
+/// so a client asking to verify the delivery is cut off.
+pub async fn accept_message<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: &str,
+    authorizer: &dyn Authorizer,
+    keypair: &Arc<KeyPair>,
+    config: &ServerConfig,
+) -> Result<AcceptedMessage> {
+    let handled = serve_connection(stream, peer, authorizer, keypair, config, Destination::Memory)
+        .await
+        .map_err(|(_, e)| e)?;
+    match handled.into_iter().next() {
+        Some(Handled::Kept(message)) => Ok(message),
+        _ => Err(AppError::Protocol(format!("{} closed the connection without sending a message", peer))),
+    }
+}
+
+/// Where the plaintext of a verified transfer ends up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Destination {
+    /// Stored in the messages directory
+    Disk,
+    /// Kept in memory and handed to the caller
+    Memory,
+}
+
+/// One transfer completed on a connection
+enum Handled {
+    Stored(ReceivedMessage),
+    Kept(AcceptedMessage),
+}
+
+impl Handled {
+    fn stored(self) -> Option<ReceivedMessage> {
+        match self {
+            Handled::Stored(message) => Some(message),
+            Handled::Kept(_) => None,
+        }
+    }
+}
+
+/// Run the handshake, then serve transfer and verify requests until the client is done
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    peer: &str,
+    authorizer: &dyn Authorizer,
+    keypair: &Arc<KeyPair>,
+    config: &ServerConfig,
+    destination: Destination,
+) -> std::result::Result<Vec<Handled>, (DisconnectReason, AppError)> {
+
+    // A peer that stops sending is dropped rather than holding the task forever
+    let stream = ReadTimeout::new(stream, config.read_timeout);
+    let timeout = stream.handle();
+    let timed_out = |e: AppError| {
+        if timeout.expired() {
+            (DisconnectReason::Timeout, AppError::Protocol(READ_TIMEOUT.to_string()))
+        } else {
+            (DisconnectReason::for_error(&e), e)
+        }
+    };
+
     // Perform handshake
-    match Handshake::server_side(&mut stream, whitelist, keypair).await {
-        Ok(_) => {},
+    let (mut stream, outcome) = match AuthenticatedChannel::accept(stream, authorizer, keypair, &config.handshake).await {
+        Ok(channel) => channel.into_parts(),
         Err(e) => {
+            let (reason, e) = timed_out(e);
             Output::auth_failed(&e.to_string());
-            return Err(e);
+            return Err((reason, e));
+        }
+    };
+
+    let mut session = ReceiveSession::new(&mut stream);
+    let mut handled = Vec::new();
+
+    // A client may pipeline several transfers; it closes the stream when done.
+    // A kept connection may sit idle between them, but not within one.
+    loop {
+        if !handled.is_empty() {
+            // A message kept for `accept_message` ends the connection
+            if destination == Destination::Memory {
+                return Ok(handled);
+            }
+            timeout.allow_idle();
         }
+        let step = match session.next_request().await {
+            Ok(Some(ClientRequest::Transfer(header))) => match destination {
+                Destination::Disk => receive_transfer(&mut session, header, &outcome, keypair, config, peer)
+                    .await
+                    .map(|message| handled.push(Handled::Stored(message))),
+                Destination::Memory => keep_transfer(&mut session, header, &outcome, keypair, config, peer)
+                    .await
+                    .map(|message| handled.push(Handled::Kept(message))),
+            },
+            Ok(Some(ClientRequest::Verify(request))) => {
+                let last = match handled.last() {
+                    Some(Handled::Stored(message)) => Some(message),
+                    _ => None,
+                };
+                answer_verify(&mut session, &request, last, keypair, config).await
+            }
+            Ok(None) => return Ok(handled),
+            Err(e) => Err(e),
+        };
+
+        // Whatever went wrong, the client is told why before the connection
+        // closes; a more specific notice sent earlier takes precedence
+        if let Err(e) = step {
+            let expired = timeout.expired();
+            let (reason, e) = timed_out(e);
+            let _ = session.disconnect(&Disconnect::new(reason, &e.to_string())).await;
+            session.linger().await;
+            let reason = session.disconnect_reason().filter(|_| !expired).unwrap_or(reason);
+            return Err((reason, e));
+        }
+    }
+}
+
+/// Expiry requested by a sender's time-to-live; out of range means never
+fn expires_at(received_at: chrono::DateTime<chrono::Utc>, ttl_secs: Option<u64>) -> Option<chrono::DateTime<chrono::Utc>> {
+    let ttl = chrono::Duration::try_seconds(i64::try_from(ttl_secs?).ok()?)?;
+    received_at.checked_add_signed(ttl)
+}
+
+/// Refuse a transfer for a policy reason rather than a fault
+async fn refuse<S: AsyncRead + AsyncWrite + Unpin, T>(
+    session: &mut ReceiveSession<'_, S>,
+    reason: DisconnectReason,
+    err: AppError,
+) -> Result<T> {
+    session.disconnect(&Disconnect::new(reason, &err.to_string())).await?;
+    Err(err)
+}
+
+/// Read back the file just delivered on this connection and report its checksum
+async fn answer_verify<S: AsyncRead + AsyncWrite + Unpin>(
+    session: &mut ReceiveSession<'_, S>,
+    request: &VerifyRequest,
+    last: Option<&ReceivedMessage>,
+    keypair: &KeyPair,
+    config: &ServerConfig,
+) -> Result<()> {
+    // What a message handler did with the data is out of the server's sight
+    if config.message_handler.is_some() {
+        let err = AppError::Server("Messages passed to a message handler cannot be verified".to_string());
+        session.reject(&err).await?;
+        return Err(err);
+    }
+    let stored = last.and_then(|message| message.path.as_ref().map(|path| (message, path)));
+    let (message, path) = match stored {
+        Some((message, path)) if path.file_name().is_some_and(|n| n.to_string_lossy() == request.saved_as) => (message, path),
+        _ => {
+            let err = AppError::Protocol(format!(
+                "Can only verify the file just delivered on this connection, not {}",
+                request.saved_as
+            ));
+            session.reject(&err).await?;
+            return Err(err);
+        }
+    };
+
+    let checksum = if path.is_dir() {
+        Err(AppError::Server("Extracted directories cannot be verified".to_string()))
+    } else {
+        // Reading back a multi-GB streamed file must not stall the runtime
+        let (path, keypair) = (path.clone(), keypair.clone());
+        tokio::task::spawn_blocking(move || stored_checksum(&path, &keypair))
+            .await
+            .unwrap_or_else(|e| Err(AppError::Server(format!("Checksum task failed: {}", e))))
+    };
+    match checksum {
+        Ok(checksum) => {
+            let response = VerifyResponse::new(&request.saved_as, &checksum).with_meta(Some(message.meta.clone()));
+            session.answer_verify(&response).await
+        }
+        Err(e) => {
+            session.reject(&e).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Receive, verify and store one transfer whose header has been read
+async fn receive_transfer<S: AsyncRead + AsyncWrite + Unpin>(
+    session: &mut ReceiveSession<'_, S>,
+    header: MessageHeader,
+    outcome: &HandshakeOutcome,
+    keypair: &KeyPair,
+    config: &ServerConfig,
+    peer: &str,
+) -> Result<ReceivedMessage> {
+    let started = Instant::now();
+    let destination = match config.message_handler {
+        Some(_) => Destination::Memory,
+        None => Destination::Disk,
     };
+    let Verified { safe_name, body, compression_stats, quota, _reservation } =
+        receive_verified(session, &header, outcome, keypair, config, destination).await?;
+    let extract = header.content == ContentKind::Directory && config.extract_dirs;
+    let bytes_written = body.len();
+    let received_at = config.clock.now();
 
-    // Receive message header
-    let header_msg = receive_message(&mut stream).await?;
+    let (filepath, filename) = match &config.message_handler {
+        Some(message_handler) => {
+            let Body::Memory(data) = body else {
+                return Err(AppError::Server(format!("{} was staged on disk instead of kept in memory", safe_name)));
+            };
+            match message_handler.handle(header.clone(), data) {
+                Ok(name) => (None, name),
+                Err(e) => {
+                    session.reject(&e).await?;
+                    return Err(e);
+                }
+            }
+        }
+        None => {
+            let (filepath, filename) = store_body(session, &header, &safe_name, body, keypair, config, received_at).await?;
+            (Some(filepath), filename)
+        }
+    };
+
+    let meta = MessageMeta::new(&header, &filename, bytes_written, peer)
+        .with_identity(outcome.peer_identity.as_deref())
+        .with_received_at(received_at)
+        .with_expires_at(expires_at(received_at, header.ttl_secs))
+        .with_sender_key(public_key_pem(&outcome.peer_public_key).ok())
+        .with_encrypted_at_rest(config.message_handler.is_none() && config.encrypt_at_rest && !extract)
+        .with_compression(compression_stats);
+    // A message handler stores the data itself, so there is no file to describe
+    if let Some(filepath) = &filepath {
+        write_sidecar(filepath, &meta)?;
+        Output::file_saved(&filename);
+    }
 
-    if !matches!(header_msg.msg_type, MessageType::MessageHeader) {
-        return Err(AppError::Protocol("Expected MessageHeader".to_string()));
+    // The file is already stored, so failing to persist the count only warns
+    if let Some(quota) = quota {
+        if let Err(e) = quota.record(bytes_written, received_at).await {
+            Output::warning(&format!("Failed to record quota usage: {}", e));
+        }
     }
 
-    let header: MessageHeader = MessageHeader::from_bytes(&header_msg.payload)?;
+    // Send acknowledgment
+    session.acknowledge(&filename).await?;
+
+    Output::success("Message transfer complete");
+
+    Ok(ReceivedMessage {
+        peer: peer.to_string(),
+        fingerprint: outcome.session.remote.clone(),
+        filename: header.filename,
+        path: filepath,
+        bytes_written,
+        checksum: header.checksum,
+        elapsed: started.elapsed(),
+        meta,
+    })
+}
+
+/// Write a verified body to the messages directory (or unpack a directory archive)
+///
+/// Returns the path it was stored at and its file name.
+async fn store_body<S: AsyncRead + AsyncWrite + Unpin>(
+    session: &mut ReceiveSession<'_, S>,
+    header: &MessageHeader,
+    safe_name: &str,
+    body: Body,
+    keypair: &KeyPair,
+    config: &ServerConfig,
+    received_at: chrono::DateTime<chrono::Utc>,
+) -> Result<(PathBuf, String)> {
+    let extract = header.content == ContentKind::Directory && config.extract_dirs;
+    ensure_dirs(config)?;
+
+    // Save to file (or unpack directory archives) with timestamp
+    let timestamp = received_at.with_timezone(&chrono::Local).format("%Y%m%d_%H%M%S");
+    let name = if extract {
+        format!("{}_{}", safe_name, timestamp)
+    } else {
+        format!("{}_{}.ftt", safe_name, timestamp)
+    };
+
+    let filepath = match resolve_target(Path::new(&config.messages_dir), &name, config.on_collision) {
+        Ok(path) => path,
+        Err(e) => return refuse(session, DisconnectReason::Rejected, e).await,
+    };
+    let filename = filepath
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or(name);
+
+    match body {
+        body if extract => {
+            let replace = filepath.exists() && config.on_collision == CollisionPolicy::Overwrite;
+            let (dest, name) = (filepath.clone(), filename.clone());
+            // Unpacking writes every entry to disk, so it must not stall the runtime
+            let count = tokio::task::spawn_blocking(move || {
+                if replace {
+                    fs::remove_dir_all(&dest)
+                        .map_err(|e| AppError::Server(format!("Failed to replace {}: {}", name, e)))?;
+                }
+                body.unpack(&dest)
+            })
+            .await
+            .unwrap_or_else(|e| Err(AppError::Server(format!("Extract task failed: {}", e))))?;
+            Output::info(&format!("Extracted {} entries into {}", count, filename));
+        }
+        Body::Memory(data) => {
+            let at_rest_key = config.encrypt_at_rest.then_some(&keypair.public_key);
+            let staged = staging_path(&filepath, config.temp_dir.as_deref().map(Path::new));
+            let stored = match write_message_async(&staged, &data, at_rest_key).await {
+                Ok(()) => move_into_place_async(&staged, &filepath).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = stored {
+                let _ = fs::remove_file(&staged);
+                return Err(e);
+            }
+        }
+        Body::Staged { file, .. } => move_into_place_async(&file.0, &filepath).await?,
+    }
+    Ok((filepath, filename))
+}
+
+/// Receive one transfer whose header has been read and keep it in memory
+async fn keep_transfer<S: AsyncRead + AsyncWrite + Unpin>(
+    session: &mut ReceiveSession<'_, S>,
+    header: MessageHeader,
+    outcome: &HandshakeOutcome,
+    keypair: &KeyPair,
+    config: &ServerConfig,
+    peer: &str,
+) -> Result<AcceptedMessage> {
+    let Verified { safe_name, body, quota, .. } =
+        receive_verified(session, &header, outcome, keypair, config, Destination::Memory).await?;
+    let Body::Memory(data) = body else {
+        return Err(AppError::Server(format!("{} was staged on disk instead of kept in memory", safe_name)));
+    };
+
+    if let Some(quota) = quota {
+        if let Err(e) = quota.record(data.len() as u64, config.clock.now()).await {
+            Output::warning(&format!("Failed to record quota usage: {}", e));
+        }
+    }
+    session.acknowledge(&safe_name).await?;
+    Output::success(&format!("Message received in memory: {} ({} bytes)", safe_name, data.len()));
+
+    Ok(AcceptedMessage {
+        peer: peer.to_string(),
+        fingerprint: outcome.session.remote.clone(),
+        filename: header.filename.clone(),
+        checksum: header.checksum.clone(),
+        data,
+        header,
+    })
+}
+
+/// A transfer that passed every check, ready to be stored or handed over
+struct Verified<'a> {
+    /// Last component of the requested filename
+    safe_name: String,
+    body: Body,
+    compression_stats: Option<CompressionStats>,
+    /// Room held in the peer's quota, if it has one, given back when dropped unrecorded
+    quota: Option<QuotaReservation>,
+    /// Memory reserved for the body, released when dropped
+    _reservation: Option<Reservation<'a>>,
+}
+
+/// Admit, receive, decrypt and verify one transfer whose header has been read
+///
+/// Every refusal is reported to the client here, so all that is left for
+/// the caller is storing the body.
+async fn receive_verified<'a, S: AsyncRead + AsyncWrite + Unpin>(
+    session: &mut ReceiveSession<'_, S>,
+    header: &MessageHeader,
+    outcome: &'a HandshakeOutcome,
+    keypair: &KeyPair,
+    config: &'a ServerConfig,
+    destination: Destination,
+) -> Result<Verified<'a>> {
     Output::info(&format!("Receiving file: {} ({} bytes)", header.filename, header.size));
+    if let Some(note) = &header.note {
+        Output::info(&format!("Note: {}", note));
+    }
 
-    // Receive encrypted data length (8 bytes)
-    let mut len_buf = [0u8; 8];
-    stream.read_exact(&mut len_buf)
-        .await
-        .map_err(|e| AppError::Protocol(format!("Failed to read data length: {}", e)))?;
-    let data_len = u64::from_be_bytes(len_buf) as usize;
+    // Checked before anything is allocated for the data, so a peer cannot
+    // announce gigabytes and have the server reserve them. Chunked transfers
+    // to disk only ever hold one chunk, so the limit does not apply to them
+    let chunked_to_disk = header.stream_key.is_some() && destination == Destination::Disk;
+    let size_limit = config.max_message_bytes.filter(|_| !chunked_to_disk);
+    if let Some(limit) = size_limit.filter(|limit| header.size > *limit) {
+        let err = AppError::Protocol(format!(
+            "message exceeds maximum size: {} bytes, limit is {} bytes",
+            header.size, limit
+        ));
+        return refuse(session, DisconnectReason::TooLarge, err).await;
+    }
+
+    // Even an empty file is sent as an encrypted body with an auth tag, so a
+    // header announcing no data at all can only come from a broken peer
+    if header.size == 0 {
+        let err = AppError::Protocol(format!("Message header for {} declares no data", header.filename));
+        return refuse(session, DisconnectReason::ProtocolError, err).await;
+    }
 
+    // Only the last path component is used, so a name like `../x` cannot
+    // escape the messages directory
+    let safe_name = match stored_filename(&header.filename) {
+        Ok(name) => name.to_string(),
+        Err(e) => return refuse(session, DisconnectReason::ProtocolError, e).await,
+    };
+
+    // Checked on the header alone, so a refused peer uploads nothing
+    if let Some((reason, err)) = payload_refusal(header, outcome, config) {
+        return refuse(session, reason, err).await;
+    }
+
+    // A peer over its quota is turned away before it uploads anything
+    let quota = match outcome.whitelist_entry.as_ref().and_then(|entry| Some((&entry.key_hash, entry.quota?))) {
+        Some((key_hash, quota)) => match config.quota_usage.reserve(key_hash, &quota, header.size, config.clock.now()) {
+            Ok(reservation) => Some(reservation),
+            Err(e) => return refuse(session, DisconnectReason::QuotaExceeded, e).await,
+        },
+        None => None,
+    };
+
+    // Held until the message is stored, so large transfers queue for memory;
+    // chunked transfers to disk only ever hold one chunk
+    let _reservation = match &config.memory_budget {
+        Some(budget) if !chunked_to_disk => Some(budget.reserve(working_set(header, config, destination)).await),
+        _ => None,
+    };
+
+    let (body, compression_stats) = match &header.stream_key {
+        Some(stream_key) => {
+            // Refused before any chunk is read, so a forbidden file is never
+            // streamed to disk; the client reads this notice when its next
+            // chunk fails to send
+            if let Some((reason, err)) = name_refusal(header, &safe_name, outcome, config) {
+                return refuse(session, reason, err).await;
+            }
+            let staged = match destination {
+                Destination::Disk => {
+                    ensure_dirs(config)?;
+                    let target = Path::new(&config.messages_dir).join(&safe_name);
+                    Some(staging_path(&target, config.temp_dir.as_deref().map(Path::new)))
+                }
+                Destination::Memory => None,
+            };
+            // Directory archives are unpacked from the staging file, so only
+            // other files are sealed at rest as they arrive
+            let extract = header.content == ContentKind::Directory && config.extract_dirs;
+            let at_rest_key = (config.encrypt_at_rest && !extract).then_some(&keypair.public_key);
+            let body = match receive_chunks(session, header, stream_key, outcome, keypair, staged, at_rest_key).await {
+                Ok(body) => body,
+                Err(e) => {
+                    session.reject(&e).await?;
+                    return Err(e);
+                }
+            };
+            (body, None)
+        }
+        None => {
+            let (data, compression_stats) = receive_whole(session, header, &safe_name, outcome, keypair, config).await?;
+            (Body::Memory(data), compression_stats)
+        }
+    };
+
+    // Mislabeled files are caught only once the plaintext is available
+    if config.detect_type && header.content == ContentKind::File {
+        if let Some(mismatch) = type_mismatch(&safe_name, body.head()) {
+            match config.on_type_mismatch {
+                TypeMismatchPolicy::Warn => Output::warning(&format!("Content type mismatch: {}", mismatch)),
+                TypeMismatchPolicy::Reject => {
+                    let err = AppError::Server(format!("content type mismatch: {}", mismatch));
+                    return refuse(session, DisconnectReason::Rejected, err).await;
+                }
+            }
+        }
+    }
+
+
+    Ok(Verified { safe_name, body, compression_stats, quota, _reservation })
+}
+
+/// Create the messages directory and the temp directory, if any
+fn ensure_dirs(config: &ServerConfig) -> Result<()> {
+    fs::create_dir_all(&config.messages_dir)
+        .map_err(|e| AppError::Server(format!("Failed to create messages directory: {}", e)))?;
+    if let Some(temp_dir) = &config.temp_dir {
+        fs::create_dir_all(temp_dir)
+            .map_err(|e| AppError::Server(format!("Failed to create temp directory: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// Plaintext of a verified transfer, ready to be stored
+enum Body {
+    /// Held in memory
+    Memory(Vec<u8>),
+    /// Already written to a staging file; `head` is its first chunk
+    Staged { file: StagedFile, len: u64, head: Vec<u8> },
+}
+
+impl Body {
+    /// Number of plaintext bytes
+    fn len(&self) -> u64 {
+        match self {
+            Body::Memory(data) => data.len() as u64,
+            Body::Staged { len, .. } => *len,
+        }
+    }
+
+    /// Leading bytes, enough for content sniffing
+    fn head(&self) -> &[u8] {
+        match self {
+            Body::Memory(data) => data,
+            Body::Staged { head, .. } => head,
+        }
+    }
+
+    /// Unpack a directory archive into `dest`, reading a staged one from disk as it goes
+    fn unpack(self, dest: &Path) -> Result<usize> {
+        match self {
+            Body::Memory(data) => extract_archive(data.as_slice(), dest),
+            Body::Staged { file, .. } => {
+                let archive = fs::File::open(&file.0)
+                    .map_err(|e| AppError::Server(format!("Failed to read {}: {}", file.0.display(), e)))?;
+                extract_archive(std::io::BufReader::new(archive), dest)
+            }
+        }
+    }
+}
+
+/// Staging file of a chunked transfer, removed unless it was moved into place
+struct StagedFile(PathBuf);
+
+impl Drop for StagedFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Why the whitelist entry or the allowed extensions refuse `safe_name`, if they do
+fn name_refusal(
+    header: &MessageHeader,
+    safe_name: &str,
+    outcome: &HandshakeOutcome,
+    config: &ServerConfig,
+) -> Option<(DisconnectReason, AppError)> {
+    if let Some(entry) = &outcome.whitelist_entry {
+        if !entry.allows(safe_name) {
+            let err = AppError::Auth(format!("Filename not allowed for this connect key: {}", header.filename));
+            return Some((DisconnectReason::Rejected, err));
+        }
+    }
+    if !config.allows_extension(safe_name) {
+        let err = AppError::Server(format!("extension not allowed: {}", header.filename));
+        return Some((DisconnectReason::Rejected, err));
+    }
+    None
+}
+
+/// Most bytes a transfer kept in memory holds at once, reserved from the memory budget
+///
+/// A body sent whole is parsed from its frame and then decrypted, so two
+/// copies of its sealed size are alive at a time. A compressed body may then
+/// grow up to the decompression limit next to the decrypted one, and sealing
+/// it at rest adds one sealed chunk at a time next to the plaintext. A
+/// chunked transfer into memory only collects its plaintext, which is never
+/// larger than its sealed size.
+fn working_set(header: &MessageHeader, config: &ServerConfig, destination: Destination) -> u64 {
+    let sealed = header.size;
+    if header.stream_key.is_some() {
+        return sealed;
+    }
+    let plaintext = match header.compression {
+        Compression::None => sealed,
+        _ => decompress_limit(config),
+    };
+    let extract = header.content == ContentKind::Directory && config.extract_dirs;
+    let stored = match destination {
+        Destination::Disk if config.encrypt_at_rest && !extract => {
+            plaintext.saturating_add(sealed_len(plaintext.min(DEFAULT_CHUNK_SIZE as u64), DEFAULT_CHUNK_SIZE))
+        }
+        _ => plaintext,
+    };
+    sealed.saturating_mul(2).max(sealed.saturating_add(plaintext)).max(stored)
+}
+
+/// Largest a compressed body may grow: no bigger than the server would accept unpacked
+fn decompress_limit(config: &ServerConfig) -> u64 {
+    config.max_message_bytes.unwrap_or(u64::MAX)
+}
+
+/// Why the security settings refuse this transfer's signature or key padding, if they do
+fn payload_refusal(
+    header: &MessageHeader,
+    outcome: &HandshakeOutcome,
+    config: &ServerConfig,
+) -> Option<(DisconnectReason, AppError)> {
+    // A signature that is present must be valid even where none is required
+    if header.signature.is_some() || config.handshake.require_signed_payloads {
+        if let Err(e) = header.verify_signature(&outcome.peer_public_key) {
+            return Some((DisconnectReason::Rejected, e));
+        }
+    }
+    let padding = header.stream_key.as_ref().map(|key| key.padding);
+    if padding.is_some_and(|p| p != RsaPadding::OaepSha256) && !config.handshake.accept_pkcs1v15_payloads {
+        let err = AppError::Crypto("Stream key wrapped with PKCS#1 v1.5; OAEP is required".to_string());
+        return Some((DisconnectReason::ProtocolError, err));
+    }
+    None
+}
+
+/// Receive a transfer sent in one piece, then decrypt, decompress and verify it
+async fn receive_whole<S: AsyncRead + AsyncWrite + Unpin>(
+    session: &mut ReceiveSession<'_, S>,
+    header: &MessageHeader,
+    safe_name: &str,
+    outcome: &HandshakeOutcome,
+    keypair: &KeyPair,
+    config: &ServerConfig,
+) -> Result<(Vec<u8>, Option<CompressionStats>)> {
     // Receive encrypted message data
-    Output::receiving(data_len);
-    let encrypted_data = receive_raw_data(&mut stream, data_len).await?;
+    Output::receiving(header.size as usize);
+    let encrypted_data = match session.read_data().await {
+        Ok(data) => data,
+        Err(e) => {
+            session.reject(&e).await?;
+            return Err(e);
+        }
+    };
+
+    // Whitelist entries may restrict which filenames a peer can write; the
+    // data is read first so the client sees this error rather than a reset
+    if let Some((reason, err)) = name_refusal(header, safe_name, outcome, config) {
+        return refuse(session, reason, err).await;
+    }
 
     // Decrypt message
     Output::decrypting();
-    let encrypted_msg = crate::crypto::EncryptedMessage::from_bytes(&encrypted_data)?;
+    // Peers from before OAEP send untagged PKCS#1 v1.5 messages, accepted only if configured
+    let parsed = if config.handshake.accept_pkcs1v15_payloads {
+        crate::crypto::EncryptedMessage::from_bytes(&encrypted_data)
+    } else {
+        crate::crypto::EncryptedMessage::from_bytes_tagged(&encrypted_data)
+    };
+    let encrypted_msg = match parsed {
+        Ok(message) => message,
+        Err(e) => {
+            session.reject(&e).await?;
+            return Err(e);
+        }
+    };
+    // Each buffer is released as soon as the next is made, keeping to the
+    // working set reserved from the memory budget
+    drop(encrypted_data);
+    if encrypted_msg.suite != outcome.cipher_suite {
+        let err = AppError::Protocol(format!(
+            "Message encrypted with {}, negotiated {}",
+            encrypted_msg.suite, outcome.cipher_suite
+        ));
+        session.reject(&err).await?;
+        return Err(err);
+    }
     let decrypted_data = decrypt_large(&keypair.private_key, &encrypted_msg)?;
+    drop(encrypted_msg);
+
+    // Only the negotiated compression is accepted
+    if header.compression != Compression::None && header.compression != outcome.compression {
+        let err = AppError::Protocol(format!(
+            "Message compressed with {}, negotiated {}",
+            header.compression, outcome.compression
+        ));
+        session.reject(&err).await?;
+        return Err(err);
+    }
+    let mut compression_stats = None;
+    let decrypted_data = match header.compression {
+        Compression::None => decrypted_data,
+        compression => {
+            let started = Instant::now();
+            // A small body must not expand past what the server would accept unpacked
+            match compression.decompress(&decrypted_data, decompress_limit(config)) {
+                Ok(data) => {
+                    let stats = CompressionStats::new(compression, data.len() as u64, decrypted_data.len() as u64, started.elapsed());
+                    Output::info(&format!("Decompressed {}", stats));
+                    compression_stats = Some(stats);
+                    data
+                }
+                Err(e) => {
+                    session.reject(&e).await?;
+                    return Err(e);
+                }
+            }
+        }
+    };
 
     // Verify checksum
     if !verify_checksum(&decrypted_data, &header.checksum)? {
-        let err_msg = Message::new(MessageType::Error, b"Checksum verification failed".to_vec());
-        send_message(&mut stream, &err_msg).await?;
+        let err = AppError::Protocol("Checksum verification failed".to_string());
+        session.reject(&err).await?;
+        return Err(err);
+    }
+    Ok((decrypted_data, compression_stats))
+}
+
+/// Receive a chunked transfer into `staged`, decrypting and hashing each chunk as it arrives
+///
+/// Only one chunk is held in memory at a time, and the staging file is
+/// removed again if anything fails. Without a staging file the chunks are
+/// collected in memory instead.
+async fn receive_chunks<S: AsyncRead + AsyncWrite + Unpin>(
+    session: &mut ReceiveSession<'_, S>,
+    header: &MessageHeader,
+    stream_key: &StreamKey,
+    outcome: &HandshakeOutcome,
+    keypair: &KeyPair,
+    staged: Option<PathBuf>,
+    at_rest_key: Option<&RsaPublicKey>,
+) -> Result<Body> {
+    if stream_key.suite != outcome.cipher_suite {
+        return Err(AppError::Protocol(format!(
+            "Message encrypted with {}, negotiated {}",
+            stream_key.suite, outcome.cipher_suite
+        )));
+    }
+    if header.compression != Compression::None {
+        return Err(AppError::Protocol("Chunked transfers are sent uncompressed".to_string()));
+    }
+
+    Output::receiving(header.size as usize);
+    Output::decrypting();
+    let opener = ChunkOpener::new(&keypair.private_key, stream_key)?;
+    let Some(staged) = staged else {
+        let mut data = Vec::new();
+        open_chunks(session, header, opener, None, &mut data).await?;
+        return Ok(Body::Memory(data));
+    };
+
+    let save_err = |e: std::io::Error| AppError::Server(format!("Failed to save message: {}", e));
+    let file = StagedFile(staged);
+    let mut writer = BufWriter::new(tokio::fs::File::create(&file.0).await.map_err(save_err)?);
+    let resealer = match at_rest_key {
+        Some(key) => {
+            let (sealer, opening) = ChunkedFileSealer::new(key, CipherSuite::default())?;
+            writer.write_all(&opening).await.map_err(save_err)?;
+            Some(sealer)
+        }
+        None => None,
+    };
+    let (len, head) = open_chunks(session, header, opener, resealer, &mut writer).await?;
+    // Flushing also waits for the file's last background write to finish
+    writer.flush().await.map_err(save_err)?;
+    Ok(Body::Staged { file, len, head })
+}
+
+/// Open every chunk of a transfer into `writer`, checking its size and checksum
+///
+/// With a `resealer` each chunk is sealed again for storage at rest before
+/// it is written. Returns the plaintext length and the first chunk, for
+/// content sniffing.
+async fn open_chunks<S: AsyncRead + AsyncWrite + Unpin, W: AsyncWrite + Unpin>(
+    session: &mut ReceiveSession<'_, S>,
+    header: &MessageHeader,
+    mut opener: ChunkOpener,
+    mut resealer: Option<ChunkedFileSealer>,
+    writer: &mut W,
+) -> Result<(u64, Vec<u8>)> {
+    let save_err = |e: std::io::Error| AppError::Server(format!("Failed to save message: {}", e));
+    let mut hasher = Sha256::new();
+    let (mut sealed, mut len, mut head) = (0u64, 0u64, Vec::new());
+    while !opener.is_finished() {
+        let chunk = session.read_chunk().await?;
+        sealed += chunk.data.len() as u64;
+        if sealed > header.size {
+            return Err(AppError::Protocol(format!(
+                "Chunks exceed the {} bytes announced in the header",
+                header.size
+            )));
+        }
+        let data = opener.open(&chunk.data, chunk.last)?;
+        hasher.update(&data);
+        match &mut resealer {
+            Some(sealer) => writer.write_all(&sealer.seal(&data, chunk.last)?).await,
+            None => writer.write_all(&data).await,
+        }
+        .map_err(save_err)?;
+        len += data.len() as u64;
+        if chunk.index == 0 {
+            head = data;
+        }
+    }
+
+    if sealed != header.size {
+        return Err(AppError::Protocol(format!(
+            "Chunks carried {} bytes, header announced {}",
+            sealed, header.size
+        )));
+    }
+    if format!("{:x}", hasher.finalize()) != header.checksum {
         return Err(AppError::Protocol("Checksum verification failed".to_string()));
     }
+    Ok((len, head))
+}
 
-    // Ensure messages directory exists
-    fs::create_dir_all(messages_dir)
-        .map_err(|e| AppError::Server(format!("Failed to create messages directory: {}", e)))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use crate::client::Client;
+    use crate::auth::{Whitelist, WhitelistAuthorizer};
+    use crate::protocol::{Message, MessageType, authenticate, calculate_checksum};
+    use crate::clock::MockClock;
+    use crate::test_support::{spawn_test_server, TEST_CONNECT_KEY};
 
-    // Save to file with timestamp
-    let timestamp = chrono::Local::now().format("%Y%m%d_%H%M%S");
-    let filename = format!("{}_{}.ftt", header.filename, timestamp);
-    let filepath = Path::new(messages_dir).join(&filename);
+    #[tokio::test]
+    async fn test_received_message_describes_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add("secret", None).unwrap();
+        let server_keys = Arc::new(KeyPair::generate().unwrap());
+        let received_at = chrono::DateTime::parse_from_rfc3339("2024-01-01T12:00:00Z").unwrap().to_utc();
+        let config = ServerConfig {
+            messages_dir: dir.path().join("messages").to_string_lossy().to_string(),
+            clock: Arc::new(MockClock::new(received_at)),
+            ..ServerConfig::default()
+        };
 
-    fs::write(&filepath, &decrypted_data)
-        .map_err(|e| AppError::Server(format!("Failed to save message: {}", e)))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, &peer.to_string(), &WhitelistAuthorizer::new(whitelist), &server_keys, &config).await
+        });
 
-    Output::file_saved(&filename);
+        let payload = b"ledger,2024-01-01,100.00\n";
+        let file = dir.path().join("ledger.csv");
+        fs::write(&file, payload).unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+        let client_fingerprint = client_keys.fingerprint().unwrap();
+        let saved_as = Client::new("127.0.0.1", port, client_keys)
+            .send_message(&file, "secret", None, None)
+            .await
+            .unwrap();
 
-    // Send acknowledgment
-    let ack_payload = filename.as_bytes().to_vec();
-    let ack_msg = Message::new(MessageType::Acknowledgment, ack_payload);
-    send_message(&mut stream, &ack_msg).await?;
+        let received = server.await.unwrap().unwrap();
+        assert_eq!(received.len(), 1);
+        let message = &received[0];
+        assert!(message.peer.starts_with("127.0.0.1:"));
+        assert_eq!(message.fingerprint, client_fingerprint);
+        assert_eq!(message.filename, "ledger.csv");
+        let path = message.path.clone().unwrap();
+        assert_eq!(path, dir.path().join("messages").join(&saved_as));
+        assert_eq!(message.bytes_written, payload.len() as u64);
+        assert_eq!(message.checksum, calculate_checksum(payload));
+        assert_eq!(fs::read(&path).unwrap(), payload);
 
-    Output::success("Message transfer complete");
+        let stamp = received_at.with_timezone(&chrono::Local).format("%Y%m%d_%H%M%S");
+        assert_eq!(saved_as, format!("ledger.csv_{}.ftt", stamp));
+        let meta = crate::server::storage::read_sidecar(&path).unwrap();
+        assert_eq!(meta.received_at, received_at.to_rfc3339());
+    }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_chunked_transfer_sealed_at_rest() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add("secret", None).unwrap();
+        let server_keys = Arc::new(KeyPair::generate().unwrap());
+        let config = ServerConfig {
+            messages_dir: dir.path().join("messages").to_string_lossy().to_string(),
+            encrypt_at_rest: true,
+            ..ServerConfig::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let keys = server_keys.clone();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, &peer.to_string(), &WhitelistAuthorizer::new(whitelist), &keys, &config).await
+        });
+
+        let payload: Vec<u8> = (0..10_000u32).flat_map(|i| format!("{},", i).into_bytes()).collect();
+        let file = dir.path().join("ledger.csv");
+        fs::write(&file, &payload).unwrap();
+        Client::new("127.0.0.1", port, KeyPair::generate().unwrap())
+            .with_chunk_size(Some(4096))
+            .send_message(&file, "secret", None, None)
+            .await
+            .unwrap();
+
+        let path = server.await.unwrap().unwrap()[0].path.clone().unwrap();
+        let stored = fs::read(&path).unwrap();
+        assert!(crate::crypto::is_chunked_file(&stored));
+        assert!(!stored.windows(64).any(|w| w == &payload[..64]));
+        assert_eq!(crate::server::storage::read_message(&path, &server_keys).unwrap(), payload);
+        // Only the message and its sidecar are left, no staging file
+        assert_eq!(fs::read_dir(dir.path().join("messages")).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_traversal_filename_stays_in_messages_dir() {
+        let (addr, server) = spawn_test_server(|server| server).await.unwrap();
+        let mut received = server.handle().subscribe_received();
+
+        let client = Client::new("127.0.0.1", addr.port(), KeyPair::generate().unwrap());
+        let saved_as = client.send_bytes(b"pwned", "../evil", TEST_CONNECT_KEY, None, None).await.unwrap();
+        assert!(saved_as.starts_with("evil_"), "{}", saved_as);
+        let err = client.send_bytes(b"pwned", "..", TEST_CONNECT_KEY, None, None).await.unwrap_err();
+        assert!(err.to_string().contains("protocol-error"), "{}", err);
+
+        let stored = received.recv().await.unwrap();
+        assert_eq!(stored.path, Some(server.messages_dir().join(&saved_as)));
+        assert_eq!(fs::read(stored.path.unwrap()).unwrap(), b"pwned");
+        let escaped = fs::read_dir(server.messages_dir().parent().unwrap())
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("evil"))
+            .count();
+        assert_eq!(escaped, 0);
+
+        let report = server.drain().await.unwrap();
+        assert_eq!(report.rejected.get("protocol-error"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_directory_archive_streams_into_extracted_tree() {
+        let (addr, server) = spawn_test_server(|server| server.with_extract_dirs(true)).await.unwrap();
+        let src = tempfile::tempdir().unwrap();
+        let ledger: Vec<u8> = (0..5000u32).flat_map(|i| i.to_le_bytes()).collect();
+        fs::create_dir_all(src.path().join("2024/q1")).unwrap();
+        fs::create_dir(src.path().join("empty")).unwrap();
+        fs::write(src.path().join("readme.txt"), b"accounts").unwrap();
+        fs::write(src.path().join("2024/q1/ledger.bin"), &ledger).unwrap();
+
+        // Far smaller than the archive, so it crosses many chunks
+        let client = Client::new("127.0.0.1", addr.port(), KeyPair::generate().unwrap()).with_chunk_size(Some(1024));
+        let saved_as = client.send_directory_archive(src.path(), TEST_CONNECT_KEY, Some("books"), None).await.unwrap();
+
+        let tree = server.messages_dir().join(&saved_as);
+        assert_eq!(fs::read(tree.join("readme.txt")).unwrap(), b"accounts");
+        assert_eq!(fs::read(tree.join("2024/q1/ledger.bin")).unwrap(), ledger);
+        assert!(tree.join("empty").is_dir());
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_client_is_dropped_after_read_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        let server_keys = Arc::new(KeyPair::generate().unwrap());
+        let config = ServerConfig {
+            messages_dir: dir.path().join("messages").to_string_lossy().to_string(),
+            read_timeout: Some(std::time::Duration::from_millis(100)),
+            ..ServerConfig::default()
+        };
+
+        // The client end stays open but never says anything
+        let (_client, server) = tokio::io::duplex(1024);
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            handle_connection_with_reason(server, "stalled", &WhitelistAuthorizer::new(whitelist), &server_keys, &config),
+        )
+        .await
+        .expect("a stalled client must not hold the connection open");
+
+        let (reason, err) = result.unwrap_err();
+        assert_eq!(reason, DisconnectReason::Timeout);
+        assert_eq!(err.to_string(), AppError::Protocol("read timeout".to_string()).to_string());
+    }
+
+    #[tokio::test]
+    async fn test_quota_rejects_peer_until_window_resets() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret;quota=2files/day", None).unwrap();
+        let clock = MockClock::new(chrono::DateTime::parse_from_rfc3339("2024-01-01T09:00:00Z").unwrap().to_utc());
+        let config = ServerConfig {
+            messages_dir: dir.path().join("messages").to_string_lossy().to_string(),
+            clock: Arc::new(clock.clone()),
+            ..ServerConfig::default()
+        };
+        let server = crate::server::Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), &config.messages_dir)
+            .unwrap()
+            .with_config(config)
+            .spawn()
+            .await
+            .unwrap();
+
+        let file = dir.path().join("ledger.csv");
+        fs::write(&file, b"ledger").unwrap();
+        let client = Client::new("127.0.0.1", server.port(), KeyPair::generate().unwrap());
+        client.send_message(&file, "secret", Some("first"), None).await.unwrap();
+        client.send_message(&file, "secret", Some("second"), None).await.unwrap();
+
+        let err = client.send_message(&file, "secret", Some("third"), None).await.unwrap_err();
+        assert!(err.to_string().contains("quota-exceeded"), "{}", err);
+        assert!(!dir.path().join("messages").read_dir().unwrap().any(|e| {
+            e.unwrap().file_name().to_string_lossy().starts_with("third")
+        }));
+
+        clock.advance(chrono::Duration::days(1));
+        client.send_message(&file, "secret", Some("third"), None).await.unwrap();
+
+        server.shutdown();
+        server.wait().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_type_mismatch_warns_or_rejects_per_policy() {
+        for policy in [TypeMismatchPolicy::Warn, TypeMismatchPolicy::Reject] {
+            let dir = tempfile::tempdir().unwrap();
+            let whitelist_path = dir.path().join("whitelist.txt");
+            Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+            let config = ServerConfig {
+                messages_dir: dir.path().join("messages").to_string_lossy().to_string(),
+                detect_type: true,
+                on_type_mismatch: policy,
+                ..ServerConfig::default()
+            };
+            let server = crate::server::Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), &config.messages_dir)
+                .unwrap()
+                .with_config(config)
+                .spawn()
+                .await
+                .unwrap();
+            let client = Client::new("127.0.0.1", server.port(), KeyPair::generate().unwrap());
+
+            let matching = dir.path().join("trades.json");
+            fs::write(&matching, b"[{\"id\": 1}]").unwrap();
+            client.send_message(&matching, "secret", None, None).await.unwrap();
+
+            let mislabeled = dir.path().join("positions.json");
+            fs::write(&mislabeled, [0u8, 159, 146, 150, 0, 1, 2, 3]).unwrap();
+            let result = client.send_message(&mislabeled, "secret", None, None).await;
+            match policy {
+                TypeMismatchPolicy::Warn => assert!(result.is_ok()),
+                TypeMismatchPolicy::Reject => {
+                    let err = result.unwrap_err().to_string();
+                    assert!(err.contains("rejected") && err.contains("binary"), "{}", err);
+                }
+            }
+
+            server.shutdown();
+            server.wait().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversize_message_reports_reason_to_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add("secret", None).unwrap();
+        let server_keys = Arc::new(KeyPair::generate().unwrap());
+        let config = ServerConfig {
+            messages_dir: dir.path().join("messages").to_string_lossy().to_string(),
+            max_message_bytes: Some(1024),
+            ..ServerConfig::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, &peer.to_string(), &WhitelistAuthorizer::new(whitelist), &server_keys, &config).await
+        });
+
+        // Large enough that the client is still writing when the server refuses
+        let file = dir.path().join("ledger.csv");
+        fs::write(&file, vec![b'x'; 4 * 1024 * 1024]).unwrap();
+        let err = Client::new("127.0.0.1", port, KeyPair::generate().unwrap())
+            .send_message(&file, "secret", None, None)
+            .await
+            .unwrap_err();
+
+        let message = err.to_string();
+        assert!(message.contains("Server disconnected: too-large: "), "{}", message);
+        assert!(message.contains("message exceeds maximum size"), "{}", message);
+        assert!(message.contains("limit is 1024 bytes"), "{}", message);
+        assert!(matches!(server.await.unwrap(), Err(AppError::Protocol(_))));
+        assert!(!dir.path().join("messages").exists());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_transfer_to_disk_ignores_message_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add("secret", None).unwrap();
+        let server_keys = Arc::new(KeyPair::generate().unwrap());
+        let messages_dir = dir.path().join("messages");
+        let config = ServerConfig {
+            messages_dir: messages_dir.to_string_lossy().to_string(),
+            max_message_bytes: Some(1024),
+            ..ServerConfig::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            handle_connection(stream, &peer.to_string(), &WhitelistAuthorizer::new(whitelist), &server_keys, &config).await
+        });
+
+        let file = dir.path().join("ledger.csv");
+        let data = vec![b'x'; 256 * 1024];
+        fs::write(&file, &data).unwrap();
+        let saved_as = Client::new("127.0.0.1", port, KeyPair::generate().unwrap())
+            .with_chunk_size(Some(16 * 1024))
+            .send_message(&file, "secret", None, None)
+            .await
+            .unwrap();
+
+        server.await.unwrap().unwrap();
+        assert_eq!(fs::read(messages_dir.join(saved_as)).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_header_without_data_refused_as_protocol_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add("secret", None).unwrap();
+        let server_keys = Arc::new(KeyPair::generate().unwrap());
+        let config = ServerConfig {
+            messages_dir: dir.path().join("messages").to_string_lossy().to_string(),
+            ..ServerConfig::default()
+        };
+
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            handle_connection(server_stream, "peer", &WhitelistAuthorizer::new(whitelist), &server_keys, &config).await
+        });
+
+        let mut channel = authenticate(client_stream, "secret", &KeyPair::generate().unwrap())
+            .await
+            .unwrap();
+        let header = MessageHeader::new("empty.csv", 0, &calculate_checksum(b""));
+        channel.send(&Message::new(MessageType::MessageHeader, header.to_bytes().unwrap())).await.unwrap();
+
+        let reply = channel.receive().await.unwrap();
+        reply.expect_type(MessageType::Disconnect).unwrap();
+        let notice = Disconnect::from_bytes(&reply.payload).unwrap();
+        assert_eq!(notice.reason, DisconnectReason::ProtocolError);
+        assert!(notice.message.contains("declares no data"), "{}", notice.message);
+        assert!(server.await.unwrap().is_err());
+        assert!(!dir.path().join("messages").exists());
+    }
+
+    #[tokio::test]
+    async fn test_memory_budget_serializes_large_transfers() {
+        use rand::RngCore;
+        use crate::server::MemoryBudget;
+
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages").to_string_lossy().to_string();
+        let budget = Arc::new(MemoryBudget::new(4 * 1024 * 1024));
+        let config = ServerConfig {
+            messages_dir: messages_dir.clone(),
+            memory_budget: Some(budget.clone()),
+            encrypt_at_rest: true,
+            ..ServerConfig::default()
+        };
+        let server_keys = KeyPair::generate().unwrap();
+        let server_key = server_keys.public_key.clone();
+        let server = crate::server::Server::new(0, &whitelist_path, server_keys, &messages_dir)
+            .unwrap()
+            .with_config(config)
+            .spawn()
+            .await
+            .unwrap();
+
+        // Decrypting a 1.2 MiB transfer holds two copies of its sealed size,
+        // so two together exceed the budget and must run one after the other
+        let data: Vec<Vec<u8>> = (0..2)
+            .map(|_| {
+                let mut data = vec![0u8; 1200 * 1024];
+                rand::thread_rng().fill_bytes(&mut data);
+                data
+            })
+            .collect();
+        let sealed = crate::crypto::encrypt_large(&server_key, &data[0]).unwrap().to_bytes().unwrap().len() as u64;
+        let working_set = (2 * sealed).div_ceil(1024) * 1024;
+        assert!(2 * working_set > budget.bytes() && working_set <= budget.bytes());
+
+        let sends: Vec<_> = data
+            .into_iter()
+            .enumerate()
+            .map(|(n, data)| {
+                let port = server.port();
+                tokio::spawn(async move {
+                    Client::new("127.0.0.1", port, KeyPair::generate().unwrap())
+                        .send_bytes(&data, &format!("large_{}.bin", n), "secret", None, None)
+                        .await
+                })
+            })
+            .collect();
+        for send in sends {
+            send.await.unwrap().unwrap();
+        }
+
+        assert_eq!(budget.peak_reserved(), working_set);
+        server.shutdown();
+        server.wait().await.unwrap();
+    }
 }
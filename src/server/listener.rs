@@ -1,83 +1,592 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use rand::Rng;
 use tokio::net::TcpListener;
-use tokio::sync::broadcast;
+use tokio::time::Instant;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
 use crate::error::{AppError, Result};
-use crate::crypto::KeyPair;
-use crate::auth::Whitelist;
+use crate::crypto::{KeyPair, CipherSuite};
+use crate::auth::{Authorizer, Whitelist, WhitelistAuthorizer};
 use crate::cli::Output;
+use crate::clock::SharedClock;
+use crate::security::SecurityLevel;
+use crate::transport::Acceptor;
+#[cfg(unix)]
+use crate::transport::bind_unix;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use super::connection_id::ConnectionId;
+use super::message_handler::MessageHandler;
+use super::rate_limit::ConnectionRateLimit;
+use super::config::{ServerConfig, CollisionPolicy};
+use crate::protocol::{AcceptedMessage, ReceivedMessage};
+use super::report::{ServerCounters, ShutdownReport};
+use super::storage::ensure_empty_dir;
+use super::transfer_log::{TransferLog, TransferRecord};
+
+/// Address the server binds unless told otherwise: every IPv4 interface
+pub const DEFAULT_BIND_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+
+/// Parse a `--bind` address such as `127.0.0.1`, `::1` or `[::1]`
+pub fn parse_bind_addr(addr: &str) -> Result<IpAddr> {
+    let trimmed = addr.trim();
+    let unbracketed = trimmed
+        .strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .unwrap_or(trimmed);
+    unbracketed.parse().map_err(|_| {
+        AppError::Config(format!(
+            "Invalid bind address '{}': expected an IPv4 or IPv6 address such as 127.0.0.1 or [::1]",
+            addr
+        ))
+    })
+}
 
 /// TCP server for receiving messages
 pub struct Server {
+    bind_addr: IpAddr,
     port: u16,
-    whitelist: Whitelist,
+    access: Access,
+    whitelist_reload: Option<Duration>,
+    drain_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    /// One permit per connection allowed at once, if `max_connections` is set
+    connection_slots: Option<Arc<Semaphore>>,
+    rate_limit: Option<Arc<ConnectionRateLimit>>,
     keypair: Arc<KeyPair>,
     shutdown_tx: broadcast::Sender<()>,
-    messages_dir: String,
+    drain_tx: broadcast::Sender<()>,
+    received_tx: broadcast::Sender<ReceivedMessage>,
+    transfer_log: Option<Arc<TransferLog>>,
+    last_accept: LastAccept,
+    config: ServerConfig,
+}
+
+/// Where a server looks up who may connect
+enum Access {
+    /// The whitelist file, re-read on reload
+    Whitelist(Arc<RwLock<Whitelist>>),
+    /// A custom authorizer; no whitelist file is involved
+    Authorizer(Arc<dyn Authorizer>),
+}
+
+/// When a server last accepted a connection, readable while it runs
+///
+/// Reset when the server starts serving, so an idle server's age counts from
+/// then. Measured on tokio's clock, which tests can pause and advance.
+#[derive(Clone, Debug)]
+pub struct LastAccept(Arc<Mutex<Instant>>);
+
+impl LastAccept {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    fn record(&self) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+    }
+
+    /// Time since the last accepted connection, or since startup if there was none
+    pub fn elapsed(&self) -> Duration {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).elapsed()
+    }
 }
 
 impl Server {
     /// Create a new server instance
     pub fn new(port: u16, whitelist_path: &Path, keypair: KeyPair, messages_dir: &str) -> Result<Self> {
         let whitelist = Whitelist::load(whitelist_path)?;
+        warn_if_empty(&whitelist);
+        Ok(Self::with_access(port, Access::Whitelist(Arc::new(RwLock::new(whitelist))), keypair, messages_dir))
+    }
+
+    /// Create a server that asks `authorizer` who may connect, with no whitelist file
+    ///
+    /// As with [`Server::with_authorizer`], no per-key filename patterns or
+    /// quotas apply unless the authorizer supplies entries of its own.
+    pub fn with_authorizer_only(port: u16, authorizer: Arc<dyn Authorizer>, keypair: KeyPair, messages_dir: &str) -> Self {
+        Self::with_access(port, Access::Authorizer(authorizer), keypair, messages_dir)
+    }
+
+    fn with_access(port: u16, access: Access, keypair: KeyPair, messages_dir: &str) -> Self {
         let (shutdown_tx, _) = broadcast::channel(1);
+        let (drain_tx, _) = broadcast::channel(1);
+        let (received_tx, _) = broadcast::channel(64);
 
-        Ok(Self {
+        Self {
+            bind_addr: DEFAULT_BIND_ADDR,
             port,
-            whitelist,
+            access,
+            whitelist_reload: None,
+            drain_timeout: None,
+            max_connections: None,
+            connection_slots: None,
+            rate_limit: None,
             keypair: Arc::new(keypair),
             shutdown_tx,
-            messages_dir: messages_dir.to_string(),
-        })
+            drain_tx,
+            received_tx,
+            transfer_log: None,
+            last_accept: LastAccept::new(),
+            config: ServerConfig {
+                messages_dir: messages_dir.to_string(),
+                ..ServerConfig::default()
+            },
+        }
+    }
+
+    /// Unpack received directory archives into the messages directory
+    pub fn with_extract_dirs(mut self, enabled: bool) -> Self {
+        self.config.extract_dirs = enabled;
+        self
+    }
+
+    /// Set the policy applied when a received file already exists
+    pub fn with_collision_policy(mut self, policy: CollisionPolicy) -> Self {
+        self.config.on_collision = policy;
+        self
+    }
+
+    /// Replace the whole connection-handling configuration
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Listen on `addr` (an interface address, or `::` for every IPv6 one) instead of every IPv4 interface
+    pub fn with_bind_addr(mut self, addr: IpAddr) -> Self {
+        self.bind_addr = addr;
+        self
+    }
+
+    /// Stage in-progress files in `dir` instead of the messages directory
+    pub fn with_temp_dir(mut self, dir: Option<&str>) -> Self {
+        self.config.temp_dir = dir.map(str::to_string);
+        self
+    }
+
+    /// Refuse to start if the messages directory already holds anything
+    pub fn with_require_empty_messages_dir(mut self, enabled: bool) -> Self {
+        self.config.require_empty_messages_dir = enabled;
+        self
+    }
+
+    /// Store received files encrypted to the server's own key instead of as plaintext
+    pub fn with_encrypt_at_rest(mut self, enabled: bool) -> Self {
+        self.config.encrypt_at_rest = enabled;
+        self
+    }
+
+    /// Refuse messages larger than `limit` bytes before reading their data
+    ///
+    /// Defaults to [`DEFAULT_MAX_MESSAGE_BYTES`](super::DEFAULT_MAX_MESSAGE_BYTES);
+    /// `None` lifts the limit.
+    pub fn with_max_message_bytes(mut self, limit: Option<u64>) -> Self {
+        self.config.max_message_bytes = limit;
+        self
+    }
+
+    /// Drop a connection once a read has waited `timeout` without data; never if `None`
+    ///
+    /// Each received byte restarts the wait, so a slow transfer that keeps
+    /// making progress is not affected. Defaults to [`DEFAULT_READ_TIMEOUT`](super::DEFAULT_READ_TIMEOUT).
+    pub fn with_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    /// Append a JSON line to `log` for every stored transfer
+    pub fn with_transfer_log(mut self, log: Option<TransferLog>) -> Self {
+        self.transfer_log = log.map(Arc::new);
+        self
+    }
+
+    /// Read the time from `clock` instead of the system clock
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.config.handshake.clock = clock.clone();
+        self.config.clock = clock;
+        self
+    }
+
+    /// Restrict the cipher suites the server will negotiate
+    pub fn with_cipher_suites(mut self, suites: &[CipherSuite]) -> Self {
+        self.config.handshake.cipher_suites = suites.to_vec();
+        self
+    }
+
+    /// Use the handshake settings of a security level
+    pub fn with_security_level(mut self, level: SecurityLevel) -> Self {
+        level.apply(&mut self.config.handshake);
+        self
+    }
+
+    /// Re-read the whitelist file about every `interval` while serving
+    ///
+    /// Each wait is stretched by up to a tenth at random so a fleet started
+    /// together does not reload in lockstep. Added and removed keys are logged
+    /// by hash; a file that fails to load leaves the current entries in place.
+    /// Has no effect once a custom authorizer decides who may connect.
+    pub fn with_whitelist_reload(mut self, interval: Option<Duration>) -> Self {
+        self.whitelist_reload = interval;
+        self
+    }
+
+    /// Stop waiting for in-flight connections this long after a drain starts
+    ///
+    /// Connections still running then are aborted, which closes them, and
+    /// counted as interrupted in the report. `None` waits for all of them.
+    pub fn with_drain_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Handle at most `max` connections at once, closing any beyond that straight away
+    ///
+    /// Excess connections are refused rather than queued, so a client
+    /// opening hundreds of sockets cannot hold the server's backlog; each
+    /// refusal is logged and counted as `busy` in the report.
+    pub fn with_max_connections(mut self, max: Option<usize>) -> Self {
+        self.max_connections = max;
+        self.connection_slots = max.map(|max| Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Accept at most `per_minute` new connections from one IP address per minute
+    ///
+    /// Connections over the limit are closed before the handshake, logged
+    /// and counted as `rate-limited` in the report. Unix socket peers are
+    /// not limited. The minute is measured on the server's clock.
+    pub fn with_connection_rate_limit(mut self, per_minute: Option<u32>) -> Self {
+        self.rate_limit = per_minute.map(|n| Arc::new(ConnectionRateLimit::new(n)));
+        self
+    }
+
+    /// Decide who may connect with `authorizer` instead of the whitelist file
+    ///
+    /// The whitelist is then dropped and never reloaded, and no per-key
+    /// filename patterns or quotas apply unless the authorizer supplies
+    /// entries of its own. To start without a whitelist file at all, use
+    /// [`Server::with_authorizer_only`].
+    pub fn with_authorizer(mut self, authorizer: Arc<dyn Authorizer>) -> Self {
+        self.access = Access::Authorizer(authorizer);
+        self
+    }
+
+    /// Hand each received message to `handler` instead of writing it to the messages directory
+    ///
+    /// The name the handler returns is what the client is told the message
+    /// was saved as. No sidecar is written, and messages are neither
+    /// encrypted at rest nor extracted; that is up to the handler.
+    pub fn with_message_handler(mut self, handler: Arc<dyn MessageHandler>) -> Self {
+        self.config.message_handler = Some(handler);
+        self
     }
 
     /// Start the server
-    pub async fn start(&self) -> Result<()> {
-        let addr = format!("0.0.0.0:{}", self.port);
-        let listener = TcpListener::bind(&addr)
+    pub async fn start(&self) -> Result<ShutdownReport> {
+        let listener = self.bind().await?;
+        let addr = local_addr(&listener)?;
+        // Subscribe before announcing the server, so a signal sent as soon as
+        // it is up is not lost
+        let signals = self.subscribe_signals();
+
+        Output::listening(addr);
+        Output::server_started(addr.port());
+
+        self.serve_on(listener, signals).await
+    }
+
+    /// Bind the configured port and serve on a background task
+    ///
+    /// Returns once the listener is bound, so connections made after this
+    /// resolves are accepted. Port 0 binds an ephemeral port; the handle
+    /// reports which one. Wrap the call in `tokio::time::timeout` to bound
+    /// startup.
+    pub async fn spawn(self) -> Result<ServerHandle> {
+        let listener = self.bind().await?;
+        let local_addr = local_addr(&listener)?;
+        let shutdown_tx = self.shutdown_tx.clone();
+        let drain_tx = self.drain_tx.clone();
+        let received_tx = self.received_tx.clone();
+        // Subscribe before the task starts so an immediate shutdown is not lost
+        let signals = self.subscribe_signals();
+        let task = tokio::spawn(async move { self.serve_on(listener, signals).await });
+
+        Ok(ServerHandle {
+            local_addr,
+            shutdown_tx,
+            drain_tx,
+            received_tx,
+            task,
+        })
+    }
+
+    async fn bind(&self) -> Result<TcpListener> {
+        self.check_startup()?;
+        let addr = SocketAddr::new(self.bind_addr, self.port);
+        TcpListener::bind(addr)
             .await
-            .map_err(|e| AppError::Server(format!("Failed to bind to {}: {}", addr, e)))?;
+            .map_err(|e| AppError::Server(format!("Failed to bind to {}: {}", addr, e)))
+    }
 
-        Output::listening("0.0.0.0", self.port);
-        Output::server_started(self.port);
+    /// Start the server on a Unix domain socket instead of a TCP port
+    ///
+    /// The socket file is created with owner-only permissions and removed
+    /// again when the server stops.
+    #[cfg(unix)]
+    pub async fn start_unix(&self, path: &Path) -> Result<ShutdownReport> {
+        let listener = bind_unix(path)?;
+        let signals = self.subscribe_signals();
+        Output::info(&format!("Listening on {}", path.display()));
+        Output::helper("Press Ctrl+C to stop the server");
 
-        let mut shutdown_rx = self.shutdown_tx.subscribe();
+        let report = self.serve_on(listener, signals).await;
+        let _ = std::fs::remove_file(path);
+        report
+    }
+
+    /// Accept and handle connections on an already bound listener
+    ///
+    /// Returns immediately on shutdown. On drain the listener is closed so new
+    /// connections are refused, and the call returns once every in-flight
+    /// connection has finished or the drain timeout has passed. Either way
+    /// the returned report summarises the run.
+    pub async fn serve(&self, listener: TcpListener) -> Result<ShutdownReport> {
+        self.serve_on(listener, self.subscribe_signals()).await
+    }
+
+    /// Accept and handle connections on an already bound Unix domain socket
+    #[cfg(unix)]
+    pub async fn serve_unix(&self, listener: UnixListener) -> Result<ShutdownReport> {
+        self.serve_on(listener, self.subscribe_signals()).await
+    }
+
+    /// Bind the configured port, receive one message and return it without storing it
+    ///
+    /// Runs the same handshake and checks as [`Server::start`], but the
+    /// decrypted data is handed back instead of being written to the
+    /// messages directory, and the port is released again afterwards. Use
+    /// [`Server::accept_one_on`] to keep accepting on one listener.
+    pub async fn accept_one(&self) -> Result<AcceptedMessage> {
+        let listener = self.bind().await?;
+        self.accept_one_on(&listener).await
+    }
+
+    /// Like [`Server::accept_one`], on an already bound listener
+    pub async fn accept_one_on(&self, listener: &TcpListener) -> Result<AcceptedMessage> {
+        let (stream, peer) = listener
+            .accept_stream()
+            .await
+            .map_err(|e| AppError::Server(format!("Failed to accept connection: {}", e)))?;
+        self.last_accept.record();
+        let connection_id = ConnectionId::random();
+        connection_id.sync_scope(|| Output::connection_from(&peer));
+
+        let authorizer = self.authorizer_for_connection();
+        connection_id
+            .scope(super::handler::accept_message(stream, &peer, authorizer.as_ref(), &self.keypair, &self.config))
+            .await
+    }
+
+    /// Checks that must pass before any connection is accepted
+    fn check_startup(&self) -> Result<()> {
+        if self.config.require_empty_messages_dir {
+            ensure_empty_dir(Path::new(&self.config.messages_dir))?;
+        }
+        Ok(())
+    }
+
+    /// Receivers for the shutdown and drain signals
+    fn subscribe_signals(&self) -> (broadcast::Receiver<()>, broadcast::Receiver<()>) {
+        (self.shutdown_tx.subscribe(), self.drain_tx.subscribe())
+    }
+
+    async fn serve_on<L: Acceptor>(
+        &self,
+        listener: L,
+        (mut shutdown_rx, mut drain_rx): (broadcast::Receiver<()>, broadcast::Receiver<()>),
+    ) -> Result<ShutdownReport> {
+        self.check_startup()?;
+        let mut connections = JoinSet::new();
+        let counters = Arc::new(ServerCounters::new());
+        let mut next_reload = self.whitelist_reload.map(next_reload_at);
+        self.last_accept.record();
 
         loop {
             tokio::select! {
-                accept_result = listener.accept() => {
+                accept_result = listener.accept_stream() => {
                     match accept_result {
-                        Ok((stream, peer_addr)) => {
-                            Output::connection_from(&peer_addr.to_string());
+                        Ok((stream, peer)) => {
+                            self.last_accept.record();
+                            if let Some(refusal) = self.rate_limited(&peer) {
+                                Output::warning(&format!("Refusing connection from {}: {}", peer, refusal));
+                                counters.connection_refused("rate-limited");
+                                continue;
+                            }
+                            let slot = match &self.connection_slots {
+                                Some(slots) => match Arc::clone(slots).try_acquire_owned() {
+                                    Ok(slot) => Some(slot),
+                                    Err(_) => {
+                                        Output::warning(&format!(
+                                            "Refusing connection from {}: already handling {} connections",
+                                            peer,
+                                            self.max_connections.unwrap_or_default()
+                                        ));
+                                        counters.connection_refused("busy");
+                                        continue;
+                                    }
+                                },
+                                None => None,
+                            };
+                            let connection_id = ConnectionId::random();
+                            connection_id.sync_scope(|| Output::connection_from(&peer));
 
-                            let whitelist = self.whitelist.clone();
+                            let authorizer = self.authorizer_for_connection();
                             let keypair = Arc::clone(&self.keypair);
-                            let messages_dir = self.messages_dir.clone();
+                            let config = self.config.clone();
+                            let received_tx = self.received_tx.clone();
+                            let transfer_log = self.transfer_log.clone();
+                            let counters = Arc::clone(&counters);
+                            counters.connection_opened();
 
-                            tokio::spawn(async move {
-                                if let Err(e) = super::handler::handle_connection(
+                            // The handler runs on its own task so a panic in it is
+                            // caught here, counted and logged, instead of silently
+                            // ending the connection
+                            let handler = tokio::spawn(connection_id.scope(async move {
+                                // Hold the connection slot until the handler is done
+                                let _slot = slot;
+                                super::handler::handle_connection_with_reason(
                                     stream,
-                                    &whitelist,
+                                    &peer,
+                                    authorizer.as_ref(),
                                     &keypair,
-                                    &messages_dir,
-                                ).await {
-                                    Output::error(&format!("Connection error: {}", e));
+                                    &config,
+                                ).await
+                            }));
+
+                            connections.spawn(connection_id.scope(async move {
+                                // Aborting this task on a drain timeout stops the handler too
+                                let _abort = AbortOnDrop(handler.abort_handle());
+                                match handler.await {
+                                    Err(e) => {
+                                        counters.connection_panicked();
+                                        Output::error(&format!("Connection handler panicked: {}", panic_message(e)));
+                                    }
+                                    Ok(Ok(received)) => {
+                                        counters.connection_closed(received.len());
+                                        for message in received {
+                                            if let Some(log) = &transfer_log {
+                                                if let Err(e) = log.append(&TransferRecord::from(&message)) {
+                                                    Output::warning(&format!("Failed to write transfer log: {}", e));
+                                                }
+                                            }
+                                            let _ = received_tx.send(message);
+                                        }
+                                    }
+                                    Ok(Err((reason, e))) => {
+                                        counters.connection_rejected(reason);
+                                        Output::error(&format!("Connection error: {}", e));
+                                    }
                                 }
-                            });
+                            }));
                         }
                         Err(e) => {
                             Output::error(&format!("Failed to accept connection: {}", e));
                         }
                     }
                 }
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                _ = tokio::time::sleep_until(next_reload.unwrap_or_else(tokio::time::Instant::now)), if next_reload.is_some() => {
+                    self.reload_whitelist();
+                    next_reload = self.whitelist_reload.map(next_reload_at);
+                }
                 _ = shutdown_rx.recv() => {
                     Output::info("Server shutting down...");
+                    connections.detach_all();
+                    return Ok(counters.report());
+                }
+                _ = drain_rx.recv() => {
                     break;
                 }
             }
         }
 
-        Ok(())
+        drop(listener);
+        Output::info("Draining: no longer accepting new connections");
+
+        let deadline = self.drain_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        while !connections.is_empty() {
+            Output::info(&format!("draining: {} connections remaining", connections.len()));
+            tokio::select! {
+                _ = connections.join_next() => {}
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {
+                    Output::warning(&format!(
+                        "Drain timed out after {:?} with {} connections still active",
+                        self.drain_timeout.unwrap_or_default(),
+                        connections.len()
+                    ));
+                    break;
+                }
+                _ = shutdown_rx.recv() => {
+                    Output::info("Server shutting down...");
+                    break;
+                }
+            }
+        }
+
+        if connections.is_empty() {
+            Output::info("Drain complete, server stopped");
+            return Ok(counters.report());
+        }
+
+        // Whatever is left is closed rather than left running unsupervised
+        connections.abort_all();
+        while connections.join_next().await.is_some() {}
+        Ok(counters.report())
+    }
+
+    /// Why a new connection from `peer` is over the per-IP rate limit, if it is
+    fn rate_limited(&self, peer: &str) -> Option<String> {
+        let limit = self.rate_limit.as_ref()?;
+        let ip = peer.parse::<SocketAddr>().ok()?.ip();
+        if limit.admit(ip, self.config.clock.now()) {
+            return None;
+        }
+        Some(format!("more than {} connections from {} in the last minute", limit.per_minute(), ip))
+    }
+
+    /// The custom authorizer, or a snapshot of the current whitelist
+    fn authorizer_for_connection(&self) -> Arc<dyn Authorizer> {
+        match &self.access {
+            Access::Authorizer(authorizer) => Arc::clone(authorizer),
+            Access::Whitelist(whitelist) => {
+                let whitelist = whitelist.read().unwrap_or_else(|e| e.into_inner()).clone();
+                Arc::new(WhitelistAuthorizer::new(whitelist))
+            }
+        }
+    }
+
+    /// Swap in a fresh copy of the whitelist file, logging what changed
+    fn reload_whitelist(&self) {
+        let Access::Whitelist(whitelist) = &self.access else {
+            return;
+        };
+        let path = whitelist.read().unwrap_or_else(|e| e.into_inner()).path().to_path_buf();
+        match Whitelist::load(&path) {
+            Ok(fresh) => {
+                warn_if_empty(&fresh);
+                let mut current = whitelist.write().unwrap_or_else(|e| e.into_inner());
+                let change = fresh.changes_since(&current);
+                *current = fresh;
+                if !change.is_empty() {
+                    Output::info(&change.to_string());
+                }
+            }
+            Err(e) => Output::warning(&format!("Whitelist reload failed, keeping previous entries: {}", e)),
+        }
     }
 
     /// Get shutdown channel sender
@@ -89,4 +598,679 @@ impl Server {
     pub fn shutdown(&self) {
         let _ = self.shutdown_tx.send(());
     }
+
+    /// Get drain channel sender
+    pub fn drain_channel(&self) -> broadcast::Sender<()> {
+        self.drain_tx.clone()
+    }
+
+    /// Handle on when the server last accepted a connection
+    pub fn last_accept(&self) -> LastAccept {
+        self.last_accept.clone()
+    }
+
+    /// Subscribe to a [`ReceivedMessage`] for every transfer the server stores
+    pub fn subscribe_received(&self) -> broadcast::Receiver<ReceivedMessage> {
+        self.received_tx.subscribe()
+    }
+
+    /// Stop accepting connections and finish once in-flight ones complete
+    pub fn drain(&self) {
+        let _ = self.drain_tx.send(());
+    }
+}
+
+/// When the next whitelist reload is due, with up to 10% jitter
+fn next_reload_at(interval: Duration) -> tokio::time::Instant {
+    let jitter = rand::thread_rng().gen_range(Duration::ZERO..=interval / 10);
+    tokio::time::Instant::now() + interval + jitter
+}
+
+/// Best-effort description of why a handler task ended abnormally
+fn panic_message(error: tokio::task::JoinError) -> String {
+    if !error.is_panic() {
+        return error.to_string();
+    }
+    let payload = error.into_panic();
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string panic payload".to_string()
+    }
+}
+
+/// Aborts a task when dropped, so aborting whatever awaits it stops it too
+struct AbortOnDrop(tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn local_addr(listener: &TcpListener) -> Result<SocketAddr> {
+    listener
+        .local_addr()
+        .map_err(|e| AppError::Server(format!("Failed to read bound address: {}", e)))
+}
+
+/// A server running on a background task, returned by [`Server::spawn`]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown_tx: broadcast::Sender<()>,
+    drain_tx: broadcast::Sender<()>,
+    received_tx: broadcast::Sender<ReceivedMessage>,
+    task: JoinHandle<Result<ShutdownReport>>,
+}
+
+impl ServerHandle {
+    /// Address the server is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Port the server is bound to, useful after binding port 0
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    /// Subscribe to a [`ReceivedMessage`] for every transfer the server stores
+    pub fn subscribe_received(&self) -> broadcast::Receiver<ReceivedMessage> {
+        self.received_tx.subscribe()
+    }
+
+    /// Trigger shutdown
+    pub fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(());
+    }
+
+    /// Stop accepting connections and finish once in-flight ones complete
+    pub fn drain(&self) {
+        let _ = self.drain_tx.send(());
+    }
+
+    /// Wait for the server to stop and return its report
+    pub async fn wait(self) -> Result<ShutdownReport> {
+        self.task
+            .await
+            .map_err(|e| AppError::Server(format!("Server task failed: {}", e)))?
+    }
+}
+
+/// An empty whitelist refuses every peer, which is rarely intended
+fn warn_if_empty(whitelist: &Whitelist) {
+    if whitelist.is_empty() {
+        Output::warning(&format!(
+            "Whitelist {} has no entries, every connection will be refused",
+            whitelist.path().display()
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use crate::protocol::handshake::PROTOCOL_MAGIC;
+    use rsa::RsaPublicKey;
+    use crate::client::Client;
+    use crate::test_support::{spawn_test_server, temp_whitelist, TEST_CONNECT_KEY};
+
+    #[tokio::test]
+    async fn test_drain_refuses_new_connections_and_finishes_in_flight() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::generate().unwrap();
+        let messages_dir = dir.path().join("messages");
+        Whitelist::create(&dir.path().join("whitelist.txt")).unwrap();
+        let server = Server::new(
+            0,
+            &dir.path().join("whitelist.txt"),
+            keypair,
+            messages_dir.to_str().unwrap(),
+        )
+        .unwrap();
+        let drain = server.drain_channel();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = tokio::spawn(async move { server.serve(listener).await });
+
+        // An in-flight connection: wait until its handler has sent the challenge
+        let mut in_flight = TcpStream::connect(addr).await.unwrap();
+        in_flight.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let mut len = [0u8; 4];
+        in_flight.read_exact(&mut len).await.unwrap();
+
+        drain.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(TcpStream::connect(addr).await.is_err());
+        assert!(!serving.is_finished());
+
+        // Once the in-flight connection ends, the drain completes
+        drop(in_flight);
+        let result = tokio::time::timeout(Duration::from_secs(5), serving).await.unwrap();
+        let report = result.unwrap().unwrap();
+        assert_eq!(report.connections, 1);
+        assert_eq!(report.interrupted, 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout_abandons_stalled_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        Whitelist::create(&dir.path().join("whitelist.txt")).unwrap();
+        let server = Server::new(0, &dir.path().join("whitelist.txt"), KeyPair::generate().unwrap(), "messages")
+            .unwrap()
+            .with_drain_timeout(Some(Duration::from_millis(200)));
+        let drain = server.drain_channel();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = tokio::spawn(async move { server.serve(listener).await });
+
+        // A client that stops answering after the challenge
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        stalled.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let mut len = [0u8; 4];
+        stalled.read_exact(&mut len).await.unwrap();
+
+        drain.send(()).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), serving).await.unwrap();
+        let report = result.unwrap().unwrap();
+        assert_eq!(report.connections, 1);
+        assert_eq!(report.interrupted, 1);
+
+        // The abandoned handler was stopped, so the connection is closed now
+        // rather than when the read timeout would have ended it
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(5), stalled.read_to_end(&mut rest)).await;
+        assert!(closed.is_ok(), "connection still open after the drain timed out");
+    }
+
+    /// Connect and wait for the challenge; `None` if the server closed the connection instead
+    async fn connect_for_challenge(addr: SocketAddr) -> Option<TcpStream> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&PROTOCOL_MAGIC).await.ok()?;
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).await.ok()?;
+        Some(stream)
+    }
+
+    #[tokio::test]
+    async fn test_connections_beyond_max_are_refused() {
+        let mut slots = None;
+        let (addr, server) = spawn_test_server(|server| {
+            let server = server.with_max_connections(Some(1)).with_drain_timeout(Some(Duration::from_millis(200)));
+            slots = server.connection_slots.clone();
+            server
+        })
+        .await
+        .unwrap();
+        let slots = slots.unwrap();
+
+        let first = connect_for_challenge(addr).await.expect("first connection is served");
+        assert!(connect_for_challenge(addr).await.is_none());
+
+        // The slot is free again once the first connection's handler has ended
+        drop(first);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while slots.available_permits() == 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        let third = connect_for_challenge(addr).await;
+        assert!(third.is_some());
+
+        let report = server.drain().await.unwrap();
+        assert_eq!(report.connections, 3);
+        assert_eq!(report.rejected.get("busy"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_connections_over_rate_limit_are_refused() {
+        let clock = crate::clock::MockClock::new(chrono::Utc::now());
+        let server_clock = Arc::new(clock.clone());
+        let (addr, server) = spawn_test_server(|server| server.with_clock(server_clock).with_connection_rate_limit(Some(2)))
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            assert!(connect_for_challenge(addr).await.is_some());
+        }
+        assert!(connect_for_challenge(addr).await.is_none());
+
+        // Once the server's clock has moved a minute on, the address may connect again
+        clock.advance(crate::server::RATE_LIMIT_WINDOW);
+        assert!(connect_for_challenge(addr).await.is_some());
+
+        let report = server.drain().await.unwrap();
+        assert_eq!(report.rejected.get("rate-limited"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_server_clock_times_out_challenges() {
+        use crate::protocol::handshake::{build_auth_response, receive_message, send_message, send_public_key, HandshakeOptions};
+        use crate::clock::Clock;
+        use crate::protocol::message::{AuthChallenge, Message, MessageType};
+
+        let clock = crate::clock::MockClock::new(chrono::Utc::now() - chrono::Duration::days(1));
+        let issued_at = clock.now();
+        let (addr, server) = spawn_test_server(|server| server.with_clock(Arc::new(clock.clone()))).await.unwrap();
+        let client_keys = KeyPair::generate().unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let challenge = AuthChallenge::from_bytes(&receive_message(&mut stream).await.unwrap().payload).unwrap();
+        assert_eq!(challenge.timestamp, issued_at.to_rfc3339());
+
+        // Answered too late by the server's clock, though at once by the system's
+        let response = build_auth_response(&challenge, TEST_CONNECT_KEY, &client_keys, &HandshakeOptions::default()).unwrap();
+        clock.advance(crate::auth::TOKEN_LIFETIME);
+        send_message(&mut stream, &Message::new(MessageType::AuthResponse, response.to_bytes().unwrap())).await.unwrap();
+        send_public_key(&mut stream, &client_keys.public_key).await.unwrap();
+        let refused = receive_message(&mut stream).await.unwrap();
+        assert!(matches!(refused.msg_type, MessageType::AuthFailure));
+        assert_eq!(refused.payload, b"Challenge expired");
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_report_counts_transfers() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let server = Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap()).unwrap();
+        let shutdown = server.shutdown_channel();
+        let mut received = server.subscribe_received();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let serving = tokio::spawn(async move { server.serve(listener).await });
+
+        let file = dir.path().join("ledger.csv");
+        std::fs::write(&file, b"ledger").unwrap();
+        let client = crate::client::Client::new("127.0.0.1", addr.port(), KeyPair::generate().unwrap());
+        for _ in 0..2 {
+            client.send_message(&file, "secret", None, None).await.unwrap();
+            received.recv().await.unwrap();
+        }
+
+        // A connection that is still handshaking when the server stops
+        let mut in_flight = TcpStream::connect(addr).await.unwrap();
+        in_flight.write_all(&PROTOCOL_MAGIC).await.unwrap();
+        let mut len = [0u8; 4];
+        in_flight.read_exact(&mut len).await.unwrap();
+
+        shutdown.send(()).unwrap();
+        let report = serving.await.unwrap().unwrap();
+        assert_eq!(report.connections, 3);
+        assert_eq!(report.files_received, 2);
+        assert_eq!(report.interrupted, 1);
+    }
+
+    #[tokio::test]
+    async fn test_report_counts_rejections_by_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut whitelist = Whitelist::load_or_create(&dir.path().join("whitelist.txt")).unwrap();
+        whitelist.add(TEST_CONNECT_KEY, None).unwrap();
+        whitelist.add("limited;quota=1files/day", None).unwrap();
+        let authorizer = Arc::new(WhitelistAuthorizer::new(whitelist));
+        let (addr, server) = spawn_test_server(|server| server.with_authorizer(authorizer).with_max_message_bytes(Some(1024)))
+            .await
+            .unwrap();
+
+        let small = dir.path().join("small.csv");
+        std::fs::write(&small, b"ledger").unwrap();
+        let large = dir.path().join("large.csv");
+        std::fs::write(&large, vec![b'x'; 4096]).unwrap();
+        let client = crate::client::Client::new("127.0.0.1", addr.port(), KeyPair::generate().unwrap());
+
+        client.send_message(&small, TEST_CONNECT_KEY, None, None).await.unwrap();
+        client.send_message(&small, "limited", None, None).await.unwrap();
+        assert!(client.send_message(&small, "limited", None, None).await.is_err());
+        assert!(client.send_message(&small, "wrong", None, None).await.is_err());
+        assert!(client.send_message(&large, TEST_CONNECT_KEY, None, None).await.is_err());
+        // Not our protocol at all
+        let mut stranger = TcpStream::connect(addr).await.unwrap();
+        stranger.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let _ = stranger.read_to_end(&mut Vec::new()).await;
+
+        // Draining waits for every handler, so all outcomes are counted
+        let report = server.drain().await.unwrap();
+        assert_eq!(report.connections, 6);
+        assert_eq!(report.files_received, 2);
+        let expected: std::collections::BTreeMap<String, u64> = [
+            ("auth-failed", 1),
+            ("protocol-error", 1),
+            ("quota-exceeded", 1),
+            ("too-large", 1),
+        ]
+        .into_iter()
+        .map(|(reason, count)| (reason.to_string(), count))
+        .collect();
+        assert_eq!(report.rejected, expected);
+        assert_eq!(report.rejected_total(), 4);
+        assert!(report.to_json().unwrap().contains(r#""rejected":{"auth-failed":1,"#));
+    }
+
+    /// Clock whose first reading panics, standing in for a bug deep in a handler
+    #[derive(Debug, Default)]
+    struct PanicOnceClock(std::sync::atomic::AtomicBool);
+
+    impl crate::clock::Clock for PanicOnceClock {
+        fn now(&self) -> chrono::DateTime<chrono::Utc> {
+            if !self.0.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                panic!("injected handler panic");
+            }
+            chrono::Utc::now()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic_is_counted_and_server_keeps_serving() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let config = ServerConfig {
+            messages_dir: messages_dir.to_str().unwrap().to_string(),
+            clock: Arc::new(PanicOnceClock::default()),
+            ..ServerConfig::default()
+        };
+        let server = Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap())
+            .unwrap()
+            .with_config(config);
+        let shutdown = server.shutdown_channel();
+        let mut received = server.subscribe_received();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        crate::cli::output::start_capture();
+        let serving = tokio::spawn(async move { server.serve(listener).await });
+        let file = dir.path().join("ledger.csv");
+        std::fs::write(&file, b"ledger").unwrap();
+        let client = crate::client::Client::new("127.0.0.1", addr.port(), KeyPair::generate().unwrap());
+
+        // The first handler panics mid-transfer; the next connection is served normally
+        assert!(client.send_message(&file, "secret", None, None).await.is_err());
+        client.send_message(&file, "secret", None, None).await.unwrap();
+        received.recv().await.unwrap();
+
+        shutdown.send(()).unwrap();
+        let report = serving.await.unwrap().unwrap();
+        let lines = crate::cli::output::take_captured();
+        assert_eq!(report.connections, 2);
+        assert_eq!(report.files_received, 1);
+        assert_eq!(report.panicked, 1);
+        assert_eq!(report.interrupted, 0);
+        assert!(
+            lines.iter().any(|line| line.contains("[conn ") && line.contains("panicked: injected handler panic")),
+            "{:#?}",
+            lines
+        );
+    }
+
+    #[tokio::test]
+    async fn test_log_lines_carry_their_connection_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let server = Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap()).unwrap();
+        let shutdown = server.shutdown_channel();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        crate::cli::output::start_capture();
+        let serving = tokio::spawn(async move { server.serve(listener).await });
+        let send = |name: &str| {
+            let file = dir.path().join(name);
+            std::fs::write(&file, name).unwrap();
+            let client = crate::client::Client::new("127.0.0.1", addr.port(), KeyPair::generate().unwrap());
+            async move { client.send_message(&file, "secret", None, None).await.unwrap() }
+        };
+        tokio::join!(send("alpha.csv"), send("bravo.csv"));
+        shutdown.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+        let lines = crate::cli::output::take_captured();
+
+        let mut by_id: std::collections::HashMap<&str, Vec<&String>> = std::collections::HashMap::new();
+        for line in &lines {
+            if let Some((_, rest)) = line.split_once("[conn ") {
+                by_id.entry(&rest[..8]).or_default().push(line);
+            }
+        }
+        assert_eq!(by_id.len(), 2, "{:#?}", lines);
+        for (id, events) in &by_id {
+            assert_eq!(id.len(), 8);
+            assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+            let mentions = |name: &str| events.iter().any(|line| line.contains(name));
+            assert!(mentions("Connection from"));
+            assert!(mentions("Message transfer complete"));
+            assert!(mentions("alpha.csv") != mentions("bravo.csv"), "{:#?}", events);
+        }
+        // Client-side lines are not part of any server connection
+        assert!(lines.iter().filter(|line| line.contains("Connecting to")).all(|line| !line.contains("[conn ")));
+    }
+
+    #[tokio::test]
+    async fn test_spawn_on_ephemeral_port() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let server = Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap()).unwrap();
+
+        let handle = tokio::time::timeout(Duration::from_secs(5), server.spawn()).await.unwrap().unwrap();
+        assert_ne!(handle.port(), 0);
+        let mut received = handle.subscribe_received();
+
+        // Ready as soon as spawn returns: no retry loop needed
+        let file = dir.path().join("ledger.csv");
+        std::fs::write(&file, b"ledger").unwrap();
+        let client = crate::client::Client::new("127.0.0.1", handle.port(), KeyPair::generate().unwrap());
+        let saved_as = client.send_message(&file, "secret", None, None).await.unwrap();
+        assert_eq!(received.recv().await.unwrap().path, Some(messages_dir.join(&saved_as)));
+
+        handle.shutdown();
+        let report = handle.wait().await.unwrap();
+        assert_eq!(report.files_received, 1);
+    }
+
+    #[tokio::test]
+    async fn test_authorizer_only_server_needs_no_whitelist_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let authorizer: Arc<dyn Authorizer> = Arc::new(|hash: &str, _: &RsaPublicKey| -> Result<bool> {
+            Ok(hash == crate::auth::hash_connect_key("directory"))
+        });
+        let messages_dir = dir.path().join("messages");
+        let server = Server::with_authorizer_only(0, authorizer, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap())
+            .spawn()
+            .await
+            .unwrap();
+
+        let client = Client::new("127.0.0.1", server.port(), KeyPair::generate().unwrap());
+        let saved_as = client.send_bytes(b"ACC-001,100.00\n", "balances.csv", "directory", None, None).await.unwrap();
+        assert!(messages_dir.join(&saved_as).exists());
+        assert!(client.send_bytes(b"x", "balances.csv", "other", None, None).await.is_err());
+        assert!(std::fs::read_dir(dir.path()).unwrap().all(|entry| entry.unwrap().path() == messages_dir));
+
+        server.shutdown();
+        server.wait().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_accept_one_returns_data_without_storing_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist = temp_whitelist(&[TEST_CONNECT_KEY]).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let server = Server::new(0, whitelist.path(), KeyPair::generate().unwrap(), messages_dir.to_str().unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let keypair = KeyPair::generate().unwrap();
+        let fingerprint = keypair.fingerprint().unwrap();
+        let file = dir.path().join("ledger.csv");
+        std::fs::write(&file, b"ACC-001,100.00\n").unwrap();
+        let client = crate::client::Client::new("127.0.0.1", port, keypair);
+
+        let (accepted, saved_as) = tokio::join!(server.accept_one_on(&listener), client.send_message(&file, TEST_CONNECT_KEY, None, Some("EOD")));
+        let accepted = accepted.unwrap();
+        assert_eq!(saved_as.unwrap(), "ledger.csv");
+        assert_eq!(accepted.fingerprint, fingerprint);
+        assert_eq!(accepted.filename, "ledger.csv");
+        assert_eq!(accepted.data, b"ACC-001,100.00\n");
+        assert_eq!(accepted.header.note.as_deref(), Some("EOD"));
+        assert!(!messages_dir.exists());
+
+        // Chunked transfers are collected in memory as well
+        let client = crate::client::Client::new("127.0.0.1", port, KeyPair::generate().unwrap()).with_chunk_size(Some(4));
+        let (accepted, sent) = tokio::join!(server.accept_one_on(&listener), client.send_message(&file, TEST_CONNECT_KEY, None, None));
+        sent.unwrap();
+        assert_eq!(accepted.unwrap().data, b"ACC-001,100.00\n");
+        assert!(!messages_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_message_handler_replaces_file_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&kept);
+        let handler = move |header: crate::protocol::MessageHeader, data: Vec<u8>| -> Result<String> {
+            if header.filename.ends_with(".sh") {
+                return Err(AppError::Server("scripts are not accepted".to_string()));
+            }
+            let mut kept = sink.lock().unwrap();
+            kept.push((header.filename, data));
+            Ok(format!("record-{}", kept.len()))
+        };
+        let (addr, server) = spawn_test_server(|server| server.with_message_handler(Arc::new(handler))).await.unwrap();
+        let mut received = server.handle().subscribe_received();
+
+        let file = dir.path().join("ledger.csv");
+        std::fs::write(&file, b"ACC-001,100.00\n").unwrap();
+        let client = crate::client::Client::new("127.0.0.1", addr.port(), KeyPair::generate().unwrap());
+        crate::cli::output::start_capture();
+        assert_eq!(client.send_message(&file, TEST_CONNECT_KEY, None, None).await.unwrap(), "record-1");
+        let handled = received.recv().await.unwrap();
+        assert_eq!(handled.meta.saved_as, "record-1");
+        assert_eq!(handled.path, None);
+        let lines = crate::cli::output::take_captured();
+        assert!(!lines.iter().any(|line| line.contains("File saved")), "{:#?}", lines);
+        assert_eq!(*kept.lock().unwrap(), [("ledger.csv".to_string(), b"ACC-001,100.00\n".to_vec())]);
+
+        let script = dir.path().join("payload.sh");
+        std::fs::write(&script, b"#!/bin/sh").unwrap();
+        let err = client.send_message(&script, TEST_CONNECT_KEY, None, None).await.unwrap_err();
+        assert!(err.to_string().contains("scripts are not accepted"), "{}", err);
+        assert!(!server.messages_dir().exists());
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_bind_addr() {
+        assert_eq!(parse_bind_addr("127.0.0.1").unwrap(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        assert_eq!(parse_bind_addr("[::1]").unwrap(), "::1".parse::<IpAddr>().unwrap());
+        assert_eq!(parse_bind_addr("::").unwrap(), "::".parse::<IpAddr>().unwrap());
+        for bad in ["localhost", "127.0.0.1:8080", "[127.0.0.1", ""] {
+            assert!(matches!(parse_bind_addr(bad), Err(AppError::Config(_))), "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_binds_configured_interface() {
+        let (_, server) = spawn_test_server(|server| server.with_bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST))).await.unwrap();
+
+        assert_eq!(server.handle().local_addr().ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_require_empty_messages_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let server = || {
+            Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap())
+                .unwrap()
+                .with_require_empty_messages_dir(true)
+        };
+
+        std::fs::create_dir(&messages_dir).unwrap();
+        let handle = server().spawn().await.unwrap();
+        handle.shutdown();
+        handle.wait().await.unwrap();
+
+        std::fs::write(messages_dir.join("leftover.ftt"), b"old run").unwrap();
+        let err = server().spawn().await.err().unwrap();
+        assert!(matches!(err, AppError::Config(_)), "{}", err);
+        assert!(err.to_string().contains("not empty"));
+    }
+
+    #[tokio::test]
+    async fn test_whitelist_edit_picked_up_by_timed_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let interval = Duration::from_millis(200);
+        let handle = Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap())
+            .unwrap()
+            .with_whitelist_reload(Some(interval))
+            .spawn()
+            .await
+            .unwrap();
+
+        let file = dir.path().join("ledger.csv");
+        std::fs::write(&file, b"ledger").unwrap();
+        let client = crate::client::Client::new("127.0.0.1", handle.port(), KeyPair::generate().unwrap());
+        assert!(client.send_message(&file, "rotated", None, None).await.is_err());
+
+        // Swap the key mid-run; within one jittered interval the new key works and the old one is gone
+        std::fs::write(&whitelist_path, "rotated\n").unwrap();
+        tokio::time::sleep(interval * 2).await;
+        client.send_message(&file, "rotated", None, None).await.unwrap();
+        assert!(client.send_message(&file, "secret", None, None).await.is_err());
+
+        handle.shutdown();
+        handle.wait().await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_transfer_over_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let whitelist_path = dir.path().join("whitelist.txt");
+        Whitelist::load_or_create(&whitelist_path).unwrap().add("secret", None).unwrap();
+        let messages_dir = dir.path().join("messages");
+        let server = Server::new(0, &whitelist_path, KeyPair::generate().unwrap(), messages_dir.to_str().unwrap()).unwrap();
+        let mut received = server.subscribe_received();
+
+        let socket = dir.path().join("finapp.sock");
+        let listener = bind_unix(&socket).unwrap();
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        tokio::spawn(async move { server.serve_unix(listener).await });
+
+        let file = dir.path().join("ledger.csv");
+        std::fs::write(&file, b"ledger").unwrap();
+        let target = format!("unix:{}", socket.display());
+        let client = crate::client::Client::new(&target, 0, KeyPair::generate().unwrap());
+        let saved_as = client.send_message(&file, "secret", None, None).await.unwrap();
+
+        assert_eq!(std::fs::read(messages_dir.join(&saved_as)).unwrap(), b"ledger");
+        let message = received.recv().await.unwrap();
+        assert_eq!(message.peer, target);
+    }
 }
@@ -0,0 +1,33 @@
+use std::fmt;
+use crate::error::Result;
+use crate::protocol::MessageHeader;
+
+/// Takes over received messages from the server instead of the messages directory
+///
+/// Called once per transfer, after the data has been decrypted, decompressed
+/// and checked against its checksum, with the header the client sent and the
+/// plaintext. The returned name is sent back to the client in the
+/// acknowledgment as the name the message was stored under. Returning an
+/// error refuses the transfer and the client is told why.
+///
+/// The handler runs on the connection's task, so slow work is best handed
+/// off. Closures `Fn(MessageHeader, Vec<u8>) -> Result<String>` work too.
+pub trait MessageHandler: Send + Sync {
+    /// Store or otherwise consume one message, returning the name it was stored under
+    fn handle(&self, header: MessageHeader, data: Vec<u8>) -> Result<String>;
+}
+
+impl<F> MessageHandler for F
+where
+    F: Fn(MessageHeader, Vec<u8>) -> Result<String> + Send + Sync,
+{
+    fn handle(&self, header: MessageHeader, data: Vec<u8>) -> Result<String> {
+        self(header, data)
+    }
+}
+
+impl fmt::Debug for dyn MessageHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MessageHandler")
+    }
+}
@@ -1,4 +1,29 @@
+pub mod config;
 pub mod listener;
 pub mod handler;
+pub mod storage;
+pub mod report;
+pub mod watch;
+pub mod proof;
+pub mod usage;
+pub mod transfer_log;
+pub mod content_type;
+pub mod budget;
+pub mod connection_id;
+pub mod message_handler;
+pub mod rate_limit;
+pub(crate) mod read_timeout;
 
-pub use listener::Server;
+pub use config::{ServerConfig, CollisionPolicy, TypeMismatchPolicy, DEFAULT_MAX_MESSAGE_BYTES, DEFAULT_READ_TIMEOUT};
+pub use listener::{Server, ServerHandle, LastAccept, DEFAULT_BIND_ADDR, parse_bind_addr};
+pub use handler::ReceivedMessage;
+pub use report::ShutdownReport;
+pub use watch::{MessageWatcher, WatchEvent};
+pub use proof::{Proof, ProofBody, VerifiedProof};
+pub use usage::{QuotaUsage, QuotaReservation, WindowUsage};
+pub use transfer_log::{TransferLog, TransferRecord, DEFAULT_TRANSFER_LOG_KEEP};
+pub use content_type::{ContentType, sniff, type_mismatch};
+pub use budget::{MemoryBudget, Reservation};
+pub use connection_id::ConnectionId;
+pub use message_handler::MessageHandler;
+pub use rate_limit::{ConnectionRateLimit, RATE_LIMIT_WINDOW};
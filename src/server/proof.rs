@@ -0,0 +1,174 @@
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use rsa::RsaPublicKey;
+use crate::error::{AppError, Result};
+use crate::crypto::{KeyPair, sign, verify, fingerprint, public_key_pem, public_key_from_pem};
+use crate::protocol::MessageMeta;
+use super::storage::{read_sidecar, stored_checksum};
+
+/// Self-contained evidence that this server received a message
+///
+/// The body holds the message's sidecar, the sender's public key and the
+/// checksum of the payload as read back from disk. The receiving server
+/// signs the body (RSA-PSS, SHA-256), so the bundle can be checked offline
+/// by anyone who knows that server's key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Proof {
+    /// What is being attested
+    pub body: ProofBody,
+    /// PEM public key of the server that signed `body`
+    pub server_key: String,
+    /// Hex RSA-PSS signature over the JSON encoding of `body`
+    pub server_signature: String,
+}
+
+/// The signed part of a [`Proof`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProofBody {
+    /// Metadata recorded when the message was received
+    pub meta: MessageMeta,
+    /// SHA-256 fingerprint of the sender's public key, if it was recorded
+    pub sender_fingerprint: Option<String>,
+    /// SHA-256 checksum of the stored payload when the proof was made
+    pub payload_checksum: String,
+    /// When the proof was made
+    pub created_at: String,
+}
+
+/// What a successful [`Proof::verify`] established
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedProof {
+    /// Name the message was stored under
+    pub saved_as: String,
+    /// Fingerprint of the server that signed the proof
+    pub server_fingerprint: String,
+    /// Fingerprint of the sender's key, if the proof names one
+    pub sender_fingerprint: Option<String>,
+}
+
+impl Proof {
+    /// Build and sign a proof for the message stored at `path`
+    pub fn create(path: &Path, keypair: &KeyPair) -> Result<Self> {
+        let meta = read_sidecar(path)?;
+        let sender_fingerprint = match &meta.sender_key {
+            Some(pem) => Some(fingerprint(&public_key_from_pem(pem)?)?),
+            None => None,
+        };
+        let body = ProofBody {
+            payload_checksum: stored_checksum(path, keypair)?,
+            sender_fingerprint,
+            meta,
+            created_at: chrono::Utc::now().to_rfc3339(),
+        };
+        let signature = sign(&keypair.private_key, &body.signed_bytes()?)?;
+
+        Ok(Self {
+            body,
+            server_key: public_key_pem(&keypair.public_key)?,
+            server_signature: hex::encode(signature),
+        })
+    }
+
+    /// Check the signature and every checksum in the proof
+    ///
+    /// With `trusted_server` the proof must also be signed by that key;
+    /// otherwise the caller should compare the returned server fingerprint
+    /// against one obtained out of band.
+    pub fn verify(&self, trusted_server: Option<&RsaPublicKey>) -> Result<VerifiedProof> {
+        let server_key = public_key_from_pem(&self.server_key)?;
+        if trusted_server.is_some_and(|trusted| *trusted != server_key) {
+            return Err(AppError::Crypto("Proof is signed by a different server key".to_string()));
+        }
+        let signature = hex::decode(&self.server_signature)
+            .map_err(|e| AppError::Crypto(format!("Malformed proof signature: {}", e)))?;
+        verify(&server_key, &self.body.signed_bytes()?, &signature)?;
+
+        let body = &self.body;
+        if body.payload_checksum != body.meta.checksum {
+            return Err(AppError::Crypto(format!(
+                "Stored payload checksum {} does not match the received checksum {}",
+                body.payload_checksum, body.meta.checksum
+            )));
+        }
+        let sender_fingerprint = match &body.meta.sender_key {
+            Some(pem) => Some(fingerprint(&public_key_from_pem(pem)?)?),
+            None => None,
+        };
+        if sender_fingerprint != body.sender_fingerprint {
+            return Err(AppError::Crypto("Sender fingerprint does not match the sender key".to_string()));
+        }
+
+        Ok(VerifiedProof {
+            saved_as: body.meta.saved_as.clone(),
+            server_fingerprint: fingerprint(&server_key)?,
+            sender_fingerprint,
+        })
+    }
+
+    /// Write the proof as pretty-printed JSON
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| AppError::Serialization(format!("Failed to serialize proof: {}", e)))?;
+        fs::write(path, json)
+            .map_err(|e| AppError::Server(format!("Failed to write {}: {}", path.display(), e)))
+    }
+
+    /// Read a proof written by [`Proof::save`]
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| AppError::Server(format!("Failed to read {}: {}", path.display(), e)))?;
+        serde_json::from_str(&json)
+            .map_err(|e| AppError::Serialization(format!("Failed to parse proof: {}", e)))
+    }
+}
+
+impl ProofBody {
+    /// Bytes covered by the server signature
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec(self)
+            .map_err(|e| AppError::Serialization(format!("Failed to serialize proof: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{MessageHeader, calculate_checksum};
+    use crate::server::storage::{write_message, write_sidecar};
+
+    #[test]
+    fn test_proof_verifies_and_detects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = KeyPair::generate().unwrap();
+        let sender = KeyPair::generate().unwrap();
+
+        let payload = b"ledger,2024-01-01,100.00\n";
+        let path = dir.path().join("ledger.csv_20240101_120000.ftt");
+        let header = MessageHeader::new("ledger.csv", 0, &calculate_checksum(payload));
+        write_message(&path, payload, None).unwrap();
+        let meta = MessageMeta::new(&header, "ledger.csv_20240101_120000.ftt", payload.len() as u64, "10.0.0.2:5000")
+            .with_sender_key(Some(sender.public_key_pem().unwrap()));
+        write_sidecar(&path, &meta).unwrap();
+
+        let proof_path = dir.path().join("proof.json");
+        Proof::create(&path, &server).unwrap().save(&proof_path).unwrap();
+        let proof = Proof::load(&proof_path).unwrap();
+        let verified = proof.verify(Some(&server.public_key)).unwrap();
+        assert_eq!(verified.server_fingerprint, server.fingerprint().unwrap());
+        assert_eq!(verified.sender_fingerprint, Some(sender.fingerprint().unwrap()));
+
+        // Any edit to the signed body breaks the signature
+        let mut tampered = proof.clone();
+        tampered.body.meta.size += 1;
+        assert!(tampered.verify(None).is_err());
+
+        // Re-signing with another key only passes if that key is trusted
+        let forger = KeyPair::generate().unwrap();
+        fs::write(&path, b"forged").unwrap();
+        let forged = Proof::create(&path, &forger).unwrap();
+        assert!(forged.verify(Some(&server.public_key)).is_err());
+        let err = forged.verify(None).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+    }
+}
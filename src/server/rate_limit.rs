@@ -0,0 +1,76 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use chrono::{DateTime, Duration, Utc};
+
+/// Span over which [`ConnectionRateLimit`] counts connections
+pub const RATE_LIMIT_WINDOW: Duration = Duration::minutes(1);
+
+/// Limits how many connections one IP address may open per minute
+///
+/// The window slides: a connection counts against its address for exactly
+/// [`RATE_LIMIT_WINDOW`] after it was accepted. Refused attempts are not
+/// counted, so a peer that backs off gets back in once its older
+/// connections age out. Times come from the caller, so the server's clock
+/// decides when they do.
+#[derive(Debug)]
+pub struct ConnectionRateLimit {
+    per_minute: u32,
+    recent: Mutex<HashMap<IpAddr, VecDeque<DateTime<Utc>>>>,
+}
+
+impl ConnectionRateLimit {
+    /// Allow each address `per_minute` new connections in any one-minute span
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Connections one address may open per minute
+    pub fn per_minute(&self) -> u32 {
+        self.per_minute
+    }
+
+    /// Record a connection from `ip` at `now`, or return `false` if it is over the limit
+    pub fn admit(&self, ip: IpAddr, now: DateTime<Utc>) -> bool {
+        let mut recent = self.recent.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget addresses that have been quiet for a whole window
+        recent.retain(|_, times| {
+            while times.front().is_some_and(|at| now.signed_duration_since(*at) >= RATE_LIMIT_WINDOW) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = recent.entry(ip).or_default();
+        if times.len() >= self.per_minute as usize {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_is_per_address_and_slides() {
+        let limit = ConnectionRateLimit::new(2);
+        let noisy: IpAddr = "192.0.2.10".parse().unwrap();
+        let other: IpAddr = "192.0.2.20".parse().unwrap();
+        let start = Utc::now();
+
+        assert!(limit.admit(noisy, start));
+        assert!(limit.admit(noisy, start + Duration::seconds(30)));
+        assert!(!limit.admit(noisy, start + Duration::seconds(31)));
+        assert!(limit.admit(other, start + Duration::seconds(31)));
+
+        // The first connection ages out after a minute, the second does not yet
+        assert!(limit.admit(noisy, start + RATE_LIMIT_WINDOW));
+        assert!(!limit.admit(noisy, start + RATE_LIMIT_WINDOW + Duration::seconds(1)));
+    }
+}
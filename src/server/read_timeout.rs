@@ -0,0 +1,163 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Message of the io error a timed-out read fails with
+pub(crate) const READ_TIMEOUT: &str = "read timeout";
+
+/// Shared between a [`ReadTimeout`] and whoever needs to steer or inspect it
+#[derive(Debug, Default)]
+struct State {
+    expired: AtomicBool,
+    idle_allowed: AtomicBool,
+}
+
+/// Handle on a [`ReadTimeout`] stream that stays usable once the stream is borrowed
+#[derive(Debug, Clone)]
+pub(crate) struct ReadTimeoutHandle(Arc<State>);
+
+impl ReadTimeoutHandle {
+    /// Whether a read on the stream has timed out
+    pub(crate) fn expired(&self) -> bool {
+        self.0.expired.load(Ordering::Relaxed)
+    }
+
+    /// Let the next read wait indefinitely for its first byte
+    ///
+    /// Used between requests on a kept connection; the timeout applies again
+    /// as soon as any data arrives.
+    pub(crate) fn allow_idle(&self) {
+        self.0.idle_allowed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Stream whose reads fail once no data has arrived for the timeout
+///
+/// Only time spent waiting inside a read counts, and every byte received
+/// restarts the clock, so a slow but steady transfer is never cut off while a
+/// peer that stops sending is. With no timeout the stream is passed through.
+pub(crate) struct ReadTimeout<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    sleep: Pin<Box<Sleep>>,
+    waiting: bool,
+    state: Arc<State>,
+}
+
+impl<S> ReadTimeout<S> {
+    /// Wrap `inner`, failing reads that see no data for `timeout`
+    pub(crate) fn new(inner: S, timeout: Option<Duration>) -> Self {
+        Self {
+            inner,
+            timeout,
+            sleep: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            waiting: false,
+            state: Arc::default(),
+        }
+    }
+
+    /// Handle for checking whether a read timed out
+    pub(crate) fn handle(&self) -> ReadTimeoutHandle {
+        ReadTimeoutHandle(self.state.clone())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ReadTimeout<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let Some(timeout) = this.timeout else {
+            return result;
+        };
+
+        if result.is_ready() {
+            this.waiting = false;
+            if buf.filled().len() > filled {
+                this.state.idle_allowed.store(false, Ordering::Relaxed);
+            }
+            return result;
+        }
+        if this.state.idle_allowed.load(Ordering::Relaxed) {
+            return Poll::Pending;
+        }
+
+        // Start the clock when this read begins waiting, not when the last
+        // one finished, so time the server spends elsewhere is not counted
+        if !this.waiting {
+            this.waiting = true;
+            this.sleep.as_mut().reset(Instant::now() + timeout);
+        }
+        match this.sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                this.state.expired.store(true, Ordering::Relaxed);
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, READ_TIMEOUT)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ReadTimeout<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_trickling_peer_outlives_the_timeout() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut stream = ReadTimeout::new(server, Some(Duration::from_millis(200)));
+        let writer = tokio::spawn(async move {
+            let mut client = client;
+            for byte in 0..8u8 {
+                tokio::time::sleep(Duration::from_millis(60)).await;
+                client.write_all(&[byte]).await.unwrap();
+            }
+            client
+        });
+
+        let mut buf = [0u8; 8];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert!(!stream.handle().expired());
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_peer_times_out_unless_idle_allowed() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut stream = ReadTimeout::new(server, Some(Duration::from_millis(50)));
+        let handle = stream.handle();
+
+        handle.allow_idle();
+        let pending = tokio::time::timeout(Duration::from_millis(150), stream.read_u8()).await;
+        assert!(pending.is_err(), "an idle-allowed read must keep waiting");
+
+        client.write_all(&[7]).await.unwrap();
+        assert_eq!(stream.read_u8().await.unwrap(), 7);
+
+        let err = stream.read_u8().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(handle.expired());
+    }
+}
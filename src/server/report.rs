@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Instant;
+use serde::Serialize;
+use crate::error::{AppError, Result};
+use crate::protocol::DisconnectReason;
+
+/// End-of-run summary returned when a server stops
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShutdownReport {
+    /// Whole seconds the server was accepting connections
+    pub uptime_secs: u64,
+    /// Connections accepted, whether or not they authenticated
+    pub connections: u64,
+    /// Files stored successfully
+    pub files_received: u64,
+    /// Connections still running when the server stopped
+    pub interrupted: usize,
+    /// Connections whose handler panicked; the server kept serving the others
+    pub panicked: u64,
+    /// Connections that ended in a refusal, counted by reason code
+    /// (`auth-failed`, `protocol-error`, `quota-exceeded`, `too-large`, ...)
+    pub rejected: BTreeMap<String, u64>,
+}
+
+impl ShutdownReport {
+    /// Connections refused for any reason
+    pub fn rejected_total(&self) -> u64 {
+        self.rejected.values().sum()
+    }
+
+    /// Serialize the report for JSON output mode
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self)
+            .map_err(|e| AppError::Serialization(format!("Failed to serialize shutdown report: {}", e)))
+    }
+}
+
+/// Counters a running server updates from its connection tasks
+#[derive(Debug)]
+pub(crate) struct ServerCounters {
+    started: Instant,
+    connections: AtomicU64,
+    files_received: AtomicU64,
+    active: AtomicUsize,
+    panicked: AtomicU64,
+    rejected: Mutex<BTreeMap<String, u64>>,
+}
+
+impl ServerCounters {
+    pub(crate) fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            files_received: AtomicU64::new(0),
+            active: AtomicUsize::new(0),
+            panicked: AtomicU64::new(0),
+            rejected: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// A connection was accepted and its handler is starting
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A connection handler finished after storing `files` files
+    pub(crate) fn connection_closed(&self, files: usize) {
+        self.files_received.fetch_add(files as u64, Ordering::Relaxed);
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A connection handler finished by refusing the peer for `reason`
+    pub(crate) fn connection_rejected(&self, reason: DisconnectReason) {
+        let mut rejected = self.rejected.lock().unwrap_or_else(|e| e.into_inner());
+        *rejected.entry(reason.code().to_string()).or_default() += 1;
+        drop(rejected);
+        self.connection_closed(0);
+    }
+
+    /// A connection was turned away before a handler started, counted under `code`
+    pub(crate) fn connection_refused(&self, code: &str) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        let mut rejected = self.rejected.lock().unwrap_or_else(|e| e.into_inner());
+        *rejected.entry(code.to_string()).or_default() += 1;
+    }
+
+    /// A connection handler panicked before finishing
+    pub(crate) fn connection_panicked(&self) {
+        self.panicked.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn report(&self) -> ShutdownReport {
+        ShutdownReport {
+            uptime_secs: self.started.elapsed().as_secs(),
+            connections: self.connections.load(Ordering::Relaxed),
+            files_received: self.files_received.load(Ordering::Relaxed),
+            interrupted: self.active.load(Ordering::Relaxed),
+            panicked: self.panicked.load(Ordering::Relaxed),
+            rejected: self.rejected.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+        }
+    }
+}
@@ -0,0 +1,843 @@
+use std::path::{Component, Path, PathBuf};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use rsa::RsaPublicKey;
+use sha2::{Digest, Sha256};
+use crate::error::{AppError, Result};
+use crate::crypto::{KeyPair, CipherSuite, ChunkedFileSealer, EncryptedMessage, decrypt_large_to_writer, rewrap_key};
+use crate::crypto::{seal_chunked_file, open_chunked_file, rewrap_chunked_file, is_chunked_file, DEFAULT_CHUNK_SIZE};
+pub use crate::protocol::MessageMeta;
+use crate::protocol::verify_checksum;
+use crate::server::config::CollisionPolicy;
+use crate::cli::Output;
+
+/// Size of each write when saving a message from the server
+const WRITE_CHUNK: usize = 256 * 1024;
+
+/// Extension appended to a stored message to name its metadata sidecar
+pub const SIDECAR_EXTENSION: &str = "meta.json";
+
+/// Extension of a message still being written, before it is moved to its final name
+pub const PARTIAL_EXTENSION: &str = "partial";
+
+/// Path of the sidecar file belonging to a stored message
+pub fn sidecar_path(message_path: &Path) -> PathBuf {
+    let mut name = message_path.as_os_str().to_owned();
+    name.push(".");
+    name.push(SIDECAR_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Write the metadata sidecar for a stored message
+pub fn write_sidecar(message_path: &Path, meta: &MessageMeta) -> Result<PathBuf> {
+    let path = sidecar_path(message_path);
+    let json = serde_json::to_string_pretty(meta)
+        .map_err(|e| AppError::Serialization(format!("Failed to serialize metadata: {}", e)))?;
+    fs::write(&path, json)
+        .map_err(|e| AppError::Server(format!("Failed to write metadata: {}", e)))?;
+    Ok(path)
+}
+
+/// Read the metadata sidecar for a stored message
+pub fn read_sidecar(message_path: &Path) -> Result<MessageMeta> {
+    let path = sidecar_path(message_path);
+    let json = fs::read_to_string(&path)
+        .map_err(|e| AppError::Server(format!("Failed to read metadata {}: {}", path.display(), e)))?;
+    serde_json::from_str(&json)
+        .map_err(|e| AppError::Serialization(format!("Failed to parse metadata: {}", e)))
+}
+
+/// Reduce a sender-supplied filename to its final component
+///
+/// Anything before the last `/` or `\` is dropped, so policy checks cannot be
+/// dodged with a path prefix.
+pub fn sanitize_filename(name: &str) -> &str {
+    name.rsplit(['/', '\\']).next().unwrap_or(name)
+}
+
+/// Name to store a sender-supplied filename under inside the messages directory
+///
+/// The name is reduced with [`sanitize_filename`]; if nothing usable is left
+/// (empty, or only dots such as `..`) it is a protocol error.
+pub fn stored_filename(name: &str) -> Result<&str> {
+    let stored = sanitize_filename(name).trim();
+    if stored.chars().all(|c| c == '.') {
+        return Err(AppError::Protocol(format!("Unusable filename in message header: {:?}", name)));
+    }
+    Ok(stored)
+}
+
+/// Write a received message, sealing it to `at_rest_key` when one is given
+///
+/// Sealed messages are stored in the chunked format, in chunks of
+/// [`DEFAULT_CHUNK_SIZE`], so they can be read back a chunk at a time.
+pub fn write_message(path: &Path, data: &[u8], at_rest_key: Option<&RsaPublicKey>) -> Result<()> {
+    let stored = match at_rest_key {
+        Some(key) => seal_chunked_file(key, CipherSuite::default(), data, DEFAULT_CHUNK_SIZE)?,
+        None => data.to_vec(),
+    };
+    fs::write(path, stored)
+        .map_err(|e| AppError::Server(format!("Failed to save message: {}", e)))
+}
+
+/// Like [`write_message`], but without blocking the async runtime
+///
+/// The file is written through `tokio::fs` in chunks, so other connections
+/// keep being served while a large message is saved. A sealed message is
+/// sealed one chunk at a time as it is written, never as a second copy.
+pub async fn write_message_async(path: &Path, data: &[u8], at_rest_key: Option<&RsaPublicKey>) -> Result<()> {
+    let save_err = |e: std::io::Error| AppError::Server(format!("Failed to save message: {}", e));
+
+    let file = tokio::fs::File::create(path).await.map_err(save_err)?;
+    let mut writer = BufWriter::with_capacity(WRITE_CHUNK, file);
+    match at_rest_key {
+        Some(key) => {
+            let (mut sealer, header) = ChunkedFileSealer::new(key, CipherSuite::default())?;
+            writer.write_all(&header).await.map_err(save_err)?;
+            if data.is_empty() {
+                writer.write_all(&sealer.seal(data, true)?).await.map_err(save_err)?;
+            }
+            let mut chunks = data.chunks(DEFAULT_CHUNK_SIZE).peekable();
+            while let Some(chunk) = chunks.next() {
+                let sealed = sealer.seal(chunk, chunks.peek().is_none())?;
+                writer.write_all(&sealed).await.map_err(save_err)?;
+            }
+        }
+        None => {
+            for chunk in data.chunks(WRITE_CHUNK) {
+                writer.write_all(chunk).await.map_err(save_err)?;
+            }
+        }
+    }
+    // Flushing also waits for the file's last background write to finish
+    writer.flush().await.map_err(save_err)
+}
+
+/// Path a message bound for `target` is written to before [`move_into_place`]
+///
+/// The file lands in `temp_dir`, or next to `target` when none is given,
+/// under a random `.partial` name so concurrent transfers never collide.
+pub fn staging_path(target: &Path, temp_dir: Option<&Path>) -> PathBuf {
+    let dir = temp_dir.or_else(|| target.parent()).unwrap_or(Path::new("."));
+    let mut name = target.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{:08x}.{}", rand::random::<u32>(), PARTIAL_EXTENSION));
+    dir.join(name)
+}
+
+/// Move a fully written staged file to `target`
+///
+/// A plain rename is atomic. When the staging directory is on a different
+/// filesystem the rename fails, so the file is instead copied next to
+/// `target` under a partial name, renamed over it and the staged copy removed;
+/// readers still never see a half-written `target`, but the data is written
+/// twice.
+pub fn move_into_place(staged: &Path, target: &Path) -> Result<()> {
+    move_into_place_with(staged, target, |from, to| fs::rename(from, to))
+}
+
+/// Like [`move_into_place`], but without blocking the async runtime
+///
+/// Across filesystems the move is a full copy of the message, so it runs on
+/// the blocking thread pool.
+pub async fn move_into_place_async(staged: &Path, target: &Path) -> Result<()> {
+    let (staged, target) = (staged.to_path_buf(), target.to_path_buf());
+    tokio::task::spawn_blocking(move || move_into_place(&staged, &target))
+        .await
+        .unwrap_or_else(|e| Err(AppError::Server(format!("Move task failed: {}", e))))
+}
+
+fn move_into_place_with(staged: &Path, target: &Path, rename: impl Fn(&Path, &Path) -> io::Result<()>) -> Result<()> {
+    let fail = |e: io::Error| AppError::Server(format!("Failed to move {} into place: {}", target.display(), e));
+    match rename(staged, target) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            Output::info(&format!(
+                "{} is on another filesystem than {}, copying instead of renaming",
+                staged.display(),
+                target.display()
+            ));
+            let partial = staging_path(target, None);
+            if let Err(e) = fs::copy(staged, &partial).and_then(|_| fs::rename(&partial, target)) {
+                let _ = fs::remove_file(&partial);
+                return Err(fail(e));
+            }
+            fs::remove_file(staged).map_err(fail)
+        }
+        Err(e) => Err(fail(e)),
+    }
+}
+
+/// Read a stored message back, decrypting it if it was encrypted at rest
+///
+/// The result is checked against the checksum recorded in the sidecar.
+pub fn read_message(path: &Path, keypair: &KeyPair) -> Result<Vec<u8>> {
+    let meta = read_sidecar(path)?;
+    let stored = fs::read(path)
+        .map_err(|e| AppError::Server(format!("Failed to read {}: {}", path.display(), e)))?;
+
+    let data = if meta.encrypted_at_rest {
+        let mut data = Vec::new();
+        decrypt_stored(keypair, stored.as_slice(), &mut data)?;
+        data
+    } else {
+        stored
+    };
+
+    if !verify_checksum(&data, &meta.checksum)? {
+        return Err(AppError::Crypto(format!("Checksum mismatch for {}", path.display())));
+    }
+    Ok(data)
+}
+
+/// Like [`read_message`], but writes the contents to `writer` instead of returning them
+///
+/// A plaintext file is copied through in chunks and an encrypted one is
+/// decrypted a chunk at a time, so memory use does not depend on its size.
+/// Only messages sealed whole, as they were before the chunked format, are
+/// still decrypted in memory. The checksum is computed as the data passes
+/// through, so a mismatch is only reported once everything has been written.
+pub fn read_message_to_writer<W: Write>(path: &Path, keypair: &KeyPair, writer: &mut W) -> Result<u64> {
+    let file = fs::File::open(path)
+        .map_err(|e| AppError::Server(format!("Failed to read {}: {}", path.display(), e)))?;
+    read_stored_to_writer(path, file, keypair, writer)
+}
+
+/// [`read_message_to_writer`] with the stored file already open as `stored`
+fn read_stored_to_writer<R: io::Read, W: Write>(path: &Path, stored: R, keypair: &KeyPair, writer: &mut W) -> Result<u64> {
+    let meta = read_sidecar(path)?;
+    let mut hashing = HashingWriter::new(writer);
+    let mut stored = BufReader::with_capacity(WRITE_CHUNK, stored);
+
+    let written = if meta.encrypted_at_rest {
+        decrypt_stored(keypair, stored, &mut hashing)?
+    } else {
+        io::copy(&mut stored, &mut hashing)
+            .map_err(|e| AppError::Server(format!("Failed to read {}: {}", path.display(), e)))?
+    };
+    hashing.flush()?;
+
+    if hashing.checksum() != meta.checksum {
+        return Err(AppError::Crypto(format!("Checksum mismatch for {}", path.display())));
+    }
+    Ok(written)
+}
+
+/// Decrypt a message stored encrypted at rest from `stored` into `writer`
+///
+/// Chunked files are decrypted a chunk at a time; messages sealed whole, as
+/// they were before, are read and decrypted in memory.
+fn decrypt_stored<R: BufRead, W: Write>(keypair: &KeyPair, mut stored: R, writer: &mut W) -> Result<u64> {
+    let head = stored
+        .fill_buf()
+        .map_err(|e| AppError::Server(format!("Failed to read stored message: {}", e)))?;
+    if is_chunked_file(head) {
+        return open_chunked_file(&keypair.private_key, stored, writer);
+    }
+    let encrypted = EncryptedMessage::from_reader(stored)?;
+    decrypt_large_to_writer(&keypair.private_key, &encrypted, writer)
+}
+
+/// Passes writes through while hashing them, for checksums of streamed data
+struct HashingWriter<'a, W> {
+    inner: &'a mut W,
+    hasher: Sha256,
+}
+
+impl<'a, W: Write> HashingWriter<'a, W> {
+    fn new(inner: &'a mut W) -> Self {
+        Self { inner, hasher: Sha256::new() }
+    }
+
+    /// Checksum of everything written so far, in the format of [`calculate_checksum`](crate::protocol::calculate_checksum)
+    fn checksum(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Checksum of a stored message's plaintext, read back from disk
+///
+/// Unlike [`read_message`] this does not compare against the sidecar, so a
+/// corrupted file yields a different checksum rather than an error. Files
+/// are hashed as they are read or decrypted, a chunk at a time, so their size
+/// does not matter; this blocks, so async callers should run it with
+/// `spawn_blocking`.
+pub fn stored_checksum(path: &Path, keypair: &KeyPair) -> Result<String> {
+    let meta = read_sidecar(path)?;
+    let read_err = |e: io::Error| AppError::Server(format!("Failed to read {}: {}", path.display(), e));
+    let open = || fs::File::open(path).map(|file| BufReader::with_capacity(WRITE_CHUNK, file)).map_err(read_err);
+
+    let mut hasher = Sha256::new();
+    if meta.encrypted_at_rest {
+        if decrypt_stored(keypair, open()?, &mut hasher).is_ok() {
+            return Ok(format!("{:x}", hasher.finalize()));
+        }
+        // Undecryptable ciphertext is corruption too; report its raw checksum
+        hasher = Sha256::new();
+    }
+    io::copy(&mut open()?, &mut hasher).map_err(read_err)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Outcome of re-encrypting or re-wrapping a messages directory
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReencryptReport {
+    /// Files moved to the new key
+    pub reencrypted: usize,
+    /// Stored messages left alone because they are plaintext
+    pub skipped: usize,
+}
+
+/// Re-encrypt every message stored encrypted at rest in `dir` to `new_key`
+///
+/// Each file is decrypted with `old` and replaced atomically by writing a
+/// temporary file and renaming it over the original, so an interrupted run
+/// never leaves a half-written message behind.
+pub fn reencrypt_dir(dir: &Path, old: &KeyPair, new_key: &RsaPublicKey) -> Result<ReencryptReport> {
+    replace_at_rest(dir, |path| {
+        let data = read_message(path, old)?;
+        seal_chunked_file(new_key, CipherSuite::default(), &data, DEFAULT_CHUNK_SIZE)
+    })
+}
+
+/// Move every message stored encrypted at rest in `dir` to `new_key` by re-wrapping its AES key
+///
+/// Unlike [`reencrypt_dir`] the bulk ciphertext is never decrypted or
+/// rewritten in a new form, which makes rotating a large archive cheap;
+/// messages sealed whole before the chunked format stay in that form.
+/// Files are replaced atomically in the same way.
+pub fn rewrap_dir(dir: &Path, old: &KeyPair, new_key: &RsaPublicKey) -> Result<ReencryptReport> {
+    replace_at_rest(dir, |path| {
+        let stored = fs::read(path)
+            .map_err(|e| AppError::Server(format!("Failed to read {}: {}", path.display(), e)))?;
+        let rewrapped = if is_chunked_file(&stored) {
+            rewrap_chunked_file(&old.private_key, new_key, &stored)
+        } else {
+            EncryptedMessage::from_bytes(&stored)
+                .and_then(|encrypted| rewrap_key(&old.private_key, new_key, &encrypted))
+                .and_then(|encrypted| encrypted.to_bytes())
+        };
+        rewrapped.map_err(|e| AppError::Crypto(format!("{}: {}", path.display(), e)))
+    })
+}
+
+/// Replace each message stored encrypted at rest in `dir` with what `seal` returns for it
+fn replace_at_rest(dir: &Path, seal: impl Fn(&Path) -> Result<Vec<u8>>) -> Result<ReencryptReport> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| AppError::Server(format!("Failed to read {}: {}", dir.display(), e)))?;
+
+    let mut report = ReencryptReport::default();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| AppError::Server(format!("Failed to read {}: {}", dir.display(), e)))?
+            .path();
+        if !path.is_file() || !sidecar_path(&path).exists() {
+            continue;
+        }
+
+        let meta = read_sidecar(&path)?;
+        if !meta.encrypted_at_rest {
+            report.skipped += 1;
+            continue;
+        }
+
+        let sealed = seal(&path)?;
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        fs::write(&tmp, sealed)
+            .map_err(|e| AppError::Server(format!("Failed to write {}: {}", tmp.display(), e)))?;
+        fs::rename(&tmp, &path)
+            .map_err(|e| AppError::Server(format!("Failed to replace {}: {}", path.display(), e)))?;
+        report.reencrypted += 1;
+    }
+    Ok(report)
+}
+
+/// Fail unless `dir` is missing or holds no entries
+///
+/// Used by one-shot receive jobs that expect to be the only writer: leftovers
+/// from an earlier run would otherwise be mixed in with this run's files.
+pub fn ensure_empty_dir(dir: &Path) -> Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(AppError::Config(format!("Failed to read {}: {}", dir.display(), e))),
+    };
+
+    let count = entries.count();
+    if count > 0 {
+        return Err(AppError::Config(format!(
+            "Messages directory {} is not empty ({} entries); move them away or drop --require-empty-messages-dir",
+            dir.display(),
+            count
+        )));
+    }
+    Ok(())
+}
+
+/// Resolve where a message named `name` should be stored in `dir`
+///
+/// If the name is free it is used as is; otherwise `policy` decides whether
+/// to pick a numbered name, reuse the existing path, or refuse.
+pub fn resolve_target(dir: &Path, name: &str, policy: CollisionPolicy) -> Result<PathBuf> {
+    let target = dir.join(name);
+    if !target.exists() {
+        return Ok(target);
+    }
+
+    match policy {
+        CollisionPolicy::Overwrite => Ok(target),
+        CollisionPolicy::Reject => Err(AppError::Server(format!("File already exists: {}", name))),
+        CollisionPolicy::Suffix => {
+            let (stem, ext) = match name.rsplit_once('.') {
+                Some((stem, ext)) if !stem.is_empty() => (stem, Some(ext)),
+                _ => (name, None),
+            };
+            (1..)
+                .map(|n| match ext {
+                    Some(ext) => dir.join(format!("{}_{}.{}", stem, n, ext)),
+                    None => dir.join(format!("{}_{}", stem, n)),
+                })
+                .find(|candidate| !candidate.exists())
+                .ok_or_else(|| AppError::Server(format!("No free name for {}", name)))
+        }
+    }
+}
+
+/// Unpack a received tar archive into `dest`, returning the number of files written
+///
+/// Every entry path must stay inside `dest`: absolute paths, `..` components
+/// and links are rejected before anything is written for that entry. The
+/// archive is read as it is unpacked, so it may come straight from a staged
+/// file. This blocks; async callers should run it with `spawn_blocking`.
+pub fn extract_archive<R: io::Read>(data: R, dest: &Path) -> Result<usize> {
+    fs::create_dir_all(dest)
+        .map_err(|e| AppError::Server(format!("Failed to create {}: {}", dest.display(), e)))?;
+
+    let mut archive = tar::Archive::new(data);
+    let entries = archive
+        .entries()
+        .map_err(|e| AppError::Server(format!("Failed to read archive: {}", e)))?;
+
+    let mut count = 0;
+    for entry in entries {
+        let mut entry = entry
+            .map_err(|e| AppError::Server(format!("Failed to read archive entry: {}", e)))?;
+        let path = entry
+            .path()
+            .map_err(|e| AppError::Server(format!("Invalid archive entry path: {}", e)))?
+            .into_owned();
+
+        let safe = path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !safe {
+            return Err(AppError::Protocol(format!(
+                "Archive entry escapes target directory: {}",
+                path.display()
+            )));
+        }
+
+        let entry_type = entry.header().entry_type();
+        if !(entry_type.is_file() || entry_type.is_dir()) {
+            return Err(AppError::Protocol(format!(
+                "Unsupported archive entry type for {}",
+                path.display()
+            )));
+        }
+
+        let target = dest.join(&path);
+        if entry_type.is_dir() {
+            fs::create_dir_all(&target)
+                .map_err(|e| AppError::Server(format!("Failed to create {}: {}", target.display(), e)))?;
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| AppError::Server(format!("Failed to create {}: {}", parent.display(), e)))?;
+        }
+        entry
+            .unpack(&target)
+            .map_err(|e| AppError::Server(format!("Failed to extract {}: {}", path.display(), e)))?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use crate::protocol::MessageHeader;
+
+    #[test]
+    fn test_move_into_place_renames_on_same_filesystem() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp_dir = dir.path().join("tmp");
+        fs::create_dir(&temp_dir).unwrap();
+        let target = dir.path().join("report.csv_20240101_120000.ftt");
+
+        let staged = staging_path(&target, Some(&temp_dir));
+        assert_eq!(staged.parent(), Some(temp_dir.as_path()));
+        assert_eq!(staged.extension().unwrap(), PARTIAL_EXTENSION);
+        assert_eq!(staging_path(&target, None).parent(), Some(dir.path()));
+
+        fs::write(&staged, b"ledger").unwrap();
+        move_into_place(&staged, &target).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"ledger");
+        assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_move_into_place_copies_across_filesystems() {
+        let dir = tempfile::tempdir().unwrap();
+        let temp_dir = dir.path().join("tmp");
+        let messages_dir = dir.path().join("messages");
+        fs::create_dir(&temp_dir).unwrap();
+        fs::create_dir(&messages_dir).unwrap();
+        let target = messages_dir.join("report.csv_20240101_120000.ftt");
+        let staged = staging_path(&target, Some(&temp_dir));
+        fs::write(&staged, b"ledger").unwrap();
+
+        let cross_device = |_: &Path, _: &Path| Err(io::Error::from(io::ErrorKind::CrossesDevices));
+        move_into_place_with(&staged, &target, cross_device).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"ledger");
+        assert!(!staged.exists());
+        let left: Vec<_> = fs::read_dir(&messages_dir).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(left, vec![target]);
+
+        // Other rename failures are reported, not papered over with a copy
+        let staged = staging_path(&messages_dir.join("other.ftt"), Some(&temp_dir));
+        fs::write(&staged, b"ledger").unwrap();
+        let denied = |_: &Path, _: &Path| Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(move_into_place_with(&staged, &messages_dir.join("other.ftt"), denied).is_err());
+        assert!(staged.exists());
+    }
+
+    #[tokio::test]
+    async fn test_large_save_leaves_runtime_responsive() {
+        use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("large.csv.ftt");
+        let data = vec![b'x'; 64 * 1024 * 1024];
+
+        // A lightweight task on the same (single-threaded) runtime, standing in
+        // for other connections; it notes the longest stall between its turns
+        let done = Arc::new(AtomicBool::new(false));
+        let (turns, longest_ms) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        let ticker = tokio::spawn({
+            let (done, turns, longest_ms) = (done.clone(), turns.clone(), longest_ms.clone());
+            async move {
+                let mut last = Instant::now();
+                while !done.load(Ordering::SeqCst) {
+                    tokio::task::yield_now().await;
+                    longest_ms.fetch_max(last.elapsed().as_millis() as u64, Ordering::SeqCst);
+                    turns.fetch_add(1, Ordering::SeqCst);
+                    last = Instant::now();
+                }
+            }
+        });
+        tokio::task::yield_now().await;
+
+        let before = turns.load(Ordering::SeqCst);
+        write_message_async(&path, &data, None).await.unwrap();
+        let during = turns.load(Ordering::SeqCst) - before;
+        done.store(true, Ordering::SeqCst);
+        ticker.await.unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().len(), data.len() as u64);
+        assert!(during > 0, "other tasks never ran during the save");
+        assert!(
+            Duration::from_millis(longest_ms.load(Ordering::SeqCst)) < Duration::from_millis(500),
+            "runtime stalled for {} ms",
+            longest_ms.load(Ordering::SeqCst)
+        );
+    }
+
+    #[test]
+    fn test_note_round_trips_into_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let message_path = dir.path().join("batch_20240101_120000.ftt");
+        fs::write(&message_path, b"data").unwrap();
+
+        let header = MessageHeader::new("batch", 4, "abc")
+            .with_note(Some("EOD settlement batch, re-run of failed job 42"));
+        let meta = MessageMeta::new(&header, "batch_20240101_120000.ftt", 4, "127.0.0.1:5000")
+            .with_identity(Some("acme-prod-1"));
+        let written = write_sidecar(&message_path, &meta).unwrap();
+
+        assert_eq!(written, dir.path().join("batch_20240101_120000.ftt.meta.json"));
+
+        let loaded = read_sidecar(&message_path).unwrap();
+        assert_eq!(loaded.note.as_deref(), Some("EOD settlement batch, re-run of failed job 42"));
+        assert_eq!(loaded.peer, "127.0.0.1:5000");
+        assert_eq!(loaded.identity.as_deref(), Some("acme-prod-1"));
+    }
+
+    #[test]
+    fn test_encrypted_at_rest_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::generate().unwrap();
+        let message_path = dir.path().join("batch_20240101_120000.ftt");
+        let plaintext = b"account,amount\n42,1000.00\n";
+
+        let header = MessageHeader::new("batch", 0, &crate::protocol::calculate_checksum(plaintext));
+        write_message(&message_path, plaintext, Some(&keypair.public_key)).unwrap();
+        let meta = MessageMeta::new(&header, "batch_20240101_120000.ftt", plaintext.len() as u64, "127.0.0.1:5000")
+            .with_encrypted_at_rest(true);
+        write_sidecar(&message_path, &meta).unwrap();
+
+        let stored = fs::read(&message_path).unwrap();
+        assert_ne!(stored, plaintext);
+        assert!(!stored.windows(6).any(|w| w == b"amount"));
+
+        assert_eq!(read_message(&message_path, &keypair).unwrap(), plaintext);
+
+        // Messages sealed whole, from before the chunked format, still read back
+        fs::write(&message_path, crate::crypto::encrypt_large(&keypair.public_key, plaintext).unwrap().to_bytes().unwrap()).unwrap();
+        assert_eq!(read_message(&message_path, &keypair).unwrap(), plaintext);
+        let mut out = Vec::new();
+        read_message_to_writer(&message_path, &keypair, &mut out).unwrap();
+        assert_eq!(out, plaintext);
+        assert_eq!(stored_checksum(&message_path, &keypair).unwrap(), header.checksum);
+    }
+
+    /// Counts the bytes read through it
+    struct CountingReader<R> {
+        inner: R,
+        read: Rc<Cell<u64>>,
+    }
+
+    impl<R: io::Read> io::Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.read.set(self.read.get() + n as u64);
+            Ok(n)
+        }
+    }
+
+    /// Discards writes, recording how far the [`CountingReader`] was ahead of them
+    struct PeakWriter {
+        read: Rc<Cell<u64>>,
+        written: u64,
+        peak: u64,
+    }
+
+    impl Write for PeakWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.peak = self.peak.max(self.read.get().saturating_sub(self.written));
+            self.written += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_large_message_read_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let keypair = KeyPair::generate().unwrap();
+        let data: Vec<u8> = (0..16 * 1024 * 1024).map(|i: u32| (i % 251) as u8).collect();
+        let checksum = crate::protocol::calculate_checksum(&data);
+
+        for encrypted in [false, true] {
+            let message_path = dir.path().join(format!("large_{}.ftt", encrypted));
+            let header = MessageHeader::new("large", data.len() as u64, &checksum);
+            write_message(&message_path, &data, encrypted.then_some(&keypair.public_key)).unwrap();
+            let meta = MessageMeta::new(&header, "large.ftt", data.len() as u64, "127.0.0.1:5000")
+                .with_encrypted_at_rest(encrypted);
+            write_sidecar(&message_path, &meta).unwrap();
+
+            let out_path = dir.path().join("out.bin");
+            let mut out = io::BufWriter::new(fs::File::create(&out_path).unwrap());
+            let written = read_message_to_writer(&message_path, &keypair, &mut out).unwrap();
+            drop(out);
+            assert_eq!(written, data.len() as u64);
+            assert!(fs::read(&out_path).unwrap() == data);
+
+            // The file is never read more than about a chunk ahead of what was written
+            let read = Rc::new(Cell::new(0));
+            let stored = CountingReader { inner: fs::File::open(&message_path).unwrap(), read: read.clone() };
+            let mut out = PeakWriter { read, written: 0, peak: 0 };
+            read_stored_to_writer(&message_path, stored, &keypair, &mut out).unwrap();
+            assert_eq!(out.written, data.len() as u64);
+            assert!(out.peak < 2 * DEFAULT_CHUNK_SIZE as u64, "read {} bytes ahead", out.peak);
+
+            // A sidecar checksum that does not match is still caught
+            write_sidecar(&message_path, &MessageMeta { checksum: "0".repeat(64), ..meta }).unwrap();
+            let err = read_message_to_writer(&message_path, &keypair, &mut io::sink()).unwrap_err();
+            assert!(err.to_string().contains("Checksum mismatch"));
+        }
+    }
+
+    #[test]
+    fn test_reencrypt_to_new_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = KeyPair::generate().unwrap();
+        let new = KeyPair::generate().unwrap();
+
+        let store = |name: &str, data: &[u8], key: Option<&RsaPublicKey>| {
+            let path = dir.path().join(name);
+            let header = MessageHeader::new(name, 0, &crate::protocol::calculate_checksum(data));
+            write_message(&path, data, key).unwrap();
+            let meta = MessageMeta::new(&header, name, data.len() as u64, "127.0.0.1:5000")
+                .with_encrypted_at_rest(key.is_some());
+            write_sidecar(&path, &meta).unwrap();
+            path
+        };
+        let first = store("a.ftt", b"first", Some(&old.public_key));
+        let second = store("b.ftt", b"second", Some(&old.public_key));
+        let plain = store("c.ftt", b"plain", None);
+
+        let report = reencrypt_dir(dir.path(), &old, &new.public_key).unwrap();
+        assert_eq!(report, ReencryptReport { reencrypted: 2, skipped: 1 });
+
+        assert_eq!(read_message(&first, &new).unwrap(), b"first");
+        assert_eq!(read_message(&second, &new).unwrap(), b"second");
+        assert!(read_message(&first, &old).is_err());
+        assert_eq!(fs::read(&plain).unwrap(), b"plain");
+    }
+
+    #[test]
+    fn test_rewrap_keeps_bulk_ciphertext() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = KeyPair::generate().unwrap();
+        let new = KeyPair::generate().unwrap();
+        let data = vec![7u8; 256 * 1024];
+
+        let header = MessageHeader::new("archive", 0, &crate::protocol::calculate_checksum(&data));
+        let store = |name: &str| {
+            let path = dir.path().join(name);
+            write_sidecar(&path, &MessageMeta::new(&header, name, data.len() as u64, "127.0.0.1:5000").with_encrypted_at_rest(true))
+                .unwrap();
+            path
+        };
+        let chunked = store("archive.ftt");
+        write_message(&chunked, &data, Some(&old.public_key)).unwrap();
+        let chunked_before = fs::read(&chunked).unwrap();
+        // Sealed whole, as messages were before the chunked format
+        let legacy = store("legacy.ftt");
+        fs::write(&legacy, crate::crypto::encrypt_large(&old.public_key, &data).unwrap().to_bytes().unwrap()).unwrap();
+        let before = EncryptedMessage::from_bytes(&fs::read(&legacy).unwrap()).unwrap();
+
+        let report = rewrap_dir(dir.path(), &old, &new.public_key).unwrap();
+        assert_eq!(report, ReencryptReport { reencrypted: 2, skipped: 0 });
+
+        let chunked_after = fs::read(&chunked).unwrap();
+        assert_eq!(chunked_after.len(), chunked_before.len());
+        assert_eq!(chunked_after[chunked_after.len() - data.len()..], chunked_before[chunked_before.len() - data.len()..]);
+        assert_ne!(chunked_after, chunked_before);
+        let after = EncryptedMessage::from_bytes(&fs::read(&legacy).unwrap()).unwrap();
+        assert_eq!(after.encrypted_data, before.encrypted_data);
+        assert_eq!(after.nonce, before.nonce);
+        assert_ne!(after.encrypted_key, before.encrypted_key);
+        for path in [&chunked, &legacy] {
+            assert_eq!(read_message(path, &new).unwrap(), data);
+            assert!(read_message(path, &old).is_err());
+        }
+
+        // A second run with the retired key cannot unwrap anything
+        assert!(rewrap_dir(dir.path(), &old, &new.public_key).is_err());
+    }
+
+    #[test]
+    fn test_oversized_note_rejected() {
+        let header = MessageHeader::new("batch", 4, "abc")
+            .with_note(Some(&"x".repeat(crate::protocol::MAX_NOTE_BYTES + 1)));
+        assert!(header.validate().is_err());
+
+        let header = MessageHeader::new("batch", 4, "abc")
+            .with_note(Some(&"x".repeat(crate::protocol::MAX_NOTE_BYTES)));
+        assert!(header.validate().is_ok());
+    }
+
+    #[test]
+    fn test_directory_archive_reconstructed() {
+        let src = tempfile::tempdir().unwrap();
+        fs::write(src.path().join("a.csv"), b"1,2,3").unwrap();
+        fs::create_dir(src.path().join("nested")).unwrap();
+        fs::write(src.path().join("nested").join("b.json"), b"{}").unwrap();
+
+        let archive = crate::client::sender::write_directory_archive(src.path(), Vec::new()).unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let target = dest.path().join("batch");
+        let count = extract_archive(archive.as_slice(), &target).unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(fs::read(target.join("a.csv")).unwrap(), b"1,2,3");
+        assert_eq!(fs::read(target.join("nested").join("b.json")).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn test_archive_traversal_rejected() {
+        let mut header = tar::Header::new_gnu();
+        let payload = b"evil";
+        header.set_size(payload.len() as u64);
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        // Bypass set_path's own validation to forge a malicious entry
+        header.as_old_mut().name[..9].copy_from_slice(b"../escape");
+        header.set_cksum();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, &payload[..]).unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let dest = tempfile::tempdir().unwrap();
+        let target = dest.path().join("batch");
+        assert!(extract_archive(archive.as_slice(), &target).is_err());
+        assert!(!dest.path().join("escape").exists());
+    }
+
+    #[test]
+    fn test_collision_suffix() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("batch.ftt"), b"old").unwrap();
+
+        let target = resolve_target(dir.path(), "batch.ftt", CollisionPolicy::Suffix).unwrap();
+        assert_eq!(target, dir.path().join("batch_1.ftt"));
+
+        fs::write(&target, b"new").unwrap();
+        let target = resolve_target(dir.path(), "batch.ftt", CollisionPolicy::Suffix).unwrap();
+        assert_eq!(target, dir.path().join("batch_2.ftt"));
+    }
+
+    #[test]
+    fn test_collision_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("batch.ftt"), b"old").unwrap();
+
+        let target = resolve_target(dir.path(), "batch.ftt", CollisionPolicy::Overwrite).unwrap();
+        assert_eq!(target, dir.path().join("batch.ftt"));
+    }
+
+    #[test]
+    fn test_collision_reject() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("batch.ftt"), b"old").unwrap();
+
+        assert!(resolve_target(dir.path(), "batch.ftt", CollisionPolicy::Reject).is_err());
+        assert!(resolve_target(dir.path(), "other.ftt", CollisionPolicy::Reject).is_ok());
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use serde::Serialize;
+use crate::error::{AppError, Result};
+use crate::protocol::ReceivedMessage;
+
+/// Rotated files kept by default besides the live log
+pub const DEFAULT_TRANSFER_LOG_KEEP: usize = 5;
+
+/// One line of the transfer log
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferRecord {
+    /// When the transfer was stored
+    pub logged_at: String,
+    /// Address of the sending peer
+    pub peer: String,
+    /// Fingerprint of the sender's public key
+    pub fingerprint: String,
+    /// Filename requested by the sender
+    pub filename: String,
+    /// Path the message was stored at; `None` when a message handler took it
+    pub path: Option<PathBuf>,
+    /// Size of the decrypted data in bytes
+    pub bytes: u64,
+    /// SHA-256 checksum of the decrypted data
+    pub checksum: String,
+    /// Time from reading the header to storing the message
+    pub elapsed_ms: u128,
+}
+
+impl From<&ReceivedMessage> for TransferRecord {
+    fn from(message: &ReceivedMessage) -> Self {
+        Self {
+            logged_at: chrono::Utc::now().to_rfc3339(),
+            peer: message.peer.clone(),
+            fingerprint: message.fingerprint.clone(),
+            filename: message.filename.clone(),
+            path: message.path.clone(),
+            bytes: message.bytes_written,
+            checksum: message.checksum.clone(),
+            elapsed_ms: message.elapsed.as_millis(),
+        }
+    }
+}
+
+/// Append-only JSONL log of received transfers, rotated by size
+///
+/// An existing log is appended to. Once a record would take the live file
+/// past `max_bytes`, it is renamed to `<path>.1` (shifting older ones up to
+/// `<path>.<keep>` and deleting the oldest) and a fresh file is started.
+/// Records are written and files rotated under one lock, so concurrent
+/// connections never interleave lines or lose a record across a rotation.
+#[derive(Debug)]
+pub struct TransferLog {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    keep: usize,
+    live: Mutex<LiveFile>,
+}
+
+#[derive(Debug)]
+struct LiveFile {
+    file: File,
+    size: u64,
+}
+
+impl TransferLog {
+    /// Open `path` for appending, never rotating
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes: None,
+            keep: DEFAULT_TRANSFER_LOG_KEEP,
+            live: Mutex::new(open_live(path)?),
+        })
+    }
+
+    /// Rotate once the live file would exceed `max_bytes`
+    pub fn with_max_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Keep this many rotated files; zero keeps none
+    pub fn with_keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Path of the live log file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append one record, rotating first if it would not fit
+    pub fn append(&self, record: &TransferRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)
+            .map_err(|e| AppError::Serialization(format!("Failed to serialize transfer record: {}", e)))?;
+        line.push(b'\n');
+
+        let mut live = self.live.lock().unwrap_or_else(|e| e.into_inner());
+        // A record bigger than the limit still goes into a file of its own
+        if self.max_bytes.is_some_and(|max| live.size > 0 && live.size + line.len() as u64 > max) {
+            self.rotate()?;
+            *live = open_live(&self.path)?;
+        }
+        live.file
+            .write_all(&line)
+            .map_err(|e| AppError::Server(format!("Failed to write {}: {}", self.path.display(), e)))?;
+        live.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift `<path>.N` to `<path>.N+1`, dropping the oldest, then move the live file to `<path>.1`
+    fn rotate(&self) -> Result<()> {
+        let fail = |e: std::io::Error| AppError::Server(format!("Failed to rotate {}: {}", self.path.display(), e));
+        if self.keep == 0 {
+            return fs::remove_file(&self.path).map_err(fail);
+        }
+        match fs::remove_file(self.rotated(self.keep)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(fail(e)),
+            _ => {}
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated(n + 1)).map_err(fail)?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1)).map_err(fail)
+    }
+
+    /// Path of the `n`th most recent rotated file
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+}
+
+fn open_live(path: &Path) -> Result<LiveFile> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| AppError::Server(format!("Failed to open transfer log {}: {}", path.display(), e)))?;
+    let size = file
+        .metadata()
+        .map_err(|e| AppError::Server(format!("Failed to read {}: {}", path.display(), e)))?
+        .len();
+    Ok(LiveFile { file, size })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn record(n: usize) -> TransferRecord {
+        TransferRecord {
+            logged_at: "2024-01-01T00:00:00+00:00".to_string(),
+            peer: "127.0.0.1:5000".to_string(),
+            fingerprint: "ab".repeat(32),
+            filename: format!("batch_{:03}.json", n),
+            path: Some(PathBuf::from(format!("messages/batch_{:03}.json.ftt", n))),
+            bytes: 100,
+            checksum: "cd".repeat(32),
+            elapsed_ms: 5,
+        }
+    }
+
+    #[test]
+    fn test_rotates_past_threshold_and_prunes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transfers.jsonl");
+        let line_len = serde_json::to_vec(&record(0)).unwrap().len() as u64 + 1;
+        let read = |n: usize| {
+            let file = if n == 0 { path.clone() } else { dir.path().join(format!("transfers.jsonl.{}", n)) };
+            fs::read_to_string(file).unwrap_or_default()
+        };
+
+        // Room for three records per file; concurrent writers lose nothing
+        let log = Arc::new(TransferLog::open(&path).unwrap().with_max_bytes(Some(line_len * 3)).with_keep(10));
+        let writers: Vec<_> = (0..4)
+            .map(|w| {
+                let log = Arc::clone(&log);
+                std::thread::spawn(move || {
+                    for n in 0..6 {
+                        log.append(&record(w * 10 + n)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let mut names = std::collections::HashSet::new();
+        for n in 0..8 {
+            let text = read(n);
+            assert_eq!(text.lines().count(), 3, "file {}", n);
+            for line in text.lines() {
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                names.insert(value["filename"].as_str().unwrap().to_string());
+            }
+        }
+        assert_eq!(names.len(), 24);
+        drop(log);
+
+        // Only `keep` rotated files survive, newest records in the live file
+        let path = dir.path().join("pruned.jsonl");
+        let log = TransferLog::open(&path).unwrap().with_max_bytes(Some(line_len * 3)).with_keep(2);
+        for n in 0..12 {
+            log.append(&record(n)).unwrap();
+        }
+        let live = fs::read_to_string(&path).unwrap();
+        assert!(live.contains("batch_009") && live.contains("batch_011"));
+        assert!(fs::read_to_string(dir.path().join("pruned.jsonl.2")).unwrap().contains("batch_003"));
+        assert!(!dir.path().join("pruned.jsonl.3").exists());
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Serialize, Deserialize};
+use crate::error::{AppError, Result};
+use crate::auth::Quota;
+
+/// What one peer has stored in its current quota window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WindowUsage {
+    /// Start of the window, in seconds since the Unix epoch
+    pub window_start: i64,
+    /// Bytes stored in the window
+    pub bytes: u64,
+    /// Files stored in the window
+    pub files: u64,
+}
+
+/// Usage counted against whitelist quotas, keyed by connect key hash
+///
+/// Windows are aligned to the clock (UTC hours or days), so every peer's
+/// quota resets at the same moment. With a path, counters are written back
+/// after every recorded transfer and survive a restart.
+///
+/// A transfer [`reserve`](Self::reserve)s its announced size before it is
+/// read, so concurrent uploads from one peer cannot all pass the check and
+/// together overrun the quota.
+#[derive(Debug, Default)]
+pub struct QuotaUsage {
+    path: Option<PathBuf>,
+    counters: Mutex<Counters>,
+    /// Generation of the counters last written to `path`
+    saved: Mutex<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    usage: HashMap<String, WindowUsage>,
+    /// Transfers reserved but not yet recorded, as bytes and files per key
+    pending: HashMap<String, (u64, u64)>,
+    /// Bumped on every recorded transfer, so older snapshots are never saved over newer ones
+    generation: u64,
+}
+
+impl Counters {
+    fn current(&self, key_hash: &str, window_start: i64) -> WindowUsage {
+        match self.usage.get(key_hash) {
+            Some(used) if used.window_start == window_start => *used,
+            _ => WindowUsage { window_start, ..WindowUsage::default() },
+        }
+    }
+
+    fn release(&mut self, key_hash: &str, bytes: u64) {
+        if let Some((pending_bytes, pending_files)) = self.pending.get_mut(key_hash) {
+            *pending_bytes = pending_bytes.saturating_sub(bytes);
+            *pending_files = pending_files.saturating_sub(1);
+            if *pending_files == 0 {
+                self.pending.remove(key_hash);
+            }
+        }
+    }
+}
+
+impl QuotaUsage {
+    /// Counters kept in memory only
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Load counters persisted at `path`; a missing file starts empty
+    pub fn load(path: &Path) -> Result<Self> {
+        let usage = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)
+                .map_err(|e| AppError::Config(format!("Invalid quota usage file {}: {}", path.display(), e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(AppError::Config(format!("Failed to read {}: {}", path.display(), e))),
+        };
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            counters: Mutex::new(Counters { usage, ..Counters::default() }),
+            saved: Mutex::new(0),
+        })
+    }
+
+    /// Usage of `key_hash` in the window containing `now`, not counting reservations
+    pub fn current(&self, key_hash: &str, quota: &Quota, now: DateTime<Utc>) -> WindowUsage {
+        self.lock().current(key_hash, window_start(quota, now))
+    }
+
+    /// Hold `incoming` bytes and one file of a peer's quota until the transfer is recorded
+    ///
+    /// Refused if the transfer, together with what is already stored and
+    /// reserved in the window, would take the peer over its quota. Dropping
+    /// the reservation without recording it gives the room back.
+    pub fn reserve(
+        self: &Arc<Self>,
+        key_hash: &str,
+        quota: &Quota,
+        incoming: u64,
+        now: DateTime<Utc>,
+    ) -> Result<QuotaReservation> {
+        let mut counters = self.lock();
+        let stored = counters.current(key_hash, window_start(quota, now));
+        let (pending_bytes, pending_files) = counters.pending.get(key_hash).copied().unwrap_or_default();
+        let used = WindowUsage {
+            bytes: stored.bytes.saturating_add(pending_bytes),
+            files: stored.files + pending_files,
+            ..stored
+        };
+        let over_files = quota.max_files.is_some_and(|max| used.files >= max);
+        let over_bytes = quota.max_bytes.is_some_and(|max| used.bytes.saturating_add(incoming) > max);
+        if over_files || over_bytes {
+            let resets_at = Utc
+                .timestamp_opt(used.window_start + quota.window.secs(), 0)
+                .single()
+                .map_or_else(|| "the next window".to_string(), |t| t.to_rfc3339());
+            return Err(AppError::Server(format!(
+                "quota exceeded: {} files and {} bytes stored of {}, resets at {}",
+                used.files, used.bytes, quota, resets_at
+            )));
+        }
+
+        let pending = counters.pending.entry(key_hash.to_string()).or_default();
+        pending.0 = pending.0.saturating_add(incoming);
+        pending.1 += 1;
+        Ok(QuotaReservation {
+            usage: Arc::clone(self),
+            key_hash: key_hash.to_string(),
+            quota: *quota,
+            bytes: incoming,
+            recorded: false,
+        })
+    }
+
+    /// Count a stored transfer of `bytes` against `key_hash`
+    ///
+    /// This writes the counters file, so async callers should go through
+    /// [`QuotaReservation::record`], which does so on a blocking thread.
+    pub fn record(&self, key_hash: &str, quota: &Quota, bytes: u64, now: DateTime<Utc>) -> Result<()> {
+        self.record_reserved(key_hash, quota, bytes, now, None)
+    }
+
+    fn record_reserved(
+        &self,
+        key_hash: &str,
+        quota: &Quota,
+        bytes: u64,
+        now: DateTime<Utc>,
+        reserved: Option<u64>,
+    ) -> Result<()> {
+        // The reservation is swapped for the real count under one guard, so
+        // a concurrent check never sees the transfer twice or not at all
+        let (json, generation) = {
+            let mut counters = self.lock();
+            if let Some(reserved) = reserved {
+                counters.release(key_hash, reserved);
+            }
+            let mut used = counters.current(key_hash, window_start(quota, now));
+            used.bytes = used.bytes.saturating_add(bytes);
+            used.files += 1;
+            counters.usage.insert(key_hash.to_string(), used);
+            counters.generation += 1;
+            match &self.path {
+                Some(_) => (Some(to_json(&counters.usage)?), counters.generation),
+                None => (None, counters.generation),
+            }
+        };
+
+        let (Some(path), Some(json)) = (&self.path, json) else {
+            return Ok(());
+        };
+        let mut saved = self.saved.lock().unwrap_or_else(|e| e.into_inner());
+        if *saved < generation {
+            save(path, &json)?;
+            *saved = generation;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Counters> {
+        self.counters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Room held in a peer's quota for one transfer, from [`QuotaUsage::reserve`]
+#[derive(Debug)]
+pub struct QuotaReservation {
+    usage: Arc<QuotaUsage>,
+    key_hash: String,
+    quota: Quota,
+    bytes: u64,
+    recorded: bool,
+}
+
+impl QuotaReservation {
+    /// Count the stored transfer as `bytes` in place of the reserved size
+    ///
+    /// The counters file is written on a blocking thread.
+    pub async fn record(mut self, bytes: u64, now: DateTime<Utc>) -> Result<()> {
+        self.recorded = true;
+        let (usage, key_hash, quota, reserved) = (Arc::clone(&self.usage), self.key_hash.clone(), self.quota, self.bytes);
+        tokio::task::spawn_blocking(move || usage.record_reserved(&key_hash, &quota, bytes, now, Some(reserved)))
+            .await
+            .unwrap_or_else(|e| Err(AppError::Server(format!("Quota usage task failed: {}", e))))
+    }
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        if !self.recorded {
+            self.usage.lock().release(&self.key_hash, self.bytes);
+        }
+    }
+}
+
+/// Start of the window containing `now`
+fn window_start(quota: &Quota, now: DateTime<Utc>) -> i64 {
+    now.timestamp().div_euclid(quota.window.secs()) * quota.window.secs()
+}
+
+fn to_json(usage: &HashMap<String, WindowUsage>) -> Result<String> {
+    serde_json::to_string_pretty(usage)
+        .map_err(|e| AppError::Serialization(format!("Failed to serialize quota usage: {}", e)))
+}
+
+/// Write the counters next to `path` first, so a crash never leaves half a file
+fn save(path: &Path, json: &str) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| AppError::Server(format!("Failed to write {}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_enforced_until_window_resets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quota_usage.json");
+        let quota = Quota::parse("100B,3files/day").unwrap();
+        let morning = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+
+        let usage = Arc::new(QuotaUsage::load(&path).unwrap());
+        drop(usage.reserve("acme", &quota, 60, morning).unwrap());
+        usage.record("acme", &quota, 60, morning).unwrap();
+
+        // Counters survive a restart
+        let usage = Arc::new(QuotaUsage::load(&path).unwrap());
+        let err = usage.reserve("acme", &quota, 50, morning).unwrap_err();
+        assert!(err.to_string().contains("quota exceeded"), "{}", err);
+        assert!(err.to_string().contains("2024-01-02T00:00:00"), "{}", err);
+        drop(usage.reserve("acme", &quota, 40, morning).unwrap());
+        drop(usage.reserve("other", &quota, 100, morning).unwrap());
+
+        usage.record("acme", &quota, 0, morning).unwrap();
+        usage.record("acme", &quota, 0, morning).unwrap();
+        assert!(usage.reserve("acme", &quota, 0, morning).is_err());
+
+        let next_day = Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 1).unwrap();
+        drop(usage.reserve("acme", &quota, 100, next_day).unwrap());
+        assert_eq!(usage.current("acme", &quota, next_day).files, 0);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_transfers_cannot_overrun_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quota_usage.json");
+        let quota = Quota::parse("100B,2files/day").unwrap();
+        let morning = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+        let usage = Arc::new(QuotaUsage::load(&path).unwrap());
+
+        // Two uploads in flight hold the room even though nothing is stored yet
+        let first = usage.reserve("acme", &quota, 60, morning).unwrap();
+        assert!(usage.reserve("acme", &quota, 60, morning).is_err());
+        let second = usage.reserve("acme", &quota, 30, morning).unwrap();
+        assert!(usage.reserve("acme", &quota, 0, morning).is_err());
+
+        // A failed transfer gives its room back; a stored one counts what it wrote
+        drop(second);
+        first.record(50, morning).await.unwrap();
+        let third = usage.reserve("acme", &quota, 50, morning).unwrap();
+        assert!(usage.reserve("acme", &quota, 0, morning).is_err());
+        third.record(50, morning).await.unwrap();
+
+        let reloaded = QuotaUsage::load(&path).unwrap();
+        assert_eq!(reloaded.current("acme", &quota, morning), WindowUsage { window_start: 1704067200, bytes: 100, files: 2 });
+    }
+}
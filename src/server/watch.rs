@@ -0,0 +1,222 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::fs;
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use crate::error::{AppError, Result};
+use crate::cli::Output;
+use super::storage::{read_sidecar, SIDECAR_EXTENSION};
+
+/// A stored message that appeared in a watched directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// Name the message is stored under
+    pub saved_as: String,
+    /// Size of the received plaintext in bytes
+    pub size: u64,
+    /// Address of the sending peer
+    pub peer: String,
+}
+
+impl fmt::Display for WatchEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} bytes) from {}", self.saved_as, self.size, self.peer)
+    }
+}
+
+/// Tail-like view of a messages directory
+///
+/// A message is reported once its metadata sidecar appears, since the
+/// server writes the sidecar after the file itself. [`MessageWatcher::run`]
+/// rescans the directory whenever the OS reports a change in it.
+pub struct MessageWatcher {
+    dir: PathBuf,
+    seen: HashSet<PathBuf>,
+}
+
+impl MessageWatcher {
+    /// Start watching `dir`; messages already stored there are not reported
+    pub fn new(dir: &Path) -> Result<Self> {
+        let mut watcher = Self {
+            dir: dir.to_path_buf(),
+            seen: HashSet::new(),
+        };
+        watcher.seen = watcher.sidecars()?;
+        Ok(watcher)
+    }
+
+    /// Report messages whose sidecar appeared since the last poll
+    ///
+    /// Sidecars that have since been removed are forgotten, so a long watch
+    /// does not accumulate every message ever stored.
+    pub fn poll(&mut self) -> Result<Vec<WatchEvent>> {
+        let sidecars = self.sidecars()?;
+        self.seen.retain(|seen| sidecars.contains(seen));
+
+        let mut events = Vec::new();
+        for sidecar in sidecars {
+            if self.seen.contains(&sidecar) {
+                continue;
+            }
+            let message_path = message_path(&sidecar);
+            // A sidecar that does not parse yet may still be being written
+            let Ok(meta) = read_sidecar(&message_path) else {
+                continue;
+            };
+            self.seen.insert(sidecar);
+            events.push(WatchEvent {
+                saved_as: meta.saved_as,
+                size: meta.size,
+                peer: meta.peer,
+            });
+        }
+        events.sort_by(|a, b| a.saved_as.cmp(&b.saved_as));
+        Ok(events)
+    }
+
+    /// Print a line for each new message until `stop` completes
+    ///
+    /// The directory is created if it does not exist yet, since there is
+    /// nothing to watch otherwise.
+    pub async fn run<F: Future<Output = ()>>(mut self, stop: F) -> Result<()> {
+        let dir = self.dir.clone();
+        let watch_err = |e: notify::Error| AppError::Server(format!("Failed to watch {}: {}", dir.display(), e));
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| AppError::Server(format!("Failed to create {}: {}", self.dir.display(), e)))?;
+
+        // notify calls back on its own thread; every change just triggers a rescan
+        let (changed_tx, mut changed) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let _ = changed_tx.send(event);
+        })
+        .map_err(watch_err)?;
+        watcher.watch(&self.dir, RecursiveMode::NonRecursive).map_err(watch_err)?;
+
+        Output::info(&format!("Watching {} (Ctrl+C to stop)", self.dir.display()));
+        // Catch anything stored between `new` and the watch starting
+        self.report()?;
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                _ = &mut stop => return Ok(()),
+                event = changed.recv() => {
+                    let Some(event) = event else {
+                        return Err(AppError::Server(format!("Stopped receiving changes to {}", self.dir.display())));
+                    };
+                    event.map_err(watch_err)?;
+                    // One write raises several events; a single rescan covers them all
+                    while changed.try_recv().is_ok() {}
+                    self.report()?;
+                }
+            }
+        }
+    }
+
+    /// Print a line for each message found by [`MessageWatcher::poll`]
+    fn report(&mut self) -> Result<()> {
+        for event in self.poll()? {
+            Output::message_received(&event.peer, &format!("{} ({} bytes)", event.saved_as, event.size));
+        }
+        Ok(())
+    }
+
+    /// Sidecar files currently in the directory; a missing directory is empty
+    fn sidecars(&self) -> Result<HashSet<PathBuf>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => {
+                return Err(AppError::Server(format!("Failed to read {}: {}", self.dir.display(), e)));
+            }
+        };
+
+        let suffix = format!(".{}", SIDECAR_EXTENSION);
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.to_string_lossy().ends_with(&suffix))
+            .collect())
+    }
+}
+
+/// Stored message a sidecar belongs to
+fn message_path(sidecar: &Path) -> PathBuf {
+    let name = sidecar.to_string_lossy();
+    PathBuf::from(&name[..name.len() - SIDECAR_EXTENSION.len() - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MessageHeader;
+    use crate::protocol::MessageMeta;
+    use crate::server::storage::{sidecar_path, write_sidecar};
+
+    #[test]
+    fn test_new_message_emits_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("old.ftt");
+        let header = MessageHeader::new("old", 3, "abc");
+        fs::write(&old, b"old").unwrap();
+        write_sidecar(&old, &MessageMeta::new(&header, "old.ftt", 3, "10.0.0.1:4000")).unwrap();
+
+        let mut watcher = MessageWatcher::new(dir.path()).unwrap();
+        assert!(watcher.poll().unwrap().is_empty());
+
+        let new = dir.path().join("ledger.csv_20240101_120000.ftt");
+        fs::write(&new, b"ledger").unwrap();
+        // Not reported until the sidecar is written
+        assert!(watcher.poll().unwrap().is_empty());
+        let header = MessageHeader::new("ledger.csv", 6, "abc");
+        write_sidecar(&new, &MessageMeta::new(&header, "ledger.csv_20240101_120000.ftt", 6, "10.0.0.2:5000")).unwrap();
+
+        let events = watcher.poll().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].to_string(), "ledger.csv_20240101_120000.ftt (6 bytes) from 10.0.0.2:5000");
+        assert!(watcher.poll().unwrap().is_empty());
+
+        // Removed messages are forgotten
+        fs::remove_file(sidecar_path(&old)).unwrap();
+        assert!(watcher.poll().unwrap().is_empty());
+        assert_eq!(watcher.seen.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_reports_messages_as_they_are_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let messages_dir = dir.path().join("messages");
+        let watcher = MessageWatcher::new(&messages_dir).unwrap();
+        let (stop_tx, stop) = tokio::sync::oneshot::channel::<()>();
+
+        crate::cli::output::start_capture();
+        let watching = tokio::spawn(watcher.run(async {
+            stop.await.ok();
+        }));
+        // Let the watch start on the directory it creates
+        while !messages_dir.exists() {
+            tokio::task::yield_now().await;
+        }
+
+        let new = messages_dir.join("ledger.csv_20240101_120000.ftt");
+        fs::write(&new, b"ledger").unwrap();
+        let header = MessageHeader::new("ledger.csv", 6, "abc");
+        write_sidecar(&new, &MessageMeta::new(&header, "ledger.csv_20240101_120000.ftt", 6, "10.0.0.2:5000")).unwrap();
+
+        let mut lines = Vec::new();
+        tokio::time::timeout(std::time::Duration::from_secs(10), async {
+            while !lines.iter().any(|line: &String| line.contains("ledger.csv_20240101_120000.ftt (6 bytes)")) {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                lines.extend(crate::cli::output::take_captured());
+                crate::cli::output::start_capture();
+            }
+        })
+        .await
+        .unwrap();
+        crate::cli::output::take_captured();
+
+        stop_tx.send(()).unwrap();
+        watching.await.unwrap().unwrap();
+    }
+}
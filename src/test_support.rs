@@ -0,0 +1,161 @@
+//! Fixtures for tests written against this crate
+//!
+//! Enabled by the `testing` feature (and always in this crate's own unit
+//! tests). Every fixture owns the temporary directory its files live in, so
+//! keep it alive for as long as the test uses them.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use crate::auth::Whitelist;
+use crate::client::Client;
+use crate::crypto::KeyPair;
+use crate::error::Result;
+use crate::identity::{NodeIdentity, WHITELIST_FILE};
+use crate::server::{Server, ServerHandle, ShutdownReport};
+
+/// Connect key the server from [`spawn_test_server`] accepts
+pub const TEST_CONNECT_KEY: &str = "test-connect-key";
+
+/// Filename [`send_bytes_to`] sends its data under
+pub const TEST_FILENAME: &str = "test.bin";
+
+/// A freshly generated node identity in its own keys directory
+pub struct TempIdentity {
+    dir: TempDir,
+    identity: NodeIdentity,
+}
+
+impl TempIdentity {
+    /// Keys directory holding the identity, suitable for `--keys`
+    pub fn keys_dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// The identity itself
+    pub fn identity(&self) -> &NodeIdentity {
+        &self.identity
+    }
+}
+
+/// A whitelist file holding some connect keys
+pub struct TempWhitelist {
+    _dir: TempDir,
+    path: PathBuf,
+}
+
+impl TempWhitelist {
+    /// Path of the whitelist file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A server started by [`spawn_test_server`]
+pub struct TestServer {
+    dir: TempDir,
+    handle: ServerHandle,
+}
+
+impl TestServer {
+    /// Directory received messages are stored in
+    pub fn messages_dir(&self) -> PathBuf {
+        self.dir.path().join("messages")
+    }
+
+    /// The running server, e.g. to subscribe to received messages
+    pub fn handle(&self) -> &ServerHandle {
+        &self.handle
+    }
+
+    /// Stop the server and return its report
+    pub async fn shutdown(self) -> Result<ShutdownReport> {
+        self.handle.shutdown();
+        self.handle.wait().await
+    }
+
+    /// Let in-flight connections finish, then return the report
+    ///
+    /// Unlike [`shutdown`](Self::shutdown), every connection made so far is
+    /// counted in the report by the time this returns.
+    pub async fn drain(self) -> Result<ShutdownReport> {
+        self.handle.drain();
+        self.handle.wait().await
+    }
+}
+
+/// Generate a node identity in a new temporary keys directory
+pub async fn temp_identity() -> Result<TempIdentity> {
+    let dir = temp_dir()?;
+    let identity = NodeIdentity::regenerate_async(dir.path()).await?;
+    Ok(TempIdentity { dir, identity })
+}
+
+/// Write a whitelist accepting each of `keys` to a new temporary directory
+pub fn temp_whitelist(keys: &[&str]) -> Result<TempWhitelist> {
+    let dir = temp_dir()?;
+    let path = dir.path().join(WHITELIST_FILE);
+    let mut whitelist = Whitelist::create(&path)?;
+    for key in keys {
+        whitelist.add(key, None)?;
+    }
+    Ok(TempWhitelist { _dir: dir, path })
+}
+
+/// Start a server on an ephemeral loopback port accepting [`TEST_CONNECT_KEY`]
+///
+/// `configure` is applied to the server before it starts, e.g.
+/// `|server| server.with_max_connections(Some(1))`; pass `|server| server`
+/// for the defaults. Returns the address to connect to and the server,
+/// which stores messages in a temporary directory of its own.
+pub async fn spawn_test_server(configure: impl FnOnce(Server) -> Server) -> Result<(SocketAddr, TestServer)> {
+    let dir = temp_dir()?;
+    let whitelist_path = dir.path().join(WHITELIST_FILE);
+    Whitelist::create(&whitelist_path)?.add(TEST_CONNECT_KEY, None)?;
+    let messages_dir = dir.path().join("messages");
+    let server = Server::new(0, &whitelist_path, KeyPair::generate_async().await?, &messages_dir.to_string_lossy())?;
+    let handle = configure(server).spawn().await?;
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, handle.port()));
+    Ok((addr, TestServer { dir, handle }))
+}
+
+/// Send `data` to the server at `addr` with a throwaway client key
+///
+/// Returns the name the server stored the message under.
+pub async fn send_bytes_to(addr: SocketAddr, connect_key: &str, data: &[u8]) -> Result<String> {
+    let client = Client::new(&addr.ip().to_string(), addr.port(), KeyPair::generate_async().await?);
+    client.send_bytes(data, TEST_FILENAME, connect_key, None, None).await
+}
+
+fn temp_dir() -> Result<TempDir> {
+    Ok(tempfile::tempdir()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_spawned_server_accepts_and_stores() {
+        let (addr, server) = spawn_test_server(|server| server).await.unwrap();
+        let saved_as = send_bytes_to(addr, TEST_CONNECT_KEY, b"ledger").await.unwrap();
+        assert_eq!(std::fs::read(server.messages_dir().join(&saved_as)).unwrap(), b"ledger");
+
+        assert!(send_bytes_to(addr, "wrong-key", b"ledger").await.is_err());
+
+        let report = server.shutdown().await.unwrap();
+        assert_eq!(report.connections, 2);
+        assert_eq!(report.files_received, 1);
+    }
+
+    #[tokio::test]
+    async fn test_temp_identity_and_whitelist() {
+        let identity = temp_identity().await.unwrap();
+        assert!(NodeIdentity::exists(identity.keys_dir()));
+        assert_eq!(NodeIdentity::load(identity.keys_dir()).unwrap().fingerprint(), identity.identity().fingerprint());
+
+        let whitelist = Whitelist::load(temp_whitelist(&["alpha", "bravo"]).unwrap().path()).unwrap();
+        assert!(whitelist.contains("alpha") && whitelist.contains("bravo"));
+        assert!(!whitelist.contains("charlie"));
+    }
+}
@@ -0,0 +1,78 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use crate::error::{AppError, Result};
+
+/// Prefix selecting a Unix domain socket instead of a TCP address
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// A byte stream the protocol can run over (TCP or a Unix domain socket)
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// A connected stream of either kind
+pub type BoxedStream = Box<dyn Transport>;
+
+/// Open a stream to `target`, either `host:port` or `unix:/path/to.sock`
+pub async fn connect(target: &str) -> Result<BoxedStream> {
+    let connect_err = |e: io::Error| AppError::Client(format!("Failed to connect to {}: {}", target, e));
+
+    match target.strip_prefix(UNIX_PREFIX) {
+        #[cfg(unix)]
+        Some(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await.map_err(connect_err)?)),
+        #[cfg(not(unix))]
+        Some(_) => Err(AppError::Client("Unix domain sockets are not supported on this platform".to_string())),
+        None => Ok(Box::new(TcpStream::connect(target).await.map_err(connect_err)?)),
+    }
+}
+
+/// Something a server can accept connections from
+pub(crate) trait Acceptor {
+    /// Wait for the next connection, returning it with a description of the peer
+    async fn accept_stream(&self) -> io::Result<(BoxedStream, String)>;
+}
+
+impl Acceptor for TcpListener {
+    async fn accept_stream(&self) -> io::Result<(BoxedStream, String)> {
+        let (stream, peer) = self.accept().await?;
+        Ok((Box::new(stream), peer.to_string()))
+    }
+}
+
+#[cfg(unix)]
+impl Acceptor for tokio::net::UnixListener {
+    async fn accept_stream(&self) -> io::Result<(BoxedStream, String)> {
+        let (stream, _) = self.accept().await?;
+        // Unix peers are unnamed; describe them by the socket they came in on
+        let local = self.local_addr()?;
+        let peer = match local.as_pathname() {
+            Some(path) => format!("{}{}", UNIX_PREFIX, path.display()),
+            None => UNIX_PREFIX.trim_end_matches(':').to_string(),
+        };
+        Ok((Box::new(stream), peer))
+    }
+}
+
+/// Bind a Unix domain socket readable and writable only by the current user
+///
+/// A stale socket file left by a previous run is replaced; any other kind of
+/// file at `path` is an error.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(AppError::Server(format!("{} exists and is not a socket", path.display())));
+        }
+        std::fs::remove_file(path)
+            .map_err(|e| AppError::Server(format!("Failed to remove stale socket {}: {}", path.display(), e)))?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)
+        .map_err(|e| AppError::Server(format!("Failed to bind to {}: {}", path.display(), e)))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .map_err(|e| AppError::Server(format!("Failed to restrict {}: {}", path.display(), e)))?;
+    Ok(listener)
+}
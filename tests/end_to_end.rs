@@ -0,0 +1,22 @@
+use stl_finapp::server::storage::read_message;
+use stl_finapp::test_support::{spawn_test_server, send_bytes_to, TEST_CONNECT_KEY, TEST_FILENAME};
+
+#[tokio::test]
+async fn test_sent_bytes_arrive_with_metadata() {
+    let (addr, server) = spawn_test_server(|server| server).await.unwrap();
+    let mut received = server.handle().subscribe_received();
+
+    let payload = b"ledger,2024-01-01,100.00\n";
+    let saved_as = send_bytes_to(addr, TEST_CONNECT_KEY, payload).await.unwrap();
+    let message = received.recv().await.unwrap();
+    assert_eq!(message.filename, TEST_FILENAME);
+    assert_eq!(message.path, Some(server.messages_dir().join(&saved_as)));
+    assert_eq!(message.bytes_written, payload.len() as u64);
+
+    // Stored in plaintext, so any key pair reads it back
+    let reader = stl_finapp::crypto::KeyPair::generate().unwrap();
+    assert_eq!(read_message(message.path.as_ref().unwrap(), &reader).unwrap(), payload);
+
+    let report = server.shutdown().await.unwrap();
+    assert_eq!(report.files_received, 1);
+}